@@ -0,0 +1,131 @@
+//! Cost of reading records written at an older evolution, run with `cargo bench -p hills --bench upgrade`.
+//!
+//! Compares reads at the stored evolution with reads through HillsClient::add_upgrade and through an equivalent
+//! hand written HillsClient::add_migration.
+
+use hills::{HillsClient, TreeKey};
+use hills_base::{GenericKey, SimpleVersion, TreeRoot};
+use hills_derive::rkyv_common_derives;
+use std::time::{Duration, Instant};
+
+const RECORDS: usize = 10_000;
+
+#[rkyv_common_derives]
+struct NoteV1 {
+    title: String,
+    body: String,
+    tags: Vec<String>,
+}
+
+impl TreeRoot for NoteV1 {
+    fn tree_name() -> &'static str {
+        "notes"
+    }
+
+    fn evolution() -> SimpleVersion {
+        SimpleVersion::new(0, 1)
+    }
+
+    fn versioning() -> bool {
+        false
+    }
+}
+
+#[rkyv_common_derives]
+struct NoteV2 {
+    title: String,
+    body: String,
+    tags: Vec<String>,
+    #[reflect(default)]
+    stars: u32,
+}
+
+impl TreeRoot for NoteV2 {
+    fn tree_name() -> &'static str {
+        "notes"
+    }
+
+    fn evolution() -> SimpleVersion {
+        SimpleVersion::new(0, 2)
+    }
+
+    fn versioning() -> bool {
+        false
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct NoteKey(GenericKey);
+
+impl TreeKey for NoteKey {
+    fn tree_name() -> &'static str {
+        "notes"
+    }
+
+    fn from_generic(key: GenericKey) -> Self {
+        NoteKey(key)
+    }
+
+    fn to_generic(&self) -> GenericKey {
+        self.0
+    }
+}
+
+/// Database with RECORDS notes written at NoteV1.
+fn populated() -> (HillsClient, Vec<NoteKey>) {
+    let mut db = HillsClient::open_local_for_test();
+    let mut notes = db.open_tree::<NoteKey, NoteV1>("bench").unwrap();
+    let keys = (0..RECORDS)
+        .map(|i| {
+            notes
+                .insert(NoteV1 {
+                    title: format!("note {i}"),
+                    body: "Lorem ipsum dolor sit amet, consectetur adipiscing elit".repeat(4),
+                    tags: vec!["one".to_string(), "two".to_string()],
+                })
+                .unwrap()
+        })
+        .collect();
+    (db, keys)
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{name:>12}: {:>8.2?} per record, {RECORDS} records in {elapsed:.2?}",
+        elapsed / RECORDS as u32
+    );
+}
+
+fn main() {
+    let (mut db, keys) = populated();
+    let notes = db.open_tree::<NoteKey, NoteV1>("bench").unwrap();
+    let started = Instant::now();
+    for key in &keys {
+        std::hint::black_box(notes.get(*key).unwrap());
+    }
+    report("same", started.elapsed());
+
+    let (mut db, keys) = populated();
+    db.add_upgrade::<NoteV1, NoteV2>().unwrap();
+    let notes = db.open_tree::<NoteKey, NoteV2>("bench").unwrap();
+    let started = Instant::now();
+    for key in &keys {
+        std::hint::black_box(notes.get(*key).unwrap());
+    }
+    report("add_upgrade", started.elapsed());
+
+    let (mut db, keys) = populated();
+    db.add_migration::<NoteV1, NoteV2>(|note| NoteV2 {
+        title: note.title,
+        body: note.body,
+        tags: note.tags,
+        stars: 0,
+    })
+    .unwrap();
+    let notes = db.open_tree::<NoteKey, NoteV2>("bench").unwrap();
+    let started = Instant::now();
+    for key in &keys {
+        std::hint::black_box(notes.get(*key).unwrap());
+    }
+    report("add_migration", started.elapsed());
+}
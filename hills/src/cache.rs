@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::Waker;
+use std::thread::JoinHandle;
+
+use hills_base::{GenericKey, TreeKey, TreeRoot};
+use postage::prelude::Stream;
+use postage::stream::PollRecv;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{Archive, CheckBytes, Deserialize, Serialize};
+
+use crate::db::{Error, ThreadWaker, TypedTree};
+use crate::sync::ChangeKind;
+use crate::sync_client::ChangeNotification;
+
+/// Read-through cache of decoded records in front of a TypedTree, for UIs that get the same records over and over.
+///
+/// Least recently used records are evicted once capacity is reached. A record is also evicted as soon as it is
+/// changed or removed, locally or by another client, so that get never returns stale data.
+///
+/// Notifications are taken in by a background thread while the cache is not used, so that an idle cache never
+/// makes the sync task wait. If more records change meanwhile than the cache holds, all of them are dropped.
+pub struct CachedTree<K, V> {
+    tree: TypedTree<K, V>,
+    /// Shared with the drain thread
+    changes: Arc<Mutex<Changes>>,
+    _drain: DrainThread,
+    capacity: usize,
+    entries: HashMap<GenericKey, (Arc<V>, u64)>,
+    /// Last use -> key, oldest first
+    recency: BTreeMap<u64, GenericKey>,
+    last_use: u64,
+    stats: CacheStats,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Records dropped because the cache was full
+    pub evictions: u64,
+    /// Records dropped because they were changed or removed
+    pub invalidations: u64,
+}
+
+/// Records changed since the cache last looked.
+struct Changes {
+    updates_rx: postage::broadcast::Receiver<ChangeNotification>,
+    tree_name: Arc<String>,
+    keys: HashSet<GenericKey>,
+    /// More records changed than the cache holds, drop all of them instead
+    all: bool,
+    /// CachedTree was dropped, drain thread exits
+    closed: bool,
+    /// Most keys to remember before switching to all
+    limit: usize,
+}
+
+impl Changes {
+    fn take_in(&mut self, notification: ChangeNotification) {
+        let key = match notification {
+            ChangeNotification::Tree { key, kind } => {
+                // Data is the same, only meta changed
+                if matches!(kind, ChangeKind::ModifyMeta) || key.tree_name != self.tree_name {
+                    return;
+                }
+                GenericKey::new(key.id, key.revision)
+            }
+            ChangeNotification::GlobalIdAssigned {
+                tree_name,
+                temporary,
+                ..
+            } if tree_name == *self.tree_name => temporary,
+            _ => return,
+        };
+        if self.all {
+            return;
+        }
+        self.keys.insert(key);
+        if self.keys.len() > self.limit {
+            self.keys.clear();
+            self.all = true;
+        }
+    }
+}
+
+fn lock_changes(changes: &Mutex<Changes>) -> Result<MutexGuard<'_, Changes>, Error> {
+    changes
+        .lock()
+        .map_err(|_| Error::Internal("cache changes lock poisoned".to_string()))
+}
+
+/// Take notifications in as they arrive, until the cache is dropped or the sender is gone.
+fn drain(changes: Arc<Mutex<Changes>>) {
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = postage::Context::from_waker(&waker);
+    loop {
+        {
+            let Ok(mut changes) = changes.lock() else {
+                return;
+            };
+            if changes.closed {
+                return;
+            }
+            loop {
+                match Pin::new(&mut changes.updates_rx).poll_recv(&mut cx) {
+                    PollRecv::Ready(notification) => changes.take_in(notification),
+                    PollRecv::Pending => break,
+                    PollRecv::Closed => return,
+                }
+            }
+        }
+        std::thread::park();
+    }
+}
+
+impl<K, V> CachedTree<K, V>
+where
+    K: TreeKey + Debug,
+    V: TreeRoot + Archive + Serialize<AllocSerializer<128>>,
+    <V as Archive>::Archived:
+        Deserialize<V, rkyv::Infallible> + for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    /// Cache up to capacity records of the tree, 0 disables caching.
+    pub fn new(tree: TypedTree<K, V>, capacity: usize) -> Self {
+        let changes = Arc::new(Mutex::new(Changes {
+            updates_rx: tree.subscribe_updates(),
+            tree_name: tree.tree_name.clone(),
+            keys: HashSet::new(),
+            all: false,
+            closed: false,
+            limit: capacity,
+        }));
+        let thread = {
+            let changes = changes.clone();
+            std::thread::spawn(move || drain(changes))
+        };
+        CachedTree {
+            tree,
+            _drain: DrainThread {
+                changes: changes.clone(),
+                thread,
+            },
+            changes,
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            last_use: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn get(&mut self, key: K) -> Result<Arc<V>, Error> {
+        self.invalidate_changed()?;
+        let generic_key = key.to_generic();
+        self.last_use += 1;
+        if let Some((value, last_use)) = self.entries.get_mut(&generic_key) {
+            self.recency.remove(last_use);
+            *last_use = self.last_use;
+            self.recency.insert(self.last_use, generic_key);
+            self.stats.hits += 1;
+            return Ok(value.clone());
+        }
+        self.stats.misses += 1;
+        let value = Arc::new(self.tree.get(key)?);
+        if self.capacity == 0 {
+            return Ok(value);
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+                self.stats.evictions += 1;
+            }
+        }
+        self.entries
+            .insert(generic_key, (value.clone(), self.last_use));
+        self.recency.insert(self.last_use, generic_key);
+        Ok(value)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Number of cached records.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    /// Underlying tree, changes made through it are seen by the cache as well.
+    pub fn tree(&mut self) -> &mut TypedTree<K, V> {
+        &mut self.tree
+    }
+
+    pub fn into_inner(self) -> TypedTree<K, V> {
+        self.tree
+    }
+
+    fn invalidate_changed(&mut self) -> Result<(), Error> {
+        let mut changes = lock_changes(&self.changes)?;
+        // Changes made right before are not taken in by the drain thread yet
+        while let Ok(notification) = changes.updates_rx.try_recv() {
+            changes.take_in(notification);
+        }
+        if changes.all {
+            changes.all = false;
+            self.stats.invalidations += self.entries.len() as u64;
+            self.entries.clear();
+            self.recency.clear();
+        }
+        for key in changes.keys.drain() {
+            if let Some((_, last_use)) = self.entries.remove(&key) {
+                self.recency.remove(&last_use);
+                self.stats.invalidations += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stops the drain thread once the cache is dropped.
+struct DrainThread {
+    changes: Arc<Mutex<Changes>>,
+    thread: JoinHandle<()>,
+}
+
+impl Drop for DrainThread {
+    fn drop(&mut self) {
+        if let Ok(mut changes) = self.changes.lock() {
+            changes.closed = true;
+        }
+        self.thread.thread().unpark();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheStats, CachedTree};
+    use crate::db::tests::{Item, ItemKey};
+    use crate::HillsClient;
+    use postage::prelude::Stream;
+
+    fn item(name: &str) -> Item {
+        Item {
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn changed_records_are_not_served_from_cache() {
+        let mut db = HillsClient::open_local_for_test();
+        let items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let mut cached = CachedTree::new(items, 2);
+        let first = cached.tree().insert(item("first")).unwrap();
+        let second = cached.tree().insert(item("second")).unwrap();
+        let third = cached.tree().insert(item("third")).unwrap();
+
+        assert_eq!(cached.get(first).unwrap().name, "first");
+        assert_eq!(cached.get(first).unwrap().name, "first");
+        cached.get(second).unwrap();
+        // Evicts first, as the least recently used one
+        cached.get(third).unwrap();
+        assert_eq!(cached.len(), 2);
+        assert_eq!(
+            cached.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 3,
+                evictions: 1,
+                invalidations: 0,
+            }
+        );
+
+        cached.tree().check_out(second);
+        cached.tree().update(second, item("renamed")).unwrap();
+        assert_eq!(cached.get(second).unwrap().name, "renamed");
+        cached.tree().check_out(third);
+        cached.tree().remove(third).unwrap();
+        assert!(cached.get(third).is_err());
+        assert_eq!(cached.stats().invalidations, 2);
+    }
+
+    #[test]
+    fn idle_cache_does_not_hold_notifications_back() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let mut updates_rx = items.subscribe_updates();
+        let mut cached = CachedTree::new(db.open_tree::<ItemKey, Item>("").unwrap(), 2);
+        let first = items.insert(item("first")).unwrap();
+        cached.get(first).unwrap();
+
+        // More than the notification channel holds, none of them are taken by the cache meanwhile
+        let mut received = 0;
+        for i in 0..2000 {
+            items.insert(item(&format!("{i}"))).unwrap();
+            while updates_rx.try_recv().is_ok() {
+                received += 1;
+            }
+        }
+        items.check_out(first);
+        items.update(first, item("renamed")).unwrap();
+        while updates_rx.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 2002);
+        assert_eq!(cached.get(first).unwrap().name, "renamed");
+        assert_eq!(cached.stats().invalidations, 1);
+    }
+}
@@ -1,17 +1,28 @@
 use crate::consts::MANAGED_TREES;
+use hills_base::GenericKey;
+use log::warn;
 use rkyv::ser::serializers::{
     AllocScratchError, CompositeSerializerError, SharedSerializeMapError,
 };
 use rkyv::validation::validators::DefaultValidatorError;
 use rkyv::validation::CheckArchiveError;
 use rkyv::{check_archived_root, to_bytes, Archive, Deserialize, Serialize};
-use sled::Db;
+use sled::{Db, Tree};
 use std::collections::HashSet;
 use std::convert::Infallible;
 use std::fmt::Debug;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::error::CapacityError;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use uuid::Uuid;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error(transparent)]
     Sled(#[from] sled::Error),
@@ -19,8 +30,12 @@ pub enum Error {
     #[error("{}", .0)]
     Internal(String),
 
-    #[error("Ws")]
-    Ws,
+    #[error("ws stream failed")]
+    Ws(#[source] Box<tungstenite::Error>),
+
+    /// Received ws message or frame is bigger than allowed by WsLimits.
+    #[error("ws message of {size} bytes exceeds the limit of {max_size} bytes")]
+    MessageTooLarge { size: usize, max_size: usize },
 
     #[error("rkyv serialize: {}", .0)]
     RkyvSerializeError(String),
@@ -30,6 +45,10 @@ pub enum Error {
 
     #[error("broadcast channel error")]
     PostageBroadcast,
+
+    /// Remote end sent something that is not part of the protocol, e.g. a Text frame.
+    #[error("Protocol violation: {}", .0)]
+    Protocol(String),
 }
 
 impl From<CompositeSerializerError<Infallible, AllocScratchError, SharedSerializeMapError>>
@@ -48,12 +67,70 @@ impl<T: Debug> From<CheckArchiveError<T, DefaultValidatorError>> for Error {
     }
 }
 
+impl From<tungstenite::Error> for Error {
+    fn from(value: tungstenite::Error) -> Self {
+        match value {
+            tungstenite::Error::Capacity(CapacityError::MessageTooLong { size, max_size }) => {
+                Error::MessageTooLarge { size, max_size }
+            }
+            e => Error::Ws(Box::new(e)),
+        }
+    }
+}
+
 impl From<Infallible> for Error {
     fn from(_value: Infallible) -> Self {
         Error::RkyvDeserializeError("Infallible".into())
     }
 }
 
+/// Maximum sizes of received ws messages and frames, for both client and server.
+///
+/// Must be large enough for the biggest record and the biggest tree overview, bigger messages drop the connection
+/// with Error::MessageTooLarge.
+#[derive(Clone, Copy, Debug)]
+pub struct WsLimits {
+    pub max_message_size: usize,
+    pub max_frame_size: usize,
+}
+
+impl Default for WsLimits {
+    fn default() -> Self {
+        WsLimits {
+            max_message_size: 256 << 20,
+            max_frame_size: 64 << 20,
+        }
+    }
+}
+
+impl WsLimits {
+    pub(crate) fn ws_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.max_message_size),
+            max_frame_size: Some(self.max_frame_size),
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether a database outlives the client or server that opened it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Regular on-disk database
+    Persistent,
+    /// Database files are removed when it is dropped, for tests and scratch instances
+    Temporary,
+}
+
+impl OpenMode {
+    pub(crate) fn open(&self, path: &Path) -> sled::Result<Db> {
+        sled::Config::new()
+            .path(path)
+            .temporary(*self == OpenMode::Temporary)
+            .open()
+    }
+}
+
 #[derive(Archive, Serialize, Deserialize)]
 #[archive(check_bytes)]
 pub struct ManagedTrees {
@@ -97,3 +174,160 @@ impl ManagedTrees {
         }
     }
 }
+
+/// Name used when none was set or the set one is blank: "node-" followed by the first 8 hex digits of the uuid.
+pub(crate) fn default_readable_name(uuid: Uuid) -> String {
+    let simple = uuid.simple().to_string();
+    format!("node-{}", &simple[..8])
+}
+
+/// Returns record key if provided bytes are one.
+/// None is returned for internal keys (see INTERNAL_TREE_KEYS) and for malformed keys.
+pub(crate) fn record_key(key_bytes: &[u8]) -> Option<GenericKey> {
+    if key_bytes.len() != GenericKey::BYTES {
+        if !key_bytes.starts_with(b"_") {
+            warn!("Skipping malformed record key: {key_bytes:?}");
+        }
+        return None;
+    }
+    GenericKey::from_bytes(key_bytes)
+}
+
+/// Iterate over all record keys in a data tree, skipping internal and malformed keys.
+pub(crate) fn record_keys(tree: &Tree) -> impl DoubleEndedIterator<Item = GenericKey> {
+    record_keys_in(tree, GenericKey::range_all_ids())
+}
+
+/// Iterate over record keys within bounds made by GenericKey::id_range and friends, skipping internal and malformed keys.
+pub(crate) fn record_keys_in(
+    tree: &Tree,
+    bounds: impl RangeBounds<[u8; GenericKey::BYTES]>,
+) -> impl DoubleEndedIterator<Item = GenericKey> {
+    tree.range(bounds).keys().filter_map(|key| match key {
+        Ok(key) => record_key(&key),
+        Err(e) => {
+            warn!("Err while iterating over record keys: {e:?}");
+            None
+        }
+    })
+}
+
+/// Highest revision of every id, in id order. Revisions of an id are next to each other, see GenericKey::to_bytes.
+pub(crate) fn latest_record_keys(tree: &Tree) -> impl Iterator<Item = GenericKey> {
+    let mut keys = record_keys(tree).peekable();
+    std::iter::from_fn(move || {
+        let mut latest = keys.next()?;
+        while let Some(key) = keys.next_if(|key| key.id == latest.id) {
+            latest = key;
+        }
+        Some(latest)
+    })
+}
+
+/// Warns when dropped later than the threshold after creation, used to pinpoint stalled operations.
+pub(crate) struct SlowOpTimer {
+    started: Instant,
+    threshold: Duration,
+    tree: Arc<String>,
+    op: &'static str,
+    key: Option<GenericKey>,
+}
+
+impl SlowOpTimer {
+    pub(crate) fn start(
+        threshold: Option<Duration>,
+        tree: &Arc<String>,
+        op: &'static str,
+        key: Option<GenericKey>,
+    ) -> Option<Self> {
+        threshold.map(|threshold| SlowOpTimer {
+            started: Instant::now(),
+            threshold,
+            tree: tree.clone(),
+            op,
+            key,
+        })
+    }
+}
+
+impl Drop for SlowOpTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        if elapsed > self.threshold {
+            match self.key {
+                Some(key) => warn!("{}/{key}: {} took {elapsed:?}", self.tree, self.op),
+                None => warn!("{}: {} took {elapsed:?}", self.tree, self.op),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_key, record_keys, record_keys_in, OpenMode};
+    use crate::consts::{INTERNAL_TREE_KEYS, KEY_POOL};
+    use hills_base::GenericKey;
+
+    #[test]
+    fn internal_and_malformed_keys_skipped() {
+        let key = GenericKey::new(0x5f5f_5f5f, 7);
+        assert_eq!(record_key(&key.to_bytes()), Some(key));
+        assert_eq!(record_key(KEY_POOL), None);
+        assert_eq!(record_key(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn scans_exclude_key_pool() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("t").unwrap();
+        tree.insert(KEY_POOL, &[]).unwrap();
+        // Id that shares first bytes with KEY_POOL, so that it is within the same byte bounds
+        let colliding = GenericKey::from_bytes(b"_key_poo").unwrap();
+        let keys = [
+            GenericKey::new(1, 0),
+            colliding,
+            GenericKey::new(u32::MAX, 0),
+        ];
+        for key in keys {
+            tree.insert(key.to_bytes(), &[]).unwrap();
+        }
+        assert_eq!(record_keys(&tree).collect::<Vec<_>>(), keys);
+        let revisions = GenericKey::all_revisions_of(colliding.id);
+        assert_eq!(
+            record_keys_in(&tree, revisions).collect::<Vec<_>>(),
+            [colliding]
+        );
+        let ids = GenericKey::id_range(colliding.id..u32::MAX);
+        assert_eq!(record_keys_in(&tree, ids).next_back(), Some(colliding));
+    }
+
+    #[test]
+    fn internal_keys_never_collide_with_records() {
+        for internal_key in INTERNAL_TREE_KEYS {
+            assert_eq!(record_key(internal_key), None);
+            assert_eq!(GenericKey::from_bytes(internal_key), None);
+        }
+        for key in [
+            GenericKey::new(0, 0),
+            GenericKey::new(u32::MAX, u32::MAX),
+            GenericKey::from_bytes(b"_key_poo").unwrap(),
+        ] {
+            let key_bytes = key.to_bytes();
+            assert!(!INTERNAL_TREE_KEYS.contains(&key_bytes.as_slice()));
+        }
+    }
+
+    #[test]
+    fn open_mode_decides_if_files_remain() {
+        for (mode, remains) in [(OpenMode::Persistent, true), (OpenMode::Temporary, false)] {
+            let path =
+                std::env::temp_dir().join(format!("hills_open_mode_{}", uuid::Uuid::new_v4()));
+            let db = mode.open(&path).unwrap();
+            db.insert(b"k", b"v").unwrap();
+            db.flush().unwrap();
+            drop(db);
+            assert_eq!(path.exists(), remains, "{mode:?}");
+            let _ = std::fs::remove_dir_all(&path);
+        }
+    }
+}
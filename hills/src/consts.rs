@@ -1,11 +1,88 @@
+use hills_base::GenericKey;
+use std::ops::Range;
+
 pub const SELF_UUID: &[u8] = b"_self_uuid";
 pub const SERVER_UUID: &[u8] = b"_server_uuid";
+/// Set when the database is unlinked from its server and cleared once the next one took over its ids,
+/// see HillsClient::unlink_server and Event::Rehomed.
+pub const REHOMED: &[u8] = b"_rehomed";
 pub const READABLE_NAME: &[u8] = b"_readable_name";
 pub const MANAGED_TREES: &[u8] = b"_managed_trees";
+pub const PENDING_KEY_REQUESTS: &[u8] = b"_pending_key_requests";
+
+/// Key pool is stored in the same sled tree as records.
 pub const KEY_POOL: &[u8] = b"_key_pool";
+/// Next id to give out to a record created while the key pool is empty, see temporary::next_temporary_id.
+pub const NEXT_TEMPORARY_ID: &[u8] = b"_next_temporary_id";
+/// Present in data trees whose changes are journaled, see HillsClient::enable_journal.
+pub const JOURNALED: &[u8] = b"_journaled";
+
+/// All internal keys that are stored alongside records in data trees.
+/// Record keys are always GenericKey::to_bytes() and exactly 8 bytes long, internal keys must start with '_'
+/// and never be 8 bytes long, so that they cannot collide with any record.
+pub const INTERNAL_TREE_KEYS: &[&[u8]] = &[KEY_POOL, NEXT_TEMPORARY_ID, JOURNALED];
+
+const _: () = {
+    let mut i = 0;
+    while i < INTERNAL_TREE_KEYS.len() {
+        let key = INTERNAL_TREE_KEYS[i];
+        assert!(
+            key.len() != GenericKey::BYTES,
+            "internal tree key cannot be 8 bytes long"
+        );
+        assert!(key[0] == b'_', "internal tree key must start with '_'");
+        i += 1;
+    }
+};
+
+/// Version of the sync protocol, asked for by clients as the websocket subprotocol and required by servers.
+/// Layout of any existing Event changes with a new version, peers of different versions refuse to connect
+/// instead of failing to decode each other's messages, see sync_common::connect_ws and accept_ws.
+pub const PROTOCOL_VERSION: &str = "hills-sync-2";
+
+/// Optional protocol features supported by this build, sent in PresentSelf.
+/// Features are only used on a connection if both sides list them, see sync_common::negotiate_capabilities.
+/// Everything in the current Event set is part of PROTOCOL_VERSION, only additions that older builds of the same
+/// version can do without belong here.
+pub const CAPABILITIES: &[&str] = &[CLIENT_IDS_CAPABILITY, REHOME_CAPABILITY];
+/// Client declares trees whose ids are chosen by clients with Event::ClientIdTree, server accepts records created
+/// with ids in CLIENT_IDS only in such trees and only from clients that support it.
+pub const CLIENT_IDS_CAPABILITY: &str = "client-ids";
+/// Client unlinked from another server announces it with Event::Rehomed, server answers with Event::IdsTaken
+/// for the ids of its records that were already issued here.
+pub const REHOME_CAPABILITY: &str = "rehome";
 
+/// Keys asked for in one GetKeySet by default, see KeyRequests.
 pub const KEYS_PER_REQUEST: u32 = 1000;
+/// Most keys the server issues for one GetKeySet, larger requests are cut down to it.
+pub const MAX_KEYS_PER_REQUEST: u32 = 100_000;
+/// Default of ClientConfig::reserved_ceiling and ServerConfig::reserved_ceiling, ids below it are never issued by
+/// the server and are reserved for well-known records, see TypedTree::insert_at.
+pub const RESERVED_CEILING: u32 = 1024;
+/// Ids at or above this value are never issued, server answers with KeysExhausted instead of wrapping around.
+/// Ids from here up to KEY_ID_CEILING are chosen by clients, see IdStrategy.
+pub const CLIENT_ID_FLOOR: u32 = 0x8000_0000;
+/// Ids from here up to u32::MAX are temporary ones, given to records created offline until the server assigns global ids.
+pub const KEY_ID_CEILING: u32 = 0xF000_0000;
+/// Ids of trees with IdStrategy::ClientHash or IdStrategy::Explicit.
+pub const CLIENT_IDS: Range<u32> = CLIENT_ID_FLOOR..KEY_ID_CEILING;
 
 pub const CLIENTS_TREE: &str = "_clients";
+/// When each client last connected or disconnected, kept apart from CLIENTS_TREE so that its layout stays the same.
+pub const LAST_SEEN_TREE: &str = "_last_seen";
 pub const DESCRIPTORS_TREE: &str = "_descriptors";
 pub const REMOVED_RECORDS_TREE: &str = "_removed_records";
+/// Trees declared by clients as having client chosen ids, see Event::ClientIdTree.
+pub const CLIENT_ID_TREES_TREE: &str = "_client_id_trees";
+/// Unused key ranges taken back from pruned clients, re-issued before advancing next_key, see HillsServer::prune_clients.
+pub const RECLAIMED_KEYS_TREE: &str = "_reclaimed_keys";
+/// Ids at or past next_key that are used by records moved over from another server, never issued by issue_key_block.
+pub const CLAIMED_KEYS_TREE: &str = "_claimed_keys";
+/// Local changes not yet sent to the server, see PendingChanges.
+pub const PENDING_CHANGES_TREE: &str = "_pending_changes";
+/// Global ids assigned to records created offline, tree name + '/' + temporary id -> global id.
+pub const TEMPORARY_IDS_TREE: &str = "_temporary_ids";
+/// Prefix of the trees that hold disk backed indexes, followed by the index name, see SledNamedIndex.
+pub const INDEX_TREE_PREFIX: &str = "_index/";
+/// Prefix of the trees that hold journals of data trees, followed by the tree name, see journal::Journal.
+pub const JOURNAL_TREE_PREFIX: &str = "_journal/";
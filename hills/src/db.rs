@@ -1,36 +1,61 @@
-use crate::common::ManagedTrees;
-use crate::consts::{DESCRIPTORS_TREE, KEY_POOL, READABLE_NAME, SELF_UUID};
-use crate::index::{TreeIndex, TypeErasedTree};
-use crate::key_pool::{ArchivedKeyPool, KeyPool};
+use crate::clock::{Clock, SystemClock};
+use crate::common::{
+    latest_record_keys, record_key, record_keys, record_keys_in, ManagedTrees, OpenMode,
+    SlowOpTimer,
+};
+use crate::consts::{
+    CLIENT_IDS, CLIENT_ID_FLOOR, DESCRIPTORS_TREE, INDEX_TREE_PREFIX, JOURNALED,
+    PENDING_CHANGES_TREE, READABLE_NAME, RESERVED_CEILING, SELF_UUID, TEMPORARY_IDS_TREE,
+};
+use crate::index::{
+    Action, IndexChange, IndexData, IndexStat, TreeIndex, TypeErasedTree, UniqueIndex,
+};
+use crate::journal::{self, Action as JournalAction, Journal};
+use crate::key_pool::{hashed_id, KeyPool};
 use crate::opaque::OpaqueKey;
-use crate::record::{ArchivedVersion, RecordMeta};
-use crate::record::{Record, Version};
-use crate::sync::{ChangeKind, RecordBorrows, RecordHotChange};
+use crate::pending::{PendingChange, PendingChanges};
+use crate::record::{ArchivedRecord, ArchivedRecordMeta, ArchivedVersion, RecordMeta};
+use crate::record::{Record, RecordHeader, Version};
+use crate::sync::{ChangeKind, RecordBorrows, RecordHotChange, TreeSchema};
+#[cfg(any(test, feature = "test-util"))]
+use crate::sync_client::start_local;
 use crate::sync_client::{
-    ChangeNotification, SyncClientCommand, SyncClientTelemetry, SyncHandle, VhrdDbCmdTx,
+    forget_server, load_server_uuid, ChangeNotification, CheckOutReply, KeyRequests,
+    ReconnectBackoff, SyncClientCommand, SyncClientTelemetry, SyncHandle, SyncSummary, VhrdDbCmdTx,
 };
-use crate::tree::{ArchivedTreeDescriptor, TreeDescriptor};
+use crate::temporary::{global_id, is_temporary, next_temporary_id};
+use crate::tree::{TreeDescriptor, TreeStats};
 use crate::VhrdDbTelem;
-use chrono::Utc;
-use hills_base::{Evolving, GenericKey, Reflect, SimpleVersion, TreeKey, TreeRoot, TypeCollection};
-use log::{error, info, trace, warn};
+use hills_base::{
+    is_backwards_compatible, Evolving, GenericKey, IdStrategy, Reflect, SimpleVersion, TreeKey,
+    TreeRoot, TypeCollection, TypeInfo,
+};
+use log::{debug, error, info, trace, warn};
 use postage::prelude::Sink;
 use rkyv::ser::serializers::{
     AllocScratchError, AllocSerializer, CompositeSerializerError, SharedSerializeMapError,
 };
 use rkyv::validation::validators::{DefaultValidator, DefaultValidatorError};
 use rkyv::validation::CheckArchiveError;
-use rkyv::{check_archived_root, to_bytes, Archive, CheckBytes, Deserialize, Serialize};
-use sled::transaction::{ConflictableTransactionError, TransactionError};
+use rkyv::{
+    check_archived_root, to_bytes, AlignedVec, Archive, CheckBytes, Deserialize, Serialize,
+};
+use serde::de::DeserializeOwned;
+use sled::transaction::TransactionError;
 use sled::{Db, Tree};
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::convert::Infallible;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::io::{Read, Write};
 use std::marker::PhantomData;
 use std::net::IpAddr;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::runtime::Runtime;
 use tokio::sync::RwLock;
@@ -41,19 +66,88 @@ pub struct HillsClient {
     db: Db,
     self_uuid: Uuid,
     descriptors: Tree,
+    /// Local changes not yet sent to the server
+    pending: Tree,
+    /// Global ids assigned to records created offline
+    temporary_ids: Tree,
     open_trees: HashMap<String, RawTreeBundle>,
+    /// Tree name -> conversions registered with add_migration, keyed by the evolution they convert from
+    migrations: HashMap<String, HashMap<SimpleVersion, Migration>>,
     cmd_tx: VhrdDbCmdTx,
     updates_tx: postage::broadcast::Sender<ChangeNotification>,
     borrows: Arc<RwLock<RecordBorrows>>,
     pub telem: VhrdDbTelem,
+    /// Created with open_local_for_test, not connected to anything
+    #[cfg(any(test, feature = "test-util"))]
+    local: bool,
+    slow_op_threshold: Option<Duration>,
+    cmd_timeout: Duration,
+    /// Source of record timestamps
+    clock: Arc<dyn Clock>,
+    /// See ClientConfig::reserved_ceiling
+    reserved_ceiling: u32,
+    /// Address of the last connect, used by sync_and_quiesce to reconnect
+    server_addr: Option<(IpAddr, u16)>,
+}
+
+/// Tunables for HillsClient::open_with_config.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Number of commands (changes, check outs, etc.) that can be queued for the sync task
+    pub command_capacity: usize,
+    /// How long to wait for a spot in a full command queue before giving up with Error::SyncBusy
+    pub command_send_timeout: Duration,
+    pub ws_limits: WsLimits,
+    /// Delays between attempts to reconnect after the connection to the server is lost
+    pub reconnect: ReconnectBackoff,
+    /// Size of key batches requested from the server and how low the pool gets before asking for more
+    pub key_requests: KeyRequests,
+    /// How often records held by this client are renewed, so that the server does not take them away,
+    /// must be well below ServerConfig::check_out_lease
+    pub check_out_renewal: Duration,
+    /// Source of record timestamps, a MockClock makes them deterministic in tests
+    pub clock: Arc<dyn Clock>,
+    /// Ids below this value are left for TypedTree::insert_at and never taken from the key pool,
+    /// must be the same as ServerConfig::reserved_ceiling of the server
+    pub reserved_ceiling: u32,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            command_capacity: 64,
+            command_send_timeout: Duration::from_secs(5),
+            ws_limits: WsLimits::default(),
+            reconnect: ReconnectBackoff::default(),
+            key_requests: KeyRequests::default(),
+            check_out_renewal: Duration::from_secs(20),
+            clock: Arc::new(SystemClock),
+            reserved_ceiling: RESERVED_CEILING,
+        }
+    }
+}
+
+/// Converts serialized Evolving data of one evolution into another one.
+type MigrateFn = Arc<dyn Fn(&[u8]) -> Result<AlignedVec, Error> + Send + Sync>;
+
+#[derive(Clone)]
+struct Migration {
+    to: SimpleVersion,
+    migrate: MigrateFn,
 }
 
 #[derive(Clone)]
 struct RawTreeBundle {
     /// Key -> Record tree
     data: Tree,
+    /// Evolution of the code that opened the tree
+    evolution: SimpleVersion,
     versioning: bool,
     indexers: Vec<Box<dyn TreeIndex>>,
+    /// Held while a record is looked up and written, see TypedTree::upsert_by
+    write_lock: Arc<Mutex<()>>,
+    /// Present if changes are journaled, see HillsClient::enable_journal
+    journal: Option<Tree>,
 }
 
 #[derive(Clone)]
@@ -62,6 +156,10 @@ pub struct TypedTree<K, V> {
     pub(crate) data: Tree,
 
     pub(crate) tree_name: Arc<String>,
+    /// Local changes not yet sent to the server, shared by all trees
+    pending: Tree,
+    /// Global ids assigned to records created offline, shared by all trees
+    temporary_ids: Tree,
     uuid: Uuid,
     username: String,
     versioning: bool,
@@ -72,13 +170,45 @@ pub struct TypedTree<K, V> {
     updates_tx: postage::broadcast::Sender<ChangeNotification>,
 
     indexers: Vec<Box<dyn TreeIndex>>,
+    /// Shared by all handles of the tree, see RawTreeBundle
+    write_lock: Arc<Mutex<()>>,
+    /// Present if changes are journaled, see HillsClient::enable_journal
+    journal: Option<Tree>,
+    migrations: Arc<HashMap<SimpleVersion, Migration>>,
     borrows: Arc<RwLock<RecordBorrows>>,
+    #[cfg(any(test, feature = "test-util"))]
+    local: bool,
+    /// Operations taking longer than this are logged with a warning
+    slow_op_threshold: Option<Duration>,
+    cmd_timeout: Duration,
+    /// Source of record timestamps
+    clock: Arc<dyn Clock>,
+    /// See ClientConfig::reserved_ceiling
+    reserved_ceiling: u32,
 
     _phantom_k: PhantomData<K>,
     _phantom_v: PhantomData<V>,
 }
 
-// TODO: Make one common error?
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeyOrValue {
+    Key,
+    Value,
+}
+
+impl Display for KeyOrValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyOrValue::Key => write!(f, "Key"),
+            KeyOrValue::Value => write!(f, "Value"),
+        }
+    }
+}
+
+/// Error returned by HillsClient and the trees.
+///
+/// Failures of the storage and sync layers are wrapped in [Error::Common] and index failures in [Error::Index],
+/// both keep the original error as the source instead of a stringified copy.
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
@@ -90,9 +220,38 @@ pub enum Error {
     #[error("{}", .0)]
     Internal(String),
 
+    #[error("Database at {} is already opened by another HillsClient or process", .0.display())]
+    DbLocked(PathBuf),
+
     #[error("Tree {} not found", .0)]
     TreeNotFound(String),
 
+    #[error("Tree {} was not opened", .0)]
+    TreeNotOpened(String),
+
+    #[error("Descriptor of tree {tree} cannot be decoded")]
+    DescriptorDecode {
+        tree: String,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error("Stored self uuid is {} bytes long instead of 16", .0)]
+    InvalidSelfUuid(usize),
+
+    #[error("Failed to parse RON")]
+    RonParse(#[from] ron::error::SpannedError),
+
+    #[error("Failed to serialize RON")]
+    RonSerialize(#[from] ron::Error),
+
+    #[cfg(feature = "gateway")]
+    #[error("JSON: {}", .0)]
+    Json(#[from] serde_json::Error),
+
+    #[error("Transaction aborted: {}", .0)]
+    TransactionAborted(String),
+
     #[error("check_archived_root failed: {}", .0)]
     RkyvCheckArchivedRoot(String),
 
@@ -105,11 +264,13 @@ pub enum Error {
     #[error("Evolution mismatch: {}", .0)]
     EvolutionMismatch(String),
 
-    #[error("Key from tree {} used with another tree {}", .0, .1)]
-    WrongKey(String, String),
-
-    #[error("Value from tree {} used with another tree {}", .0, .1)]
-    WrongValue(String, String),
+    /// Key or value belonging to one tree was used with another.
+    #[error("{what} from tree {got} used with tree {expected}")]
+    TreeMismatch {
+        expected: String,
+        got: String,
+        what: KeyOrValue,
+    },
 
     #[error("{}", .0)]
     VersioningMismatch(String),
@@ -120,11 +281,35 @@ pub enum Error {
     #[error("Mpsc send failed")]
     Mpsc,
 
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Malformed export stream: {}", .0)]
+    MalformedExport(String),
+
+    #[error("Sync task did not accept a command in time, try again later")]
+    SyncBusy,
+
     #[error("Provided key is not in the tree")]
     RecordNotFound,
 
+    #[error("Request was not answered, not connected to the server")]
+    NotConnected,
+
+    #[error("Record is checked out by {}", .0)]
+    CheckedOutByOther(Uuid),
+
+    #[error("Server refused the request: {}", .0)]
+    Refused(String),
+
+    #[error("Timed out after {:?}", .0)]
+    Timeout(Duration),
+
     #[error(transparent)]
     Index(#[from] hills_base::index::IndexError),
+
+    #[error(transparent)]
+    Common(#[from] CommonError),
 }
 
 impl From<CompositeSerializerError<Infallible, AllocScratchError, SharedSerializeMapError>>
@@ -152,41 +337,54 @@ impl From<Infallible> for Error {
 impl<T: Debug> From<TransactionError<T>> for Error {
     fn from(value: TransactionError<T>) -> Self {
         match value {
-            TransactionError::Abort(e) => Error::Internal(format!("{e:?}")),
+            TransactionError::Abort(e) => Error::TransactionAborted(format!("{e:?}")),
             TransactionError::Storage(e) => Error::Sled(e),
         }
     }
 }
 
-use crate::common::Error as CommonError;
-impl From<CommonError> for Error {
-    fn from(value: CommonError) -> Self {
-        match value {
-            CommonError::Sled(e) => Error::Sled(e),
-            CommonError::Internal(e) => Error::Internal(e),
-            CommonError::Ws => Error::Internal("common::Error::Ws".to_string()),
-            CommonError::RkyvSerializeError(e) => Error::RkyvSerializeError(e),
-            CommonError::RkyvDeserializeError(e) => Error::RkyvDeserializeError(e),
-            CommonError::PostageBroadcast => Error::Internal("postage broadcasr".into()),
-        }
-    }
+use crate::common::{default_readable_name, Error as CommonError, WsLimits};
+
+/// Key and meta information of a freshly inserted record.
+pub struct Inserted<K> {
+    pub key: K,
+    pub meta: RecordMeta,
 }
 
 /// Holds information about record's borrow state.
 pub enum RecordCheckOutState {
     /// Record is currently not checked out by any client
     Empty,
-    /// Record is checked out by .0 and maybe others, this client is waiting as well
-    WaitingFor(Uuid),
+    /// Record is checked out by .0 and maybe others, this client is waiting as well at position .1 in the queue
+    /// (holder is at 0, so 1 means this client is next in line)
+    WaitingFor(Uuid, usize),
     /// Record is checked out by .0 and maybe others, this client is not waiting
     CheckedOutBy(Uuid),
     /// Record is borrowed by this client and could be modified
     CheckedOut,
 }
 
+/// Place of this client in a record's check out queue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueuedCheckOut {
+    /// Client currently holding the record
+    pub holder: Uuid,
+    /// Index in the queue, holder is at 0, so 1 means this client is next in line
+    pub position: usize,
+    /// Clients ahead of this one, starting with the holder
+    pub ahead: Vec<Uuid>,
+}
+
 impl HillsClient {
+    /// Open or create a database at the provided path and start synchronisation task.
+    ///
+    /// Only one HillsClient can use a path at a time, open it once and pass TypedTree handles around instead.
+    /// Error::DbLocked is returned if the path is already in use, see also HillsClient::is_locked.
+    ///
+    /// With OpenMode::Temporary the database files are removed once the client is dropped.
     pub fn open<P: AsRef<Path>>(
         path: P,
+        mode: OpenMode,
         rt: &Runtime,
     ) -> Result<
         (
@@ -196,72 +394,381 @@ impl HillsClient {
         ),
         Error,
     > {
-        #[cfg(not(test))]
-        let db = sled::open(path)?;
-        #[cfg(test)]
-        let db = sled::Config::new().temporary(true).path(path).open()?;
-        let descriptors = db.open_tree(DESCRIPTORS_TREE)?;
+        Self::open_with_config(path, mode, rt, ClientConfig::default())
+    }
 
-        let self_uuid = match db.get(SELF_UUID)? {
-            Some(uuid_bytes) => {
-                if uuid_bytes.len() != 16 {
-                    return Err(Error::Internal("Invalid self_uuid".into()));
-                }
-                let mut uuid = [0u8; 16];
-                uuid[..].copy_from_slice(&uuid_bytes);
-                let uuid = Uuid::from_bytes(uuid);
-                trace!("Self uuid is {uuid}");
-                uuid
-            }
-            None => {
-                let uuid = Uuid::new_v4();
-                trace!("Created new db, uuid={uuid}");
-                let uuid_bytes = uuid.into_bytes();
-                db.insert(SELF_UUID, &uuid_bytes)?;
-                uuid
-            }
+    /// Whether a database at the provided path is currently opened by a HillsClient in this or another process,
+    /// e.g. to show "already running" before opening it. False if there is no database at the path.
+    pub fn is_locked<P: AsRef<Path>>(path: P) -> bool {
+        // sled holds an exclusive lock on this file for as long as the database is open
+        let Ok(file) = std::fs::File::open(path.as_ref().join("db")) else {
+            return false;
         };
+        matches!(file.try_lock(), Err(std::fs::TryLockError::WouldBlock))
+    }
+
+    /// Same as open, but with non-default tunables.
+    pub fn open_with_config<P: AsRef<Path>>(
+        path: P,
+        mode: OpenMode,
+        rt: &Runtime,
+        config: ClientConfig,
+    ) -> Result<
+        (
+            HillsClient,
+            postage::broadcast::Receiver<ChangeNotification>,
+            JoinHandle<()>,
+        ),
+        Error,
+    > {
+        let path = path.as_ref();
+        // sled reports a held lock only as an ErrorKind::Other with a message, so probe the lock before opening.
+        // This also keeps a failed Temporary open from removing the files of the database that holds the lock.
+        if Self::is_locked(path) {
+            return Err(Error::DbLocked(path.to_path_buf()));
+        }
+        let db = mode.open(path)?;
+        Self::start(db, rt, config)
+    }
+
+    /// Start synchronisation task for an opened database.
+    fn start(
+        db: Db,
+        rt: &Runtime,
+        config: ClientConfig,
+    ) -> Result<
+        (
+            HillsClient,
+            postage::broadcast::Receiver<ChangeNotification>,
+            JoinHandle<()>,
+        ),
+        Error,
+    > {
+        let descriptors = db.open_tree(DESCRIPTORS_TREE)?;
+        let pending = db.open_tree(PENDING_CHANGES_TREE)?;
+        let temporary_ids = db.open_tree(TEMPORARY_IDS_TREE)?;
+
+        let self_uuid = load_or_create_self_uuid(&db)?;
+        ensure_readable_name(&db, self_uuid)?;
 
-        let sync_handle = SyncHandle::new(db.clone());
+        let sync_handle = SyncHandle::new(db.clone(), self_uuid);
         let (updates_tx, updates_rx) = postage::broadcast::channel(1024);
         let borrows = Arc::new(RwLock::new(RecordBorrows::default()));
         let (cmd_tx, telem, syncer_join) =
-            sync_handle.start(rt, updates_tx.clone(), borrows.clone());
+            sync_handle.start(rt, config.clone(), updates_tx.clone(), borrows.clone());
         Ok((
             HillsClient {
                 db,
                 self_uuid,
                 descriptors,
+                pending,
+                temporary_ids,
                 open_trees: HashMap::default(),
+                migrations: HashMap::default(),
                 cmd_tx,
                 updates_tx,
                 borrows,
                 telem,
+                #[cfg(any(test, feature = "test-util"))]
+                local: false,
+                slow_op_threshold: None,
+                cmd_timeout: config.command_send_timeout,
+                clock: config.clock,
+                reserved_ceiling: config.reserved_ceiling,
+                server_addr: None,
             },
             updates_rx,
             syncer_join,
         ))
     }
 
+    /// Open a temporary database without a sync task and without tokio runtime, for tests that only exercise
+    /// local operations (insert, get, indexes, etc).
+    ///
+    /// Each new tree gets all the non-reserved keys and check outs are granted immediately, as if this client
+    /// was alone on a server.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn open_local_for_test() -> HillsClient {
+        Self::open_local_for_test_with_config(ClientConfig::default())
+    }
+
+    /// Same as open_local_for_test, but with non-default tunables, e.g. a MockClock.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn open_local_for_test_with_config(config: ClientConfig) -> HillsClient {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("open temporary db");
+        let descriptors = db
+            .open_tree(DESCRIPTORS_TREE)
+            .expect("open descriptors tree");
+        let pending = db
+            .open_tree(PENDING_CHANGES_TREE)
+            .expect("open pending changes tree");
+        let temporary_ids = db
+            .open_tree(TEMPORARY_IDS_TREE)
+            .expect("open temporary ids tree");
+        let self_uuid = load_or_create_self_uuid(&db).expect("create self uuid");
+        ensure_readable_name(&db, self_uuid).expect("set readable name");
+        let (updates_tx, _) = postage::broadcast::channel(1024);
+        let borrows = Arc::new(RwLock::new(RecordBorrows::default()));
+        let (cmd_tx, telem) = start_local();
+        HillsClient {
+            db,
+            self_uuid,
+            descriptors,
+            pending,
+            temporary_ids,
+            open_trees: HashMap::default(),
+            migrations: HashMap::default(),
+            cmd_tx,
+            updates_tx,
+            borrows,
+            telem,
+            local: true,
+            slow_op_threshold: None,
+            cmd_timeout: config.command_send_timeout,
+            clock: config.clock,
+            reserved_ceiling: config.reserved_ceiling,
+            server_addr: None,
+        }
+    }
+
+    /// Whether this client was opened with open_local_for_test, never the case without the test-util feature.
+    #[cfg(any(test, feature = "test-util"))]
+    fn is_local(&self) -> bool {
+        self.local
+    }
+
+    #[cfg(not(any(test, feature = "test-util")))]
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    /// Local changes that were not yet sent to the server, at most one per record (the latest one).
+    pub fn pending_changes(&self) -> Result<Vec<PendingChange>, Error> {
+        Ok(PendingChanges::all(&self.pending)?)
+    }
+
+    /// Size of every index added to the open trees, sorted by tree name.
+    /// Most indexes are kept in memory, large ones might be better replaced by a SledNamedIndex or disabled.
+    pub fn index_stats(&self) -> Vec<IndexStat> {
+        let mut stats: Vec<IndexStat> = self
+            .open_trees
+            .iter()
+            .flat_map(|(tree_name, bundle)| {
+                bundle.indexers.iter().map(|indexer| IndexStat {
+                    tree_name: tree_name.clone(),
+                    kind: indexer.kind(),
+                    len: indexer.len(),
+                    memory_bytes: indexer.memory_bytes(),
+                })
+            })
+            .collect();
+        stats.sort_by(|a, b| a.tree_name.cmp(&b.tree_name));
+        stats
+    }
+
+    /// Save the indexes that keep a copy on disk (e.g. NamedIndex::persisted), so that adding them on the next launch
+    /// does not need a rebuild. Meant to be called before exiting, any change made afterwards discards the saved copy.
+    pub fn persist_indexes(&self) -> Result<(), Error> {
+        for bundle in self.open_trees.values() {
+            let tree = TypeErasedTree {
+                tree: &bundle.data,
+                evolution: bundle.evolution,
+            };
+            for indexer in &bundle.indexers {
+                indexer.persist(tree)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of records and revisions in a tree, their size and keys left, without opening it.
+    /// Goes through all the records, so takes time proportional to the tree size.
+    /// A versioned tree with many more revisions than ids might benefit from TypedTree::compact_history.
+    pub fn tree_stats<K, V>(&self) -> Result<TreeStats, Error>
+    where
+        K: TreeKey,
+        V: TreeRoot,
+    {
+        let tree_name = check_key_type::<K, V>()?;
+        let mut stats = TreeStats::default();
+        if let Some(stored_bytes) = self.descriptors.get(tree_name.as_bytes())? {
+            let (descriptor, _) =
+                TreeDescriptor::from_stored_bytes(&stored_bytes).map_err(|e| {
+                    Error::DescriptorDecode {
+                        tree: tree_name.to_string(),
+                        source: Box::new(e),
+                    }
+                })?;
+            stats.latest_evolution = descriptor.evolutions.keys().copied().max();
+        }
+        if !self
+            .db
+            .tree_names()
+            .iter()
+            .any(|name| name == tree_name.as_bytes())
+        {
+            return Ok(stats);
+        }
+        let data = self.db.open_tree(tree_name)?;
+        let mut last_id = None;
+        for entry in data.range(GenericKey::range_all_ids()) {
+            let (key_bytes, record_bytes) = entry?;
+            let Some(key) = record_key(&key_bytes) else {
+                continue;
+            };
+            stats.revisions += 1;
+            if last_id != Some(key.id) {
+                stats.ids += 1;
+                last_id = Some(key.id);
+            }
+            stats.approximate_bytes += (key_bytes.len() + record_bytes.len()) as u64;
+        }
+        stats.key_pool = KeyPool::stats_for(&data)?;
+        Ok(stats)
+    }
+
+    /// Journal every change of a tree from now on, so that its records can be rebuilt with rebuild_from_journal.
+    ///
+    /// Must be called before the tree is opened and stays enabled across restarts. Each change is written
+    /// once more into the journal, which is never trimmed, so it grows with every change made to the tree.
+    /// Entries are appended right after the change itself, a crash in between loses the last one.
+    pub fn enable_journal<K, V>(&mut self) -> Result<(), Error>
+    where
+        K: TreeKey,
+        V: TreeRoot,
+    {
+        let tree_name = check_key_type::<K, V>()?;
+        if self.open_trees.contains_key(tree_name) {
+            return Err(Error::Usage(format!(
+                "Journal of {tree_name} must be enabled before the tree is opened"
+            )));
+        }
+        self.db.open_tree(tree_name)?.insert(JOURNALED, &[])?;
+        Ok(())
+    }
+
+    /// All journaled changes of a tree in the order they were made, empty if its journal was never enabled.
+    pub fn journal<K, V>(&self) -> Result<Journal, Error>
+    where
+        K: TreeKey,
+        V: TreeRoot,
+    {
+        let tree_name = check_key_type::<K, V>()?;
+        let journal = self.db.open_tree(journal::journal_tree_name(tree_name))?;
+        Ok(journal::read(&journal, tree_name)?)
+    }
+
+    /// Create a database at the provided path holding the records of the journaled tree as they were right after
+    /// the entry with serial up_to, or after all of them if None, then open it same as open.
+    ///
+    /// Path must not hold a database yet. Records keep their iterations, so a record restored this way and written
+    /// back into a live database with update is synced as a change. Local changes that never reached the server are
+    /// not pending in the rebuilt database and the tree is not journaled there.
+    pub fn rebuild_from_journal<P: AsRef<Path>>(
+        path: P,
+        rt: &Runtime,
+        journal: &Journal,
+        up_to: Option<u64>,
+    ) -> Result<
+        (
+            HillsClient,
+            postage::broadcast::Receiver<ChangeNotification>,
+            JoinHandle<()>,
+        ),
+        Error,
+    > {
+        let path = path.as_ref();
+        if path.join("db").exists() {
+            return Err(Error::Usage(format!(
+                "Cannot rebuild {} into {}, it already holds a database",
+                journal.tree_name,
+                path.display()
+            )));
+        }
+        if journal.tree_name.starts_with('_') {
+            return Err(Error::Usage("Tree names cannot start with '_'".to_string()));
+        }
+        let db = OpenMode::Persistent.open(path)?;
+        let data = db.open_tree(&journal.tree_name)?;
+        let applied = journal::replay(&data, journal, up_to)?;
+        ManagedTrees::add_to_managed(&db, &journal.tree_name)?;
+        debug!(
+            "Rebuilt {} from {applied} journal entries",
+            journal.tree_name
+        );
+        Self::start(db, rt, ClientConfig::default())
+    }
+
+    /// Tree that stores a disk backed index, kept apart from the data trees.
+    pub(crate) fn open_index_tree(&self, index_name: &str) -> Result<Tree, Error> {
+        Ok(self
+            .db
+            .open_tree(format!("{INDEX_TREE_PREFIX}{index_name}"))?)
+    }
+
+    /// Log a warning whenever a tree operation or index rebuild takes longer than the threshold,
+    /// None disables the checks. Applies to trees opened afterwards.
+    pub fn set_slow_op_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_op_threshold = threshold;
+    }
+
+    /// Set the name this client presents itself with to the server, a blank name reverts to the default one.
     pub fn set_readable_name(&mut self, name: impl AsRef<str>) -> Result<(), Error> {
+        let name = match name.as_ref().trim() {
+            "" => default_readable_name(self.self_uuid),
+            name => name.to_string(),
+        };
         if let Some(existing) = self.db.get(READABLE_NAME)? {
             let existing = std::str::from_utf8(&existing).unwrap_or("");
-            if existing != name.as_ref() {
-                self.db.insert(READABLE_NAME, name.as_ref())?;
+            if existing != name {
+                self.db.insert(READABLE_NAME, name.as_str())?;
             }
         } else {
-            self.db.insert(READABLE_NAME, name.as_ref())?;
+            self.db.insert(READABLE_NAME, name.as_str())?;
         }
-        trace!("Self readable name is {}", name.as_ref());
+        trace!("Self readable name is {name}");
         Ok(())
     }
 
+    /// Server this database was linked with on its first connection, None if it never connected to any.
+    /// Unlike telemetry's connected it does not change when going offline, e.g. to tell a fresh database apart.
+    pub fn server_uuid(&self) -> Result<Option<Uuid>, Error> {
+        Ok(load_server_uuid(&self.db)?)
+    }
+
+    /// Forget the server this database is linked with, so that the next connect links it with whichever server answers.
+    /// Disconnects if connected. Unused keys issued by the old server are dropped, new ones are requested after connecting.
+    /// Records keep their ids and are pushed to the new server through the tree overview exchange, it does not issue
+    /// ids used by them to anyone else. Records with ids the new server already issued to other clients are moved
+    /// to new ids, see ChangeNotification::IdTaken.
+    /// Removed records are only tracked by the server, so there is nothing else to clear on the client.
+    pub fn unlink_server(&mut self) -> Result<(), Error> {
+        forget_server(&self.db)?;
+        send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::UnlinkServer,
+        )
+    }
+
+    /// Name this client presents itself with, "node-" followed by a part of the uuid unless set otherwise.
+    pub fn readable_name(&self) -> Result<String, Error> {
+        let name = self.db.get(READABLE_NAME)?;
+        let name = name
+            .as_deref()
+            .and_then(|name| std::str::from_utf8(name).ok());
+        Ok(name.unwrap_or_default().to_string())
+    }
+
     pub fn open_tree<K, V>(&mut self, username: impl AsRef<str>) -> Result<TypedTree<K, V>, Error>
     where
         K: TreeKey,
         V: TreeRoot + Reflect,
     {
-        let tree_name = <V as TreeRoot>::tree_name();
+        let tree_name = check_key_type::<K, V>()?;
         if tree_name.starts_with("_") {
             return Err(Error::Usage("Tree names cannot start with '_'".to_string()));
         }
@@ -275,12 +782,23 @@ impl HillsClient {
                 username: username.as_ref().to_string(),
                 versioning: raw_tree.versioning,
                 tree_name: Arc::new(tree_name.to_string()),
+                pending: self.pending.clone(),
+                temporary_ids: self.temporary_ids.clone(),
                 // event_tx: self.event_tx.clone(),
                 updates_tx: self.updates_tx.clone(),
                 uuid: self.self_uuid,
                 indexers: raw_tree.indexers.clone(),
+                write_lock: raw_tree.write_lock.clone(),
+                journal: raw_tree.journal.clone(),
+                migrations: Arc::new(self.migrations.get(tree_name).cloned().unwrap_or_default()),
                 borrows: self.borrows.clone(),
                 cmd_tx: self.cmd_tx.clone(),
+                #[cfg(any(test, feature = "test-util"))]
+                local: self.local,
+                slow_op_threshold: self.slow_op_threshold,
+                cmd_timeout: self.cmd_timeout,
+                clock: self.clock.clone(),
+                reserved_ceiling: self.reserved_ceiling,
 
                 _phantom_k: Default::default(),
                 _phantom_v: Default::default(),
@@ -288,19 +806,32 @@ impl HillsClient {
             None => {
                 self.open_cold_tree::<K, V>()?;
                 let Some(bundle) = self.open_trees.get(tree_name) else {
-                    return Err(Error::Internal("open_cold_tree failed".to_string()));
+                    return Err(Error::TreeNotOpened(tree_name.to_string()));
                 };
                 Ok(TypedTree {
                     data: bundle.data.clone(),
                     username: username.as_ref().to_string(),
                     versioning,
                     tree_name: Arc::new(tree_name.to_string()),
+                    pending: self.pending.clone(),
+                    temporary_ids: self.temporary_ids.clone(),
                     // event_tx: self.event_tx.clone(),
                     updates_tx: self.updates_tx.clone(),
                     uuid: self.self_uuid,
                     indexers: bundle.indexers.clone(),
+                    write_lock: bundle.write_lock.clone(),
+                    journal: bundle.journal.clone(),
+                    migrations: Arc::new(
+                        self.migrations.get(tree_name).cloned().unwrap_or_default(),
+                    ),
                     borrows: self.borrows.clone(),
                     cmd_tx: self.cmd_tx.clone(),
+                    #[cfg(any(test, feature = "test-util"))]
+                    local: self.local,
+                    slow_op_threshold: self.slow_op_threshold,
+                    cmd_timeout: self.cmd_timeout,
+                    clock: self.clock.clone(),
+                    reserved_ceiling: self.reserved_ceiling,
 
                     _phantom_k: Default::default(),
                     _phantom_v: Default::default(),
@@ -318,36 +849,234 @@ impl HillsClient {
         let tree_name = <V as TreeRoot>::tree_name();
         let evolution = <V as TreeRoot>::evolution();
         let Some(bundle) = self.open_trees.get_mut(tree_name) else {
-            return Err(Error::Internal("open_cold_tree failed".to_string()));
+            return Err(Error::TreeNotOpened(tree_name.to_string()));
         };
-        indexer.rebuild(TypeErasedTree {
-            tree: &mut bundle.data,
+        let timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &Arc::new(tree_name.to_string()),
+            "index rebuild",
+            None,
+        );
+        let tree = TypeErasedTree {
+            tree: &bundle.data,
             evolution,
-        })?;
+        };
+        if !indexer.load(tree)? {
+            indexer.rebuild(tree)?;
+        }
+        drop(timer);
+        self.register_indexer(tree_name, indexer)
+    }
+
+    /// Open a tree with its indexes declared up front.
+    ///
+    /// If the tree is not opened yet, all the indexes are filled in a single pass over its records while it is being
+    /// opened, instead of a rebuild for each of them, so for a new tree they cost nothing.
+    /// Otherwise same as add_indexer for each of them followed by open_tree.
+    pub fn open_tree_with_indexes<K, V>(
+        &mut self,
+        username: impl AsRef<str>,
+        indexers: Vec<Box<dyn TreeIndex + Send>>,
+    ) -> Result<TypedTree<K, V>, Error>
+    where
+        K: TreeKey,
+        V: TreeRoot + Reflect,
+    {
+        let tree_name = check_key_type::<K, V>()?;
+        if self.open_trees.contains_key(tree_name) {
+            for indexer in indexers {
+                self.add_indexer::<K, V>(indexer)?;
+            }
+            return self.open_tree(username);
+        }
+        self.open_cold_tree::<K, V>()?;
+        let mut indexers = indexers;
+        let evolution = <V as TreeRoot>::evolution();
+        let Some(bundle) = self.open_trees.get(tree_name) else {
+            return Err(Error::TreeNotOpened(tree_name.to_string()));
+        };
+        let timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &Arc::new(tree_name.to_string()),
+            "index build",
+            None,
+        );
+        let tree = TypeErasedTree {
+            tree: &bundle.data,
+            evolution,
+        };
+        // Persisted indexes that are still up to date are left out of the pass
+        let mut is_loaded = Vec::with_capacity(indexers.len());
+        for indexer in &mut indexers {
+            is_loaded.push(indexer.load(tree)?);
+        }
+        for key in tree.all_revisions() {
+            let Some(bytes) = bundle.data.get(key.to_bytes())? else {
+                continue;
+            };
+            let archived_record = check_archived_root::<Record>(&bytes)?;
+            let meta: RecordMeta = archived_record.meta.deserialize(&mut rkyv::Infallible)?;
+            let record_evolution: SimpleVersion = archived_record
+                .data_evolution
+                .deserialize(&mut rkyv::Infallible)?;
+            if record_evolution != evolution {
+                error!("{key}: record evolution is {record_evolution} and code is {evolution}, not indexing");
+            }
+            // Validated once by the first indexer and shared with the rest
+            let data = IndexData::new(archived_record.data.as_slice());
+            for (indexer, _) in indexers
+                .iter_mut()
+                .zip(&is_loaded)
+                .filter(|(_, is_loaded)| !**is_loaded)
+            {
+                if record_evolution == evolution {
+                    indexer.update(tree, key, None, &data, Action::Insert)?;
+                }
+                indexer.meta_changed(key, &meta)?;
+            }
+        }
+        drop(timer);
+        for indexer in indexers {
+            self.register_indexer(tree_name, indexer)?;
+        }
+        self.open_tree(username)
+    }
+
+    /// Attach an index that already matches the tree to it and let the sync task keep it up to date too.
+    fn register_indexer(
+        &mut self,
+        tree_name: &str,
+        indexer: Box<dyn TreeIndex + Send>,
+    ) -> Result<(), Error> {
+        let Some(bundle) = self.open_trees.get_mut(tree_name) else {
+            return Err(Error::TreeNotOpened(tree_name.to_string()));
+        };
         bundle.indexers.push(indexer.clone());
-        let r = self.cmd_tx.blocking_send(SyncClientCommand::RegisterIndex {
-            tree_name: tree_name.to_string(),
-            indexer,
-        });
+        let r = send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::RegisterIndex {
+                tree_name: tree_name.to_string(),
+                indexer,
+            },
+        );
         if r.is_err() {
             warn!("db: add_indexer: send failed");
         }
         Ok(())
     }
 
+    /// Register a conversion of records written at Src::evolution() into Dst::evolution() of the same tree.
+    ///
+    /// get and get_archived apply registered conversions one after another until a record reaches the evolution
+    /// of the code, so a client on an older build can read records written by a newer one, as long as the newer
+    /// type and a down migration are compiled in. Applies to trees opened afterwards.
+    pub fn add_migration<Src, Dst>(
+        &mut self,
+        migrate: impl Fn(Src) -> Dst + Send + Sync + 'static,
+    ) -> Result<(), Error>
+    where
+        Src: TreeRoot + Archive,
+        <Src as Archive>::Archived:
+            Deserialize<Src, rkyv::Infallible> + for<'a> CheckBytes<DefaultValidator<'a>>,
+        Dst: TreeRoot + Archive + Serialize<AllocSerializer<128>>,
+    {
+        self.insert_migration::<Src, Dst>(move |src| Ok(migrate(src)))
+    }
+
+    /// Register an upgrade of records written at Src::evolution() into Dst, that only adds fields to it.
+    ///
+    /// Fields are carried over by name, added ones are filled in with None or their Default, so get and get_archived
+    /// can read older records without a hand written migration. Types must be backwards compatible
+    /// (see is_backwards_compatible) and keep the names of the existing root fields, use add_migration otherwise.
+    /// Applies to trees opened afterwards.
+    ///
+    /// Fields are carried over through serde, each read of an older record deserializes it, writes it out as RON text,
+    /// parses that into Dst and archives the result, hence the serde bounds. That is about 4 times slower than
+    /// an equivalent add_migration (see benches/upgrade.rs), which is worth writing for trees that are read often.
+    pub fn add_upgrade<Src, Dst>(&mut self) -> Result<(), Error>
+    where
+        Src: TreeRoot + Reflect + Archive + serde::Serialize,
+        <Src as Archive>::Archived:
+            Deserialize<Src, rkyv::Infallible> + for<'a> CheckBytes<DefaultValidator<'a>>,
+        Dst: TreeRoot + Reflect + Archive + Serialize<AllocSerializer<128>> + DeserializeOwned,
+    {
+        let mut src_tc = TypeCollection::new();
+        Src::reflect(&mut src_tc);
+        let mut dst_tc = TypeCollection::new();
+        Dst::reflect(&mut dst_tc);
+        if !is_backwards_compatible(&src_tc, &dst_tc) {
+            debug!("Cannot upgrade from:\n{src_tc}\ninto:\n{dst_tc}");
+            return Err(Error::EvolutionMismatch(format!(
+                "{} {} is not backwards compatible with {}",
+                Src::tree_name(),
+                Dst::evolution(),
+                Src::evolution()
+            )));
+        }
+        if let (Some(TypeInfo::Struct(src_si)), Some(TypeInfo::Struct(dst_si))) =
+            (src_tc.refs.get(&src_tc.root), dst_tc.refs.get(&dst_tc.root))
+        {
+            let renamed = src_si
+                .fields
+                .iter()
+                .zip(dst_si.fields.iter())
+                .find(|(f, f_new)| f.ident != f_new.ident);
+            if let Some((f, f_new)) = renamed {
+                return Err(Error::Usage(format!(
+                    "{} renamed into {}, upgrade carries fields over by name, use add_migration instead",
+                    f.ident, f_new.ident
+                )));
+            }
+        }
+        self.insert_migration::<Src, Dst>(|src| {
+            let src = ron::ser::to_string(&src)?;
+            Ok(ron::de::from_str(&src)?)
+        })
+    }
+
+    fn insert_migration<Src, Dst>(
+        &mut self,
+        migrate: impl Fn(Src) -> Result<Dst, Error> + Send + Sync + 'static,
+    ) -> Result<(), Error>
+    where
+        Src: TreeRoot + Archive,
+        <Src as Archive>::Archived:
+            Deserialize<Src, rkyv::Infallible> + for<'a> CheckBytes<DefaultValidator<'a>>,
+        Dst: TreeRoot + Archive + Serialize<AllocSerializer<128>>,
+    {
+        let tree_name = Src::tree_name();
+        if Dst::tree_name() != tree_name {
+            return Err(Error::Usage(format!(
+                "Migration from {tree_name} into {}, must be within the same tree",
+                Dst::tree_name()
+            )));
+        }
+        let (from, to) = (Src::evolution(), Dst::evolution());
+        if from == to {
+            return Err(Error::Usage(format!(
+                "Migration of {tree_name} from {from} into the same evolution"
+            )));
+        }
+        let migrate: MigrateFn = Arc::new(move |data: &[u8]| {
+            let archived = check_archived_root::<Evolving<Src>>(data)?;
+            let value: Evolving<Src> = archived.deserialize(&mut rkyv::Infallible)?;
+            Ok(to_bytes::<_, 128>(&Evolving(migrate(value.0)?))?)
+        });
+        trace!("Registered {tree_name} migration from {from} to {to}");
+        self.migrations
+            .entry(tree_name.to_string())
+            .or_default()
+            .insert(from, Migration { to, migrate });
+        Ok(())
+    }
+
     fn open_cold_tree<K, V>(&mut self) -> Result<(), Error>
     where
         K: TreeKey,
         V: TreeRoot + Reflect,
     {
-        let key_tree_name = <K as TreeKey>::tree_name();
-        let tree_name = <V as TreeRoot>::tree_name();
-        if key_tree_name != tree_name {
-            return Err(Error::WrongKey(
-                key_tree_name.to_string(),
-                tree_name.to_string(),
-            ));
-        }
+        let tree_name = check_key_type::<K, V>()?;
         if tree_name.starts_with("_") {
             return Err(Error::Usage("Tree names cannot start with '_'".to_string()));
         }
@@ -357,15 +1086,19 @@ impl HillsClient {
         let mut current_tc = TypeCollection::new();
         let evolution = <V as TreeRoot>::evolution();
         V::reflect(&mut current_tc);
+        let schema_hash = current_tc.schema_hash();
 
         match self.descriptors.get(tree_name.as_bytes())? {
-            Some(descriptor_bytes) => {
-                let descriptor: &ArchivedTreeDescriptor =
-                    check_archived_root::<TreeDescriptor>(&descriptor_bytes)?;
+            Some(stored_bytes) => {
+                let (mut descriptor, outdated) = TreeDescriptor::from_stored_bytes(&stored_bytes)
+                    .map_err(|e| Error::DescriptorDecode {
+                    tree: tree_name.to_string(),
+                    source: Box::new(e),
+                })?;
                 let max_evolution = descriptor
                     .evolutions
                     .keys()
-                    .map(|k| k.as_original())
+                    .copied()
                     .max()
                     .unwrap_or(SimpleVersion::new(0, 0));
                 trace!(
@@ -377,6 +1110,10 @@ impl HillsClient {
                         "Cannot change versioning of a tree after creation".to_owned(),
                     ));
                 }
+                let mut changed = outdated;
+                if outdated {
+                    info!("Migrating descriptor of '{tree_name}' to the current format");
+                }
                 // if evolution < current_evolution {
                 //     return Err(Error::EvolutionMismatch("Code evolution is older than database already have".into()));
                 // }
@@ -388,28 +1125,39 @@ impl HillsClient {
                             evolution
                         );
                     }
-                    Ordering::Equal => {
-                        match descriptor.evolutions.get(&evolution.as_archived()) {
-                            Some(known_evolution) => {
-                                let known_tc: TypeCollection =
-                                    known_evolution.deserialize(&mut rkyv::Infallible)?;
-                                if current_tc != known_tc {
-                                    return Err(Error::EvolutionMismatch("Type definitions changed compared to what's in the database".into()));
-                                }
-                                trace!("Type definitions matches exactly");
+                    Ordering::Equal => match descriptor.evolutions.get(&evolution) {
+                        Some(known_tc) => {
+                            if !current_tc.is_same_schema(known_tc) {
+                                debug!("{tree_name} schema in the database:\n{known_tc}\nin code:\n{current_tc}");
+                                return Err(Error::EvolutionMismatch(
+                                    "Type definitions changed compared to what's in the database"
+                                        .into(),
+                                ));
                             }
-                            None => {
-                                warn!("Didn't found {evolution} in the database tree descriptor");
+                            if current_tc != *known_tc {
+                                trace!("Only doc comments changed, updating them");
+                                descriptor.evolutions.insert(evolution, current_tc);
+                                changed = true;
+                            } else {
+                                trace!("Type definitions matches exactly");
                             }
                         }
-                    }
+                        None => {
+                            warn!("{tree_name} descriptor is missing {evolution}, adding it");
+                            descriptor.evolutions.insert(evolution, current_tc);
+                            changed = true;
+                        }
+                    },
                     Ordering::Greater => {
                         info!("Will need to evolve {} to {}", max_evolution, evolution);
-                        let mut descriptor: TreeDescriptor =
-                            descriptor.deserialize(&mut rkyv::Infallible)?;
                         descriptor.evolutions.insert(evolution, current_tc);
+                        changed = true;
                     }
                 }
+                if changed {
+                    self.descriptors
+                        .insert(tree_name.as_bytes(), descriptor.to_stored_bytes()?)?;
+                }
             }
             None => {
                 trace!("Create new tree {tree_name}");
@@ -422,23 +1170,47 @@ impl HillsClient {
                     evolutions: [(evolution, current_tc)].into(),
                     versioning,
                 };
-                let descriptor_bytes = to_bytes::<_, 1024>(&descriptor)?;
                 self.descriptors
-                    .insert(tree_name.as_bytes(), descriptor_bytes.as_slice())?;
-                let r = self
-                    .cmd_tx
-                    .blocking_send(SyncClientCommand::TreeCreated(tree_name.to_string()));
+                    .insert(tree_name.as_bytes(), descriptor.to_stored_bytes()?)?;
+                let r = send_cmd(
+                    &mut self.cmd_tx,
+                    self.cmd_timeout,
+                    SyncClientCommand::TreeCreated(tree_name.to_string()),
+                );
                 if r.is_err() {
                     warn!("db: TreeCreated send failed");
                 }
             }
         }
+        let r = send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::TreeOpened {
+                tree_name: tree_name.to_string(),
+                schema: TreeSchema {
+                    evolution,
+                    hash: schema_hash,
+                },
+                client_ids: !matches!(V::id_strategy(), IdStrategy::ServerPool),
+            },
+        );
+        if r.is_err() {
+            warn!("db: TreeOpened send failed");
+        }
         let data = self.db.open_tree(tree_name.as_bytes())?;
+        if self.is_local() && KeyPool::stats_for(&data)? == 0 {
+            KeyPool::feed_for(&data, self.reserved_ceiling..CLIENT_ID_FLOOR)
+                .map_err(Error::Internal)?;
+        }
 
+        let journal = journal::journal_of(&self.db, &data, tree_name)?;
         let bundle = RawTreeBundle {
             data,
+            evolution,
             versioning,
             indexers: Vec::new(),
+            write_lock: Arc::new(Mutex::new(())),
+            journal,
         };
         self.open_trees
             .insert(tree_name.to_string(), bundle.clone());
@@ -446,21 +1218,142 @@ impl HillsClient {
     }
 
     pub fn connect(&mut self, ip_addr: IpAddr, port: u16) {
-        let r = self
-            .cmd_tx
-            .blocking_send(SyncClientCommand::Connect(ip_addr, port));
+        self.server_addr = Some((ip_addr, port));
+        let r = send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::Connect(ip_addr, port),
+        );
         if r.is_err() {
             warn!("db: connect: send failed");
         }
     }
 
     pub fn disconnect(&mut self) {
-        let r = self.cmd_tx.blocking_send(SyncClientCommand::Disconnect);
+        let r = send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::Disconnect,
+        );
         if r.is_err() {
             warn!("db: disconnect: send failed");
         }
     }
 
+    /// Exchange overviews of all trees with the server without reconnecting,
+    /// pulling and pushing any records that are missing or outdated on either side.
+    pub fn resync(&mut self) {
+        let r = send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::FullReSync,
+        );
+        if r.is_err() {
+            warn!("db: resync: send failed");
+        }
+    }
+
+    /// Same as resync, but only for one tree.
+    pub fn resync_tree(&mut self, tree_name: impl AsRef<str>) {
+        let r = send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::ReSyncTree(tree_name.as_ref().to_string()),
+        );
+        if r.is_err() {
+            warn!("db: resync_tree: send failed");
+        }
+    }
+
+    /// Connect to the last server again if disconnected, then exchange overviews of all trees until the server
+    /// has the same records as this client, including the ones created offline. Resolves with the number of
+    /// records sent and received meanwhile.
+    ///
+    /// Meant for tools that sync and exit, long running clients are kept in sync by hot changes instead.
+    /// Resolves to Error::NotConnected if connect was never called or the connection failed or dropped,
+    /// Error::Timeout if the trees did not settle in time, e.g. because other clients keep changing them.
+    pub fn sync_and_quiesce(
+        &mut self,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<SyncSummary, Error>> {
+        if let Some((ip_addr, port)) = self.server_addr {
+            // Ignored when already connected
+            self.connect(ip_addr, port);
+        }
+        let (done_tx, mut done_rx) = postage::oneshot::channel();
+        let deadline = Instant::now() + timeout;
+        let requested = send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::Quiesce {
+                deadline,
+                done: done_tx,
+            },
+        );
+        async move {
+            requested?;
+            let wait = async move {
+                match postage::prelude::Stream::recv(&mut done_rx).await {
+                    Some(summary) => Ok(summary),
+                    // Sync task gave up at the deadline
+                    None if Instant::now() >= deadline => Err(Error::Timeout(timeout)),
+                    None => Err(Error::NotConnected),
+                }
+            };
+            tokio::time::timeout(timeout, wait)
+                .await
+                .map_err(|_| Error::Timeout(timeout))?
+        }
+    }
+
+    /// Request one record from the server without syncing the whole tree, apply it locally and return its value.
+    /// The tree is not opened, its other records are only synced once it is.
+    ///
+    /// Resolves to None if the server does not have the record, Error::NotConnected if the request was not answered.
+    pub fn fetch_record<K, V>(&mut self, key: K) -> impl Future<Output = Result<Option<V>, Error>>
+    where
+        K: TreeKey + Debug,
+        V: TreeRoot + Reflect + Archive + Serialize<AllocSerializer<128>>,
+        <V as Archive>::Archived:
+            Deserialize<V, rkyv::Infallible> + for<'a> CheckBytes<DefaultValidator<'a>>,
+    {
+        let key = key.to_generic();
+        let (done_tx, mut done_rx) = postage::oneshot::channel();
+        let db = self.db.clone();
+        let requested = check_key_type::<K, V>().and_then(|tree_name| {
+            send_cmd(
+                &mut self.cmd_tx,
+                self.cmd_timeout,
+                SyncClientCommand::FetchRecord {
+                    tree_name: tree_name.to_string(),
+                    key,
+                    done: done_tx,
+                },
+            )?;
+            Ok(tree_name)
+        });
+        let migrations = requested
+            .as_ref()
+            .ok()
+            .and_then(|tree_name| self.migrations.get(*tree_name).cloned())
+            .unwrap_or_default();
+        async move {
+            let tree_name = requested?;
+            if postage::prelude::Stream::recv(&mut done_rx).await.is_none() {
+                return Err(Error::NotConnected);
+            }
+            let Some(bytes) = db.open_tree(tree_name)?.get(key.to_bytes())? else {
+                return Ok(None);
+            };
+            let archived_record = check_archived_root::<Record>(&bytes)?;
+            let migrated = migrate_record(&migrations, archived_record, V::evolution())?;
+            let data = migrated.as_deref().unwrap_or(&archived_record.data);
+            let archived_data = check_archived_root::<Evolving<V>>(data)?;
+            let deserialized: Evolving<V> = archived_data.deserialize(&mut rkyv::Infallible)?;
+            Ok(Some(deserialized.0))
+        }
+    }
+
     pub fn telemetry<F: FnMut(&SyncClientTelemetry)>(&self, mut f: F) {
         if let Ok(telem) = self.telem.try_read() {
             f(&telem);
@@ -468,58 +1361,352 @@ impl HillsClient {
     }
 }
 
-impl<K, V> TypedTree<K, V>
-where
-    K: TreeKey + Debug,
+fn lock_writes(write_lock: &Mutex<()>) -> Result<MutexGuard<'_, ()>, Error> {
+    write_lock
+        .lock()
+        .map_err(|_| Error::Internal("tree write lock poisoned".to_string()))
+}
+
+/// Send a command to the sync task without blocking forever if it is stalled and the channel is full.
+fn send_cmd(
+    cmd_tx: &mut VhrdDbCmdTx,
+    timeout: Duration,
+    cmd: SyncClientCommand,
+) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+    // Callers are not async and might not be inside a runtime, so the send is driven on this thread,
+    // which the channel unparks as soon as there is room
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut send = pin!(postage::sink::Sink::send(cmd_tx, cmd));
+    loop {
+        match send.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok(())) => return Ok(()),
+            Poll::Ready(Err(_)) => return Err(Error::Mpsc),
+            Poll::Pending => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(Error::SyncBusy);
+                }
+                std::thread::park_timeout(deadline - now);
+            }
+        }
+    }
+}
+
+/// Unparks a thread that drives a future by hand, see send_cmd.
+pub(crate) struct ThreadWaker(pub(crate) std::thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Start of a TypedTree::export stream.
+const EXPORT_MAGIC: &[u8] = b"hills-export-1\n";
+/// Number of records imported at once, indexes and the tree are updated for the whole batch or not at all.
+const IMPORT_BATCH: usize = 1024;
+/// Frames longer than this are rejected on import, such records could not be synced anyway, see WsLimits.
+const MAX_FRAME_LEN: u32 = 256 << 20;
+
+fn write_frame(writer: &mut impl Write, bytes: &[u8]) -> Result<(), Error> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| Error::Usage(format!("frame of {} bytes is too big", bytes.len())))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// Read one length-prefixed frame into the buffer, returns false if the stream ended cleanly before it.
+fn read_frame(reader: &mut impl Read, frame: &mut AlignedVec) -> Result<bool, Error> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(Error::MalformedExport(format!(
+            "frame of {len} bytes is too big"
+        )));
+    }
+    // Grow with the data actually read, the length alone is not trusted with an allocation
+    frame.clear();
+    let mut chunk = [0u8; 8192];
+    let mut left = len as usize;
+    while left > 0 {
+        let n = chunk.len().min(left);
+        reader.read_exact(&mut chunk[..n])?;
+        frame.extend_from_slice(&chunk[..n]);
+        left -= n;
+    }
+    Ok(true)
+}
+
+/// Convert record data into code_evolution with the migrations of its tree, None if it already is.
+fn migrate_record(
+    migrations: &HashMap<SimpleVersion, Migration>,
+    archived_record: &ArchivedRecord,
+    code_evolution: SimpleVersion,
+) -> Result<Option<AlignedVec>, Error> {
+    let record_evolution = archived_record.data_evolution.as_original();
+    let mut evolution = record_evolution;
+    let mut migrated: Option<AlignedVec> = None;
+    // Each migration is used at most once, otherwise they are going in circles
+    for _ in 0..=migrations.len() {
+        if evolution == code_evolution {
+            return Ok(migrated);
+        }
+        let Some(migration) = migrations.get(&evolution) else {
+            break;
+        };
+        let data = migrated.as_deref().unwrap_or(&archived_record.data);
+        migrated = Some((migration.migrate)(data)?);
+        evolution = migration.to;
+    }
+    Err(Error::EvolutionMismatch(format!(
+        "record evolution is {record_evolution} and code is {code_evolution}, no migration from {evolution}"
+    )))
+}
+
+/// Returns the tree name if key type belongs to the same tree as the value type.
+fn check_key_type<K: TreeKey, V: TreeRoot>() -> Result<&'static str, Error> {
+    let key_tree_name = <K as TreeKey>::tree_name();
+    let tree_name = <V as TreeRoot>::tree_name();
+    if key_tree_name != tree_name {
+        return Err(Error::TreeMismatch {
+            expected: tree_name.to_string(),
+            got: key_tree_name.to_string(),
+            what: KeyOrValue::Key,
+        });
+    }
+    Ok(tree_name)
+}
+
+fn load_or_create_self_uuid(db: &Db) -> Result<Uuid, Error> {
+    match db.get(SELF_UUID)? {
+        Some(uuid_bytes) => {
+            if uuid_bytes.len() != 16 {
+                return Err(Error::InvalidSelfUuid(uuid_bytes.len()));
+            }
+            let mut uuid = [0u8; 16];
+            uuid[..].copy_from_slice(&uuid_bytes);
+            let uuid = Uuid::from_bytes(uuid);
+            trace!("Self uuid is {uuid}");
+            Ok(uuid)
+        }
+        None => {
+            let uuid = Uuid::new_v4();
+            trace!("Created new db, uuid={uuid}");
+            let uuid_bytes = uuid.into_bytes();
+            db.insert(SELF_UUID, &uuid_bytes)?;
+            Ok(uuid)
+        }
+    }
+}
+
+/// Store the default readable name if none was set yet, so that the server never sees a blank one.
+fn ensure_readable_name(db: &Db, self_uuid: Uuid) -> Result<(), Error> {
+    let is_blank = match db.get(READABLE_NAME)? {
+        Some(name) => std::str::from_utf8(&name).map_or(true, |name| name.trim().is_empty()),
+        None => true,
+    };
+    if is_blank {
+        let name = default_readable_name(self_uuid);
+        trace!("No readable name set, using {name}");
+        db.insert(READABLE_NAME, name.as_str())?;
+    }
+    Ok(())
+}
+
+impl<K, V> TypedTree<K, V>
+where
+    K: TreeKey + Debug,
     V: TreeRoot + Archive + Serialize<AllocSerializer<128>>,
     <V as Archive>::Archived:
         Deserialize<V, rkyv::Infallible> + for<'a> CheckBytes<DefaultValidator<'a>>,
 {
+    /// Whether the tree belongs to a client opened with open_local_for_test, see HillsClient::is_local.
+    #[cfg(any(test, feature = "test-util"))]
+    fn is_local(&self) -> bool {
+        self.local
+    }
+
+    #[cfg(not(any(test, feature = "test-util")))]
+    fn is_local(&self) -> bool {
+        false
+    }
+
     // pub fn feed_key_pool(&mut self, additional_range: Range<u32>) -> Result<(), Error> {
     //     KeyPool::feed_for(&self.data, additional_range).map_err(Error::Internal)
     // }
 
+    /// Receiver of the same notifications the user gets, local and incoming changes of all trees.
+    pub(crate) fn subscribe_updates(&self) -> postage::broadcast::Receiver<ChangeNotification> {
+        self.updates_tx.subscribe()
+    }
+
+    /// Whether the record was created while no keys were available from the server.
+    /// Such records stay local until the server is reached and they are moved to a global key,
+    /// ChangeNotification::GlobalIdAssigned is sent then.
+    pub fn is_temporary(&self, key: K) -> bool {
+        is_temporary(key.to_generic().id)
+    }
+
+    /// Key a temporary record was moved to, the key itself if it is not temporary and None if no global id
+    /// was assigned yet.
+    pub fn global_key(&self, key: K) -> Result<Option<K>, Error> {
+        let key = key.to_generic();
+        if !is_temporary(key.id) {
+            return Ok(Some(K::from_generic(key)));
+        }
+        let global_id = global_id(&self.temporary_ids, &self.tree_name, key.id)?;
+        Ok(global_id.map(|id| K::from_generic(GenericKey::new(id, key.revision))))
+    }
+
     pub fn key_pool_stats(&self) -> Result<u32, Error> {
         Ok(KeyPool::stats_for(&self.data)?)
     }
 
+    /// Next key from the pool, or a temporary one if the pool is empty, see temporary::assign_global_ids.
     fn pool_get_key(&mut self) -> Result<GenericKey, Error> {
-        self.data.transaction(|tx_db| match tx_db.get(KEY_POOL)? {
-            Some(key_pool) => {
-                // let key_pool: &ArchivedKeyPool = unsafe { archived_root::<KeyPool>(&key_pool) };
-                let key_pool: &ArchivedKeyPool = check_archived_root::<KeyPool>(&key_pool)
-                    .map_err(|_| ConflictableTransactionError::Abort("checked_archived_root"))?;
-                let mut key_pool: KeyPool =
-                    key_pool.deserialize(&mut rkyv::Infallible).map_err(|_| {
-                        ConflictableTransactionError::Abort("get_next_key: deserialize")
-                    })?;
-                let next_key = match key_pool.get() {
-                    Some(next_key) => {
-                        let key_pool = to_bytes::<_, 8>(&key_pool)
-                            .map_err(|_| ConflictableTransactionError::Abort("to_bytes"))?;
-                        tx_db.insert(KEY_POOL, &*key_pool)?;
-                        let next_key = GenericKey::new(next_key, 0);
-                        next_key
-                    }
-                    None => {
-                        return Ok(Err(Error::OutOfKeys));
-                    }
-                };
-                Ok(Ok(next_key))
+        let next_key = self.data.transaction(KeyPool::take_in)?;
+        match next_key {
+            Some(next_key) => Ok(GenericKey::new(next_key, 0)),
+            None => {
+                let id = next_temporary_id(&self.data)?.ok_or(Error::OutOfKeys)?;
+                trace!(
+                    "{}: key pool is empty, using temporary id {id}",
+                    self.tree_name
+                );
+                Ok(GenericKey::new(id, 0))
             }
-            None => Ok(Err(Error::OutOfKeys)),
-        })?
+        }
     }
 
     pub fn insert(&mut self, value: V) -> Result<K, Error> {
-        let generic_key = self.pool_get_key()?;
-        let key_bytes = generic_key.to_bytes();
-        let evolution = <V as TreeRoot>::evolution();
+        self.insert_with_meta(value).map(|inserted| inserted.key)
+    }
+
+    /// Same as insert, but also returns the meta information that was written, avoiding a separate meta() call.
+    pub fn insert_with_meta(&mut self, value: V) -> Result<Inserted<K>, Error> {
+        let _timer = SlowOpTimer::start(self.slow_op_threshold, &self.tree_name, "insert", None);
+        let generic_key = self.new_key(&value)?;
+        self.insert_with_key(generic_key, value)
+    }
+
+    /// Key for a new record, according to the id strategy of the tree.
+    fn new_key(&mut self, value: &V) -> Result<GenericKey, Error> {
+        match V::id_strategy() {
+            IdStrategy::ServerPool => {
+                let generic_key = self.pool_get_key()?;
+                if self.data.contains_key(generic_key.to_bytes())? {
+                    return Err(Error::Internal("Duplicate key from KeyPool".to_string()));
+                }
+                Ok(generic_key)
+            }
+            IdStrategy::ClientHash(hashed) => {
+                let generic_key = GenericKey::new(hashed_id(hashed(value)), 0);
+                if self.data.contains_key(generic_key.to_bytes())? {
+                    let existing = self.get(K::from_generic(generic_key))?;
+                    let reason = if hashed(&existing) == hashed(value) {
+                        "record already exist"
+                    } else {
+                        "hash collides with another record"
+                    };
+                    return Err(Error::Usage(format!(
+                        "insert {}/{generic_key}: {reason}",
+                        self.tree_name
+                    )));
+                }
+                Ok(generic_key)
+            }
+            IdStrategy::Explicit => Err(Error::Usage(format!(
+                "insert {}: ids are explicit, use insert_with_id",
+                self.tree_name
+            ))),
+        }
+    }
+
+    /// Insert a record with an id chosen by the caller, into a tree with IdStrategy::Explicit.
+    ///
+    /// Id must be in CLIENT_IDS. Creating records with the same id on several nodes while not connected is
+    /// up to the caller to avoid, the first one to reach the server wins and the others are refused
+    /// (ChangeNotification::Refused), each node keeps its own record until the caller resolves it.
+    pub fn insert_with_id(&mut self, id: u32, value: V) -> Result<Inserted<K>, Error> {
+        let _timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &self.tree_name,
+            "insert_with_id",
+            Some(GenericKey::new(id, 0)),
+        );
+        if !matches!(V::id_strategy(), IdStrategy::Explicit) {
+            return Err(Error::Usage(format!(
+                "insert_with_id {}/{id}: ids are not explicit in this tree",
+                self.tree_name
+            )));
+        }
+        if !CLIENT_IDS.contains(&id) {
+            return Err(Error::Usage(format!(
+                "insert_with_id {}/{id}: id must be in {CLIENT_IDS:?}",
+                self.tree_name
+            )));
+        }
+        let generic_key = GenericKey::new(id, 0);
+        if self.data.contains_key(generic_key.to_bytes())? {
+            return Err(Error::Usage(format!(
+                "insert_with_id {}/{generic_key}: record already exist",
+                self.tree_name
+            )));
+        }
+        self.insert_with_key(generic_key, value)
+    }
 
-        if self.data.contains_key(key_bytes)? {
-            return Err(Error::Internal("Duplicate key from KeyPool".to_string()));
+    /// Insert a well-known record with a fixed id, that is never issued by the key pool.
+    ///
+    /// Id must be below ClientConfig::reserved_ceiling. If several nodes create the same record while not connected,
+    /// the first one to reach the server wins.
+    pub fn insert_at(&mut self, id: u32, value: V) -> Result<Inserted<K>, Error> {
+        let _timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &self.tree_name,
+            "insert_at",
+            Some(GenericKey::new(id, 0)),
+        );
+        if id >= self.reserved_ceiling {
+            return Err(Error::Usage(format!(
+                "insert_at {}/{id}: id must be below {}",
+                self.tree_name, self.reserved_ceiling
+            )));
+        }
+        let generic_key = GenericKey::new(id, 0);
+        if self.data.contains_key(generic_key.to_bytes())? {
+            return Err(Error::Usage(format!(
+                "insert_at {}/{generic_key}: record already exist",
+                self.tree_name
+            )));
         }
+        self.insert_with_key(generic_key, value)
+    }
+
+    fn insert_with_key(&mut self, generic_key: GenericKey, value: V) -> Result<Inserted<K>, Error> {
         let data = to_bytes::<_, 128>(&Evolving(value))?;
+        let write_lock = self.write_lock.clone();
+        let _guard = lock_writes(&write_lock)?;
+        self.insert_serialized(generic_key, data)
+    }
+
+    /// Index and write a new record, write_lock must be held.
+    fn insert_serialized(
+        &mut self,
+        generic_key: GenericKey,
+        data: AlignedVec,
+    ) -> Result<Inserted<K>, Error> {
+        let key_bytes = generic_key.to_bytes();
+        let evolution = <V as TreeRoot>::evolution();
+        let index_data = IndexData::new(&data);
         for indexer in &mut self.indexers {
             indexer.update(
                 TypeErasedTree {
@@ -527,7 +1714,8 @@ where
                     evolution,
                 },
                 generic_key,
-                &data,
+                None,
+                &index_data,
                 crate::index::Action::Insert,
             )?;
         }
@@ -537,24 +1725,31 @@ where
         } else {
             Version::NonVersioned
         };
+        let now = self.clock.now();
         let meta = RecordMeta {
             key: generic_key,
             version: versioning,
             modified_on: self.uuid.into_bytes(),
             modified_by: self.username.clone(),
-            modified: Utc::now().into(),
-            created: Utc::now().into(),
+            modified: now.into(),
+            created: now.into(),
             rkyv_version: SimpleVersion::rkyv_version(),
         };
         let record = Record {
             meta_iteration: 0,
-            meta,
+            meta: meta.clone(),
             data_iteration: 0,
             data,
             data_evolution: evolution,
         };
         let record = to_bytes::<_, 128>(&record)?;
         self.data.insert(key_bytes, &*record)?;
+        journal::append(self.journal.as_ref(), generic_key, || {
+            JournalAction::Create(record.to_vec())
+        })?;
+        for indexer in &mut self.indexers {
+            indexer.meta_changed(generic_key, &meta)?;
+        }
 
         let change = RecordHotChange {
             tree: String::from(self.tree_name.as_str()),
@@ -563,22 +1758,53 @@ where
             data_iteration: 0,
             meta_iteration: 0,
         };
-        self.cmd_tx
-            .blocking_send(SyncClientCommand::Change(change))
-            .map_err(|_| Error::Mpsc)?;
+        self.queue_change(change)?;
 
-        let notification = ChangeNotification::Tree {
-            key: OpaqueKey::new(self.tree_name.clone(), generic_key),
-            kind: ChangeKind::CreateOrChange,
-        };
-        if let Err(_) = self.updates_tx.try_send(notification) {
-            warn!("Notification send: mpsc fail");
+        Ok(Inserted {
+            key: K::from_generic(generic_key),
+            meta,
+        })
+    }
+
+    /// Update a record that holds the same indexed value (e.g. name) as the provided one, or insert a new record
+    /// if there is none. Existing record must be checked out.
+    ///
+    /// Lookup and write happen under the tree's write lock, so that an insert or update through another handle
+    /// cannot slip in between.
+    pub fn upsert_by(&mut self, index: &impl UniqueIndex<K>, value: V) -> Result<K, Error> {
+        let evolving = Evolving(value);
+        let data = to_bytes::<_, 128>(&evolving)?;
+        let write_lock = self.write_lock.clone();
+        let _guard = lock_writes(&write_lock)?;
+        match index.find_existing(&data)? {
+            Some(key) => {
+                let generic_key = key.to_generic();
+                self.update_serialized(key, data)?;
+                Ok(K::from_generic(generic_key))
+            }
+            None => {
+                let generic_key = self.new_key(&evolving.0)?;
+                Ok(self.insert_serialized(generic_key, data)?.key)
+            }
         }
+    }
 
-        Ok(K::from_generic(generic_key))
+    /// Replace record data, returns new (meta_iteration, data_iteration).
+    pub fn update(&mut self, key: K, value: V) -> Result<(u32, u32), Error> {
+        let data = to_bytes::<_, 128>(&Evolving(value))?;
+        let write_lock = self.write_lock.clone();
+        let _guard = lock_writes(&write_lock)?;
+        self.update_serialized(key, data)
     }
 
-    pub fn update(&mut self, key: K, value: V) -> Result<(), Error> {
+    /// Check and replace record data, write_lock must be held.
+    fn update_serialized(&mut self, key: K, data: AlignedVec) -> Result<(u32, u32), Error> {
+        let _timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &self.tree_name,
+            "update",
+            Some(key.to_generic()),
+        );
         let generic_key = key.to_generic();
         if !self.is_checked_out(key) {
             return Err(Error::Usage(format!(
@@ -623,7 +1849,9 @@ where
                     self.tree_name
                 )));
             }
-            let data = to_bytes::<_, 128>(&Evolving(value))?;
+            let index_data = IndexData::new(&data);
+            let old_data = (replacing.data_evolution.as_original() == evolution)
+                .then(|| IndexData::new(replacing.data.as_slice()));
             for indexer in &mut self.indexers {
                 indexer.update(
                     TypeErasedTree {
@@ -631,7 +1859,8 @@ where
                         evolution,
                     },
                     generic_key,
-                    &data,
+                    old_data.as_ref(),
+                    &index_data,
                     crate::index::Action::Update,
                 )?;
             }
@@ -646,7 +1875,7 @@ where
                 version: versioning,
                 modified_on: self.uuid.into_bytes(),
                 modified_by: self.username.clone(),
-                modified: Utc::now().into(),
+                modified: self.clock.now().into(),
                 created: replacing
                     .meta
                     .created
@@ -663,7 +1892,13 @@ where
             };
             let record_bytes = to_bytes::<_, 128>(&record)?;
             self.data.insert(key_bytes, &*record_bytes)?;
+            journal::append(self.journal.as_ref(), generic_key, || {
+                JournalAction::Modify(record_bytes.to_vec())
+            })?;
             // self.latest_revision_index.insert(key_bytes, &[])?;
+            for indexer in &mut self.indexers {
+                indexer.meta_changed(generic_key, &record.meta)?;
+            }
 
             let change = RecordHotChange {
                 tree: String::from(self.tree_name.as_str()),
@@ -672,19 +1907,9 @@ where
                 data_iteration: record.data_iteration,
                 kind: ChangeKind::CreateOrChange,
             };
-            self.cmd_tx
-                .blocking_send(SyncClientCommand::Change(change))
-                .map_err(|_| Error::Mpsc)?;
-
-            let notification = ChangeNotification::Tree {
-                key: OpaqueKey::new(self.tree_name.clone(), generic_key),
-                kind: ChangeKind::CreateOrChange,
-            };
-            if let Err(_) = self.updates_tx.try_send(notification) {
-                warn!("Notification send: mpsc fail");
-            }
+            self.queue_change(change)?;
 
-            Ok(())
+            Ok((record.meta_iteration, record.data_iteration))
         } else {
             Err(Error::Usage(format!(
                 "update {}/{generic_key}, not found, create record first",
@@ -693,61 +1918,237 @@ where
         }
     }
 
+    /// Release a checked out draft, its data cannot be changed afterwards and the next revision can be created.
+    ///
+    /// Named so to not clash with giving a check out back (release). user_state tells apart Released states
+    /// (Approved, Obsolete, ..), its meaning is up to the user. Only meta changes and is synced as ModifyMeta.
+    pub fn release_version(&mut self, key: K, user_state: u32) -> Result<(), Error> {
+        let _timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &self.tree_name,
+            "release_version",
+            Some(key.to_generic()),
+        );
+        let generic_key = key.to_generic();
+        if !self.is_checked_out(key) {
+            return Err(Error::Usage(format!(
+                "Cannot release: {}/{generic_key} - not checked out",
+                self.tree_name
+            )));
+        }
+        if !self.versioning {
+            return Err(Error::VersioningMismatch(format!(
+                "Cannot release {}/{generic_key}, tree is not versioned",
+                self.tree_name
+            )));
+        }
+        let key_bytes = generic_key.to_bytes();
+        let Some(record_bytes) = self.data.get(key_bytes)? else {
+            return Err(Error::RecordNotFound);
+        };
+        let archived_record = check_archived_root::<Record>(&record_bytes)?;
+        if !matches!(archived_record.meta.version, ArchivedVersion::Draft(_)) {
+            return Err(Error::VersioningMismatch(format!(
+                "Cannot release {}/{generic_key}, it is not a draft",
+                self.tree_name
+            )));
+        }
+
+        let mut meta: RecordMeta = archived_record.meta.deserialize(&mut rkyv::Infallible)?;
+        meta.version = Version::Released(user_state);
+        meta.modified_on = self.uuid.into_bytes();
+        meta.modified_by = self.username.clone();
+        meta.modified = self.clock.now().into();
+        let mut data = AlignedVec::new();
+        data.extend_from_slice(archived_record.data.as_slice());
+        let record = Record {
+            meta_iteration: archived_record.meta_iteration + 1,
+            meta,
+            data_iteration: archived_record.data_iteration,
+            data_evolution: archived_record.data_evolution.as_original(),
+            data,
+        };
+        let record_bytes = to_bytes::<_, 128>(&record)?;
+        self.data.insert(key_bytes, &*record_bytes)?;
+        journal::append(self.journal.as_ref(), generic_key, || {
+            JournalAction::Modify(record_bytes.to_vec())
+        })?;
+        for indexer in &mut self.indexers {
+            indexer.meta_changed(generic_key, &record.meta)?;
+        }
+
+        let change = RecordHotChange {
+            tree: String::from(self.tree_name.as_str()),
+            key: generic_key,
+            meta_iteration: record.meta_iteration,
+            data_iteration: record.data_iteration,
+            kind: ChangeKind::ModifyMeta,
+        };
+        self.queue_change(change)
+    }
+
+    /// Start the next revision of a checked out Released record, as a draft holding the same data.
+    ///
+    /// Only the latest revision can be branched. The new one is not checked out, check it out before updating.
+    pub fn create_revision(&mut self, key: K) -> Result<K, Error> {
+        let _timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &self.tree_name,
+            "create_revision",
+            Some(key.to_generic()),
+        );
+        let generic_key = key.to_generic();
+        if !self.is_checked_out(key) {
+            return Err(Error::Usage(format!(
+                "Cannot create revision: {}/{generic_key} - not checked out",
+                self.tree_name
+            )));
+        }
+        if !self.versioning {
+            return Err(Error::VersioningMismatch(format!(
+                "Cannot create revision of {}/{generic_key}, tree is not versioned",
+                self.tree_name
+            )));
+        }
+        // Held from the checks to the insert, so that two callers cannot both branch the latest revision
+        let write_lock = self.write_lock.clone();
+        let _guard = lock_writes(&write_lock)?;
+        let Some(record_bytes) = self.data.get(generic_key.to_bytes())? else {
+            return Err(Error::RecordNotFound);
+        };
+        let archived_record = check_archived_root::<Record>(&record_bytes)?;
+        if !matches!(archived_record.meta.version, ArchivedVersion::Released(_)) {
+            return Err(Error::VersioningMismatch(format!(
+                "Cannot create revision of {}/{generic_key}, it is not released",
+                self.tree_name
+            )));
+        }
+        let id_revisions = GenericKey::id_range(generic_key.id..generic_key.id + 1);
+        if let Some(latest) = record_keys_in(&self.data, id_revisions).next_back() {
+            if latest != generic_key {
+                return Err(Error::VersioningMismatch(format!(
+                    "Cannot create revision of {}/{generic_key}, {latest} already exists",
+                    self.tree_name
+                )));
+            }
+        }
+        let Some(revision) = generic_key.revision.checked_add(1) else {
+            return Err(Error::VersioningMismatch(format!(
+                "Cannot create revision of {}/{generic_key}, revisions are used up",
+                self.tree_name
+            )));
+        };
+        // Migrated to the code evolution if needed, so that indexes see the same data as for any other insert
+        let value = self.deserialize_record(&record_bytes)?;
+        let next = GenericKey::new(generic_key.id, revision);
+        let data = to_bytes::<_, 128>(&Evolving(value))?;
+        self.insert_serialized(next, data)
+            .map(|inserted| inserted.key)
+    }
+
     pub fn get(&self, key: K) -> Result<V, Error> {
+        let _timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &self.tree_name,
+            "get",
+            Some(key.to_generic()),
+        );
         let key_bytes = key.to_generic().to_bytes();
         let value = self.data.get(key_bytes)?;
         match value {
-            Some(bytes) => {
-                let archived_record = check_archived_root::<Record>(&bytes)?;
-                let record_evolution: SimpleVersion = archived_record
-                    .data_evolution
-                    .deserialize(&mut rkyv::Infallible)
-                    .expect("");
-                if record_evolution != V::evolution() {
-                    return Err(Error::EvolutionMismatch(format!(
-                        "record evolution is {record_evolution} and code is {}",
-                        V::evolution()
-                    )));
-                }
+            Some(bytes) => self.deserialize_record(&bytes),
+            None => Err(Error::RecordNotFound),
+        }
+    }
+
+    /// Read several records at once, missing ones are None. Results are in the same order as the keys.
+    ///
+    /// Records are looked up in key order, evolution mismatch of any of them fails the whole batch
+    /// with an error naming the record.
+    pub fn get_many(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<Vec<(K, Option<V>)>, Error> {
+        let _timer = SlowOpTimer::start(self.slow_op_threshold, &self.tree_name, "get_many", None);
+        let keys: Vec<K> = keys.into_iter().collect();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by_key(|&i| keys[i].to_generic().to_bytes());
+        let mut values: Vec<Option<V>> = Vec::with_capacity(keys.len());
+        values.resize_with(keys.len(), || None);
+        for i in order {
+            let key = keys[i].to_generic();
+            let Some(bytes) = self.data.get(key.to_bytes())? else {
+                continue;
+            };
+            let value = self
+                .deserialize_record(&bytes)
+                .map_err(|e| self.name_record(e, key))?;
+            values[i] = Some(value);
+        }
+        Ok(keys.into_iter().zip(values).collect())
+    }
 
-                let archived_data = check_archived_root::<Evolving<V>>(&archived_record.data)?;
-                let deserialized: Evolving<V> = archived_data.deserialize(&mut rkyv::Infallible)?;
-                Ok(deserialized.0)
+    /// Point an evolution mismatch to the record it happened with, when reading many of them at once.
+    fn name_record(&self, e: Error, key: GenericKey) -> Error {
+        match e {
+            Error::EvolutionMismatch(e) => {
+                Error::EvolutionMismatch(format!("{}/{key}: {e}", self.tree_name))
             }
-            None => Err(Error::RecordNotFound),
+            e => e,
         }
     }
 
+    /// Check record bytes and deserialize its data, migrated to the code evolution if needed.
+    fn deserialize_record(&self, bytes: &[u8]) -> Result<V, Error> {
+        let archived_record = check_archived_root::<Record>(bytes)?;
+        let migrated = self.migrate(archived_record)?;
+        let data = migrated.as_deref().unwrap_or(&archived_record.data);
+        let archived_data = check_archived_root::<Evolving<V>>(data)?;
+        let deserialized: Evolving<V> = archived_data.deserialize(&mut rkyv::Infallible)?;
+        Ok(deserialized.0)
+    }
+
     pub fn get_archived<F: FnMut(&V::Archived) -> R, R>(
         &self,
         key: K,
         mut f: F,
     ) -> Result<Option<R>, Error> {
+        let _timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &self.tree_name,
+            "get_archived",
+            Some(key.to_generic()),
+        );
         let key_bytes = key.to_generic().to_bytes();
         let value = self.data.get(key_bytes)?;
         match value {
             Some(bytes) => {
                 let archived_record = check_archived_root::<Record>(&bytes)?;
-
-                let record_evolution: SimpleVersion = archived_record
-                    .data_evolution
-                    .deserialize(&mut rkyv::Infallible)
-                    .expect("");
-                if record_evolution != V::evolution() {
-                    return Err(Error::EvolutionMismatch(format!(
-                        "record evolution is {record_evolution} and code is {}",
-                        V::evolution()
-                    )));
-                }
-
-                let archived_data = check_archived_root::<Evolving<V>>(&archived_record.data)?;
+                let migrated = self.migrate(archived_record)?;
+                let data = migrated.as_deref().unwrap_or(&archived_record.data);
+                let archived_data = check_archived_root::<Evolving<V>>(data)?;
                 Ok(Some(f(archived_data.0.get())))
             }
             None => Ok(None),
         }
     }
 
+    /// Convert record data into the evolution of the code with registered migrations, None if it already is.
+    fn migrate(&self, archived_record: &ArchivedRecord) -> Result<Option<AlignedVec>, Error> {
+        migrate_record(&self.migrations, archived_record, V::evolution())
+    }
+
+    /// Remove a checked out record.
+    ///
+    /// Id of a record that never reached the server, because it was removed before its creation was sent,
+    /// goes back to the key pool and is handed out again by the next insert.
     pub fn remove(&mut self, key: K) -> Result<Option<()>, Error> {
+        let _timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &self.tree_name,
+            "remove",
+            Some(key.to_generic()),
+        );
         let generic_key = key.to_generic();
         if !self.is_checked_out(key) {
             return Err(Error::Usage(format!(
@@ -767,94 +2168,539 @@ where
                         self.tree_name,
                     )));
                 }
-                for indexer in &mut self.indexers {
-                    let r = indexer.update(
-                        TypeErasedTree {
-                            tree: &mut self.data,
-                            evolution: <V as TreeRoot>::evolution(),
-                        },
-                        generic_key,
-                        &archived_record.data,
-                        crate::index::Action::Remove,
-                    );
-                    if r.is_err() {
-                        log::error!(
-                            "Indexer for {} failed at deleting with key {generic_key}",
-                            self.tree_name
-                        );
-                    }
-                }
-                self.data.remove(key_bytes)?;
-
-                let change = RecordHotChange {
-                    tree: String::from(self.tree_name.as_str()),
-                    key: generic_key,
-                    meta_iteration: archived_record.meta_iteration,
-                    data_iteration: archived_record.data_iteration,
-                    kind: ChangeKind::Remove,
-                };
-                self.cmd_tx
-                    .blocking_send(SyncClientCommand::Change(change))
-                    .map_err(|_| Error::Mpsc)?;
-
-                let notification = ChangeNotification::Tree {
-                    key: OpaqueKey::new(self.tree_name.clone(), generic_key),
-                    kind: ChangeKind::Remove,
-                };
-                if let Err(_) = self.updates_tx.try_send(notification) {
-                    warn!("Notification send: mpsc fail");
-                }
-
+                self.remove_archived(generic_key, archived_record)?;
                 Ok(Some(()))
             }
             None => Ok(None),
         }
     }
 
-    pub fn check_out(&mut self, key: K) {
-        if let Err(_) = self.cmd_tx.blocking_send(SyncClientCommand::CheckOut(
-            self.tree_name.as_str().to_string(),
-            key.to_generic(),
-        )) {
-            error!("check_out: mpsc error");
+    /// Update indexes, remove the record and let the sync task and the user know about it.
+    fn remove_archived(
+        &mut self,
+        generic_key: GenericKey,
+        archived_record: &ArchivedRecord,
+    ) -> Result<(), Error> {
+        // Withdrawn before the record is gone, so that the sync task cannot send its creation in between
+        let is_draft = self.withdraw_draft(generic_key, archived_record)?;
+        let data = IndexData::new(&archived_record.data);
+        for indexer in &mut self.indexers {
+            let r = indexer.update(
+                TypeErasedTree {
+                    tree: &mut self.data,
+                    evolution: <V as TreeRoot>::evolution(),
+                },
+                generic_key,
+                None,
+                &data,
+                crate::index::Action::Remove,
+            );
+            if r.is_err() {
+                log::error!(
+                    "Indexer for {} failed at deleting with key {generic_key}",
+                    self.tree_name
+                );
+            }
+        }
+        self.data.remove(generic_key.to_bytes())?;
+        journal::append(self.journal.as_ref(), generic_key, || JournalAction::Remove)?;
+        if is_draft {
+            trace!(
+                "{}: {generic_key} never reached the server, reusing its id",
+                self.tree_name
+            );
+            self.data
+                .transaction(|tx_data| KeyPool::return_in(tx_data, generic_key.id))?;
+            self.notify_user(generic_key, ChangeKind::Remove);
+            return Ok(());
         }
+        self.notify_removed(generic_key, archived_record)
     }
 
-    pub fn release(&mut self, key: K) {
-        if let Err(_) = self.cmd_tx.blocking_send(SyncClientCommand::Release(
-            self.tree_name.as_str().to_string(),
-            key.to_generic(),
-        )) {
-            error!("check_out: mpsc error");
+    /// Take back the creation of a record created here from the key pool, if the sync task did not send it yet.
+    /// Other revisions and records imported from elsewhere with the same iterations are never drafts.
+    fn withdraw_draft(
+        &self,
+        generic_key: GenericKey,
+        archived_record: &ArchivedRecord,
+    ) -> Result<bool, Error> {
+        let is_pool_id = matches!(V::id_strategy(), IdStrategy::ServerPool)
+            && generic_key.id >= self.reserved_ceiling
+            && !is_temporary(generic_key.id);
+        let is_created_here = archived_record.meta.modified_on == self.uuid.into_bytes()
+            && archived_record.meta_iteration == 0
+            && archived_record.data_iteration == 0;
+        let id_revisions = GenericKey::id_range(generic_key.id..generic_key.id + 1);
+        let is_only_revision = record_keys_in(&self.data, id_revisions).count() == 1;
+        if generic_key.revision != 0 || !is_pool_id || !is_created_here || !is_only_revision {
+            return Ok(false);
+        }
+        if self.is_local() {
+            return Ok(true);
         }
+        Ok(PendingChanges::withdraw_creation(
+            &self.pending,
+            self.tree_name.as_str(),
+            generic_key,
+        )?)
     }
 
-    pub fn is_checked_out(&self, key: K) -> bool {
-        let rd = self.borrows.blocking_read();
-        if let Some(borrowed_keys) = rd.borrows.get(self.tree_name.as_str()) {
-            let key = key.to_generic();
-            match borrowed_keys.get(&key) {
-                Some(queue) => queue.get(0) == Some(&self.uuid),
-                None => false,
+    /// Let the sync task and the user know about an already removed record.
+    fn notify_removed(
+        &mut self,
+        generic_key: GenericKey,
+        archived_record: &ArchivedRecord,
+    ) -> Result<(), Error> {
+        let change = RecordHotChange {
+            tree: String::from(self.tree_name.as_str()),
+            key: generic_key,
+            meta_iteration: archived_record.meta_iteration,
+            data_iteration: archived_record.data_iteration,
+            kind: ChangeKind::Remove,
+        };
+        self.queue_change(change)
+    }
+
+    /// Remove superseded revisions of a versioned tree, keeping the latest keep_last_n revisions of each id
+    /// (at least the latest one) and optionally all Released ones. Revisions that are checked out by anyone are kept.
+    ///
+    /// Returns the number of removed revisions.
+    pub fn compact_history(
+        &mut self,
+        keep_released: bool,
+        keep_last_n: usize,
+    ) -> Result<usize, Error> {
+        let _timer = SlowOpTimer::start(
+            self.slow_op_threshold,
+            &self.tree_name,
+            "compact_history",
+            None,
+        );
+        if !self.versioning {
+            return Err(Error::Usage(format!(
+                "Cannot compact history of un-versioned tree {}",
+                self.tree_name
+            )));
+        }
+
+        // Record keys are ordered by id and then by revision
+        let mut ids: Vec<Vec<GenericKey>> = vec![];
+        for generic_key in record_keys(&self.data) {
+            match ids.last_mut() {
+                Some(revisions) if revisions[0].id == generic_key.id => revisions.push(generic_key),
+                _ => ids.push(vec![generic_key]),
             }
-        } else {
-            false
         }
-    }
 
-    pub fn checked_out_by(&self, key: K) -> RecordCheckOutState {
-        let rd = self.borrows.blocking_read();
-        if let Some(borrowed_keys) = rd.borrows.get(self.tree_name.as_str()) {
-            let key = key.to_generic();
+        let mut superseded = vec![];
+        for revisions in ids {
+            for &generic_key in revisions.iter().rev().skip(keep_last_n.max(1)) {
+                if !matches!(
+                    self.checked_out_by(K::from_generic(generic_key)),
+                    RecordCheckOutState::Empty
+                ) {
+                    continue;
+                }
+                let Some(bytes) = self.data.get(generic_key.to_bytes())? else {
+                    continue;
+                };
+                let archived_record = check_archived_root::<Record>(&bytes)?;
+                if keep_released
+                    && matches!(archived_record.meta.version, ArchivedVersion::Released(_))
+                {
+                    continue;
+                }
+                superseded.push((generic_key, bytes));
+            }
+        }
+
+        let mut removed = Vec::with_capacity(superseded.len());
+        for (generic_key, bytes) in &superseded {
+            removed.push((*generic_key, check_archived_root::<Record>(bytes)?));
+        }
+        let changes: Vec<IndexChange> = removed
+            .iter()
+            .map(|(generic_key, record)| {
+                (
+                    *generic_key,
+                    None,
+                    IndexData::new(record.data.as_slice()),
+                    Action::Remove,
+                )
+            })
+            .collect();
+        // Records are removed even if indexes fail, same as in remove, indexes are rebuilt afterwards instead
+        let indexes_failed = self.update_indexes(&changes).is_err();
+        let mut batch = sled::Batch::default();
+        for (generic_key, _) in &removed {
+            batch.remove(&generic_key.to_bytes());
+        }
+        self.data.apply_batch(batch)?;
+        for (generic_key, _) in &removed {
+            journal::append(self.journal.as_ref(), *generic_key, || {
+                JournalAction::Remove
+            })?;
+        }
+        if indexes_failed {
+            error!(
+                "{}: indexes failed at compacting history, rebuilding",
+                self.tree_name
+            );
+            let tree = TypeErasedTree {
+                tree: &self.data,
+                evolution: <V as TreeRoot>::evolution(),
+            };
+            for indexer in &mut self.indexers {
+                indexer.rebuild(tree)?;
+            }
+        }
+        for (generic_key, record) in &removed {
+            self.notify_removed(*generic_key, record)?;
+        }
+        Ok(removed.len())
+    }
+
+    /// Write all the records of this tree into the writer one at a time, so that memory use does not depend on
+    /// the tree size. Records are written as is, with meta and iterations, see import for the format.
+    ///
+    /// Returns the number of exported records.
+    pub fn export(&self, mut writer: impl Write) -> Result<usize, Error> {
+        let _timer = SlowOpTimer::start(self.slow_op_threshold, &self.tree_name, "export", None);
+        writer.write_all(EXPORT_MAGIC)?;
+        write_frame(&mut writer, self.tree_name.as_bytes())?;
+        let mut count = 0;
+        for generic_key in record_keys(&self.data) {
+            let Some(bytes) = self.data.get(generic_key.to_bytes())? else {
+                continue;
+            };
+            write_frame(&mut writer, &bytes)?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Read records written by export in batches of IMPORT_BATCH and add them to this tree.
+    ///
+    /// Stream format: EXPORT_MAGIC, then length-prefixed frames (u32 little endian length and that many bytes),
+    /// first one is the tree name, all the others are Records.
+    /// Records that are missing or older locally are written, indexed and synced as if they were just changed,
+    /// others are skipped. Records new to this database are written with a meta iteration of at least 1, so that
+    /// the server takes them as changes and not as creations with ids that were never issued to this client.
+    /// Returns the number of written records. If a batch fails, none of its records are written,
+    /// but the previous batches stay.
+    pub fn import(&mut self, mut reader: impl Read) -> Result<usize, Error> {
+        let _timer = SlowOpTimer::start(self.slow_op_threshold, &self.tree_name, "import", None);
+        let mut magic = [0u8; EXPORT_MAGIC.len()];
+        match reader.read_exact(&mut magic) {
+            Ok(()) if magic == EXPORT_MAGIC => {}
+            Ok(()) => return Err(Error::MalformedExport("wrong magic".to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::MalformedExport("too short".to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let mut frame = AlignedVec::new();
+        if !read_frame(&mut reader, &mut frame)? {
+            return Err(Error::MalformedExport("no tree name".to_string()));
+        }
+        let exported_tree = String::from_utf8_lossy(frame.as_slice()).to_string();
+        if exported_tree != *self.tree_name {
+            return Err(Error::TreeMismatch {
+                expected: self.tree_name.to_string(),
+                got: exported_tree,
+                what: KeyOrValue::Value,
+            });
+        }
+
+        let mut count = 0;
+        let mut batch = Vec::with_capacity(IMPORT_BATCH);
+        loop {
+            let mut frame = AlignedVec::new();
+            let is_last = !read_frame(&mut reader, &mut frame)?;
+            if !is_last {
+                batch.push(frame);
+            }
+            if batch.len() == IMPORT_BATCH || (is_last && !batch.is_empty()) {
+                count += self.import_records(&batch)?;
+                batch.clear();
+            }
+            if is_last {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Write serialized Records that are missing or older locally, indexes are updated for all of them at once.
+    /// Returns the number of written records, nothing is written if one of the indexes fails.
+    pub(crate) fn import_records(&mut self, records: &[AlignedVec]) -> Result<usize, Error> {
+        let mut newer: Vec<(&ArchivedRecord, &AlignedVec, Action)> = vec![];
+        // Records being replaced, if they can be handed to the indexes as old data
+        let mut replaced: Vec<Option<sled::IVec>> = vec![];
+        let mut position: HashMap<GenericKey, usize> = HashMap::new();
+        for record_bytes in records {
+            let record = check_archived_root::<Record>(record_bytes)?;
+            let generic_key = GenericKey::from_archived(&record.meta.key);
+            let iterations = (record.meta_iteration, record.data_iteration);
+            if let Some(&i) = position.get(&generic_key) {
+                // Same record twice in one batch, keep the newer one
+                let (batched, _, action) = newer[i];
+                if (batched.meta_iteration, batched.data_iteration) < iterations {
+                    newer[i] = (record, record_bytes, action);
+                }
+                continue;
+            }
+            let (action, old) = match self.data.get(generic_key.to_bytes())? {
+                Some(existing_bytes) => {
+                    let existing = check_archived_root::<Record>(&existing_bytes)?;
+                    if (existing.meta_iteration, existing.data_iteration) >= iterations {
+                        continue;
+                    }
+                    let is_same_evolution =
+                        existing.data_evolution.as_original() == <V as TreeRoot>::evolution();
+                    (Action::Update, is_same_evolution.then_some(existing_bytes))
+                }
+                None => (Action::Insert, None),
+            };
+            position.insert(generic_key, newer.len());
+            newer.push((record, record_bytes, action));
+            replaced.push(old);
+        }
+
+        let mut changes: Vec<IndexChange> = Vec::with_capacity(newer.len());
+        for ((record, _, action), old) in newer.iter().zip(&replaced) {
+            let old_data = match old {
+                Some(old) => Some(IndexData::new(
+                    check_archived_root::<Record>(old)?.data.as_slice(),
+                )),
+                None => None,
+            };
+            changes.push((
+                GenericKey::from_archived(&record.meta.key),
+                old_data,
+                IndexData::new(record.data.as_slice()),
+                *action,
+            ));
+        }
+        self.update_indexes(&changes)?;
+        let mut batch = sled::Batch::default();
+        let mut meta_iterations = Vec::with_capacity(newer.len());
+        let mut journaled = Vec::new();
+        for (record, record_bytes, action) in &newer {
+            let generic_key = GenericKey::from_archived(&record.meta.key);
+            if matches!(action, Action::Insert) && record.meta_iteration == 0 {
+                let mut data = AlignedVec::new();
+                data.extend_from_slice(record.data.as_slice());
+                let record = Record {
+                    meta_iteration: 1,
+                    meta: record.meta.deserialize(&mut rkyv::Infallible)?,
+                    data_iteration: record.data_iteration,
+                    data_evolution: record.data_evolution.as_original(),
+                    data,
+                };
+                let record_bytes = to_bytes::<_, 128>(&record)?;
+                batch.insert(&generic_key.to_bytes(), record_bytes.as_slice());
+                journaled.push((generic_key, JournalAction::Create(record_bytes.to_vec())));
+                meta_iterations.push(1);
+            } else {
+                batch.insert(&generic_key.to_bytes(), record_bytes.as_slice());
+                let record_bytes = record_bytes.to_vec();
+                journaled.push((
+                    generic_key,
+                    match action {
+                        Action::Insert => JournalAction::Create(record_bytes),
+                        _ => JournalAction::Modify(record_bytes),
+                    },
+                ));
+                meta_iterations.push(record.meta_iteration);
+            }
+        }
+        self.data.apply_batch(batch)?;
+        for (generic_key, action) in journaled {
+            journal::append(self.journal.as_ref(), generic_key, || action)?;
+        }
+
+        for ((record, _, _), meta_iteration) in newer.iter().zip(meta_iterations) {
+            let generic_key = GenericKey::from_archived(&record.meta.key);
+            if !self.indexers.is_empty() {
+                let meta: RecordMeta = record.meta.deserialize(&mut rkyv::Infallible)?;
+                for indexer in &mut self.indexers {
+                    indexer.meta_changed(generic_key, &meta)?;
+                }
+            }
+            let change = RecordHotChange {
+                tree: String::from(self.tree_name.as_str()),
+                key: generic_key,
+                meta_iteration,
+                data_iteration: record.data_iteration,
+                kind: ChangeKind::CreateOrChange,
+            };
+            self.queue_change(change)?;
+        }
+        Ok(newer.len())
+    }
+
+    /// Update all indexes with the changes of a bulk operation, before it is written to the tree.
+    /// If one of them fails, the ones already updated are rebuilt, so that all of them keep matching the tree.
+    fn update_indexes(&mut self, changes: &[IndexChange]) -> Result<(), Error> {
+        let tree = TypeErasedTree {
+            tree: &self.data,
+            evolution: <V as TreeRoot>::evolution(),
+        };
+        for i in 0..self.indexers.len() {
+            if let Err(e) = self.indexers[i].update_batch(tree, changes) {
+                for indexer in &mut self.indexers[..i] {
+                    if let Err(e) = indexer.rebuild(tree) {
+                        error!(
+                            "{}: index rebuild after a failed batch: {e:?}",
+                            self.tree_name
+                        );
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_out(&mut self, key: K) {
+        if self.is_local() {
+            let borrows = &mut self.borrows.blocking_write().borrows;
+            let borrowed_keys = borrows.entry(self.tree_name.to_string()).or_default();
+            borrowed_keys.insert(key.to_generic(), vec![self.uuid]);
+            return;
+        }
+        if send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::CheckOut(self.tree_name.as_str().to_string(), key.to_generic()),
+        )
+        .is_err()
+        {
+            error!("check_out: mpsc error");
+        }
+    }
+
+    /// Check out a record without waiting in the queue behind another client.
+    /// Resolves once the server answered: Ok if this client holds the record now, Error::CheckedOutByOther if someone
+    /// else does, Error::NotConnected if the request was not answered.
+    pub fn try_check_out(&mut self, key: K) -> impl Future<Output = Result<(), Error>> {
+        let key = key.to_generic();
+        let (done_tx, mut done_rx) = postage::oneshot::channel();
+        let requested = if self.is_local() {
+            self.check_out(K::from_generic(key));
+            None
+        } else {
+            Some(send_cmd(
+                &mut self.cmd_tx,
+                self.cmd_timeout,
+                SyncClientCommand::TryCheckOut {
+                    tree_name: self.tree_name.as_str().to_string(),
+                    key,
+                    done: done_tx,
+                },
+            ))
+        };
+        let uuid = self.uuid;
+        async move {
+            match requested {
+                // Check outs are granted immediately
+                None => return Ok(()),
+                Some(r) => r?,
+            }
+            match postage::prelude::Stream::recv(&mut done_rx).await {
+                None => Err(Error::NotConnected),
+                Some(CheckOutReply::Queue(queue)) => match queue.first() {
+                    Some(holder) if *holder == uuid => Ok(()),
+                    Some(holder) => Err(Error::CheckedOutByOther(*holder)),
+                    None => Err(Error::Internal(format!(
+                        "check out of {key} answered with an empty queue"
+                    ))),
+                },
+                Some(CheckOutReply::Refused(reason)) => Err(Error::Refused(reason)),
+            }
+        }
+    }
+
+    pub fn release(&mut self, key: K) {
+        if self.is_local() {
+            if let Some(borrowed_keys) = self
+                .borrows
+                .blocking_write()
+                .borrows
+                .get_mut(self.tree_name.as_str())
+            {
+                borrowed_keys.remove(&key.to_generic());
+            }
+            return;
+        }
+        if send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::Release(self.tree_name.as_str().to_string(), key.to_generic()),
+        )
+        .is_err()
+        {
+            error!("check_out: mpsc error");
+        }
+    }
+
+    /// Leave the queue of a record that is checked out by another client, without ever becoming its holder.
+    /// Does nothing if this client already holds the record, release it instead.
+    pub fn cancel_checkout(&mut self, key: K) {
+        if self.is_local() {
+            // Check outs are granted immediately, nothing to cancel
+            return;
+        }
+        if send_cmd(
+            &mut self.cmd_tx,
+            self.cmd_timeout,
+            SyncClientCommand::CancelCheckOut(
+                self.tree_name.as_str().to_string(),
+                key.to_generic(),
+            ),
+        )
+        .is_err()
+        {
+            error!("cancel_checkout: mpsc error");
+        }
+    }
+
+    /// Records with temporary ids are only known to this client and are always checked out by it.
+    pub fn is_checked_out(&self, key: K) -> bool {
+        if is_temporary(key.to_generic().id) {
+            return true;
+        }
+        let rd = self.borrows.blocking_read();
+        if let Some(borrowed_keys) = rd.borrows.get(self.tree_name.as_str()) {
+            let key = key.to_generic();
+            match borrowed_keys.get(&key) {
+                Some(queue) => queue.first() == Some(&self.uuid),
+                None => false,
+            }
+        } else {
+            false
+        }
+    }
+
+    pub fn checked_out_by(&self, key: K) -> RecordCheckOutState {
+        if is_temporary(key.to_generic().id) {
+            return RecordCheckOutState::CheckedOut;
+        }
+        let rd = self.borrows.blocking_read();
+        if let Some(borrowed_keys) = rd.borrows.get(self.tree_name.as_str()) {
+            let key = key.to_generic();
             match borrowed_keys.get(&key) {
                 Some(queue) => {
-                    let Some(checked_out_by) = queue.get(0) else {
+                    let Some(checked_out_by) = queue.first() else {
                         return RecordCheckOutState::Empty;
                     };
                     if checked_out_by == &self.uuid {
                         RecordCheckOutState::CheckedOut
-                    } else if queue.contains(&self.uuid) {
-                        RecordCheckOutState::WaitingFor(*checked_out_by)
+                    } else if let Some(position) = queue.iter().position(|uuid| *uuid == self.uuid)
+                    {
+                        RecordCheckOutState::WaitingFor(*checked_out_by, position)
                     } else {
                         RecordCheckOutState::CheckedOutBy(*checked_out_by)
                     }
@@ -866,70 +2712,194 @@ where
         }
     }
 
+    /// All clients in the record's check out queue, starting with the holder, empty if it is not checked out.
+    pub fn checkout_queue(&self, key: K) -> Vec<Uuid> {
+        let rd = self.borrows.blocking_read();
+        rd.borrows
+            .get(self.tree_name.as_str())
+            .and_then(|borrowed_keys| borrowed_keys.get(&key.to_generic()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Position of this client in the record's check out queue, None if it is not waiting for the record
+    /// (including when it already holds it).
+    pub fn queue_position(&self, key: K) -> Option<QueuedCheckOut> {
+        let rd = self.borrows.blocking_read();
+        let queue = rd
+            .borrows
+            .get(self.tree_name.as_str())?
+            .get(&key.to_generic())?;
+        let position = queue.iter().position(|uuid| *uuid == self.uuid)?;
+        if position == 0 {
+            return None;
+        }
+        Some(QueuedCheckOut {
+            holder: queue[0],
+            position,
+            ahead: queue[..position].to_vec(),
+        })
+    }
+
     pub fn meta(&self, key: K) -> Result<Option<(u32, RecordMeta, u32, SimpleVersion)>, Error> {
         let key_bytes = key.to_generic().to_bytes();
         let value = self.data.get(key_bytes)?;
         match value {
             Some(bytes) => {
-                let archived_record = check_archived_root::<Record>(&bytes)?;
-                let meta: RecordMeta = archived_record.meta.deserialize(&mut rkyv::Infallible)?;
-                let evolution = archived_record
-                    .data_evolution
-                    .deserialize(&mut rkyv::Infallible)
-                    .expect("");
-
+                let header = RecordHeader::check(&bytes)?;
+                let meta: RecordMeta = header.meta().deserialize(&mut rkyv::Infallible)?;
                 Ok(Some((
-                    archived_record.meta_iteration,
+                    header.meta_iteration(),
                     meta,
-                    archived_record.data_iteration,
-                    evolution,
+                    header.data_iteration(),
+                    header.data_evolution(),
                 )))
             }
             None => Ok(None),
         }
     }
 
-    // pub fn latest_revisions(&self) -> impl Iterator<Item = K> {
-    //     todo!()
-    // }
-
-    pub fn all_revisions(&self) -> impl Iterator<Item = K> {
-        self.data.iter().keys().filter_map(|key| {
-            if let Ok(key) = key {
-                if key == KEY_POOL {
-                    return None;
-                }
-                let key = GenericKey::from_bytes(&key)?;
-                let key = K::from_generic(key);
-                Some(key)
-            } else {
-                warn!("Err in all_revisions");
-                None
+    /// Returns (meta_iteration, data_iteration, data_evolution) without deserializing meta or data.
+    pub fn iterations(&self, key: K) -> Result<Option<(u32, u32, SimpleVersion)>, Error> {
+        let key_bytes = key.to_generic().to_bytes();
+        let value = self.data.get(key_bytes)?;
+        match value {
+            Some(bytes) => {
+                let header = RecordHeader::check(&bytes)?;
+                Ok(Some((
+                    header.meta_iteration(),
+                    header.data_iteration(),
+                    header.data_evolution(),
+                )))
             }
-        })
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the latest local change to the record was not yet sent to the server.
+    pub fn is_pending(&self, key: K) -> Result<bool, Error> {
+        Ok(PendingChanges::is_pending(
+            &self.pending,
+            self.tree_name.as_str(),
+            key.to_generic(),
+        )?)
     }
 
-    pub fn iter_archived_with<F: FnMut(K, &V::Archived)>(
+    /// Wait until the record's data_iteration reaches at least min_data_iteration, either already or with a later
+    /// local or incoming change. Must be awaited from within a tokio runtime.
+    pub fn wait_for_iteration(
         &self,
-        mut f: F,
-    ) {
-        for key in self.data.iter().keys() {
-            let Ok(key) = key else {
-                continue
+        key: K,
+        min_data_iteration: u32,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<(), Error>> {
+        let key = key.to_generic();
+        // Subscribe before the first check, so that a change arriving in between is not missed
+        let mut updates_rx = self.updates_tx.subscribe();
+        let data = self.data.clone();
+        let tree_name = self.tree_name.clone();
+        let is_reached = move || -> Result<bool, Error> {
+            let Some(bytes) = data.get(key.to_bytes())? else {
+                return Ok(false);
             };
-            if key == KEY_POOL {
-                continue
+            Ok(RecordHeader::check(&bytes)?.data_iteration() >= min_data_iteration)
+        };
+        let wait = async move {
+            if is_reached()? {
+                return Ok(());
             }
-            let Some(key) = GenericKey::from_bytes(&key) else {
-                continue
-            };
-            let key = K::from_generic(key);
-            let key_bytes = key.to_generic().to_bytes();
-            let Ok(Some(bytes)) = self.data.get(key_bytes) else {
-                continue
+            while let Some(notification) = postage::prelude::Stream::recv(&mut updates_rx).await {
+                let ChangeNotification::Tree {
+                    key: changed,
+                    kind: ChangeKind::CreateOrChange,
+                } = notification
+                else {
+                    continue;
+                };
+                let is_same_record = changed.tree_name == tree_name
+                    && changed.id == key.id
+                    && changed.revision == key.revision;
+                if is_same_record && is_reached()? {
+                    return Ok(());
+                }
+            }
+            Err(Error::Mpsc)
+        };
+        async move {
+            tokio::time::timeout(timeout, wait)
+                .await
+                .map_err(|_| Error::Timeout(timeout))?
+        }
+    }
+
+    /// Persist the change as pending and hand it over to the sync task, which takes it out once sent, then let
+    /// the user know about it with the same ChangeKind, so that a meta only change (ModifyMeta) can be told apart
+    /// from a data one.
+    fn queue_change(&mut self, change: RecordHotChange) -> Result<(), Error> {
+        let (key, kind) = (change.key, change.kind.clone());
+        // Records with temporary ids are sent as a whole once a global id is assigned
+        if !is_temporary(change.key.id) {
+            if !self.is_local() {
+                PendingChanges::push(&self.pending, &change)?;
+            }
+            send_cmd(
+                &mut self.cmd_tx,
+                self.cmd_timeout,
+                SyncClientCommand::Change(change),
+            )?;
+        }
+        self.notify_user(key, kind);
+        Ok(())
+    }
+
+    fn notify_user(&mut self, key: GenericKey, kind: ChangeKind) {
+        let notification = ChangeNotification::Tree {
+            key: OpaqueKey::new(self.tree_name.clone(), key),
+            kind,
+        };
+        if self.updates_tx.try_send(notification).is_err() {
+            warn!("Notification send: mpsc fail");
+        }
+    }
+
+    /// Only the highest revision of each record, superseded ones are skipped.
+    /// Same as all_revisions for non-versioned trees, where every record has only revision 0.
+    pub fn latest_revisions(&self) -> impl Iterator<Item = K> {
+        latest_record_keys(&self.data).map(K::from_generic)
+    }
+
+    pub fn all_revisions(&self) -> impl Iterator<Item = K> {
+        record_keys(&self.data).map(K::from_generic)
+    }
+
+    /// All records with their values in key order, read in one pass over the tree.
+    ///
+    /// A record that cannot be read, e.g. because of an evolution mismatch without a migration, is yielded as an
+    /// Err naming it and the iteration goes on, so the caller decides whether to skip it or stop.
+    pub fn iter_values(&self) -> impl Iterator<Item = Result<(K, V), Error>> + '_ {
+        self.data
+            .range(GenericKey::range_all_ids())
+            .filter_map(move |entry| {
+                let (key_bytes, bytes) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                let key = record_key(&key_bytes)?;
+                let value = self
+                    .deserialize_record(&bytes)
+                    .map_err(|e| self.name_record(e, key));
+                Some(value.map(|value| (K::from_generic(key), value)))
+            })
+    }
+
+    pub fn iter_archived_with<F: FnMut(K, &V::Archived)>(&self, mut f: F) {
+        for generic_key in record_keys(&self.data) {
+            let key = K::from_generic(generic_key);
+            let Ok(Some(bytes)) = self.data.get(generic_key.to_bytes()) else {
+                continue;
             };
             let Ok(archived_record) = check_archived_root::<Record>(&bytes) else {
-                continue
+                continue;
             };
 
             let record_evolution: SimpleVersion = archived_record
@@ -941,13 +2911,1663 @@ where
                     "record evolution is {record_evolution} and code is {}",
                     V::evolution()
                 );
-                continue
+                continue;
             }
 
-            let Ok(archived_data) = check_archived_root::<Evolving<V>>(&archived_record.data) else {
-                continue
+            let Ok(archived_data) = check_archived_root::<Evolving<V>>(&archived_record.data)
+            else {
+                continue;
             };
             f(key, archived_data.0.get());
         }
     }
+
+    /// Keys of records that were last modified by the provided user, only record meta is read.
+    pub fn filter_by_author<'a>(
+        &'a self,
+        author: &'a str,
+    ) -> impl Iterator<Item = Result<K, Error>> + 'a {
+        self.filter_by_meta(move |meta| meta.modified_by.as_str() == author)
+    }
+
+    /// Keys of records that were last modified on the provided node, only record meta is read.
+    pub fn filter_by_node(&self, node: Uuid) -> impl Iterator<Item = Result<K, Error>> + '_ {
+        let node = node.into_bytes();
+        self.filter_by_meta(move |meta| meta.modified_on == node)
+    }
+
+    fn filter_by_meta<'a>(
+        &'a self,
+        mut predicate: impl FnMut(&ArchivedRecordMeta) -> bool + 'a,
+    ) -> impl Iterator<Item = Result<K, Error>> + 'a {
+        self.data.iter().filter_map(move |entry| {
+            let (key_bytes, record_bytes) = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let key = record_key(&key_bytes)?;
+            let header = match RecordHeader::check(&record_bytes) {
+                Ok(header) => header,
+                Err(e) => return Some(Err(e)),
+            };
+            predicate(header.meta()).then(|| Ok(K::from_generic(key)))
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{send_cmd, ClientConfig, Error, HillsClient, KeyOrValue, OpenMode, TypedTree};
+    use crate::clock::MockClock;
+    use crate::consts::CLIENT_IDS;
+    use crate::index::multi_named::MultiNamedIndex;
+    use crate::index::named::NamedIndex;
+    use crate::index::partition::PartitionIndex;
+    use crate::index::sled_named::SledNamedIndex;
+    use crate::index::{IndexData, Similarity, TreeSearch};
+    use crate::key_pool::KeyPool;
+    use crate::opaque::OpaqueKey;
+    use crate::opaque::{ExportFormat, OpaqueTree};
+    use crate::pending::PendingChanges;
+    use crate::record::{Record, RecordHeader, RecordMeta, Version};
+    use crate::sync::{ChangeKind, RecordHotChange};
+    use crate::sync_client::{ChangeNotification, SyncClientCommand};
+    use crate::tree::{TreeDescriptor, TreeStats};
+    use hills_base::index::IndexError;
+    use hills_base::{
+        Evolving, GenericKey, IdStrategy, Reflect, SimpleVersion, TreeKey, TreeRoot, TypeCollection,
+    };
+    use hills_derive::rkyv_common_derives;
+    use rkyv::{check_archived_root, to_bytes, AlignedVec, Deserialize};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[rkyv_common_derives]
+    pub(crate) struct Item {
+        pub(crate) name: String,
+    }
+
+    impl TreeRoot for Item {
+        fn tree_name() -> &'static str {
+            "items"
+        }
+
+        fn evolution() -> SimpleVersion {
+            SimpleVersion::new(0, 0)
+        }
+
+        fn versioning() -> bool {
+            false
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub(crate) struct ItemKey(GenericKey);
+
+    impl TreeKey for ItemKey {
+        fn tree_name() -> &'static str {
+            "items"
+        }
+
+        fn from_generic(key: GenericKey) -> Self {
+            ItemKey(key)
+        }
+
+        fn to_generic(&self) -> GenericKey {
+            self.0
+        }
+    }
+
+    #[test]
+    fn missing_current_evolution_is_repaired() {
+        let mut db = HillsClient::open_local_for_test();
+        db.open_tree::<ItemKey, Item>("").unwrap();
+        let descriptor = TreeDescriptor {
+            evolutions: Default::default(),
+            versioning: false,
+        };
+        db.descriptors
+            .insert("items", descriptor.to_stored_bytes().unwrap())
+            .unwrap();
+        db.open_trees.clear();
+
+        db.open_tree::<ItemKey, Item>("").unwrap();
+        let descriptor = stored_descriptor(&db, "items");
+        let mut current_tc = TypeCollection::new();
+        Item::reflect(&mut current_tc);
+        assert_eq!(
+            descriptor.evolutions.get(&SimpleVersion::new(0, 0)),
+            Some(&current_tc)
+        );
+    }
+
+    fn stored_descriptor(db: &HillsClient, tree_name: &str) -> TreeDescriptor {
+        let stored_bytes = db.descriptors.get(tree_name).unwrap().unwrap();
+        let (descriptor, outdated) = TreeDescriptor::from_stored_bytes(&stored_bytes).unwrap();
+        assert!(!outdated);
+        descriptor
+    }
+
+    #[test]
+    fn descriptor_in_baseline_format_is_migrated() {
+        use crate::tree::v0;
+
+        let mut db = HillsClient::open_local_for_test();
+        // Written before doc comments, field ids and defaults were reflected, with bare type names
+        let field = |ident: &str, ty: &str| v0::StructField {
+            ident: ident.to_string(),
+            ty: ty.to_string(),
+        };
+        let item = v0::TypeInfo::Struct(v0::StructInfo {
+            fields: vec![field("name", "String")],
+        });
+        let tc = v0::TypeCollection {
+            root: "Item".to_string(),
+            refs: [("Item".to_string(), item)].into(),
+        };
+        let descriptor = v0::TreeDescriptor {
+            evolutions: [(SimpleVersion::new(0, 0), tc)].into(),
+            versioning: false,
+        };
+        let descriptor_bytes = to_bytes::<_, 1024>(&descriptor).unwrap();
+        db.descriptors
+            .insert("items", descriptor_bytes.as_slice())
+            .unwrap();
+
+        db.open_tree::<ItemKey, Item>("").unwrap();
+        let descriptor = stored_descriptor(&db, "items");
+        let mut current_tc = TypeCollection::new();
+        Item::reflect(&mut current_tc);
+        assert_eq!(
+            descriptor.evolutions.get(&SimpleVersion::new(0, 0)),
+            Some(&current_tc)
+        );
+        assert_eq!(
+            db.tree_stats::<ItemKey, Item>().unwrap().latest_evolution,
+            Some(SimpleVersion::new(0, 0))
+        );
+    }
+
+    #[test]
+    fn descriptor_with_bare_type_names_is_accepted() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut current_tc = TypeCollection::new();
+        Item::reflect(&mut current_tc);
+        // Written before type names were qualified with their module path
+        let mut bare_tc = TypeCollection::new();
+        Item::reflect(&mut bare_tc);
+        let bare = |name: &str| name.rsplit("::").next().unwrap().to_string();
+        bare_tc.root = bare(&bare_tc.root);
+        bare_tc.refs = std::mem::take(&mut bare_tc.refs)
+            .into_iter()
+            .map(|(name, info)| (bare(&name), info))
+            .collect();
+        assert_ne!(bare_tc, current_tc);
+        let descriptor = TreeDescriptor {
+            evolutions: [(SimpleVersion::new(0, 0), bare_tc)].into(),
+            versioning: false,
+        };
+        db.descriptors
+            .insert("items", descriptor.to_stored_bytes().unwrap())
+            .unwrap();
+
+        db.open_tree::<ItemKey, Item>("").unwrap();
+        let descriptor = stored_descriptor(&db, "items");
+        assert_eq!(
+            descriptor.evolutions.get(&SimpleVersion::new(0, 0)),
+            Some(&current_tc)
+        );
+    }
+
+    #[test]
+    fn newer_evolution_is_stored() {
+        let mut db = HillsClient::open_local_for_test();
+        db.open_tree::<NoteKey, NoteV1>("").unwrap();
+        db.open_trees.clear();
+        db.open_tree::<NoteKey, NoteV3>("").unwrap();
+        let descriptor = stored_descriptor(&db, "notes");
+        let mut evolutions: Vec<_> = descriptor.evolutions.keys().copied().collect();
+        evolutions.sort();
+        assert_eq!(
+            evolutions,
+            vec![SimpleVersion::new(0, 1), SimpleVersion::new(0, 3)]
+        );
+    }
+
+    #[test]
+    fn local_client_inserts_and_updates() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let key = items
+            .insert(Item {
+                name: "first".to_string(),
+            })
+            .unwrap();
+        items.check_out(key);
+        assert!(items.is_checked_out(key));
+        let renamed = Item {
+            name: "renamed".to_string(),
+        };
+        items.update(key, renamed.clone()).unwrap();
+        assert_eq!(items.get(key).unwrap(), renamed);
+        items.release(key);
+        assert!(!items.is_checked_out(key));
+        assert_eq!(
+            items.iterations(key).unwrap(),
+            Some((1, 1, SimpleVersion::new(0, 0)))
+        );
+    }
+
+    #[test]
+    fn upsert_by_name() {
+        let mut db = HillsClient::open_local_for_test();
+        let index = NamedIndex::<ItemKey, Item>::new(crate::field_extractor!(Item, name));
+        db.add_indexer::<ItemKey, Item>(index.indexer()).unwrap();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let item = Item {
+            name: "first".to_string(),
+        };
+        let key = items.upsert_by(&index, item.clone()).unwrap();
+        assert!(matches!(
+            items.upsert_by(&index, item.clone()),
+            Err(Error::Usage(_))
+        ));
+        items.check_out(key);
+        assert_eq!(items.upsert_by(&index, item).unwrap(), key);
+        assert_eq!(items.iterations(key).unwrap().map(|i| i.1), Some(1));
+    }
+
+    #[test]
+    fn indexes_declared_on_open() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let first = items
+            .insert(Item {
+                name: "first".to_string(),
+            })
+            .unwrap();
+        // Make the next open a cold one, as after a restart
+        db.open_trees.remove("items");
+
+        let names = NamedIndex::<ItemKey, Item>::new(crate::field_extractor!(Item, name));
+        let versions = PartitionIndex::<ItemKey, Version>::by_version();
+        let mut items = db
+            .open_tree_with_indexes::<ItemKey, Item>("", vec![names.indexer(), versions.indexer()])
+            .unwrap();
+        assert_eq!(versions.keys_in(&Version::NonVersioned), vec![first]);
+        let item = Item {
+            name: "first".to_string(),
+        };
+        // Found by name, but not checked out
+        assert!(matches!(
+            items.upsert_by(&names, item),
+            Err(Error::Usage(_))
+        ));
+
+        let second = items
+            .insert(Item {
+                name: "second".to_string(),
+            })
+            .unwrap();
+        assert_eq!(versions.buckets(), vec![(Version::NonVersioned, 2)]);
+        let again = Item {
+            name: "second".to_string(),
+        };
+        items.check_out(second);
+        assert_eq!(items.upsert_by(&names, again).unwrap(), second);
+    }
+
+    #[test]
+    fn duplicate_reports_existing_key() {
+        let mut db = HillsClient::open_local_for_test();
+        let index = NamedIndex::<ItemKey, Item>::new(crate::field_extractor!(Item, name));
+        db.add_indexer::<ItemKey, Item>(index.indexer()).unwrap();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let item = Item {
+            name: "first".to_string(),
+        };
+        let key = items.insert(item.clone()).unwrap();
+        match items.insert(item) {
+            Err(Error::Index(IndexError::Duplicate { value, existing })) => {
+                assert_eq!(value, "first");
+                assert_eq!(existing, key.to_generic());
+            }
+            _ => panic!("expected Duplicate error"),
+        }
+    }
+
+    #[test]
+    fn malformed_ron_is_typed_error() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let r = OpaqueTree::insert_from_ron_str(&mut items, "(name: ");
+        assert!(matches!(r, Err(Error::RonParse(_))));
+    }
+
+    #[test]
+    fn key_of_another_tree_is_tree_mismatch() {
+        let mut db = HillsClient::open_local_for_test();
+        let r = db.open_tree::<DocKey, Item>("");
+        assert!(matches!(
+            r,
+            Err(Error::TreeMismatch {
+                what: KeyOrValue::Key,
+                ..
+            })
+        ));
+
+        let items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let doc_key = OpaqueKey::new(Arc::new("docs".to_string()), GenericKey::new(1, 0));
+        let r = OpaqueTree::is_checked_out(&items, &doc_key);
+        let Err(Error::TreeMismatch { expected, got, .. }) = r else {
+            panic!("expected TreeMismatch, got {r:?}");
+        };
+        assert_eq!((expected.as_str(), got.as_str()), ("items", "docs"));
+    }
+
+    #[test]
+    fn filter_by_author_and_node() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut items_alice = db.open_tree::<ItemKey, Item>("alice").unwrap();
+        let mut items_bob = db.open_tree::<ItemKey, Item>("bob").unwrap();
+        let alice_key = items_alice
+            .insert(Item {
+                name: "a".to_string(),
+            })
+            .unwrap();
+        let bob_key = items_bob
+            .insert(Item {
+                name: "b".to_string(),
+            })
+            .unwrap();
+
+        let by_alice: Vec<_> = items_bob
+            .filter_by_author("alice")
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(by_alice, vec![alice_key]);
+        assert_eq!(items_alice.filter_by_author("carol").count(), 0);
+
+        let (_, meta, _, _) = items_alice.meta(bob_key).unwrap().unwrap();
+        let node = uuid::Uuid::from_bytes(meta.modified_on);
+        let on_node: Vec<_> = items_alice
+            .filter_by_node(node)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(on_node, vec![alice_key, bob_key]);
+        assert_eq!(items_alice.filter_by_node(uuid::Uuid::nil()).count(), 0);
+    }
+
+    #[test]
+    fn queue_position_counts_clients_ahead() {
+        let mut db = HillsClient::open_local_for_test();
+        let items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let key = ItemKey(GenericKey::new(1, 0));
+        let (holder, other) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        items
+            .borrows
+            .blocking_write()
+            .borrows
+            .entry("items".to_string())
+            .or_default()
+            .insert(key.0, vec![holder, other, items.uuid]);
+
+        let queued = items.queue_position(key).unwrap();
+        assert_eq!(queued.holder, holder);
+        assert_eq!(queued.position, 2);
+        assert_eq!(queued.ahead, vec![holder, other]);
+        assert_eq!(items.queue_position(ItemKey(GenericKey::new(2, 0))), None);
+    }
+
+    #[test]
+    fn full_command_channel_is_sync_busy() {
+        let (mut cmd_tx, _cmd_rx) = postage::mpsc::channel(1);
+        let timeout = Duration::from_millis(10);
+        send_cmd(&mut cmd_tx, timeout, SyncClientCommand::Disconnect).unwrap();
+        let r = send_cmd(&mut cmd_tx, timeout, SyncClientCommand::Disconnect);
+        assert!(matches!(r, Err(Error::SyncBusy)));
+    }
+
+    #[test]
+    fn blocked_command_is_sent_once_there_is_room() {
+        let (mut cmd_tx, mut cmd_rx) = postage::mpsc::channel(1);
+        let timeout = Duration::from_secs(5);
+        send_cmd(&mut cmd_tx, timeout, SyncClientCommand::Disconnect).unwrap();
+        let receiver = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut received = 0;
+            while received < 2 && postage::stream::Stream::blocking_recv(&mut cmd_rx).is_some() {
+                received += 1;
+            }
+            received
+        });
+        let started = std::time::Instant::now();
+        send_cmd(&mut cmd_tx, timeout, SyncClientCommand::Disconnect).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(receiver.join().unwrap(), 2);
+    }
+
+    #[rkyv_common_derives]
+    struct Doc {
+        title: String,
+    }
+
+    impl TreeRoot for Doc {
+        fn tree_name() -> &'static str {
+            "docs"
+        }
+
+        fn evolution() -> SimpleVersion {
+            SimpleVersion::new(0, 0)
+        }
+
+        fn versioning() -> bool {
+            true
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct DocKey(GenericKey);
+
+    impl TreeKey for DocKey {
+        fn tree_name() -> &'static str {
+            "docs"
+        }
+
+        fn from_generic(key: GenericKey) -> Self {
+            DocKey(key)
+        }
+
+        fn to_generic(&self) -> GenericKey {
+            self.0
+        }
+    }
+
+    /// Write a copy of the first revision under another revision number and version, as if it was released.
+    fn put_revision(docs: &TypedTree<DocKey, Doc>, first: DocKey, revision: u32, version: Version) {
+        let bytes = docs.data.get(first.0.to_bytes()).unwrap().unwrap();
+        let archived = check_archived_root::<Record>(&bytes).unwrap();
+        let mut meta: RecordMeta = archived.meta.deserialize(&mut rkyv::Infallible).unwrap();
+        meta.key = GenericKey::new(first.0.id, revision);
+        meta.version = version;
+        let mut data = AlignedVec::new();
+        data.extend_from_slice(&archived.data);
+        let record = Record {
+            meta_iteration: archived.meta_iteration,
+            meta,
+            data_iteration: archived.data_iteration,
+            data_evolution: archived.data_evolution.as_original(),
+            data,
+        };
+        let record_bytes = to_bytes::<_, 128>(&record).unwrap();
+        docs.data
+            .insert(record.meta.key.to_bytes(), record_bytes.as_slice())
+            .unwrap();
+    }
+
+    #[test]
+    fn released_draft_cannot_be_updated() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut docs = db.open_tree::<DocKey, Doc>("").unwrap();
+        let doc = |title: &str| Doc {
+            title: title.to_string(),
+        };
+        let key = docs.insert(doc("draft")).unwrap();
+        assert!(matches!(docs.release_version(key, 1), Err(Error::Usage(_))));
+        docs.check_out(key);
+        docs.update(key, doc("final")).unwrap();
+        docs.release_version(key, 1).unwrap();
+
+        let (meta_iteration, meta, data_iteration, _) = docs.meta(key).unwrap().unwrap();
+        assert_eq!(meta.version, Version::Released(1));
+        assert_eq!((meta_iteration, data_iteration), (2, 1));
+        assert_eq!(docs.get(key).unwrap().title, "final");
+        assert!(matches!(
+            docs.release_version(key, 2),
+            Err(Error::VersioningMismatch(_))
+        ));
+        assert!(matches!(
+            docs.update(key, doc("changed")),
+            Err(Error::VersioningMismatch(_))
+        ));
+
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let item = items
+            .insert(Item {
+                name: "item".to_string(),
+            })
+            .unwrap();
+        items.check_out(item);
+        assert!(matches!(
+            items.release_version(item, 0),
+            Err(Error::VersioningMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn revision_is_branched_from_released() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut docs = db.open_tree::<DocKey, Doc>("").unwrap();
+        let first = docs
+            .insert(Doc {
+                title: "doc".to_string(),
+            })
+            .unwrap();
+        docs.check_out(first);
+        assert!(matches!(
+            docs.create_revision(first),
+            Err(Error::VersioningMismatch(_))
+        ));
+        docs.release_version(first, 0).unwrap();
+        // Watch what is handed to the sync task, as a client with a server would
+        let (cmd_tx, mut cmd_rx) = postage::mpsc::channel(64);
+        docs.cmd_tx = cmd_tx;
+        docs.local = false;
+
+        let second = docs.create_revision(first).unwrap();
+        assert_eq!(second.0, GenericKey::new(first.0.id, 1));
+        let mut changes = vec![];
+        while let Ok(cmd) = postage::prelude::Stream::try_recv(&mut cmd_rx) {
+            if let SyncClientCommand::Change(change) = cmd {
+                changes.push((change.key, change.kind, change.meta_iteration));
+            }
+        }
+        assert!(matches!(
+            changes.as_slice(),
+            [(key, ChangeKind::CreateOrChange, 0)] if *key == second.0
+        ));
+        let pending = PendingChanges::all(&docs.pending).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].key.id, second.0.id);
+        assert_eq!(pending[0].key.revision, 1);
+        assert!(matches!(pending[0].kind, ChangeKind::CreateOrChange));
+        assert_eq!(docs.get(second).unwrap().title, "doc");
+        let (_, meta, _, _) = docs.meta(second).unwrap().unwrap();
+        assert_eq!(meta.version, Version::Draft(0));
+        assert_eq!(docs.latest_revisions().collect::<Vec<_>>(), vec![second]);
+        assert!(matches!(
+            docs.create_revision(first),
+            Err(Error::VersioningMismatch(_))
+        ));
+        assert!(matches!(docs.create_revision(second), Err(Error::Usage(_))));
+    }
+
+    #[test]
+    fn compact_history_keeps_latest_and_released() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut docs = db.open_tree::<DocKey, Doc>("").unwrap();
+        let first = docs
+            .insert(Doc {
+                title: "doc".to_string(),
+            })
+            .unwrap();
+        put_revision(&docs, first, 0, Version::Released(0));
+        put_revision(&docs, first, 1, Version::Released(0));
+        put_revision(&docs, first, 2, Version::Released(0));
+        put_revision(&docs, first, 3, Version::Draft(0));
+
+        assert_eq!(docs.compact_history(true, 1).unwrap(), 0);
+        assert_eq!(docs.all_revisions().count(), 4);
+
+        assert_eq!(docs.compact_history(false, 2).unwrap(), 2);
+        let revisions: Vec<u32> = docs.all_revisions().map(|k| k.0.revision).collect();
+        assert_eq!(revisions, vec![2, 3]);
+
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        assert!(matches!(
+            items.compact_history(false, 1),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn latest_revisions_skip_superseded() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut docs = db.open_tree::<DocKey, Doc>("").unwrap();
+        let first = docs
+            .insert(Doc {
+                title: "first".to_string(),
+            })
+            .unwrap();
+        let second = docs
+            .insert(Doc {
+                title: "second".to_string(),
+            })
+            .unwrap();
+        put_revision(&docs, first, 1, Version::Released(0));
+        put_revision(&docs, first, 2, Version::Draft(0));
+
+        let latest: Vec<GenericKey> = docs.latest_revisions().map(|k| k.0).collect();
+        assert_eq!(
+            latest,
+            vec![
+                GenericKey::new(first.0.id, 2),
+                GenericKey::new(second.0.id, 0)
+            ]
+        );
+        let opaque: Vec<GenericKey> = OpaqueTree::latest_revisions(&docs)
+            .map(|k| GenericKey::new(k.id, k.revision))
+            .collect();
+        assert_eq!(opaque, latest);
+
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        for name in ["a", "b"] {
+            items.insert(Item { name: name.into() }).unwrap();
+        }
+        assert!(items.latest_revisions().eq(items.all_revisions()));
+    }
+
+    #[test]
+    fn failed_import_batch_is_rolled_back() {
+        let mut source = HillsClient::open_local_for_test();
+        let mut items = source.open_tree::<ItemKey, Item>("").unwrap();
+        let mut keys = vec![];
+        for name in ["a", "b", "c"] {
+            keys.push(
+                items
+                    .insert(Item {
+                        name: name.to_string(),
+                    })
+                    .unwrap(),
+            );
+        }
+        let mut exported = vec![];
+        items.export(&mut exported).unwrap();
+
+        let mut target = HillsClient::open_local_for_test();
+        let index = NamedIndex::<ItemKey, Item>::new(crate::field_extractor!(Item, name));
+        target
+            .add_indexer::<ItemKey, Item>(index.indexer())
+            .unwrap();
+        let mut imported = target.open_tree::<ItemKey, Item>("").unwrap();
+        let existing = imported
+            .insert_at(
+                5,
+                Item {
+                    name: "c".to_string(),
+                },
+            )
+            .unwrap()
+            .key;
+        assert!(matches!(
+            imported.import(exported.as_slice()),
+            Err(Error::Index(IndexError::Duplicate { .. }))
+        ));
+        for key in &keys {
+            assert!(matches!(imported.get(*key), Err(Error::RecordNotFound)));
+        }
+        assert_eq!(index.get("a"), None);
+        assert_eq!(index.get("b"), None);
+        assert_eq!(index.get("c"), Some(existing));
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let mut source = HillsClient::open_local_for_test();
+        let mut items = source.open_tree::<ItemKey, Item>("").unwrap();
+        let mut keys = vec![];
+        for name in ["a", "b", "c"] {
+            keys.push(
+                items
+                    .insert(Item {
+                        name: name.to_string(),
+                    })
+                    .unwrap(),
+            );
+        }
+        let mut exported = vec![];
+        assert_eq!(items.export(&mut exported).unwrap(), 3);
+
+        let mut target = HillsClient::open_local_for_test();
+        let mut imported = target.open_tree::<ItemKey, Item>("").unwrap();
+        assert_eq!(imported.import(exported.as_slice()).unwrap(), 3);
+        for key in &keys {
+            assert_eq!(imported.get(*key).unwrap(), items.get(*key).unwrap());
+            let (_, data_iteration, evolution) = items.iterations(*key).unwrap().unwrap();
+            // Not a creation, the ids were issued to the source database
+            assert_eq!(
+                imported.iterations(*key).unwrap(),
+                Some((1, data_iteration, evolution))
+            );
+        }
+        assert_eq!(imported.import(exported.as_slice()).unwrap(), 0);
+
+        let mut huge = super::EXPORT_MAGIC.to_vec();
+        huge.extend_from_slice(&5u32.to_le_bytes());
+        huge.extend_from_slice(b"items");
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            imported.import(huge.as_slice()),
+            Err(Error::MalformedExport(_))
+        ));
+
+        let mut docs = target.open_tree::<DocKey, Doc>("").unwrap();
+        assert!(matches!(
+            docs.import(exported.as_slice()),
+            Err(Error::TreeMismatch {
+                what: KeyOrValue::Value,
+                ..
+            })
+        ));
+        assert!(matches!(
+            docs.import(&b"not an export"[..]),
+            Err(Error::MalformedExport(_))
+        ));
+    }
+
+    #[rkyv_common_derives]
+    struct NoteV1 {
+        title: String,
+        body: String,
+    }
+
+    impl TreeRoot for NoteV1 {
+        fn tree_name() -> &'static str {
+            "notes"
+        }
+
+        fn evolution() -> SimpleVersion {
+            SimpleVersion::new(0, 1)
+        }
+
+        fn versioning() -> bool {
+            false
+        }
+    }
+
+    /// Fields reordered, so NoteV1 cannot view it as a prefix
+    #[rkyv_common_derives]
+    struct NoteV2 {
+        pinned: bool,
+        body: String,
+        title: String,
+    }
+
+    impl TreeRoot for NoteV2 {
+        fn tree_name() -> &'static str {
+            "notes"
+        }
+
+        fn evolution() -> SimpleVersion {
+            SimpleVersion::new(0, 2)
+        }
+
+        fn versioning() -> bool {
+            false
+        }
+    }
+
+    /// Only adds fields to NoteV1
+    #[rkyv_common_derives]
+    struct NoteV3 {
+        title: String,
+        body: String,
+        tags: Option<Vec<String>>,
+        #[reflect(default)]
+        stars: u32,
+    }
+
+    impl TreeRoot for NoteV3 {
+        fn tree_name() -> &'static str {
+            "notes"
+        }
+
+        fn evolution() -> SimpleVersion {
+            SimpleVersion::new(0, 3)
+        }
+
+        fn versioning() -> bool {
+            false
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct NoteKey(GenericKey);
+
+    impl TreeKey for NoteKey {
+        fn tree_name() -> &'static str {
+            "notes"
+        }
+
+        fn from_generic(key: GenericKey) -> Self {
+            NoteKey(key)
+        }
+
+        fn to_generic(&self) -> GenericKey {
+            self.0
+        }
+    }
+
+    #[test]
+    fn newer_record_read_through_down_migration() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut notes_v2 = db.open_tree::<NoteKey, NoteV2>("").unwrap();
+        let key = notes_v2
+            .insert(NoteV2 {
+                pinned: true,
+                body: "body".to_string(),
+                title: "title".to_string(),
+            })
+            .unwrap();
+
+        let notes_v1 = db.open_tree::<NoteKey, NoteV1>("").unwrap();
+        assert!(matches!(
+            notes_v1.get(key),
+            Err(Error::EvolutionMismatch(_))
+        ));
+        let Err(Error::EvolutionMismatch(e)) = notes_v1.get_many([key]) else {
+            panic!("batch with a record of another evolution must fail");
+        };
+        assert!(e.starts_with(&format!("notes/{}", key.0)), "{e}");
+        let mut values = notes_v1.iter_values();
+        assert!(matches!(
+            values.next(),
+            Some(Err(Error::EvolutionMismatch(_)))
+        ));
+        assert!(values.next().is_none());
+
+        db.add_migration(|note: NoteV2| NoteV1 {
+            title: note.title,
+            body: note.body,
+        })
+        .unwrap();
+        let notes_v1 = db.open_tree::<NoteKey, NoteV1>("").unwrap();
+        let expected = NoteV1 {
+            title: "title".to_string(),
+            body: "body".to_string(),
+        };
+        assert_eq!(notes_v1.get(key).unwrap(), expected);
+        let title = notes_v1.get_archived(key, |note| note.title.to_string());
+        assert_eq!(title.unwrap().as_deref(), Some("title"));
+        let values: Vec<_> = notes_v1.iter_values().map(Result::unwrap).collect();
+        assert_eq!(values, vec![(key, expected.clone())]);
+        let missing = NoteKey(GenericKey::new(key.0.id + 1, 0));
+        let many = notes_v1.get_many([missing, key]).unwrap();
+        assert_eq!(many, vec![(missing, None), (key, Some(expected))]);
+
+        assert!(matches!(
+            db.add_migration(|note: NoteV1| note),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn older_record_read_with_added_fields_filled_in() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut notes_v1 = db.open_tree::<NoteKey, NoteV1>("").unwrap();
+        let key = notes_v1
+            .insert(NoteV1 {
+                title: "title".to_string(),
+                body: "body".to_string(),
+            })
+            .unwrap();
+
+        assert!(matches!(
+            db.add_upgrade::<NoteV2, NoteV3>(),
+            Err(Error::EvolutionMismatch(_))
+        ));
+        db.add_upgrade::<NoteV1, NoteV3>().unwrap();
+        let notes_v3 = db.open_tree::<NoteKey, NoteV3>("").unwrap();
+        let expected = NoteV3 {
+            title: "title".to_string(),
+            body: "body".to_string(),
+            tags: None,
+            stars: 0,
+        };
+        assert_eq!(notes_v3.get(key).unwrap(), expected);
+    }
+
+    #[test]
+    fn partition_by_version() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut docs = db.open_tree::<DocKey, Doc>("").unwrap();
+        let released = docs
+            .insert(Doc {
+                title: "released".to_string(),
+            })
+            .unwrap();
+        put_revision(&docs, released, 0, Version::Released(0));
+
+        let index = PartitionIndex::<DocKey, Version>::by_version();
+        db.add_indexer::<DocKey, Doc>(index.indexer()).unwrap();
+        let mut docs = db.open_tree::<DocKey, Doc>("").unwrap();
+        assert_eq!(index.keys_in(&Version::Released(0)), vec![released]);
+
+        let draft = docs
+            .insert(Doc {
+                title: "draft".to_string(),
+            })
+            .unwrap();
+        assert_eq!(index.keys_in(&Version::Draft(0)), vec![draft]);
+        assert_eq!(
+            index.buckets(),
+            vec![(Version::Draft(0), 1), (Version::Released(0), 1)]
+        );
+
+        docs.check_out(draft);
+        docs.remove(draft).unwrap();
+        assert!(index.keys_in(&Version::Draft(0)).is_empty());
+        assert_eq!(index.buckets(), vec![(Version::Released(0), 1)]);
+    }
+
+    #[test]
+    fn full_history_round_trip() {
+        let mut source = HillsClient::open_local_for_test();
+        let mut docs = source.open_tree::<DocKey, Doc>("").unwrap();
+        let first = docs
+            .insert(Doc {
+                title: "doc".to_string(),
+            })
+            .unwrap();
+        put_revision(&docs, first, 0, Version::Released(1));
+        put_revision(&docs, first, 1, Version::Released(0));
+        put_revision(&docs, first, 2, Version::Draft(0));
+
+        for format in [ExportFormat::Ron, ExportFormat::RonPretty] {
+            let history = docs.export_full_history(format).unwrap();
+            let mut target = HillsClient::open_local_for_test();
+            let mut imported = target.open_tree::<DocKey, Doc>("").unwrap();
+            assert_eq!(imported.import_full_history(&history).unwrap(), 3);
+            assert_eq!(imported.import_full_history(&history).unwrap(), 0);
+
+            let revisions: Vec<DocKey> = imported.all_revisions().collect();
+            assert_eq!(revisions, docs.all_revisions().collect::<Vec<_>>());
+            for key in revisions {
+                let (_, meta, _, _) = imported.meta(key).unwrap().unwrap();
+                let (_, source_meta, _, _) = docs.meta(key).unwrap().unwrap();
+                assert_eq!(
+                    format!("{:?}", meta.version),
+                    format!("{:?}", source_meta.version)
+                );
+                assert_eq!(imported.get(key).unwrap(), docs.get(key).unwrap());
+                let (meta_iteration, data_iteration, evolution) =
+                    docs.iterations(key).unwrap().unwrap();
+                assert_eq!(
+                    imported.iterations(key).unwrap(),
+                    Some((meta_iteration.max(1), data_iteration, evolution))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn offline_changes_are_pending() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let path = std::env::temp_dir().join(format!("hills_pending_{}", uuid::Uuid::new_v4()));
+        let (mut db, _updates_rx, _join) =
+            HillsClient::open(&path, OpenMode::Temporary, &rt).unwrap();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let inserted = items
+            .insert_at(
+                1,
+                Item {
+                    name: "offline".to_string(),
+                },
+            )
+            .unwrap();
+        assert!(items.is_pending(inserted.key).unwrap());
+        assert!(!items.is_pending(ItemKey(GenericKey::new(2, 0))).unwrap());
+
+        let pending = db.pending_changes().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].key.tree_name.as_str(), "items");
+        assert_eq!(pending[0].key.id, 1);
+        assert!(matches!(pending[0].kind, ChangeKind::CreateOrChange));
+    }
+
+    #[test]
+    fn reserved_ceiling_is_configurable() {
+        let mut db = HillsClient::open_local_for_test_with_config(ClientConfig {
+            reserved_ceiling: 16,
+            ..Default::default()
+        });
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let item = |name: &str| Item {
+            name: name.to_string(),
+        };
+        assert_eq!(items.insert_at(15, item("settings")).unwrap().key.0.id, 15);
+        assert!(matches!(
+            items.insert_at(16, item("too high")),
+            Err(Error::Usage(_))
+        ));
+        assert_eq!(items.insert(item("pooled")).unwrap().0.id, 16);
+    }
+
+    #[test]
+    fn ids_of_unsent_records_are_reused() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let path = std::env::temp_dir().join(format!("hills_reuse_{}", uuid::Uuid::new_v4()));
+        let (mut db, _updates_rx, _join) =
+            HillsClient::open(&path, OpenMode::Temporary, &rt).unwrap();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        KeyPool::feed_for(&items.data, 2000..2010).unwrap();
+        let item = |name: &str| Item {
+            name: name.to_string(),
+        };
+        let draft = items.insert(item("draft")).unwrap();
+        let sent = items.insert(item("sent")).unwrap();
+        // As if the sync task already took the creation of the second one out of the queue
+        let creation = RecordHotChange {
+            tree: "items".to_string(),
+            key: sent.0,
+            meta_iteration: 0,
+            data_iteration: 0,
+            kind: ChangeKind::CreateOrChange,
+        };
+        assert!(PendingChanges::claim(&items.pending, &creation)
+            .unwrap()
+            .is_some());
+        for key in [draft, sent] {
+            let mut borrows = items.borrows.blocking_write();
+            let borrowed_keys = borrows.borrows.entry("items".to_string()).or_default();
+            borrowed_keys.insert(key.0, vec![items.uuid]);
+        }
+
+        items.remove(draft).unwrap();
+        items.remove(sent).unwrap();
+        let pending = db.pending_changes().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].key.id, sent.0.id);
+        assert!(matches!(pending[0].kind, ChangeKind::Remove));
+        assert_eq!(items.key_pool_stats().unwrap(), 9);
+        assert_eq!(items.insert(item("reused")).unwrap(), draft);
+    }
+
+    #[test]
+    fn second_open_is_db_locked() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let path = std::env::temp_dir().join(format!("hills_locked_{}", uuid::Uuid::new_v4()));
+        let _first = HillsClient::open(&path, OpenMode::Temporary, &rt).unwrap();
+        assert!(HillsClient::is_locked(&path));
+        let second = HillsClient::open(&path, OpenMode::Temporary, &rt);
+        assert!(matches!(second, Err(Error::DbLocked(_))));
+    }
+
+    #[test]
+    fn is_locked_only_while_open() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let path = std::env::temp_dir().join(format!("hills_probe_{}", uuid::Uuid::new_v4()));
+        assert!(!HillsClient::is_locked(&path));
+        let (client, _, _) = HillsClient::open(&path, OpenMode::Persistent, &rt).unwrap();
+        assert!(HillsClient::is_locked(&path));
+        drop(client);
+        // Sync task holds its own handle to the database until it notices the client is gone
+        let released = (0..50).any(|_| {
+            std::thread::sleep(Duration::from_millis(20));
+            !HillsClient::is_locked(&path)
+        });
+        let _ = std::fs::remove_dir_all(&path);
+        assert!(released);
+    }
+
+    #[test]
+    fn journal_rebuilds_records_up_to_serial() {
+        let mut db = HillsClient::open_local_for_test();
+        db.enable_journal::<ItemKey, Item>().unwrap();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        assert!(db.enable_journal::<ItemKey, Item>().is_err());
+        let a = items.insert(Item { name: "a".into() }).unwrap();
+        let b = items.insert(Item { name: "b".into() }).unwrap();
+        items.check_out(a);
+        items.update(a, Item { name: "a2".into() }).unwrap();
+        items.check_out(b);
+        items.remove(b).unwrap();
+
+        let journal = db.journal::<ItemKey, Item>().unwrap();
+        let serials: Vec<u64> = journal.entries.iter().map(|e| e.serial).collect();
+        assert_eq!(serials, [0, 1, 2, 3]);
+        assert!(matches!(
+            journal.entries[3].action,
+            crate::journal::Action::Remove
+        ));
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let rebuild = |up_to| {
+            let path = std::env::temp_dir().join(format!("hills_journal_{}", uuid::Uuid::new_v4()));
+            let (mut rebuilt, _, _) =
+                HillsClient::rebuild_from_journal(&path, &rt, &journal, up_to).unwrap();
+            let items = rebuilt.open_tree::<ItemKey, Item>("").unwrap();
+            let names: Vec<String> = items.iter_values().map(|r| r.unwrap().1.name).collect();
+            let twice = HillsClient::rebuild_from_journal(&path, &rt, &journal, up_to);
+            assert!(matches!(
+                twice,
+                Err(Error::DbLocked(_)) | Err(Error::Usage(_))
+            ));
+            drop(items);
+            drop(rebuilt);
+            let _ = std::fs::remove_dir_all(&path);
+            names
+        };
+        assert_eq!(rebuild(Some(1)), ["a", "b"]);
+        assert_eq!(rebuild(Some(2)), ["a2", "b"]);
+        assert_eq!(rebuild(None), ["a2"]);
+    }
+
+    #[test]
+    fn persisted_index_is_loaded_until_changed() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut db = HillsClient::open_local_for_test();
+        let extracted = Arc::new(AtomicUsize::new(0));
+        let counting_index = |db: &HillsClient| {
+            let extracted = extracted.clone();
+            NamedIndex::<ItemKey, Item>::new(move |item: &ArchivedItem| {
+                extracted.fetch_add(1, Ordering::Relaxed);
+                Ok(item.name.to_string())
+            })
+            .persisted(db, "by_name")
+            .unwrap()
+        };
+
+        let names = counting_index(&db);
+        db.add_indexer::<ItemKey, Item>(names.indexer()).unwrap();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let first = items
+            .insert(Item {
+                name: "first".into(),
+            })
+            .unwrap();
+        items
+            .insert(Item {
+                name: "second".into(),
+            })
+            .unwrap();
+        db.persist_indexes().unwrap();
+
+        // As after a restart, the saved copy is loaded without going through the records
+        db.open_trees.remove("items");
+        extracted.store(0, Ordering::Relaxed);
+        let names = counting_index(&db);
+        db.add_indexer::<ItemKey, Item>(names.indexer()).unwrap();
+        assert_eq!(extracted.load(Ordering::Relaxed), 0);
+        assert_eq!(names.get("first"), Some(first));
+
+        // Changes discard the copy until it is saved again
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        items
+            .insert(Item {
+                name: "third".into(),
+            })
+            .unwrap();
+        db.open_trees.remove("items");
+        extracted.store(0, Ordering::Relaxed);
+        let names = counting_index(&db);
+        db.add_indexer::<ItemKey, Item>(names.indexer()).unwrap();
+        assert_eq!(extracted.load(Ordering::Relaxed), 3);
+        assert!(names.get("third").is_some());
+    }
+
+    #[test]
+    fn index_stats_report_size() {
+        let mut db = HillsClient::open_local_for_test();
+        let names = NamedIndex::<ItemKey, Item>::new(crate::field_extractor!(Item, name));
+        let versions = PartitionIndex::<ItemKey, Version>::by_version();
+        let mut items = db
+            .open_tree_with_indexes::<ItemKey, Item>("", vec![names.indexer(), versions.indexer()])
+            .unwrap();
+        for name in ["first", "second"] {
+            items
+                .insert(Item {
+                    name: name.to_string(),
+                })
+                .unwrap();
+        }
+        let stats = db.index_stats();
+        assert_eq!(stats.len(), 2);
+        for stat in &stats {
+            assert_eq!(stat.tree_name, "items");
+            assert_eq!(stat.len, 2);
+            assert!(stat.memory_bytes > 0);
+        }
+        let named = stats.iter().find(|stat| stat.kind == "named").unwrap();
+        assert!(named.memory_bytes >= "first".len() + "second".len());
+    }
+
+    #[test]
+    fn sled_named_index_persists_mapping() {
+        let mut db = HillsClient::open_local_for_test();
+        let names = SledNamedIndex::<ItemKey, Item>::open(
+            &db,
+            "items_by_name",
+            crate::field_extractor!(Item, name),
+        )
+        .unwrap()
+        .case_sensitive(false);
+        let mut items = db
+            .open_tree_with_indexes::<ItemKey, Item>("", vec![names.indexer()])
+            .unwrap();
+        let first = items
+            .insert(Item {
+                name: "First".to_string(),
+            })
+            .unwrap();
+        let second = items
+            .insert(Item {
+                name: "second".to_string(),
+            })
+            .unwrap();
+        match items.insert(Item {
+            name: "FIRST".to_string(),
+        }) {
+            Err(Error::Index(IndexError::Duplicate { value, existing })) => {
+                assert_eq!(value, "first");
+                assert_eq!(existing, first.to_generic());
+            }
+            _ => panic!("expected Duplicate error"),
+        }
+        assert_eq!(names.get("first"), Some(first));
+
+        items.check_out(second);
+        items
+            .update(
+                second,
+                Item {
+                    name: "renamed".to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(names.get("second"), None);
+        assert_eq!(names.get("renamed"), Some(second));
+        let similar = names.get_similar("ren");
+        assert_eq!(similar.len(), 1);
+        assert_eq!(similar[0].0, second);
+
+        items.check_out(first);
+        items.remove(first).unwrap();
+        assert_eq!(names.get("first"), None);
+
+        let stats = db.index_stats();
+        assert_eq!(stats[0].kind, "sled_named");
+        assert_eq!(stats[0].len, 1);
+        assert_eq!(stats[0].memory_bytes, 0);
+
+        // Opened again as after a restart, before any rebuild
+        let names = SledNamedIndex::<ItemKey, Item>::open(
+            &db,
+            "items_by_name",
+            crate::field_extractor!(Item, name),
+        )
+        .unwrap();
+        assert_eq!(names.get("renamed"), Some(second));
+    }
+
+    #[test]
+    fn sled_named_index_is_reused_until_changed_elsewhere() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut db = HillsClient::open_local_for_test();
+        let extracted = Arc::new(AtomicUsize::new(0));
+        let counting_index = |db: &HillsClient| {
+            let extracted = extracted.clone();
+            SledNamedIndex::<ItemKey, Item>::open(
+                db,
+                "items_by_name",
+                move |item: &ArchivedItem| {
+                    extracted.fetch_add(1, Ordering::Relaxed);
+                    Ok(item.name.to_string())
+                },
+            )
+            .unwrap()
+        };
+
+        let names = counting_index(&db);
+        let mut items = db
+            .open_tree_with_indexes::<ItemKey, Item>("", vec![names.indexer()])
+            .unwrap();
+        let first = items
+            .insert(Item {
+                name: "first".into(),
+            })
+            .unwrap();
+        items
+            .insert(Item {
+                name: "second".into(),
+            })
+            .unwrap();
+        db.persist_indexes().unwrap();
+
+        // As after a restart, registered again without going through the records
+        for _ in 0..2 {
+            db.open_trees.remove("items");
+            extracted.store(0, Ordering::Relaxed);
+            let names = counting_index(&db);
+            db.open_tree_with_indexes::<ItemKey, Item>("", vec![names.indexer()])
+                .unwrap();
+            assert_eq!(extracted.load(Ordering::Relaxed), 0);
+            assert_eq!(names.get("first"), Some(first));
+            db.persist_indexes().unwrap();
+        }
+
+        // Changed while the index was not registered
+        db.open_trees.remove("items");
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        items.check_out(first);
+        items
+            .update(
+                first,
+                Item {
+                    name: "renamed".into(),
+                },
+            )
+            .unwrap();
+        db.open_trees.remove("items");
+        extracted.store(0, Ordering::Relaxed);
+        let names = counting_index(&db);
+        db.add_indexer::<ItemKey, Item>(names.indexer()).unwrap();
+        assert_eq!(extracted.load(Ordering::Relaxed), 2);
+        assert_eq!(names.get("first"), None);
+        assert_eq!(names.get("renamed"), Some(first));
+    }
+
+    #[test]
+    fn update_replaces_old_names() {
+        let mut db = HillsClient::open_local_for_test();
+        let names = NamedIndex::<ItemKey, Item>::new(crate::field_extractor!(Item, name))
+            .case_sensitive(false);
+        let aliases = MultiNamedIndex::<ItemKey, Item>::new(|item: &ArchivedItem| {
+            Ok(vec![item.name.to_string(), format!("{}-alias", item.name)])
+        });
+        let mut items = db
+            .open_tree_with_indexes::<ItemKey, Item>("", vec![names.indexer(), aliases.indexer()])
+            .unwrap();
+        items
+            .insert(Item {
+                name: "First".into(),
+            })
+            .unwrap();
+        let second = items
+            .insert(Item {
+                name: "Second".into(),
+            })
+            .unwrap();
+
+        items.check_out(second);
+        items
+            .update(
+                second,
+                Item {
+                    name: "Renamed".into(),
+                },
+            )
+            .unwrap();
+        assert_eq!(names.get("second"), None);
+        assert_eq!(names.get("renamed"), Some(second));
+        assert_eq!(aliases.get("Second-alias"), None);
+        assert_eq!(aliases.get("Renamed-alias"), Some(second));
+        assert_eq!(db.index_stats().iter().map(|s| s.len).sum::<usize>(), 2 + 4);
+    }
+
+    #[test]
+    fn named_index_search() {
+        let mut db = HillsClient::open_local_for_test();
+        let names = NamedIndex::<ItemKey, Item>::new(crate::field_extractor!(Item, name))
+            .case_sensitive(false);
+        db.add_indexer::<ItemKey, Item>(names.indexer()).unwrap();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let bolt = items
+            .insert(Item {
+                name: "Bolt".into(),
+            })
+            .unwrap();
+        let bolt_m3 = items
+            .insert(Item {
+                name: "Bolt M3".into(),
+            })
+            .unwrap();
+        items.insert(Item { name: "Nut".into() }).unwrap();
+
+        let hits = names.search("BOLT");
+        let hits: Vec<(ItemKey, &str, Similarity)> = hits
+            .iter()
+            .map(|hit| (hit.key, hit.name.as_str(), hit.similarity))
+            .collect();
+        assert_eq!(
+            hits,
+            vec![
+                (bolt, "bolt", Similarity::Exact),
+                (bolt_m3, "bolt m3", Similarity::Loose)
+            ]
+        );
+        assert_eq!(
+            names.name_desc(bolt_m3).unwrap(),
+            ("bolt m3".to_string(), String::new())
+        );
+        assert!(names.search("washer").is_empty());
+    }
+
+    #[test]
+    fn index_data_is_shared_between_indexers() {
+        let bytes = to_bytes::<_, 128>(&Evolving(Item {
+            name: "first".to_string(),
+        }))
+        .unwrap();
+        let data = IndexData::new(&bytes);
+        assert_eq!(data.archived::<Item>().unwrap().name, "first");
+        // Second decode skips validation and must see the same value
+        assert_eq!(data.archived::<Item>().unwrap().name, "first");
+
+        let malformed = IndexData::new(&bytes[..bytes.len() - 1]);
+        assert!(malformed.archived::<Item>().is_err());
+        assert!(malformed.archived::<Item>().is_err());
+    }
+
+    #[test]
+    fn record_header_matches_full_check() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let key = items
+            .insert(Item {
+                name: "first".to_string(),
+            })
+            .unwrap();
+        let bytes = items
+            .data
+            .get(key.to_generic().to_bytes())
+            .unwrap()
+            .unwrap();
+        let archived = check_archived_root::<Record>(&bytes).unwrap();
+        let header = RecordHeader::check(&bytes).unwrap();
+        assert_eq!(header.meta_iteration(), archived.meta_iteration);
+        assert_eq!(header.data_iteration(), archived.data_iteration);
+        assert_eq!(
+            header.data_evolution(),
+            archived.data_evolution.as_original()
+        );
+        assert_eq!(header.meta().key, archived.meta.key);
+        assert_eq!(header.meta().modified_by, archived.meta.modified_by);
+
+        assert!(RecordHeader::check(&bytes[..8]).is_err());
+        assert!(RecordHeader::check(&[]).is_err());
+    }
+
+    #[test]
+    fn local_changes_are_notified_with_their_kind() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut docs = db.open_tree::<DocKey, Doc>("").unwrap();
+        let mut updates_rx = docs.updates_tx.subscribe();
+        let key = docs
+            .insert(Doc {
+                title: "first".to_string(),
+            })
+            .unwrap();
+        docs.check_out(key);
+        docs.release_version(key, 1).unwrap();
+        let mut kinds = vec![];
+        while let Ok(notification) = postage::prelude::Stream::try_recv(&mut updates_rx) {
+            if let ChangeNotification::Tree { kind, .. } = notification {
+                kinds.push(kind);
+            }
+        }
+        assert!(matches!(
+            kinds.as_slice(),
+            [ChangeKind::CreateOrChange, ChangeKind::ModifyMeta]
+        ));
+    }
+
+    #[rkyv_common_derives]
+    struct Tag {
+        label: String,
+    }
+
+    impl TreeRoot for Tag {
+        fn tree_name() -> &'static str {
+            "tags"
+        }
+
+        fn evolution() -> SimpleVersion {
+            SimpleVersion::new(0, 0)
+        }
+
+        fn versioning() -> bool {
+            false
+        }
+
+        fn id_strategy() -> IdStrategy<Self> {
+            IdStrategy::Explicit
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct TagKey(GenericKey);
+
+    impl TreeKey for TagKey {
+        fn tree_name() -> &'static str {
+            "tags"
+        }
+
+        fn from_generic(key: GenericKey) -> Self {
+            TagKey(key)
+        }
+
+        fn to_generic(&self) -> GenericKey {
+            self.0
+        }
+    }
+
+    #[test]
+    fn explicit_ids_are_provided_by_caller() {
+        let mut db = HillsClient::open_local_for_test();
+        let mut tags = db.open_tree::<TagKey, Tag>("").unwrap();
+        let tag = || Tag {
+            label: "urgent".to_string(),
+        };
+        assert!(matches!(tags.insert(tag()), Err(Error::Usage(_))));
+        assert!(matches!(
+            tags.insert_with_id(CLIENT_IDS.start - 1, tag()),
+            Err(Error::Usage(_))
+        ));
+        let id = CLIENT_IDS.start + 7;
+        let inserted = tags.insert_with_id(id, tag()).unwrap();
+        assert_eq!(inserted.key.0, GenericKey::new(id, 0));
+        assert_eq!(tags.get(inserted.key).unwrap().label, "urgent");
+        assert!(matches!(
+            tags.insert_with_id(id, tag()),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn timestamps_come_from_the_clock() {
+        let start = chrono::DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let clock = MockClock::new(start);
+        let mut db = HillsClient::open_local_for_test_with_config(ClientConfig {
+            clock: Arc::new(clock.clone()),
+            ..ClientConfig::default()
+        });
+        let mut items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let key = items
+            .insert(Item {
+                name: "first".to_string(),
+            })
+            .unwrap();
+        clock.advance(chrono::Duration::hours(1));
+        items.check_out(key);
+        items
+            .update(
+                key,
+                Item {
+                    name: "renamed".to_string(),
+                },
+            )
+            .unwrap();
+
+        let (_, meta, _, _) = items.meta(key).unwrap().unwrap();
+        assert_eq!(chrono::DateTime::from(meta.created), start);
+        assert_eq!(
+            chrono::DateTime::from(meta.modified),
+            start + chrono::Duration::hours(1)
+        );
+    }
+
+    #[test]
+    fn tree_stats_count_ids_and_revisions() {
+        let mut db = HillsClient::open_local_for_test();
+        let empty = db.tree_stats::<DocKey, Doc>().unwrap();
+        assert_eq!(empty, TreeStats::default());
+
+        let mut docs = db.open_tree::<DocKey, Doc>("").unwrap();
+        let pool = docs.key_pool_stats().unwrap();
+        let first = docs
+            .insert(Doc {
+                title: "first".to_string(),
+            })
+            .unwrap();
+        docs.insert(Doc {
+            title: "second".to_string(),
+        })
+        .unwrap();
+        put_revision(&docs, first, 1, Version::Released(0));
+
+        let stats = db.tree_stats::<DocKey, Doc>().unwrap();
+        assert_eq!(stats.ids, 2);
+        assert_eq!(stats.revisions, 3);
+        assert!(stats.approximate_bytes > 0);
+        assert_eq!(stats.key_pool, pool - 2);
+        assert_eq!(stats.latest_evolution, Some(SimpleVersion::new(0, 0)));
+    }
+
+    #[test]
+    fn blank_readable_name_falls_back_to_default() {
+        let mut db = HillsClient::open_local_for_test();
+        let default_name = db.readable_name().unwrap();
+        assert!(default_name.starts_with("node-"));
+        assert_eq!(default_name.len(), "node-".len() + 8);
+
+        db.set_readable_name("bench 1").unwrap();
+        assert_eq!(db.readable_name().unwrap(), "bench 1");
+        db.set_readable_name("  ").unwrap();
+        assert_eq!(db.readable_name().unwrap(), default_name);
+    }
+
+    #[test]
+    fn sync_errors_keep_their_source() {
+        use crate::common::Error as CommonError;
+        use std::error::Error as _;
+        use tokio_tungstenite::tungstenite;
+
+        let err: Error = CommonError::from(tungstenite::Error::ConnectionClosed).into();
+        assert!(matches!(err, Error::Common(CommonError::Ws(_))));
+        let source = err.source().expect("ws error is kept as the source");
+        assert_eq!(
+            source.to_string(),
+            tungstenite::Error::ConnectionClosed.to_string()
+        );
+
+        let err: Error = CommonError::from(tungstenite::Error::Capacity(
+            tungstenite::error::CapacityError::MessageTooLong {
+                size: 10,
+                max_size: 5,
+            },
+        ))
+        .into();
+        assert!(matches!(
+            err,
+            Error::Common(CommonError::MessageTooLarge {
+                size: 10,
+                max_size: 5
+            })
+        ));
+    }
 }
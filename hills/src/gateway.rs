@@ -0,0 +1,210 @@
+//! Minimal HTTP/JSON request router over OpaqueTree for clients that cannot speak the native sync protocol,
+//! such as browser apps. It does not bring an HTTP server of its own: the server passes method, path and body in
+//! and sends the returned status and body back with CONTENT_TYPE.
+//!
+//! Routes, records and keys are represented as JSON, keys as `"{id}.{revision}"` strings:
+//! * `GET /{tree}` - array of all the keys in a tree
+//! * `GET /{tree}/{id}.{revision}` - record
+//! * `POST /{tree}` - insert a record, returns its key
+//! * `PUT /{tree}/{id}.{revision}` - update a record
+//! * `DELETE /{tree}/{id}.{revision}` - remove a record
+//!
+//! Records are checked out for the duration of PUT and DELETE and released afterwards, unless this client
+//! held them already. Records held by another client are answered with 409.
+//! Errors are answered with `{"error": "..."}`.
+
+use crate::db::Error;
+use crate::opaque::{OpaqueKey, OpaqueTree};
+use hills_base::GenericKey;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+
+/// Content type of all the response bodies.
+pub const CONTENT_TYPE: &str = "application/json";
+
+pub struct Gateway {
+    trees: HashMap<String, Box<dyn OpaqueTree>>,
+    rt: Handle,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    fn ok(body: impl Into<String>) -> Self {
+        Response {
+            status: 200,
+            body: body.into(),
+        }
+    }
+
+    fn no_content() -> Self {
+        Response {
+            status: 204,
+            body: String::new(),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        let body = serde_json::json!({ "error": message.into() });
+        Response {
+            status,
+            body: body.to_string(),
+        }
+    }
+}
+
+impl From<Error> for Response {
+    fn from(e: Error) -> Self {
+        let status = match e {
+            Error::RecordNotFound | Error::TreeNotFound(_) => 404,
+            Error::Usage(_) | Error::Json(_) | Error::TreeMismatch { .. } => 400,
+            Error::Index(_) | Error::CheckedOutByOther(_) | Error::Refused(_) => 409,
+            Error::SyncBusy | Error::NotConnected => 503,
+            _ => 500,
+        };
+        Response::error(status, e.to_string())
+    }
+}
+
+impl Gateway {
+    /// Check outs done for PUT and DELETE are waited for on rt, so handle must not be called from an async context.
+    pub fn new(rt: Handle) -> Self {
+        Gateway {
+            trees: HashMap::new(),
+            rt,
+        }
+    }
+
+    /// Make a tree accessible through the gateway under its name.
+    pub fn add_tree(&mut self, tree_name: impl AsRef<str>, tree: Box<dyn OpaqueTree>) {
+        self.trees.insert(tree_name.as_ref().to_string(), tree);
+    }
+
+    /// Route a request, blocks until the record is checked out for PUT and DELETE.
+    pub fn handle(&mut self, method: &str, path: &str, body: &str) -> Response {
+        let mut segments = path.trim_matches('/').split('/');
+        let tree_name = segments.next().unwrap_or("");
+        let key = segments.next();
+        if segments.next().is_some() {
+            return Response::error(404, format!("Unknown path {path}"));
+        }
+        let Some(tree) = self.trees.get_mut(tree_name) else {
+            return Error::TreeNotFound(tree_name.to_string()).into();
+        };
+        let key = match key.map(parse_key).transpose() {
+            Ok(key) => key.map(|key| OpaqueKey::new(Arc::new(tree_name.to_string()), key)),
+            Err(e) => return Response::error(400, e),
+        };
+        let r = match (method, key) {
+            ("GET", None) => {
+                let keys: Vec<String> = tree
+                    .all_revisions()
+                    .map(|key| format!("{}.{}", key.id, key.revision))
+                    .collect();
+                serde_json::to_string(&keys)
+                    .map(Response::ok)
+                    .map_err(Error::from)
+            }
+            ("GET", Some(key)) => tree.to_json_string(&key).map(Response::ok),
+            ("POST", None) => tree.insert_from_json_str(body).and_then(|key| {
+                serde_json::to_string(&key.to_string())
+                    .map(Response::ok)
+                    .map_err(Error::from)
+            }),
+            ("PUT", Some(key)) => with_check_out(&self.rt, tree.as_mut(), &key, |tree| {
+                tree.update_from_json_str(&key, body)
+            })
+            .map(|_| Response::no_content()),
+            ("DELETE", Some(key)) => {
+                with_check_out(&self.rt, tree.as_mut(), &key, |tree| tree.remove(&key))
+                    .map(|_| Response::no_content())
+            }
+            _ => Ok(Response::error(
+                405,
+                format!("{method} {path} is not supported"),
+            )),
+        };
+        r.unwrap_or_else(Response::from)
+    }
+}
+
+/// Run f with the record checked out, releasing it afterwards if it was not held before.
+fn with_check_out<R>(
+    rt: &Handle,
+    tree: &mut dyn OpaqueTree,
+    key: &OpaqueKey,
+    f: impl FnOnce(&mut dyn OpaqueTree) -> Result<R, Error>,
+) -> Result<R, Error> {
+    if tree.is_checked_out(key)? {
+        return f(tree);
+    }
+    rt.block_on(tree.try_check_out(key))?;
+    let r = f(tree);
+    tree.release(key)?;
+    r
+}
+
+fn parse_key(key: &str) -> Result<GenericKey, String> {
+    let (id, revision) = key
+        .split_once('.')
+        .ok_or_else(|| format!("Key must be id.revision, got {key}"))?;
+    let id = id.parse().map_err(|_| format!("Wrong id in {key}"))?;
+    let revision = revision
+        .parse()
+        .map_err(|_| format!("Wrong revision in {key}"))?;
+    Ok(GenericKey::new(id, revision))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Gateway;
+    use crate::db::tests::{Item, ItemKey};
+    use crate::{HillsClient, TreeKey};
+    use hills_base::GenericKey;
+
+    #[test]
+    fn insert_get_update_remove() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut db = HillsClient::open_local_for_test();
+        let items = db.open_tree::<ItemKey, Item>("").unwrap();
+        let mut gateway = Gateway::new(rt.handle().clone());
+        gateway.add_tree("items", Box::new(items.clone()));
+
+        let r = gateway.handle("POST", "/items", r#"{"name": "first"}"#);
+        assert_eq!(r.status, 200);
+        let key: String = serde_json::from_str(&r.body).unwrap();
+        let path = format!("/items/{key}");
+        let r = gateway.handle("GET", &path, "");
+        assert_eq!(r.status, 200);
+        assert_eq!(r.body, r#"{"name":"first"}"#);
+        assert_eq!(
+            gateway.handle("GET", "/items", "").body,
+            format!("[\"{key}\"]")
+        );
+
+        let r = gateway.handle("PUT", &path, r#"{"name": "second"}"#);
+        assert_eq!(r.status, 204);
+        let (id, revision) = key.split_once('.').unwrap();
+        let generic = GenericKey::new(id.parse().unwrap(), revision.parse().unwrap());
+        let item_key = ItemKey::from_generic(generic);
+        assert_eq!(items.get(item_key).unwrap().name, "second");
+        assert!(!items.is_checked_out(item_key));
+
+        assert_eq!(gateway.handle("DELETE", &path, "").status, 204);
+        assert!(items.get(item_key).is_err());
+        assert_eq!(gateway.handle("GET", &path, "").status, 404);
+        assert_eq!(gateway.handle("GET", "/items", "").body, "[]");
+
+        let r = gateway.handle("POST", "/items", "(name: \"ron\")");
+        assert_eq!(r.status, 400);
+        assert!(r.body.starts_with(r#"{"error":"#));
+        assert_eq!(gateway.handle("GET", "/items/1.x", "").status, 400);
+        assert_eq!(gateway.handle("GET", "/unknown", "").status, 404);
+        assert_eq!(gateway.handle("PUT", "/items", "").status, 405);
+    }
+}
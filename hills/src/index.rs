@@ -1,20 +1,33 @@
 use dyn_clone::DynClone;
-use hills_base::{GenericKey, SimpleVersion};
-use rkyv::{check_archived_root, Deserialize};
+use hills_base::index::IndexError;
+use hills_base::{Evolving, GenericKey, SimpleVersion};
+use log::warn;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{
+    archived_root, check_archived_root, to_bytes, Archive, CheckBytes, Deserialize, Serialize,
+};
 use sled::Tree;
+use std::any::TypeId;
+use std::cell::Cell;
+use std::collections::BTreeMap;
 
-use crate::{consts::KEY_POOL, db::Error, record::Record};
+use crate::record::{Record, RecordHeader, RecordMeta};
+use crate::{common::record_keys, db::Error};
 
 mod latest_revisions;
 pub mod multi_named;
 pub mod named;
+pub mod partition;
+pub mod sled_named;
 
+#[derive(Clone, Copy, Debug)]
 pub enum Action {
     Insert,
     Update,
     Remove,
 }
 
+#[derive(Clone, Copy)]
 pub struct TypeErasedTree<'a> {
     pub(crate) tree: &'a Tree,
     pub(crate) evolution: SimpleVersion,
@@ -23,17 +36,98 @@ pub struct TypeErasedTree<'a> {
 pub trait TreeIndex: DynClone {
     fn rebuild(&mut self, tree: TypeErasedTree) -> Result<(), Error>;
 
+    /// Apply a change of one record before it is written to the tree, data is the new value or the removed one.
+    /// On Action::Update old_data is the value being replaced, if known and of the same evolution, so that the old
+    /// entry can be found without looking through the whole index.
     fn update(
         &mut self,
         tree: TypeErasedTree,
         key: GenericKey,
-        data: &[u8],
+        old_data: Option<&IndexData>,
+        data: &IndexData,
         action: Action,
     ) -> Result<(), Error>;
+
+    /// Called after a record was written, for indexes that depend on its meta (version state, author, etc.)
+    /// rather than on data. Removal is still reported through update with Action::Remove.
+    fn meta_changed(&mut self, _key: GenericKey, _meta: &RecordMeta) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Apply changes of a bulk operation at once, before they are written to the tree.
+    /// If one of them fails, none of them stay applied.
+    ///
+    /// Default implementation calls update for each change and rebuilds the index from the tree on failure.
+    fn update_batch(&mut self, tree: TypeErasedTree, changes: &[IndexChange]) -> Result<(), Error> {
+        for (key, old_data, data, action) in changes {
+            if let Err(e) = self.update(tree, *key, old_data.as_ref(), data, *action) {
+                self.rebuild(tree)?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Short name of the index kind, shown in HillsClient::index_stats.
+    fn kind(&self) -> &'static str {
+        "custom"
+    }
+
+    /// Number of entries the index holds.
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate heap memory held by the index in bytes, allocator and collection node overhead is not counted.
+    fn memory_bytes(&self) -> usize {
+        0
+    }
+
+    /// Save the index to disk, see HillsClient::persist_indexes. Indexes that are not persisted do nothing.
+    fn persist(&self, _tree: TypeErasedTree) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Replace the index with the copy saved by persist, if it still matches the tree.
+    /// Returns false when there is nothing to load and the index has to be rebuilt instead.
+    fn load(&mut self, _tree: TypeErasedTree) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+/// Size of one index, see HillsClient::index_stats.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexStat {
+    pub tree_name: String,
+    /// TreeIndex::kind, e.g. "named"
+    pub kind: &'static str,
+    pub len: usize,
+    pub memory_bytes: usize,
+}
+
+/// Key, replaced and new serialized data and what happens to the record, see TreeIndex::update_batch.
+pub type IndexChange<'a> = (GenericKey, Option<IndexData<'a>>, IndexData<'a>, Action);
+
+/// Serialized data of one record, shared by all the indexers of a tree, so that it is validated only once
+/// no matter how many of them decode it.
+pub struct IndexData<'a> {
+    bytes: &'a [u8],
+    /// Type the bytes were already validated as, by the first indexer that decoded them.
+    validated_as: Cell<Option<TypeId>>,
 }
 
 dyn_clone::clone_trait_object!(TreeIndex);
 
+/// Index that maps each record to a unique value, such as a name, usable with TypedTree::upsert_by.
+pub trait UniqueIndex<K> {
+    /// Returns the key of a record that already holds the same indexed value as the provided serialized data.
+    fn find_existing(&self, data: &[u8]) -> Result<Option<K>, Error>;
+}
+
 pub trait TreeSearch {
     type Key;
 
@@ -55,7 +149,7 @@ pub struct SearchHit<K> {
     pub similarity: Similarity,
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Similarity {
     Exact,
     Loose,
@@ -63,20 +157,7 @@ pub enum Similarity {
 
 impl<'a> TypeErasedTree<'a> {
     pub fn all_revisions(&self) -> impl Iterator<Item = GenericKey> {
-        self.tree.iter().keys().filter_map(|key| {
-            if let Ok(key) = key {
-                if key == KEY_POOL {
-                    return None;
-                }
-                if key.len() != 8 {
-                    return None;
-                }
-                let key = GenericKey::from_bytes(&key)?;
-                Some(key)
-            } else {
-                None
-            }
-        })
+        record_keys(self.tree)
     }
 
     pub fn get_with<T, F: FnMut(&[u8]) -> T>(&self, key: GenericKey, mut f: F) -> Result<T, Error> {
@@ -102,6 +183,156 @@ impl<'a> TypeErasedTree<'a> {
             None => Err(Error::RecordNotFound),
         }
     }
+
+    /// Record's meta, unlike data it does not depend on evolution.
+    pub fn meta(&self, key: GenericKey) -> Result<RecordMeta, Error> {
+        let Some(bytes) = self.tree.get(key.to_bytes())? else {
+            return Err(Error::RecordNotFound);
+        };
+        let header = RecordHeader::check(&bytes)?;
+        Ok(header.meta().deserialize(&mut rkyv::Infallible)?)
+    }
+}
+
+/// Create an extractor for NamedIndex that reads one field of an archived value.
+/// Field must exist and its archived form must implement Display (e.g. String, numbers).
+///
+/// ```ignore
+/// let index = NamedIndex::<PartKey, Part>::new(field_extractor!(Part, part_number));
+/// ```
+#[macro_export]
+macro_rules! field_extractor {
+    ($ty:ty, $field:ident) => {
+        |value: &<$ty as rkyv::Archive>::Archived| -> Result<String, $crate::IndexError> {
+            Ok(value.$field.to_string())
+        }
+    };
+}
+
+impl<'a> IndexData<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        IndexData {
+            bytes,
+            validated_as: Cell::new(None),
+        }
+    }
+
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Archived value, so that typed extractors can work with it directly.
+    /// Bytes are validated by the first call only, following ones with the same V reuse the result.
+    pub fn archived<V>(&self) -> Result<&'a V::Archived, IndexError>
+    where
+        V: Archive + 'static,
+        <Evolving<V> as Archive>::Archived: for<'b> CheckBytes<DefaultValidator<'b>>,
+    {
+        let type_id = TypeId::of::<V>();
+        if self.validated_as.get() == Some(type_id) {
+            // Safety: bytes are immutable for 'a and were validated as Evolving<V> by check_archived_root below
+            let evolving = unsafe { archived_root::<Evolving<V>>(self.bytes) };
+            return Ok(evolving.0.get());
+        }
+        let evolving = check_archived_root::<Evolving<V>>(self.bytes)?;
+        self.validated_as.set(Some(type_id));
+        Ok(evolving.0.get())
+    }
+}
+
+/// Approximate heap memory of a name -> key map, names included.
+pub(crate) fn names_memory_bytes(index: &BTreeMap<String, GenericKey>) -> usize {
+    let entry_size = std::mem::size_of::<String>() + std::mem::size_of::<GenericKey>();
+    index.keys().map(|name| entry_size + name.capacity()).sum()
+}
+
+/// Key of the saved names in an index tree, see persist_names.
+const PERSISTED_NAMES: &[u8] = b"names";
+
+/// Copy of a name -> key map saved to disk, along with the state of the data tree it was made from.
+#[derive(Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
+struct PersistedNames {
+    evolution: SimpleVersion,
+    records: u64,
+    index: BTreeMap<String, GenericKey>,
+}
+
+/// Save a name -> key map to an index tree, so that it can be loaded instead of rebuilt on the next launch.
+pub(crate) fn persist_names(
+    target: &Tree,
+    tree: TypeErasedTree,
+    index: &BTreeMap<String, GenericKey>,
+) -> Result<(), Error> {
+    let persisted = PersistedNames {
+        evolution: tree.evolution,
+        records: tree.all_revisions().count() as u64,
+        index: index.clone(),
+    };
+    let bytes = to_bytes::<_, 1024>(&persisted)?;
+    target.insert(PERSISTED_NAMES, bytes.as_slice())?;
+    Ok(())
+}
+
+/// Name -> key map saved by persist_names, None if there is none or the data tree has a different number of records
+/// or evolution now.
+pub(crate) fn load_names(
+    target: &Tree,
+    tree: TypeErasedTree,
+) -> Result<Option<BTreeMap<String, GenericKey>>, Error> {
+    let Some(bytes) = target.get(PERSISTED_NAMES)? else {
+        return Ok(None);
+    };
+    let Ok(persisted) = check_archived_root::<PersistedNames>(&bytes) else {
+        warn!("persisted index is malformed, rebuilding");
+        return Ok(None);
+    };
+    let evolution: SimpleVersion = persisted.evolution.deserialize(&mut rkyv::Infallible)?;
+    if evolution != tree.evolution || persisted.records != tree.all_revisions().count() as u64 {
+        return Ok(None);
+    }
+    Ok(Some(persisted.index.deserialize(&mut rkyv::Infallible)?))
+}
+
+/// Forget the saved copy once the index in memory diverges from it.
+pub(crate) fn invalidate_names(target: &Tree) -> Result<(), Error> {
+    target.remove(PERSISTED_NAMES)?;
+    Ok(())
+}
+
+/// Previous values of the names changed by an update or a batch, so that they can be restored if it fails halfway.
+#[derive(Default)]
+pub(crate) struct UndoLog(Vec<(String, Option<GenericKey>)>);
+
+impl UndoLog {
+    pub(crate) fn insert(
+        &mut self,
+        index: &mut BTreeMap<String, GenericKey>,
+        name: String,
+        key: GenericKey,
+    ) {
+        let previous = index.insert(name.clone(), key);
+        self.0.push((name, previous));
+    }
+
+    pub(crate) fn remove(&mut self, index: &mut BTreeMap<String, GenericKey>, name: &str) {
+        if let Some(previous) = index.remove(name) {
+            self.0.push((name.to_string(), Some(previous)));
+        }
+    }
+
+    pub(crate) fn roll_back(self, index: &mut BTreeMap<String, GenericKey>) {
+        for (name, previous) in self.0.into_iter().rev() {
+            match previous {
+                Some(key) => {
+                    index.insert(name, key);
+                }
+                None => {
+                    index.remove(&name);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -124,8 +355,7 @@ impl StringPostProcess {
             s.as_str()
         };
         s.chars()
-            .into_iter()
-            .filter(|c| !self.ignore_chars.contains(&c))
+            .filter(|c| !self.ignore_chars.contains(c))
             .collect()
     }
 }
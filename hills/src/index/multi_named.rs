@@ -4,44 +4,173 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use hills_base::{index::IndexError, GenericKey, TreeKey};
+use hills_base::{index::IndexError, Evolving, GenericKey, TreeKey, TreeRoot};
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{Archive, CheckBytes};
+use sled::Tree;
 
-use crate::db::Error;
+use crate::db::{Error, HillsClient};
 
-use super::{Action, Similarity, StringPostProcess, TreeIndex, TypeErasedTree};
+use super::{
+    invalidate_names, load_names, names_memory_bytes, persist_names, Action, IndexChange,
+    IndexData, Similarity, StringPostProcess, TreeIndex, TypeErasedTree, UndoLog,
+};
 
-type ExtractStrFn = fn(data: &[u8]) -> Result<Vec<String>, IndexError>;
+/// Extracts all names from an already validated archived value.
+pub type ExtractStrFn<V> =
+    Arc<dyn Fn(&<V as Archive>::Archived) -> Result<Vec<String>, IndexError> + Send + Sync>;
 
 /// Index that maps multiple unique names to the same record key.
 /// Optionally some characters or case could be ignored and whitespace trimmed.
-#[derive(Clone)]
-pub struct MultiNamedIndex<K> {
+pub struct MultiNamedIndex<K, V: Archive> {
     storage: Arc<RwLock<Storage>>,
-    extractor: ExtractStrFn,
+    extractor: ExtractStrFn<V>,
     settings: StringPostProcess,
+    /// Index tree the names are saved to, see persisted
+    persist_to: Option<Tree>,
     _phantom: PhantomData<K>,
 }
 
+impl<K, V: Archive> Clone for MultiNamedIndex<K, V> {
+    fn clone(&self) -> Self {
+        MultiNamedIndex {
+            storage: self.storage.clone(),
+            extractor: self.extractor.clone(),
+            settings: self.settings.clone(),
+            persist_to: self.persist_to.clone(),
+            _phantom: PhantomData {},
+        }
+    }
+}
+
 #[derive(Default)]
 struct Storage {
     index: BTreeMap<String, GenericKey>,
+    /// Whether the saved copy matches the index, so that only the first change after saving or loading removes it
+    is_persisted: bool,
 }
 
-#[derive(Clone)]
-struct MultiNamedIndexer {
+struct MultiNamedIndexer<V: Archive> {
     storage: Arc<RwLock<Storage>>,
-    extractor: ExtractStrFn,
+    extractor: ExtractStrFn<V>,
     settings: StringPostProcess,
+    persist_to: Option<Tree>,
+}
+
+impl<V: Archive> Clone for MultiNamedIndexer<V> {
+    fn clone(&self) -> Self {
+        MultiNamedIndexer {
+            storage: self.storage.clone(),
+            extractor: self.extractor.clone(),
+            settings: self.settings.clone(),
+            persist_to: self.persist_to.clone(),
+        }
+    }
+}
+
+impl<V: Archive + 'static> MultiNamedIndexer<V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    fn extract(&self, data: &IndexData) -> Result<Vec<String>, IndexError> {
+        (self.extractor)(data.archived::<V>()?)
+    }
+
+    fn invalidate_persisted(&self, storage: &mut Storage) -> Result<(), Error> {
+        if let (Some(persist_to), true) = (&self.persist_to, storage.is_persisted) {
+            invalidate_names(persist_to)?;
+            storage.is_persisted = false;
+        }
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        index: &mut BTreeMap<String, GenericKey>,
+        key: GenericKey,
+        old_data: Option<&IndexData>,
+        data: &IndexData,
+        action: Action,
+        undo: &mut UndoLog,
+    ) -> Result<(), Error> {
+        match action {
+            Action::Insert => {
+                let names = self.extract(data)?;
+                for name in names {
+                    let name = self.settings.post_process(name);
+                    if let Some(existing) = index.get(&name) {
+                        return Err(Error::Index(IndexError::Duplicate {
+                            existing: *existing,
+                            value: name,
+                        }));
+                    }
+                    undo.insert(index, name, key);
+                }
+            }
+            Action::Update => {
+                let old_names = old_data
+                    .and_then(|old_data| self.extract(old_data).ok())
+                    .map(|old_names| {
+                        old_names
+                            .into_iter()
+                            .map(|s| self.settings.post_process(s))
+                            .filter(|s| index.get(s) == Some(&key))
+                            .collect::<Vec<String>>()
+                    });
+                // Without the old data look through all the names
+                let old_names = old_names.unwrap_or_else(|| {
+                    index
+                        .iter()
+                        .filter(|(_, v)| **v == key)
+                        .map(|(k, _)| k.to_string())
+                        .collect()
+                });
+                let new_names = self.extract(data)?;
+                let new_names: Vec<String> = new_names
+                    .into_iter()
+                    .map(|s| self.settings.post_process(s))
+                    .collect();
+                for new_name in &new_names {
+                    if let Some(k) = index.get(new_name) {
+                        if *k != key {
+                            return Err(Error::Index(IndexError::Duplicate {
+                                value: new_name.to_string(),
+                                existing: *k,
+                            }));
+                        }
+                    }
+                }
+                for old_name in old_names.iter().filter(|s| !new_names.contains(s)) {
+                    undo.remove(index, old_name);
+                }
+                for new_name in new_names {
+                    undo.insert(index, new_name, key);
+                }
+            }
+            Action::Remove => {
+                let names = self.extract(data)?;
+                for name in names {
+                    let name = self.settings.post_process(name);
+                    undo.remove(index, &name);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-impl TreeIndex for MultiNamedIndexer {
+impl<V: Archive + 'static> TreeIndex for MultiNamedIndexer<V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
     fn rebuild(&mut self, tree: TypeErasedTree) -> Result<(), Error> {
         let Ok(mut wr) = self.storage.write() else {
             return Err(Error::Index(IndexError::RwLock));
         };
+        self.invalidate_persisted(&mut wr)?;
         wr.index.clear();
         for key in tree.all_revisions() {
-            let names = match tree.get_with(key, |data| (self.extractor)(data)) {
+            let names = match tree.get_with(key, |data| self.extract(&IndexData::new(data))) {
                 Ok(Ok(names)) => names,
                 Ok(Err(e)) => {
                     log::error!("{key}: {:?}, skipping", e);
@@ -54,8 +183,11 @@ impl TreeIndex for MultiNamedIndexer {
             };
             for name in names {
                 let name = self.settings.post_process(name);
-                if wr.index.contains_key(&name) {
-                    return Err(Error::Index(IndexError::Duplicate(name)));
+                if let Some(existing) = wr.index.get(&name) {
+                    return Err(Error::Index(IndexError::Duplicate {
+                        existing: *existing,
+                        value: name,
+                    }));
                 }
                 wr.index.insert(name, key);
             }
@@ -68,76 +200,125 @@ impl TreeIndex for MultiNamedIndexer {
         &mut self,
         _tree: TypeErasedTree,
         key: GenericKey,
-        data: &[u8],
+        old_data: Option<&IndexData>,
+        data: &IndexData,
         action: Action,
     ) -> Result<(), Error> {
         let Ok(mut wr) = self.storage.write() else {
             return Err(Error::Index(IndexError::RwLock));
         };
-        match action {
-            Action::Insert => {
-                let names = (self.extractor)(data)?;
-                for name in names {
-                    let name = self.settings.post_process(name);
-                    if wr.index.contains_key(&name) {
-                        return Err(Error::Index(IndexError::Duplicate(name)));
-                    }
-                    wr.index.insert(name, key);
-                }
-            }
-            Action::Update => {
-                let old_names: Vec<String> = wr
-                    .index
-                    .iter()
-                    .filter(|(_, v)| **v == key)
-                    .map(|(k, _)| k.to_string())
-                    .collect();
-                let new_names = (self.extractor)(data)?;
-                let new_names: Vec<String> = new_names
-                    .into_iter()
-                    .map(|s| self.settings.post_process(s))
-                    .collect();
-                for new_name in &new_names {
-                    if let Some(k) = wr.index.get(new_name) {
-                        if *k != key {
-                            return Err(Error::Index(IndexError::Duplicate(new_name.to_string())));
-                        }
-                    }
-                }
-                for old_name in old_names.iter().filter(|s| !new_names.contains(s)) {
-                    wr.index.remove(old_name);
-                }
-                for new_name in new_names {
-                    wr.index.insert(new_name, key);
-                }
-            }
-            Action::Remove => {
-                let names = (self.extractor)(data)?;
-                for name in names {
-                    let name = self.settings.post_process(name);
-                    wr.index.remove(&name);
-                }
-            }
+        self.invalidate_persisted(&mut wr)?;
+        let mut undo = UndoLog::default();
+        let r = self.apply(&mut wr.index, key, old_data, data, action, &mut undo);
+        if r.is_err() {
+            undo.roll_back(&mut wr.index);
         }
         // log::debug!("MultiNamed {:?}", wr.index);
+        r
+    }
+
+    fn update_batch(
+        &mut self,
+        _tree: TypeErasedTree,
+        changes: &[IndexChange],
+    ) -> Result<(), Error> {
+        let Ok(mut wr) = self.storage.write() else {
+            return Err(Error::Index(IndexError::RwLock));
+        };
+        self.invalidate_persisted(&mut wr)?;
+        let mut undo = UndoLog::default();
+        for (key, old_data, data, action) in changes {
+            let r = self.apply(
+                &mut wr.index,
+                *key,
+                old_data.as_ref(),
+                data,
+                *action,
+                &mut undo,
+            );
+            if let Err(e) = r {
+                undo.roll_back(&mut wr.index);
+                return Err(e);
+            }
+        }
         Ok(())
     }
+
+    fn kind(&self) -> &'static str {
+        "multi_named"
+    }
+
+    fn len(&self) -> usize {
+        self.storage.read().map(|rd| rd.index.len()).unwrap_or(0)
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.storage
+            .read()
+            .map(|rd| names_memory_bytes(&rd.index))
+            .unwrap_or(0)
+    }
+
+    fn persist(&self, tree: TypeErasedTree) -> Result<(), Error> {
+        let Some(persist_to) = &self.persist_to else {
+            return Ok(());
+        };
+        let Ok(mut wr) = self.storage.write() else {
+            return Err(Error::Index(IndexError::RwLock));
+        };
+        if !wr.is_persisted {
+            persist_names(persist_to, tree, &wr.index)?;
+            wr.is_persisted = true;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, tree: TypeErasedTree) -> Result<bool, Error> {
+        let Some(persist_to) = &self.persist_to else {
+            return Ok(false);
+        };
+        let Some(index) = load_names(persist_to, tree)? else {
+            return Ok(false);
+        };
+        let Ok(mut wr) = self.storage.write() else {
+            return Err(Error::Index(IndexError::RwLock));
+        };
+        wr.index = index;
+        wr.is_persisted = true;
+        Ok(true)
+    }
 }
 
-impl<K: TreeKey> MultiNamedIndex<K> {
-    pub fn new(exctractor: ExtractStrFn) -> Self {
+impl<K: TreeKey, V: Archive + 'static> MultiNamedIndex<K, V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    pub fn new(
+        extractor: impl Fn(&V::Archived) -> Result<Vec<String>, IndexError> + Send + Sync + 'static,
+    ) -> Self {
         MultiNamedIndex {
             storage: Arc::new(RwLock::new(Storage::default())),
-            extractor: exctractor,
+            extractor: Arc::new(extractor),
             settings: StringPostProcess {
                 case_sensitive: true,
                 ignore_chars: vec![],
                 trim_whitespace: false,
             },
+            persist_to: None,
             _phantom: PhantomData {},
         }
     }
 
+    /// Keep a copy of the index in the client's database, same as NamedIndex::persisted.
+    pub fn persisted(mut self, client: &HillsClient, name: impl AsRef<str>) -> Result<Self, Error>
+    where
+        V: TreeRoot,
+    {
+        let index_name = format!("{}/{}", <V as TreeRoot>::tree_name(), name.as_ref());
+        self.persist_to = Some(client.open_index_tree(&index_name)?);
+        Ok(self)
+    }
+
     pub fn case_sensitive(mut self, is_case_sensitive: bool) -> Self {
         self.settings.case_sensitive = is_case_sensitive;
         self
@@ -158,6 +339,7 @@ impl<K: TreeKey> MultiNamedIndex<K> {
             storage: self.storage.clone(),
             extractor: self.extractor.clone(),
             settings: self.settings.clone(),
+            persist_to: self.persist_to.clone(),
         })
     }
 
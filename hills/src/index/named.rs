@@ -4,45 +4,160 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use hills_base::{index::IndexError, GenericKey, TreeKey};
+use hills_base::{index::IndexError, Evolving, GenericKey, TreeKey, TreeRoot};
 use log::error;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{Archive, CheckBytes};
+use sled::Tree;
 
-use crate::db::Error;
+use crate::db::{Error, HillsClient};
 
-use super::{Action, Similarity, StringPostProcess, TreeIndex, TypeErasedTree};
+use super::{
+    invalidate_names, load_names, names_memory_bytes, persist_names, Action, IndexChange,
+    IndexData, SearchHit, Similarity, StringPostProcess, TreeIndex, TreeSearch, TypeErasedTree,
+    UndoLog, UniqueIndex,
+};
 
-type ExtractStrFn = fn(data: &[u8]) -> Result<String, IndexError>;
+/// Extracts a name from an already validated archived value.
+pub type ExtractStrFn<V> =
+    Arc<dyn Fn(&<V as Archive>::Archived) -> Result<String, IndexError> + Send + Sync>;
 
 /// Index that maps unique name to a record's key.
 /// Optionally some characters or case could be ignored and whitespace trimmed.
-#[derive(Clone)]
-pub struct NamedIndex<K: TreeKey> {
+pub struct NamedIndex<K: TreeKey, V: Archive> {
     storage: Arc<RwLock<Storage>>,
-    extractor: ExtractStrFn,
+    extractor: ExtractStrFn<V>,
     post_process: StringPostProcess,
+    /// Index tree the names are saved to, see persisted
+    persist_to: Option<Tree>,
     _phantom: PhantomData<K>,
 }
 
+impl<K: TreeKey, V: Archive> Clone for NamedIndex<K, V> {
+    fn clone(&self) -> Self {
+        NamedIndex {
+            storage: self.storage.clone(),
+            extractor: self.extractor.clone(),
+            post_process: self.post_process.clone(),
+            persist_to: self.persist_to.clone(),
+            _phantom: PhantomData {},
+        }
+    }
+}
+
 #[derive(Default)]
 struct Storage {
     index: BTreeMap<String, GenericKey>,
+    /// Whether the saved copy matches the index, so that only the first change after saving or loading removes it
+    is_persisted: bool,
 }
 
-#[derive(Clone)]
-struct NamedIndexer {
+struct NamedIndexer<V: Archive> {
     storage: Arc<RwLock<Storage>>,
-    extractor: ExtractStrFn,
+    extractor: ExtractStrFn<V>,
     post_process: StringPostProcess,
+    persist_to: Option<Tree>,
+}
+
+impl<V: Archive> Clone for NamedIndexer<V> {
+    fn clone(&self) -> Self {
+        NamedIndexer {
+            storage: self.storage.clone(),
+            extractor: self.extractor.clone(),
+            post_process: self.post_process.clone(),
+            persist_to: self.persist_to.clone(),
+        }
+    }
+}
+
+impl<V: Archive + 'static> NamedIndexer<V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    fn extract(&self, data: &IndexData) -> Result<String, IndexError> {
+        (self.extractor)(data.archived::<V>()?)
+    }
+
+    fn invalidate_persisted(&self, storage: &mut Storage) -> Result<(), Error> {
+        if let (Some(persist_to), true) = (&self.persist_to, storage.is_persisted) {
+            invalidate_names(persist_to)?;
+            storage.is_persisted = false;
+        }
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        index: &mut BTreeMap<String, GenericKey>,
+        key: GenericKey,
+        old_data: Option<&IndexData>,
+        data: &IndexData,
+        action: Action,
+        undo: &mut UndoLog,
+    ) -> Result<(), Error> {
+        match action {
+            Action::Insert => {
+                let s = self.extract(data)?;
+                let s = self.post_process.post_process(s);
+                if let Some(existing) = index.get(&s) {
+                    return Err(Error::Index(IndexError::Duplicate {
+                        existing: *existing,
+                        value: s,
+                    }));
+                }
+                undo.insert(index, s, key);
+            }
+            Action::Update => {
+                let old_name = old_data
+                    .and_then(|old_data| self.extract(old_data).ok())
+                    .map(|old_name| self.post_process.post_process(old_name))
+                    .filter(|old_name| index.get(old_name) == Some(&key));
+                // Without the old data, or if it does not match the index, look through all the names
+                let Some(old_name) = old_name.or_else(|| {
+                    index
+                        .iter()
+                        .find(|(_, v)| **v == key)
+                        .map(|(k, _)| k.to_string())
+                }) else {
+                    return Err(Error::Index(IndexError::Other(
+                        "old name not found".to_string(),
+                    )));
+                };
+                let new_name = self.extract(data)?;
+                let new_name = self.post_process.post_process(new_name);
+                if old_name != new_name {
+                    if let Some(existing) = index.get(&new_name) {
+                        return Err(Error::Index(IndexError::Duplicate {
+                            existing: *existing,
+                            value: new_name,
+                        }));
+                    }
+                    undo.remove(index, &old_name);
+                    undo.insert(index, new_name, key);
+                }
+            }
+            Action::Remove => {
+                let s = self.extract(data)?;
+                let s = self.post_process.post_process(s);
+                undo.remove(index, &s);
+            }
+        }
+        Ok(())
+    }
 }
 
-impl TreeIndex for NamedIndexer {
+impl<V: Archive + 'static> TreeIndex for NamedIndexer<V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
     fn rebuild(&mut self, tree: TypeErasedTree) -> Result<(), Error> {
         let Ok(mut wr) = self.storage.write() else {
             return Err(Error::Index(IndexError::RwLock));
         };
+        self.invalidate_persisted(&mut wr)?;
         wr.index.clear();
         for key in tree.all_revisions() {
-            let s = match tree.get_with(key, |data| (self.extractor)(data)) {
+            let s = match tree.get_with(key, |data| self.extract(&IndexData::new(data))) {
                 Ok(Ok(s)) => s,
                 Ok(Err(e)) => {
                     error!("{key}: {:?}, skipping", e);
@@ -54,8 +169,11 @@ impl TreeIndex for NamedIndexer {
                 }
             };
             let s = self.post_process.post_process(s);
-            if wr.index.contains_key(&s) {
-                return Err(Error::Index(IndexError::Duplicate(s)));
+            if let Some(existing) = wr.index.get(&s) {
+                return Err(Error::Index(IndexError::Duplicate {
+                    existing: *existing,
+                    value: s,
+                }));
             }
             wr.index.insert(s, key);
         }
@@ -67,67 +185,129 @@ impl TreeIndex for NamedIndexer {
         &mut self,
         _tree: TypeErasedTree,
         key: GenericKey,
-        data: &[u8],
+        old_data: Option<&IndexData>,
+        data: &IndexData,
         action: Action,
     ) -> Result<(), Error> {
         let Ok(mut wr) = self.storage.write() else {
             return Err(Error::Index(IndexError::RwLock));
         };
-        match action {
-            Action::Insert => {
-                let s = (self.extractor)(data)?;
-                let s = self.post_process.post_process(s);
-                if wr.index.contains_key(&s) {
-                    return Err(Error::Index(IndexError::Duplicate(s)));
-                }
-                wr.index.insert(s, key);
-            }
-            Action::Update => {
-                let Some(old_name) = wr
-                    .index
-                    .iter()
-                    .find(|(_, v)| **v == key)
-                    .map(|(k, _)| k.to_string())
-                else {
-                    return Err(Error::Index(IndexError::Other(
-                        "old name not found".to_string(),
-                    )));
-                };
-                let new_name = (self.extractor)(data)?;
-                let new_name = self.post_process.post_process(new_name);
-                if old_name != new_name {
-                    if wr.index.contains_key(&new_name) {
-                        return Err(Error::Index(IndexError::Duplicate(new_name)));
-                    }
-                    wr.index.remove(&old_name);
-                    wr.index.insert(new_name, key);
-                }
-            }
-            Action::Remove => {
-                let s = (self.extractor)(data)?;
-                let s = self.post_process.post_process(s);
-                wr.index.remove(&s);
-            }
+        self.invalidate_persisted(&mut wr)?;
+        let mut undo = UndoLog::default();
+        let r = self.apply(&mut wr.index, key, old_data, data, action, &mut undo);
+        if r.is_err() {
+            undo.roll_back(&mut wr.index);
         }
         // log::debug!("Named {:?}", wr.index);
+        r
+    }
+
+    fn update_batch(
+        &mut self,
+        _tree: TypeErasedTree,
+        changes: &[IndexChange],
+    ) -> Result<(), Error> {
+        let Ok(mut wr) = self.storage.write() else {
+            return Err(Error::Index(IndexError::RwLock));
+        };
+        self.invalidate_persisted(&mut wr)?;
+        let mut undo = UndoLog::default();
+        for (key, old_data, data, action) in changes {
+            let r = self.apply(
+                &mut wr.index,
+                *key,
+                old_data.as_ref(),
+                data,
+                *action,
+                &mut undo,
+            );
+            if let Err(e) = r {
+                undo.roll_back(&mut wr.index);
+                return Err(e);
+            }
+        }
         Ok(())
     }
+
+    fn kind(&self) -> &'static str {
+        "named"
+    }
+
+    fn len(&self) -> usize {
+        self.storage.read().map(|rd| rd.index.len()).unwrap_or(0)
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.storage
+            .read()
+            .map(|rd| names_memory_bytes(&rd.index))
+            .unwrap_or(0)
+    }
+
+    fn persist(&self, tree: TypeErasedTree) -> Result<(), Error> {
+        let Some(persist_to) = &self.persist_to else {
+            return Ok(());
+        };
+        let Ok(mut wr) = self.storage.write() else {
+            return Err(Error::Index(IndexError::RwLock));
+        };
+        if !wr.is_persisted {
+            persist_names(persist_to, tree, &wr.index)?;
+            wr.is_persisted = true;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, tree: TypeErasedTree) -> Result<bool, Error> {
+        let Some(persist_to) = &self.persist_to else {
+            return Ok(false);
+        };
+        let Some(index) = load_names(persist_to, tree)? else {
+            return Ok(false);
+        };
+        let Ok(mut wr) = self.storage.write() else {
+            return Err(Error::Index(IndexError::RwLock));
+        };
+        wr.index = index;
+        wr.is_persisted = true;
+        Ok(true)
+    }
 }
 
-impl<K: TreeKey> NamedIndex<K> {
-    pub fn new(exctractor: ExtractStrFn) -> Self {
+impl<K: TreeKey, V: Archive + 'static> NamedIndex<K, V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    pub fn new(
+        extractor: impl Fn(&V::Archived) -> Result<String, IndexError> + Send + Sync + 'static,
+    ) -> Self {
         NamedIndex {
             storage: Arc::new(RwLock::new(Storage::default())),
-            extractor: exctractor,
+            extractor: Arc::new(extractor),
             post_process: StringPostProcess {
                 case_sensitive: true,
                 ignore_chars: vec![],
                 trim_whitespace: false,
             },
+            persist_to: None,
             _phantom: PhantomData {},
         }
     }
 
+    /// Keep a copy of the index in the client's database, saved by HillsClient::persist_indexes, so that add_indexer
+    /// loads it on the next launch instead of going through all the records.
+    ///
+    /// The copy is only loaded if the tree still has the same number of records and evolution, records changed in
+    /// place while the index was not added are not noticed. Name must be unique among the indexes of the tree.
+    pub fn persisted(mut self, client: &HillsClient, name: impl AsRef<str>) -> Result<Self, Error>
+    where
+        V: TreeRoot,
+    {
+        let index_name = format!("{}/{}", <V as TreeRoot>::tree_name(), name.as_ref());
+        self.persist_to = Some(client.open_index_tree(&index_name)?);
+        Ok(self)
+    }
+
     pub fn case_sensitive(mut self, is_case_sensitive: bool) -> Self {
         self.post_process.case_sensitive = is_case_sensitive;
         self
@@ -148,6 +328,7 @@ impl<K: TreeKey> NamedIndex<K> {
             storage: self.storage.clone(),
             extractor: self.extractor.clone(),
             post_process: self.post_process.clone(),
+            persist_to: self.persist_to.clone(),
         })
     }
 
@@ -161,6 +342,14 @@ impl<K: TreeKey> NamedIndex<K> {
     }
 
     pub fn get_similar(&self, s: impl AsRef<str>) -> Vec<(K, Similarity)> {
+        self.similar_names(s)
+            .into_iter()
+            .map(|(_, k, similarity)| (K::from_generic(k), similarity))
+            .collect()
+    }
+
+    /// Exact match first, then up to 20 names containing the query, along with the names as stored.
+    fn similar_names(&self, s: impl AsRef<str>) -> Vec<(String, GenericKey, Similarity)> {
         let Ok(rd) = self.storage.read() else {
             return vec![];
         };
@@ -169,11 +358,11 @@ impl<K: TreeKey> NamedIndex<K> {
 
         let s = self.post_process.post_process(s);
         if let Some(k) = rd.index.get(s.as_str()) {
-            similar.push((K::from_generic(*k), Similarity::Exact));
+            similar.push((s.clone(), *k, Similarity::Exact));
         }
         for (k, v) in &rd.index {
             if k.starts_with(&s) || k.contains(&s) {
-                similar.push((K::from_generic(*v), Similarity::Loose));
+                similar.push((k.clone(), *v, Similarity::Loose));
                 if similar.len() >= 20 {
                     break;
                 }
@@ -182,3 +371,63 @@ impl<K: TreeKey> NamedIndex<K> {
         similar
     }
 }
+
+/// Search over the indexed names, e.g. for a UI that works with any tree:
+///
+/// ```ignore
+/// let names = NamedIndex::<PartKey, Part>::new(field_extractor!(Part, part_number)).case_sensitive(false);
+/// client.add_indexer::<PartKey, Part>(names.indexer())?;
+/// for hit in names.search("stm32") {
+///     println!("{}: {:?}", hit.name, hit.key);
+/// }
+/// ```
+///
+/// Names are returned as stored in the index, after case folding and ignoring characters if those are enabled.
+impl<K: TreeKey, V: Archive + 'static> TreeSearch for NamedIndex<K, V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    type Key = K;
+
+    fn search(&self, query: impl AsRef<str>) -> Vec<SearchHit<K>> {
+        let similar = self.similar_names(query);
+        let exact = similar
+            .iter()
+            .find(|(_, _, similarity)| *similarity == Similarity::Exact)
+            .map(|(_, k, _)| *k);
+        similar
+            .into_iter()
+            // Exact match is also found again among the loose ones
+            .filter(|(_, k, similarity)| *similarity == Similarity::Exact || Some(*k) != exact)
+            .map(|(name, k, similarity)| SearchHit {
+                key: K::from_generic(k),
+                name,
+                description: String::new(),
+                similarity,
+            })
+            .collect()
+    }
+
+    /// Indexed name of a record, goes through all the names. Description is always empty.
+    fn name_desc(&self, key: K) -> Result<(String, String), Error> {
+        let Ok(rd) = self.storage.read() else {
+            return Err(Error::Index(IndexError::RwLock));
+        };
+        let key = key.to_generic();
+        rd.index
+            .iter()
+            .find(|(_, v)| **v == key)
+            .map(|(name, _)| (name.clone(), String::new()))
+            .ok_or(Error::RecordNotFound)
+    }
+}
+
+impl<K: TreeKey, V: Archive + 'static> UniqueIndex<K> for NamedIndex<K, V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    fn find_existing(&self, data: &[u8]) -> Result<Option<K>, Error> {
+        let s = (self.extractor)(IndexData::new(data).archived::<V>()?)?;
+        Ok(self.get(s))
+    }
+}
@@ -0,0 +1,189 @@
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use hills_base::{index::IndexError, GenericKey, TreeKey};
+use log::error;
+
+use crate::db::Error;
+use crate::record::{RecordMeta, Version};
+
+use super::{Action, IndexData, TreeIndex, TypeErasedTree};
+
+/// Extracts a bucket from a record's meta.
+pub type ExtractBucketFn<B> = Arc<dyn Fn(&RecordMeta) -> B + Send + Sync>;
+
+/// Index that splits records into a few buckets by their meta, e.g. by Draft / Released state.
+/// Listing one bucket does not require decoding all the records.
+pub struct PartitionIndex<K: TreeKey, B> {
+    storage: Arc<RwLock<Storage<B>>>,
+    extractor: ExtractBucketFn<B>,
+    _phantom: PhantomData<K>,
+}
+
+impl<K: TreeKey, B> Clone for PartitionIndex<K, B> {
+    fn clone(&self) -> Self {
+        PartitionIndex {
+            storage: self.storage.clone(),
+            extractor: self.extractor.clone(),
+            _phantom: PhantomData {},
+        }
+    }
+}
+
+struct Storage<B> {
+    buckets: BTreeMap<B, HashSet<GenericKey>>,
+    bucket_of: HashMap<GenericKey, B>,
+}
+
+impl<B: Ord + Clone> Storage<B> {
+    fn insert(&mut self, key: GenericKey, bucket: B) {
+        self.remove(key);
+        self.buckets.entry(bucket.clone()).or_default().insert(key);
+        self.bucket_of.insert(key, bucket);
+    }
+
+    fn remove(&mut self, key: GenericKey) {
+        let Some(bucket) = self.bucket_of.remove(&key) else {
+            return;
+        };
+        if let Some(keys) = self.buckets.get_mut(&bucket) {
+            keys.remove(&key);
+            if keys.is_empty() {
+                self.buckets.remove(&bucket);
+            }
+        }
+    }
+}
+
+struct PartitionIndexer<B> {
+    storage: Arc<RwLock<Storage<B>>>,
+    extractor: ExtractBucketFn<B>,
+}
+
+impl<B> Clone for PartitionIndexer<B> {
+    fn clone(&self) -> Self {
+        PartitionIndexer {
+            storage: self.storage.clone(),
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+impl<B: Ord + Clone + Send + Sync + 'static> TreeIndex for PartitionIndexer<B> {
+    fn rebuild(&mut self, tree: TypeErasedTree) -> Result<(), Error> {
+        let Ok(mut wr) = self.storage.write() else {
+            return Err(Error::Index(IndexError::RwLock));
+        };
+        wr.buckets.clear();
+        wr.bucket_of.clear();
+        for key in tree.all_revisions() {
+            match tree.meta(key) {
+                Ok(meta) => wr.insert(key, (self.extractor)(&meta)),
+                Err(e) => {
+                    error!("{key}: {:?}, skipping", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn update(
+        &mut self,
+        _tree: TypeErasedTree,
+        key: GenericKey,
+        _old_data: Option<&IndexData>,
+        _data: &IndexData,
+        action: Action,
+    ) -> Result<(), Error> {
+        // Buckets only depend on meta, which is delivered through meta_changed once the record is written
+        if let Action::Remove = action {
+            let Ok(mut wr) = self.storage.write() else {
+                return Err(Error::Index(IndexError::RwLock));
+            };
+            wr.remove(key);
+        }
+        Ok(())
+    }
+
+    fn meta_changed(&mut self, key: GenericKey, meta: &RecordMeta) -> Result<(), Error> {
+        let Ok(mut wr) = self.storage.write() else {
+            return Err(Error::Index(IndexError::RwLock));
+        };
+        wr.insert(key, (self.extractor)(meta));
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "partition"
+    }
+
+    fn len(&self) -> usize {
+        self.storage
+            .read()
+            .map(|rd| rd.bucket_of.len())
+            .unwrap_or(0)
+    }
+
+    fn memory_bytes(&self) -> usize {
+        let Ok(rd) = self.storage.read() else {
+            return 0;
+        };
+        // Every key is held twice: in its bucket and in bucket_of along with a copy of the bucket
+        let key_size = size_of::<GenericKey>();
+        let bucket_size = size_of::<B>();
+        rd.bucket_of.len() * (2 * key_size + bucket_size) + rd.buckets.len() * bucket_size
+    }
+}
+
+impl<K: TreeKey, B: Ord + Clone + Send + Sync + 'static> PartitionIndex<K, B> {
+    pub fn new(extractor: impl Fn(&RecordMeta) -> B + Send + Sync + 'static) -> Self {
+        PartitionIndex {
+            storage: Arc::new(RwLock::new(Storage {
+                buckets: BTreeMap::new(),
+                bucket_of: HashMap::new(),
+            })),
+            extractor: Arc::new(extractor),
+            _phantom: PhantomData {},
+        }
+    }
+
+    pub fn indexer(&self) -> Box<dyn TreeIndex + Send> {
+        Box::new(PartitionIndexer {
+            storage: self.storage.clone(),
+            extractor: self.extractor.clone(),
+        })
+    }
+
+    /// Keys of all the records currently in the bucket, in no particular order.
+    pub fn keys_in(&self, bucket: &B) -> Vec<K> {
+        let Ok(rd) = self.storage.read() else {
+            return vec![];
+        };
+        rd.buckets
+            .get(bucket)
+            .map(|keys| keys.iter().map(|k| K::from_generic(*k)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Non-empty buckets with the number of records in each.
+    pub fn buckets(&self) -> Vec<(B, usize)> {
+        let Ok(rd) = self.storage.read() else {
+            return vec![];
+        };
+        rd.buckets
+            .iter()
+            .map(|(bucket, keys)| (bucket.clone(), keys.len()))
+            .collect()
+    }
+}
+
+impl<K: TreeKey> PartitionIndex<K, Version> {
+    /// Partition by record state: NonVersioned, Draft(n) or Released(n), user state included.
+    pub fn by_version() -> Self {
+        Self::new(|meta| meta.version.clone())
+    }
+}
@@ -0,0 +1,374 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use hills_base::{index::IndexError, Evolving, GenericKey, SimpleVersion, TreeKey};
+use log::{error, warn};
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{
+    check_archived_root, to_bytes, AlignedVec, Archive, CheckBytes, Deserialize, Serialize,
+};
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree};
+use sled::Tree;
+
+use crate::db::{Error, HillsClient};
+
+use super::named::ExtractStrFn;
+use super::{
+    Action, IndexChange, IndexData, Similarity, StringPostProcess, TreeIndex, TypeErasedTree,
+    UniqueIndex,
+};
+
+/// Name -> key entries, followed by the name.
+const NAME_PREFIX: u8 = b'n';
+/// Key -> name entries, so that an update does not need to look through all the names.
+const KEY_PREFIX: u8 = b'k';
+/// State of the data tree the entries match, written by persist and removed by the first change after it.
+const STAMP: &[u8] = b"stamp";
+
+#[derive(Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
+struct Stamp {
+    evolution: SimpleVersion,
+    /// Tree::checksum of the data tree, so that changes made while the index was not registered are noticed
+    checksum: u32,
+}
+
+impl Stamp {
+    fn of(tree: TypeErasedTree) -> Result<Stamp, Error> {
+        Ok(Stamp {
+            evolution: tree.evolution,
+            checksum: tree.tree.checksum()?,
+        })
+    }
+}
+
+fn name_entry(name: &str) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(1 + name.len());
+    entry.push(NAME_PREFIX);
+    entry.extend_from_slice(name.as_bytes());
+    entry
+}
+
+fn key_entry(key: GenericKey) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(1 + GenericKey::BYTES);
+    entry.push(KEY_PREFIX);
+    entry.extend_from_slice(&key.to_bytes());
+    entry
+}
+
+fn abort<T>(e: impl Into<Error>) -> Result<T, ConflictableTransactionError<Error>> {
+    Err(ConflictableTransactionError::Abort(e.into()))
+}
+
+/// Same as NamedIndex, but the name -> key mapping is kept in its own sled tree instead of memory.
+///
+/// Memory use is bounded by the sled cache regardless of the tree size, at the cost of slower lookups.
+/// The index is rebuilt by streaming records straight to disk when added, so it never needs to fit in memory.
+/// After HillsClient::persist_indexes it is reused on the next launch instead, as long as the tree was not changed
+/// in the meantime, which is checked by going through the records once without decoding them.
+/// NamedIndex is faster and should be preferred for small trees.
+pub struct SledNamedIndex<K: TreeKey, V: Archive> {
+    index: Tree,
+    extractor: ExtractStrFn<V>,
+    post_process: StringPostProcess,
+    _phantom: PhantomData<K>,
+}
+
+impl<K: TreeKey, V: Archive> Clone for SledNamedIndex<K, V> {
+    fn clone(&self) -> Self {
+        SledNamedIndex {
+            index: self.index.clone(),
+            extractor: self.extractor.clone(),
+            post_process: self.post_process.clone(),
+            _phantom: PhantomData {},
+        }
+    }
+}
+
+struct SledNamedIndexer<V: Archive> {
+    index: Tree,
+    extractor: ExtractStrFn<V>,
+    post_process: StringPostProcess,
+}
+
+impl<V: Archive> Clone for SledNamedIndexer<V> {
+    fn clone(&self) -> Self {
+        SledNamedIndexer {
+            index: self.index.clone(),
+            extractor: self.extractor.clone(),
+            post_process: self.post_process.clone(),
+        }
+    }
+}
+
+impl<V: Archive + 'static> SledNamedIndexer<V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    fn extract(&self, data: &IndexData) -> Result<String, IndexError> {
+        let s = (self.extractor)(data.archived::<V>()?)?;
+        Ok(self.post_process.post_process(s))
+    }
+
+    /// Old data is not needed, the old name is looked up by key.
+    fn apply(
+        &self,
+        tx: &TransactionalTree,
+        key: GenericKey,
+        data: &IndexData,
+        action: Action,
+    ) -> Result<(), ConflictableTransactionError<Error>> {
+        match action {
+            Action::Insert => {
+                let name = match self.extract(data) {
+                    Ok(name) => name,
+                    Err(e) => return abort(e),
+                };
+                if let Some(existing) = tx.get(name_entry(&name))? {
+                    // Same record indexed again, e.g. when filled in while opening a tree
+                    if existing == key.to_bytes().as_slice() {
+                        return Ok(());
+                    }
+                    return abort(duplicate(name, &existing));
+                }
+                tx.insert(name_entry(&name), &key.to_bytes())?;
+                tx.insert(key_entry(key), name.as_bytes())?;
+            }
+            Action::Update => {
+                let Some(old_name) = tx.get(key_entry(key))? else {
+                    return abort(IndexError::Other("old name not found".to_string()));
+                };
+                let new_name = match self.extract(data) {
+                    Ok(name) => name,
+                    Err(e) => return abort(e),
+                };
+                if old_name != new_name.as_bytes() {
+                    if let Some(existing) = tx.get(name_entry(&new_name))? {
+                        return abort(duplicate(new_name, &existing));
+                    }
+                    let mut old_entry = vec![NAME_PREFIX];
+                    old_entry.extend_from_slice(&old_name);
+                    tx.remove(old_entry)?;
+                    tx.insert(name_entry(&new_name), &key.to_bytes())?;
+                    tx.insert(key_entry(key), new_name.as_bytes())?;
+                }
+            }
+            Action::Remove => {
+                if let Some(old_name) = tx.remove(key_entry(key))? {
+                    let mut old_entry = vec![NAME_PREFIX];
+                    old_entry.extend_from_slice(&old_name);
+                    tx.remove(old_entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn duplicate(value: String, existing: &[u8]) -> IndexError {
+    match GenericKey::from_bytes(existing) {
+        Some(existing) => IndexError::Duplicate { value, existing },
+        None => IndexError::Other(format!("malformed key of {value} in the index")),
+    }
+}
+
+fn transaction_result(r: Result<(), TransactionError<Error>>) -> Result<(), Error> {
+    match r {
+        Ok(()) => Ok(()),
+        Err(TransactionError::Abort(e)) => Err(e),
+        Err(TransactionError::Storage(e)) => Err(Error::Sled(e)),
+    }
+}
+
+impl<V: Archive + 'static> TreeIndex for SledNamedIndexer<V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    fn rebuild(&mut self, tree: TypeErasedTree) -> Result<(), Error> {
+        self.index.clear()?;
+        for key in tree.all_revisions() {
+            let name = match tree.get_with(key, |data| self.extract(&IndexData::new(data))) {
+                Ok(Ok(name)) => name,
+                Ok(Err(e)) => {
+                    error!("{key}: {:?}, skipping", e);
+                    continue;
+                }
+                Err(e) => {
+                    error!("{key}: {:?}, skipping", e);
+                    continue;
+                }
+            };
+            if let Some(existing) = self.index.get(name_entry(&name))? {
+                return Err(Error::Index(duplicate(name, &existing)));
+            }
+            self.index.insert(name_entry(&name), &key.to_bytes())?;
+            self.index.insert(key_entry(key), name.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn update(
+        &mut self,
+        _tree: TypeErasedTree,
+        key: GenericKey,
+        _old_data: Option<&IndexData>,
+        data: &IndexData,
+        action: Action,
+    ) -> Result<(), Error> {
+        transaction_result(self.index.transaction(|tx| {
+            tx.remove(STAMP)?;
+            self.apply(tx, key, data, action)
+        }))
+    }
+
+    fn update_batch(
+        &mut self,
+        _tree: TypeErasedTree,
+        changes: &[IndexChange],
+    ) -> Result<(), Error> {
+        transaction_result(self.index.transaction(|tx| {
+            tx.remove(STAMP)?;
+            for (key, _, data, action) in changes {
+                self.apply(tx, *key, data, *action)?;
+            }
+            Ok(())
+        }))
+    }
+
+    fn kind(&self) -> &'static str {
+        "sled_named"
+    }
+
+    /// Counts the entries on disk, so takes time proportional to the index size.
+    fn len(&self) -> usize {
+        self.index.scan_prefix([KEY_PREFIX]).count()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.index.scan_prefix([KEY_PREFIX]).next().is_none()
+    }
+
+    fn persist(&self, tree: TypeErasedTree) -> Result<(), Error> {
+        let stamp = to_bytes::<_, 64>(&Stamp::of(tree)?)?;
+        self.index.insert(STAMP, stamp.as_slice())?;
+        Ok(())
+    }
+
+    /// Entries are already on disk, they are kept if the tree is in the same state as when they were persisted.
+    /// Otherwise they are cleared, so that they can be filled in again record by record.
+    fn load(&mut self, tree: TypeErasedTree) -> Result<bool, Error> {
+        let is_current = match self.index.get(STAMP)? {
+            Some(stamp_bytes) => {
+                // Short values are stored inline by sled and might not be aligned enough for rkyv
+                let mut aligned = AlignedVec::new();
+                aligned.extend_from_slice(&stamp_bytes);
+                match check_archived_root::<Stamp>(&aligned) {
+                    Ok(stamp) => {
+                        let stamp: Stamp = stamp.deserialize(&mut rkyv::Infallible)?;
+                        let current = Stamp::of(tree)?;
+                        stamp.evolution == current.evolution && stamp.checksum == current.checksum
+                    }
+                    Err(_) => {
+                        warn!("index stamp is malformed, rebuilding");
+                        false
+                    }
+                }
+            }
+            None => false,
+        };
+        if !is_current {
+            self.index.clear()?;
+        }
+        Ok(is_current)
+    }
+}
+
+impl<K: TreeKey, V: Archive + 'static> SledNamedIndex<K, V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    /// Open an index stored in the client's database, name must be unique among the indexes of this client.
+    pub fn open(
+        client: &HillsClient,
+        name: impl AsRef<str>,
+        extractor: impl Fn(&V::Archived) -> Result<String, IndexError> + Send + Sync + 'static,
+    ) -> Result<Self, Error> {
+        Ok(SledNamedIndex {
+            index: client.open_index_tree(name.as_ref())?,
+            extractor: Arc::new(extractor),
+            post_process: StringPostProcess {
+                case_sensitive: true,
+                ignore_chars: vec![],
+                trim_whitespace: false,
+            },
+            _phantom: PhantomData {},
+        })
+    }
+
+    pub fn case_sensitive(mut self, is_case_sensitive: bool) -> Self {
+        self.post_process.case_sensitive = is_case_sensitive;
+        self
+    }
+
+    pub fn ignore_chars(mut self, ignore_chars: impl IntoIterator<Item = char>) -> Self {
+        self.post_process.ignore_chars = ignore_chars.into_iter().collect();
+        self
+    }
+
+    pub fn trim_whitespace(mut self, is_trim_whitespace: bool) -> Self {
+        self.post_process.trim_whitespace = is_trim_whitespace;
+        self
+    }
+
+    pub fn indexer(&self) -> Box<dyn TreeIndex + Send> {
+        Box::new(SledNamedIndexer {
+            index: self.index.clone(),
+            extractor: self.extractor.clone(),
+            post_process: self.post_process.clone(),
+        })
+    }
+
+    pub fn get(&self, s: impl AsRef<str>) -> Option<K> {
+        let s = self.post_process.post_process(s);
+        let key_bytes = self.index.get(name_entry(&s)).ok().flatten()?;
+        GenericKey::from_bytes(&key_bytes).map(K::from_generic)
+    }
+
+    /// Exact match first, then up to 20 names containing the query, which requires going through all of them.
+    pub fn get_similar(&self, s: impl AsRef<str>) -> Vec<(K, Similarity)> {
+        let mut similar = vec![];
+
+        let s = self.post_process.post_process(s);
+        if let Some(k) = self.get(&s) {
+            similar.push((k, Similarity::Exact));
+        }
+        for entry in self.index.scan_prefix([NAME_PREFIX]) {
+            let Ok((name, key_bytes)) = entry else {
+                break;
+            };
+            let Ok(name) = std::str::from_utf8(&name[1..]) else {
+                continue;
+            };
+            if name.contains(&s) {
+                let Some(key) = GenericKey::from_bytes(&key_bytes) else {
+                    continue;
+                };
+                similar.push((K::from_generic(key), Similarity::Loose));
+                if similar.len() >= 20 {
+                    break;
+                }
+            }
+        }
+        similar
+    }
+}
+
+impl<K: TreeKey, V: Archive + 'static> UniqueIndex<K> for SledNamedIndex<K, V>
+where
+    <Evolving<V> as Archive>::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    fn find_existing(&self, data: &[u8]) -> Result<Option<K>, Error> {
+        let s = (self.extractor)(IndexData::new(data).archived::<V>()?)?;
+        Ok(self.get(s))
+    }
+}
@@ -1,18 +1,132 @@
+//! Opt-in log of every change made to a data tree, so that its records can be rebuilt as they were at any point,
+//! see HillsClient::enable_journal and HillsClient::rebuild_from_journal.
+
+use crate::common::Error;
+use crate::consts::{JOURNALED, JOURNAL_TREE_PREFIX, NEXT_TEMPORARY_ID};
+use crate::temporary::is_temporary;
 use hills_base::GenericKey;
-use rkyv::{Archive, Deserialize, Serialize};
+use rkyv::{check_archived_root, to_bytes, AlignedVec, Archive, Deserialize, Serialize};
+use sled::{Db, Tree};
+
+/// Serial of the next entry, stored in the journal tree alongside the entries keyed by their serial.
+const NEXT_SERIAL: &[u8] = b"_next_serial";
 
-#[derive(Archive, Serialize, Deserialize)]
+/// One change of a record, in the order it was made.
+#[derive(Archive, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[archive(check_bytes)]
 pub struct JournalEntry {
-    serial: u64,
-    key: GenericKey,
-    action: Action,
+    /// Starts at 0 and grows by one with each change of the tree.
+    pub serial: u64,
+    pub key: GenericKey,
+    pub action: Action,
 }
 
-#[derive(Archive, Serialize, Deserialize)]
+#[derive(Archive, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[archive(check_bytes)]
 pub enum Action {
-    Create,
-    Modify,
-    Release,
-    ChangeState(u32),
-    AssignGlobalId(u32),
+    /// Record was written for the first time, holds the serialized Record.
+    Create(Vec<u8>),
+    /// Record data or meta changed, holds the whole serialized Record after the change.
+    Modify(Vec<u8>),
+    Remove,
+}
+
+/// All journaled changes of one tree, see HillsClient::journal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Journal {
+    pub tree_name: String,
+    /// Sorted by serial.
+    pub entries: Vec<JournalEntry>,
+}
+
+pub(crate) fn journal_tree_name(tree_name: &str) -> String {
+    format!("{JOURNAL_TREE_PREFIX}{tree_name}")
+}
+
+/// Journal of a data tree, None if its changes are not journaled.
+pub(crate) fn journal_of(db: &Db, data: &Tree, tree_name: &str) -> Result<Option<Tree>, Error> {
+    if !data.contains_key(JOURNALED)? {
+        return Ok(None);
+    }
+    Ok(Some(db.open_tree(journal_tree_name(tree_name))?))
+}
+
+/// Append a change right after it was written to the data tree, does nothing if the tree is not journaled.
+///
+/// Action is only made for journaled trees, so that the others do not pay for copying records.
+pub(crate) fn append(
+    journal: Option<&Tree>,
+    key: GenericKey,
+    action: impl FnOnce() -> Action,
+) -> Result<(), Error> {
+    let Some(journal) = journal else {
+        return Ok(());
+    };
+    let mut serial = 0;
+    journal.update_and_fetch(NEXT_SERIAL, |next| {
+        serial = next
+            .and_then(|next| next.try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+        Some((serial + 1).to_be_bytes().to_vec())
+    })?;
+    let entry = JournalEntry {
+        serial,
+        key,
+        action: action(),
+    };
+    journal.insert(serial.to_be_bytes(), to_bytes::<_, 256>(&entry)?.as_slice())?;
+    Ok(())
+}
+
+/// Read all the entries of a journal tree.
+pub(crate) fn read(journal: &Tree, tree_name: &str) -> Result<Journal, Error> {
+    let mut entries = Vec::new();
+    for entry in journal.iter() {
+        let (serial, bytes) = entry?;
+        if serial.len() != 8 {
+            continue;
+        }
+        // Short values are stored inline by sled and might not be aligned enough for rkyv
+        let mut aligned = AlignedVec::with_capacity(bytes.len());
+        aligned.extend_from_slice(&bytes);
+        let archived = check_archived_root::<JournalEntry>(&aligned)?;
+        entries.push(archived.deserialize(&mut rkyv::Infallible)?);
+    }
+    Ok(Journal {
+        tree_name: tree_name.to_string(),
+        entries,
+    })
+}
+
+/// Apply entries up to and including serial up_to (all of them if None) to a data tree, in serial order.
+///
+/// Returns the number of applied entries.
+pub(crate) fn replay(data: &Tree, journal: &Journal, up_to: Option<u64>) -> Result<usize, Error> {
+    let mut entries: Vec<&JournalEntry> = journal
+        .entries
+        .iter()
+        .filter(|entry| up_to.map(|up_to| entry.serial <= up_to).unwrap_or(true))
+        .collect();
+    entries.sort_by_key(|entry| entry.serial);
+    let mut batch = sled::Batch::default();
+    let mut next_temporary_id = None;
+    for entry in &entries {
+        let key_bytes = entry.key.to_bytes();
+        match &entry.action {
+            Action::Create(record_bytes) | Action::Modify(record_bytes) => {
+                batch.insert(&key_bytes, record_bytes.as_slice());
+                if is_temporary(entry.key.id) {
+                    next_temporary_id = next_temporary_id.max(Some(entry.key.id.saturating_add(1)));
+                }
+            }
+            Action::Remove => batch.remove(&key_bytes),
+        }
+    }
+    // Temporary ids of replayed records are not given out again
+    if let Some(next_temporary_id) = next_temporary_id {
+        batch.insert(NEXT_TEMPORARY_ID, &next_temporary_id.to_be_bytes());
+    }
+    data.apply_batch(batch)?;
+    Ok(entries.len())
 }
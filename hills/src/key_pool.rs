@@ -1,8 +1,9 @@
 use crate::common::Error;
-use crate::consts::KEY_POOL;
+use crate::consts::{CLIENT_IDS, KEY_POOL, PENDING_KEY_REQUESTS};
 use rkyv::{check_archived_root, to_bytes, Archive, Deserialize, Serialize};
-use sled::transaction::ConflictableTransactionError;
-use sled::Tree;
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use sled::{Db, Tree};
+use std::collections::HashSet;
 use std::ops::Range;
 
 #[derive(Archive, Debug, Serialize, Deserialize)]
@@ -38,6 +39,72 @@ impl KeyPool {
         }
     }
 
+    /// Take the next key out of the pool stored in a data tree, None if the pool is empty or was never fed.
+    pub fn take_in(
+        tx_tree: &TransactionalTree,
+    ) -> Result<Option<u32>, ConflictableTransactionError<&'static str>> {
+        let Some(key_pool) = tx_tree.get(KEY_POOL)? else {
+            return Ok(None);
+        };
+        let key_pool: &ArchivedKeyPool = check_archived_root::<KeyPool>(&key_pool)
+            .map_err(|_| ConflictableTransactionError::Abort("checked_archived_root"))?;
+        let mut key_pool: KeyPool = key_pool
+            .deserialize(&mut rkyv::Infallible)
+            .map_err(|_| ConflictableTransactionError::Abort("get_next_key: deserialize"))?;
+        let Some(next_key) = key_pool.get() else {
+            return Ok(None);
+        };
+        let key_pool = to_bytes::<_, 8>(&key_pool)
+            .map_err(|_| ConflictableTransactionError::Abort("to_bytes"))?;
+        tx_tree.insert(KEY_POOL, &*key_pool)?;
+        Ok(Some(next_key))
+    }
+
+    /// Put a freed key back, merging it with adjacent ranges. Keys that are already in the pool are ignored.
+    pub fn return_key(&mut self, id: u32) {
+        if self.ranges.iter().any(|range| range.contains(&id)) {
+            return;
+        }
+        let before = self.ranges.iter().position(|range| range.end == id);
+        let after = self.ranges.iter().position(|range| range.start == id + 1);
+        match (before, after) {
+            (Some(before), Some(after)) => {
+                self.ranges[before].end = self.ranges[after].end;
+                self.ranges.remove(after);
+            }
+            (Some(before), None) => self.ranges[before].end += 1,
+            (None, Some(after)) => self.ranges[after].start -= 1,
+            // Handed out first, before the ranges that were not touched yet
+            (None, None) => self.ranges.insert(0, id..id + 1),
+        }
+    }
+
+    /// Return the id of a removed record to the pool stored in a data tree.
+    ///
+    /// Only ids of records the server never heard of can be returned. It remembers removed records and ignores
+    /// any later change to them, so a reused id would never sync. Returned ids do not collide with ranges issued
+    /// later either: they come from a range issued to this client and the server's next_key only moves forward.
+    pub fn return_in(
+        tx_tree: &TransactionalTree,
+        id: u32,
+    ) -> Result<(), ConflictableTransactionError<&'static str>> {
+        let mut key_pool = match tx_tree.get(KEY_POOL)? {
+            Some(key_pool) => {
+                let key_pool: &ArchivedKeyPool = check_archived_root::<KeyPool>(&key_pool)
+                    .map_err(|_| ConflictableTransactionError::Abort("checked_archived_root"))?;
+                key_pool
+                    .deserialize(&mut rkyv::Infallible)
+                    .map_err(|_| ConflictableTransactionError::Abort("return_key: deserialize"))?
+            }
+            None => KeyPool::new(vec![]),
+        };
+        key_pool.return_key(id);
+        let key_pool = to_bytes::<_, 8>(&key_pool)
+            .map_err(|_| ConflictableTransactionError::Abort("to_bytes"))?;
+        tx_tree.insert(KEY_POOL, &*key_pool)?;
+        Ok(())
+    }
+
     pub fn total_keys_available(&self) -> u32 {
         self.ranges.iter().fold(0, |acc, r| acc + r.end - r.start)
     }
@@ -80,9 +147,78 @@ impl KeyPool {
     }
 }
 
+/// Trees for which GetKeySet was sent, but KeySet was not yet received.
+/// Persisted, so that unanswered requests are re-issued after reconnect or restart instead of being lost.
+#[derive(Archive, Default, Serialize, Deserialize)]
+#[archive(check_bytes)]
+pub struct PendingKeyRequests {
+    pub trees: HashSet<String>,
+    pub _dummy22: [u8; 18],
+}
+
+impl PendingKeyRequests {
+    pub fn is_pending(db: &Db, tree_name: impl AsRef<str>) -> Result<bool, Error> {
+        match db.get(PENDING_KEY_REQUESTS)? {
+            Some(pending) => {
+                let pending = check_archived_root::<PendingKeyRequests>(&pending)?;
+                Ok(pending.trees.contains(tree_name.as_ref()))
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn set_pending(db: &Db, tree_name: impl AsRef<str>, is_pending: bool) -> Result<(), Error> {
+        let tree_name = tree_name.as_ref();
+        let mut pending: PendingKeyRequests = match db.get(PENDING_KEY_REQUESTS)? {
+            Some(pending) => {
+                let pending = check_archived_root::<PendingKeyRequests>(&pending)?;
+                pending.deserialize(&mut rkyv::Infallible)?
+            }
+            None => PendingKeyRequests::default(),
+        };
+        let changed = if is_pending {
+            pending.trees.insert(tree_name.to_string())
+        } else {
+            pending.trees.remove(tree_name)
+        };
+        if changed {
+            let pending_bytes = to_bytes::<_, 128>(&pending)?;
+            db.insert(PENDING_KEY_REQUESTS, pending_bytes.as_slice())?;
+        }
+        Ok(())
+    }
+}
+
+/// Id of a record in a tree with IdStrategy::ClientHash, same on every node and every build.
+/// 64-bit FNV-1a of the bytes, reduced to CLIENT_IDS.
+pub(crate) fn hashed_id(bytes: &[u8]) -> u32 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    let span = (CLIENT_IDS.end - CLIENT_IDS.start) as u64;
+    CLIENT_IDS.start + (hash % span) as u32
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::key_pool::KeyPool;
+    use crate::consts::CLIENT_IDS;
+    use crate::key_pool::{hashed_id, KeyPool, PendingKeyRequests};
+
+    #[test]
+    fn hashed_ids_are_stable() {
+        assert_eq!(hashed_id(b"M3x8"), hashed_id(b"M3x8"));
+        assert_ne!(hashed_id(b"M3x8"), hashed_id(b"M3x10"));
+        // Must not change between builds, nodes would disagree on ids otherwise
+        assert_eq!(
+            hashed_id(b""),
+            CLIENT_IDS.start + (0xcbf2_9ce4_8422_2325u64 % 0x7000_0000) as u32
+        );
+        for name in ["", "a", "M3x8", "a much longer natural key"] {
+            assert!(CLIENT_IDS.contains(&hashed_id(name.as_bytes())));
+        }
+    }
 
     #[test]
     fn empty() {
@@ -104,4 +240,29 @@ mod tests {
         assert_eq!(pool.get(), Some(10));
         assert_eq!(pool.get(), None);
     }
+
+    #[test]
+    fn returned_keys_are_merged() {
+        let mut pool = KeyPool::new(vec![(2..4), (10..12)]);
+        pool.return_key(4);
+        pool.return_key(9);
+        pool.return_key(2);
+        assert_eq!(pool.ranges, vec![(2..5), (9..12)]);
+        pool.return_key(0);
+        assert_eq!(pool.ranges, vec![(0..1), (2..5), (9..12)]);
+        pool.return_key(1);
+        assert_eq!(pool.ranges, vec![(0..5), (9..12)]);
+        assert_eq!(pool.get(), Some(0));
+    }
+
+    #[test]
+    fn pending_requests() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        assert!(!PendingKeyRequests::is_pending(&db, "parts").unwrap());
+        PendingKeyRequests::set_pending(&db, "parts", true).unwrap();
+        assert!(PendingKeyRequests::is_pending(&db, "parts").unwrap());
+        assert!(!PendingKeyRequests::is_pending(&db, "users").unwrap());
+        PendingKeyRequests::set_pending(&db, "parts", false).unwrap();
+        assert!(!PendingKeyRequests::is_pending(&db, "parts").unwrap());
+    }
 }
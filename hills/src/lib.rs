@@ -1,22 +1,35 @@
+pub mod cache;
+pub mod clock;
 mod common;
 mod consts;
 pub mod db;
+#[cfg(feature = "gateway")]
+pub mod gateway;
 pub mod index;
-mod journal;
+pub mod journal;
 mod key_pool;
 pub mod opaque;
+mod pending;
 pub mod record;
 mod sync;
 pub mod sync_client;
 mod sync_common;
 pub mod sync_server;
+mod temporary;
+mod throughput;
 pub mod tree;
 
-pub use db::{HillsClient, TypedTree};
-pub use sync_client::VhrdDbTelem;
+pub use cache::CachedTree;
+pub use common::{Error as CommonError, OpenMode, WsLimits};
+pub use consts::{CLIENT_IDS, PROTOCOL_VERSION, RESERVED_CEILING};
+pub use db::{ClientConfig, HillsClient, Inserted, TypedTree};
+pub use journal::Journal;
+pub use pending::PendingChange;
+pub use sync::ChangeKind;
+pub use sync_client::{KeyRequests, ReconnectBackoff, SyncSummary, VhrdDbTelem};
 
 pub use hills_base::index::IndexError;
-pub use hills_base::{GenericKey, TreeKey, UtcDateTime};
+pub use hills_base::{GenericKey, IdStrategy, TreeKey, UtcDateTime};
 pub use uuid;
 
 // TODO: remove unwraps
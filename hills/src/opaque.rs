@@ -1,11 +1,12 @@
-use crate::consts::KEY_POOL;
-use crate::db::{Error, RecordCheckOutState};
-use crate::record::RecordMeta;
+use crate::common::{latest_record_keys, record_keys};
+use crate::db::{Error, KeyOrValue, RecordCheckOutState};
+use crate::record::{Record, RecordMeta};
 use crate::TypedTree;
-use hills_base::{GenericKey, SimpleVersion, TreeKey, TreeRoot};
+use futures_util::future::BoxFuture;
+use hills_base::{Evolving, GenericKey, SimpleVersion, TreeKey, TreeRoot};
 use rkyv::ser::serializers::AllocSerializer;
 use rkyv::validation::validators::DefaultValidator;
-use rkyv::{Archive, CheckBytes, Deserialize, Serialize};
+use rkyv::{to_bytes, Archive, CheckBytes, Deserialize, Serialize};
 use ron::ser::PrettyConfig;
 use std::fmt::Debug;
 use std::sync::Arc;
@@ -27,6 +28,29 @@ impl OpaqueKey {
     }
 }
 
+/// Text format of OpaqueTree exports.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Ron,
+    RonPretty,
+}
+
+/// All revisions of all records of a tree, as written by export_full_history.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FullHistory<V> {
+    tree: String,
+    records: Vec<HistoryEntry<V>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct HistoryEntry<V> {
+    key: GenericKey,
+    meta_iteration: u32,
+    meta: RecordMeta,
+    data_iteration: u32,
+    value: V,
+}
+
 pub trait OpaqueTree {
     fn keys_in_pool(&self) -> Result<u32, Error>;
     fn record_meta(
@@ -40,20 +64,37 @@ pub trait OpaqueTree {
     fn to_ron_str_pretty(&self, key: &OpaqueKey) -> Result<String, Error>;
     fn insert_from_ron_str(&mut self, value: &str) -> Result<GenericKey, Error>;
     fn update_from_ron_str(&mut self, key: &OpaqueKey, value: &str) -> Result<(), Error>;
+    #[cfg(feature = "gateway")]
+    fn to_json_string(&self, key: &OpaqueKey) -> Result<String, Error>;
+    #[cfg(feature = "gateway")]
+    fn insert_from_json_str(&mut self, value: &str) -> Result<GenericKey, Error>;
+    #[cfg(feature = "gateway")]
+    fn update_from_json_str(&mut self, key: &OpaqueKey, value: &str) -> Result<(), Error>;
     fn remove(&mut self, key: &OpaqueKey) -> Result<(), Error>;
 
+    /// Every revision of every record in key order, with its meta (including Version state) and value.
+    fn export_full_history(&self, format: ExportFormat) -> Result<String, Error>;
+    /// Restore revisions written by export_full_history with their keys, meta and iterations.
+    /// Records that are missing or older locally are written, returns the number of written records.
+    /// Nothing is written if one of the records cannot be indexed.
+    fn import_full_history(&mut self, history: &str) -> Result<usize, Error>;
+
     fn is_checked_out(&self, key: &OpaqueKey) -> Result<bool, Error>;
     fn checked_out_by(&self, key: &OpaqueKey) -> Result<RecordCheckOutState, Error>;
     fn check_out(&mut self, key: &OpaqueKey) -> Result<(), Error>;
+    /// See TypedTree::try_check_out.
+    fn try_check_out(&mut self, key: &OpaqueKey) -> BoxFuture<'static, Result<(), Error>>;
     fn release(&mut self, key: &OpaqueKey) -> Result<(), Error>;
+    fn cancel_checkout(&mut self, key: &OpaqueKey) -> Result<(), Error>;
 
     fn versioning(&self) -> bool;
 }
 
 impl<K, V> OpaqueTree for TypedTree<K, V>
 where
-    K: TreeKey + Debug,
+    K: TreeKey + Debug + 'static,
     V: TreeRoot
+        + 'static
         + Archive
         + Serialize<AllocSerializer<128>>
         + serde::Serialize
@@ -74,46 +115,54 @@ where
     }
 
     fn all_revisions(&self) -> Box<dyn Iterator<Item = OpaqueKey> + '_> {
-        Box::new(self.data.iter().keys().filter_map(|key| {
-            if let Ok(key) = key {
-                if key == KEY_POOL {
-                    return None;
-                }
-                let key = GenericKey::from_bytes(&key)?;
-                let key = OpaqueKey {
-                    tree_name: self.tree_name.clone(),
-                    id: key.id,
-                    revision: key.revision,
-                };
-                Some(key)
-            } else {
-                log::error!("Err in OpaqueTree::all_revisions");
-                None
-            }
-        }))
+        Box::new(record_keys(&self.data).map(|key| OpaqueKey::new(self.tree_name.clone(), key)))
     }
 
     fn latest_revisions(&self) -> Box<dyn Iterator<Item = OpaqueKey>> {
-        todo!()
+        let tree_name = self.tree_name.clone();
+        Box::new(
+            latest_record_keys(&self.data).map(move |key| OpaqueKey::new(tree_name.clone(), key)),
+        )
     }
 
     fn to_ron_str_pretty(&self, key: &OpaqueKey) -> Result<String, Error> {
         let key = check_key(key, self.tree_name.as_str())?;
         let value = self.get(key)?;
-        let s = ron::ser::to_string_pretty(&value, PrettyConfig::default().compact_arrays(true))
-            .map_err(|e| Error::Internal(format!("{e:?}")))?;
+        let s = ron::ser::to_string_pretty(&value, PrettyConfig::default().compact_arrays(true))?;
         Ok(s)
     }
 
     fn insert_from_ron_str(&mut self, value: &str) -> Result<GenericKey, Error> {
-        let value: V = ron::de::from_str(value).map_err(|e| Error::Internal(format!("{e:?}")))?;
+        let value: V = ron::de::from_str(value)?;
         self.insert(value).map(|k| k.to_generic())
     }
 
     fn update_from_ron_str(&mut self, key: &OpaqueKey, value: &str) -> Result<(), Error> {
         let key = check_key(key, self.tree_name.as_str())?;
-        let value: V = ron::de::from_str(value).map_err(|e| Error::Internal(format!("{e:?}")))?;
-        self.update(key, value)
+        let value: V = ron::de::from_str(value)?;
+        self.update(key, value)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "gateway")]
+    fn to_json_string(&self, key: &OpaqueKey) -> Result<String, Error> {
+        let key = check_key(key, self.tree_name.as_str())?;
+        let value = self.get(key)?;
+        Ok(serde_json::to_string(&value)?)
+    }
+
+    #[cfg(feature = "gateway")]
+    fn insert_from_json_str(&mut self, value: &str) -> Result<GenericKey, Error> {
+        let value: V = serde_json::from_str(value)?;
+        self.insert(value).map(|k| k.to_generic())
+    }
+
+    #[cfg(feature = "gateway")]
+    fn update_from_json_str(&mut self, key: &OpaqueKey, value: &str) -> Result<(), Error> {
+        let key = check_key(key, self.tree_name.as_str())?;
+        let value: V = serde_json::from_str(value)?;
+        self.update(key, value)?;
+        Ok(())
     }
 
     fn remove(&mut self, key: &OpaqueKey) -> Result<(), Error> {
@@ -122,13 +171,68 @@ where
         Ok(())
     }
 
+    fn export_full_history(&self, format: ExportFormat) -> Result<String, Error> {
+        let mut records = vec![];
+        for generic_key in record_keys(&self.data) {
+            let Some((meta_iteration, meta, data_iteration, _)) =
+                self.meta(K::from_generic(generic_key))?
+            else {
+                continue;
+            };
+            records.push(HistoryEntry {
+                key: generic_key,
+                meta_iteration,
+                meta,
+                data_iteration,
+                value: self.get(K::from_generic(generic_key))?,
+            });
+        }
+        let history = FullHistory {
+            tree: self.tree_name.to_string(),
+            records,
+        };
+        let s = match format {
+            ExportFormat::Ron => ron::ser::to_string(&history)?,
+            ExportFormat::RonPretty => {
+                ron::ser::to_string_pretty(&history, PrettyConfig::default())?
+            }
+        };
+        Ok(s)
+    }
+
+    fn import_full_history(&mut self, history: &str) -> Result<usize, Error> {
+        let history: FullHistory<V> = ron::de::from_str(history)?;
+        if history.tree != *self.tree_name {
+            return Err(Error::TreeMismatch {
+                expected: self.tree_name.to_string(),
+                got: history.tree,
+                what: KeyOrValue::Value,
+            });
+        }
+        let mut records = Vec::with_capacity(history.records.len());
+        for entry in history.records {
+            let record = Record {
+                meta_iteration: entry.meta_iteration,
+                meta: RecordMeta {
+                    key: entry.key,
+                    ..entry.meta
+                },
+                data_iteration: entry.data_iteration,
+                data_evolution: V::evolution(),
+                data: to_bytes::<_, 128>(&Evolving(entry.value))?,
+            };
+            records.push(to_bytes::<_, 128>(&record)?);
+        }
+        self.import_records(&records)
+    }
+
     fn versioning(&self) -> bool {
         V::versioning()
     }
 
     fn is_checked_out(&self, key: &OpaqueKey) -> Result<bool, Error> {
         let key = check_key(key, self.tree_name.as_str())?;
-        Ok(<TypedTree<K, V>>::is_checked_out(&self, key))
+        Ok(<TypedTree<K, V>>::is_checked_out(self, key))
     }
 
     fn check_out(&mut self, key: &OpaqueKey) -> Result<(), Error> {
@@ -137,24 +241,38 @@ where
         Ok(())
     }
 
+    fn try_check_out(&mut self, key: &OpaqueKey) -> BoxFuture<'static, Result<(), Error>> {
+        match check_key(key, self.tree_name.as_str()) {
+            Ok(key) => Box::pin(<TypedTree<K, V>>::try_check_out(self, key)),
+            Err(e) => Box::pin(async move { Err(e) }),
+        }
+    }
+
     fn release(&mut self, key: &OpaqueKey) -> Result<(), Error> {
         let key = check_key(key, self.tree_name.as_str())?;
         <TypedTree<K, V>>::release(self, key);
         Ok(())
     }
 
+    fn cancel_checkout(&mut self, key: &OpaqueKey) -> Result<(), Error> {
+        let key = check_key(key, self.tree_name.as_str())?;
+        <TypedTree<K, V>>::cancel_checkout(self, key);
+        Ok(())
+    }
+
     fn checked_out_by(&self, key: &OpaqueKey) -> Result<RecordCheckOutState, Error> {
         let key = check_key(key, self.tree_name.as_str())?;
-        Ok(<TypedTree<K, V>>::checked_out_by(&self, key))
+        Ok(<TypedTree<K, V>>::checked_out_by(self, key))
     }
 }
 
 fn check_key<K: TreeKey>(key: &OpaqueKey, tree_name: &str) -> Result<K, Error> {
     if key.tree_name.as_str() != tree_name {
-        return Err(Error::Usage(format!(
-            "Tried to use {} key with {} tree",
-            key.tree_name, tree_name
-        )));
+        return Err(Error::TreeMismatch {
+            expected: tree_name.to_string(),
+            got: key.tree_name.to_string(),
+            what: KeyOrValue::Key,
+        });
     }
     let key = GenericKey {
         id: key.id,
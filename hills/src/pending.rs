@@ -0,0 +1,172 @@
+use crate::common::Error;
+use crate::opaque::OpaqueKey;
+use crate::sync::{ChangeKind, RecordHotChange};
+use chrono::{DateTime, Utc};
+use hills_base::{GenericKey, UtcDateTime};
+use rkyv::{check_archived_root, to_bytes, Archive, Deserialize, Serialize};
+use sled::{IVec, Tree};
+use std::sync::Arc;
+
+/// Local change that was not yet sent to the server.
+#[derive(Clone, Debug)]
+pub struct PendingChange {
+    pub key: OpaqueKey,
+    pub kind: ChangeKind,
+    /// When the change was made locally
+    pub when: UtcDateTime,
+}
+
+/// Persisted in PENDING_CHANGES_TREE under tree name + '/' + record key, only the latest change of each record is kept.
+#[derive(Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
+struct PendingEntry {
+    kind: ChangeKind,
+    meta_iteration: u32,
+    data_iteration: u32,
+    when: UtcDateTime,
+}
+
+fn entry_key(tree_name: &str, key: GenericKey) -> Vec<u8> {
+    let mut entry_key = Vec::with_capacity(tree_name.len() + 1 + GenericKey::BYTES);
+    entry_key.extend_from_slice(tree_name.as_bytes());
+    entry_key.push(b'/');
+    entry_key.extend_from_slice(&key.to_bytes());
+    entry_key
+}
+
+fn split_entry_key(entry_key: &[u8]) -> Option<(&str, GenericKey)> {
+    if entry_key.len() < 1 + GenericKey::BYTES {
+        return None;
+    }
+    let (tree_name, key) = entry_key.split_at(entry_key.len() - GenericKey::BYTES);
+    let tree_name = std::str::from_utf8(&tree_name[..tree_name.len() - 1]).ok()?;
+    Some((tree_name, GenericKey::from_bytes(key)?))
+}
+
+pub(crate) struct PendingChanges;
+
+impl PendingChanges {
+    /// Remember a change right when it is made, before it is handed over to the sync task.
+    pub fn push(pending: &Tree, change: &RecordHotChange) -> Result<(), Error> {
+        let entry = PendingEntry {
+            kind: change.kind.clone(),
+            meta_iteration: change.meta_iteration,
+            data_iteration: change.data_iteration,
+            when: Utc::now().into(),
+        };
+        let entry_bytes = to_bytes::<_, 64>(&entry)?;
+        pending.insert(entry_key(&change.tree, change.key), entry_bytes.as_slice())?;
+        Ok(())
+    }
+
+    /// Take a change out of the queue right before sending it, None if it was superseded by a newer change
+    /// of the same record (which is sent instead) or withdrawn by withdraw_creation meanwhile.
+    pub fn claim(pending: &Tree, change: &RecordHotChange) -> Result<Option<IVec>, Error> {
+        let entry_key = entry_key(&change.tree, change.key);
+        let Some(entry_bytes) = pending.get(&entry_key)? else {
+            return Ok(None);
+        };
+        let entry = check_archived_root::<PendingEntry>(&entry_bytes)?;
+        let kind: ChangeKind = entry.kind.deserialize(&mut rkyv::Infallible)?;
+        let is_same_change = entry.meta_iteration == change.meta_iteration
+            && entry.data_iteration == change.data_iteration
+            && std::mem::discriminant(&kind) == std::mem::discriminant(&change.kind);
+        if !is_same_change {
+            return Ok(None);
+        }
+        // Someone else got it first if it was overwritten or withdrawn concurrently
+        let claimed = pending
+            .compare_and_swap(&entry_key, Some(&entry_bytes), None as Option<&[u8]>)?
+            .is_ok();
+        Ok(claimed.then_some(entry_bytes))
+    }
+
+    /// Put a claimed change back after sending failed, unless the record was changed again meanwhile.
+    pub fn restore(pending: &Tree, change: &RecordHotChange, claimed: IVec) -> Result<(), Error> {
+        let entry_key = entry_key(&change.tree, change.key);
+        let _ = pending.compare_and_swap(entry_key, None as Option<&[u8]>, Some(claimed))?;
+        Ok(())
+    }
+
+    /// Take back the creation of a record that is being removed, true if it was still waiting to be sent
+    /// and no other change of the record was sent before it, so that the server never heard of the record.
+    pub fn withdraw_creation(
+        pending: &Tree,
+        tree_name: &str,
+        key: GenericKey,
+    ) -> Result<bool, Error> {
+        let creation = RecordHotChange {
+            tree: tree_name.to_string(),
+            key,
+            meta_iteration: 0,
+            data_iteration: 0,
+            kind: ChangeKind::CreateOrChange,
+        };
+        Ok(Self::claim(pending, &creation)?.is_some())
+    }
+
+    /// Drop the change of a record without sending it, e.g. after the record was moved to another key.
+    pub fn forget(pending: &Tree, tree_name: &str, key: GenericKey) -> Result<(), Error> {
+        pending.remove(entry_key(tree_name, key))?;
+        Ok(())
+    }
+
+    pub fn is_pending(pending: &Tree, tree_name: &str, key: GenericKey) -> Result<bool, Error> {
+        Ok(pending.contains_key(entry_key(tree_name, key))?)
+    }
+
+    pub fn all(pending: &Tree) -> Result<Vec<PendingChange>, Error> {
+        let mut changes = vec![];
+        for entry in pending.iter() {
+            let (entry_key, entry_bytes) = entry?;
+            let Some((tree_name, key)) = split_entry_key(&entry_key) else {
+                return Err(Error::Internal("malformed pending change key".to_string()));
+            };
+            let entry = check_archived_root::<PendingEntry>(&entry_bytes)?;
+            changes.push(PendingChange {
+                key: OpaqueKey::new(Arc::new(tree_name.to_string()), key),
+                kind: entry.kind.deserialize(&mut rkyv::Infallible)?,
+                when: entry.when.deserialize(&mut rkyv::Infallible)?,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Changes to replay after reconnecting, in the order they were made.
+    pub fn queued(pending: &Tree) -> Result<Vec<RecordHotChange>, Error> {
+        let mut changes = vec![];
+        for entry in pending.iter() {
+            let (entry_key, entry_bytes) = entry?;
+            let Some((tree_name, key)) = split_entry_key(&entry_key) else {
+                return Err(Error::Internal("malformed pending change key".to_string()));
+            };
+            let entry = check_archived_root::<PendingEntry>(&entry_bytes)?;
+            let when: UtcDateTime = entry.when.deserialize(&mut rkyv::Infallible)?;
+            let when: DateTime<Utc> = when.into();
+            let change = RecordHotChange {
+                tree: tree_name.to_string(),
+                key,
+                meta_iteration: entry.meta_iteration,
+                data_iteration: entry.data_iteration,
+                kind: entry.kind.deserialize(&mut rkyv::Infallible)?,
+            };
+            changes.push((when, change));
+        }
+        changes.sort_by_key(|(when, _)| *when);
+        Ok(changes.into_iter().map(|(_, change)| change).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entry_key, split_entry_key};
+    use hills_base::GenericKey;
+
+    #[test]
+    fn entry_key_round_trip() {
+        let key = GenericKey::new(1025, 2);
+        let entry_key = entry_key("a/b", key);
+        assert_eq!(split_entry_key(&entry_key), Some(("a/b", key)));
+        assert_eq!(split_entry_key(b"short"), None);
+    }
+}
@@ -1,5 +1,9 @@
 use hills_base::{GenericKey, SimpleVersion, UtcDateTime};
-use rkyv::{AlignedVec, Archive, Deserialize, Serialize};
+use rkyv::{check_archived_value, AlignedVec, Archive, Deserialize, Serialize};
+use std::mem::size_of;
+use std::ptr::addr_of;
+
+use crate::db::Error;
 
 /// Tree record holding meta information, record iteration and data itself.
 #[derive(Archive, Serialize, Deserialize)]
@@ -20,7 +24,54 @@ pub struct Record {
     pub data: AlignedVec,
 }
 
-#[derive(Archive, Clone, Serialize, Deserialize)]
+/// Fixed part of a serialized record: iterations, evolution and meta, with data left unchecked.
+///
+/// check_archived_root::<Record> goes through all of the data bytes, which dominates reads that never look at it.
+/// Data is not reachable from here, so it cannot be used without being checked.
+pub(crate) struct RecordHeader<'a> {
+    record: &'a ArchivedRecord,
+}
+
+impl<'a> RecordHeader<'a> {
+    pub(crate) fn check(bytes: &'a [u8]) -> Result<Self, Error> {
+        let Some(root_pos) = bytes.len().checked_sub(size_of::<ArchivedRecord>()) else {
+            return Err(Error::RkyvCheckArchivedRoot(
+                "record is shorter than its header".to_string(),
+            ));
+        };
+        let root = bytes[root_pos..].as_ptr() as *const ArchivedRecord;
+        if !root.is_aligned() {
+            return Err(Error::RkyvCheckArchivedRoot(
+                "record header is underaligned".to_string(),
+            ));
+        }
+        // Safety: root is in bounds and aligned, only the address of meta is taken, nothing is read
+        let meta_pos = unsafe { addr_of!((*root).meta) } as usize - bytes.as_ptr() as usize;
+        check_archived_value::<RecordMeta>(bytes, meta_pos)?;
+        // Safety: meta was just checked and all the other fields are plain integers, valid for any bytes,
+        // data relative pointer is never followed
+        let record = unsafe { &*root };
+        Ok(RecordHeader { record })
+    }
+
+    pub(crate) fn meta_iteration(&self) -> u32 {
+        self.record.meta_iteration
+    }
+
+    pub(crate) fn meta(&self) -> &'a ArchivedRecordMeta {
+        &self.record.meta
+    }
+
+    pub(crate) fn data_iteration(&self) -> u32 {
+        self.record.data_iteration
+    }
+
+    pub(crate) fn data_evolution(&self) -> SimpleVersion {
+        self.record.data_evolution.as_original()
+    }
+}
+
+#[derive(Archive, Clone, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
 #[archive(check_bytes)]
 pub struct RecordMeta {
     /// Same ID as in a Record's key
@@ -47,7 +98,20 @@ pub struct RecordMeta {
 }
 
 /// Record state
-#[derive(Archive, Clone, Debug, Serialize, Deserialize)]
+#[derive(
+    Archive,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 #[archive(check_bytes)]
 #[archive_attr(derive(Debug))]
 pub enum Version {
@@ -3,11 +3,11 @@ use hills_base::{GenericKey, SimpleVersion};
 use rkyv::{Archive, Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
-use std::net::SocketAddr;
 use std::ops::Range;
+use std::time::Instant;
 use uuid::Uuid;
 
-#[derive(Archive, Debug, Serialize, Deserialize)]
+#[derive(Archive, Clone, Debug, Serialize, Deserialize)]
 #[archive(check_bytes)]
 #[archive_attr(derive(Debug))]
 pub struct RecordHotChange {
@@ -45,6 +45,8 @@ pub enum Event {
     PresentSelf {
         uuid: [u8; 16],
         readable_name: String,
+        /// Optional protocol features supported by the sender, see consts::CAPABILITIES.
+        capabilities: Vec<String>,
     },
 
     GetTreeOverview {
@@ -53,34 +55,98 @@ pub enum Event {
     TreeOverview {
         tree: String,
         records: HashMap<GenericKey, RecordIteration>,
+        /// Schema of the tree as used by the sender, None if unknown (server never opens trees with types).
+        schema: Option<TreeSchema>,
     },
     RequestRecords {
         tree: String,
         keys: Vec<GenericKey>,
     },
+    /// Reply to RequestRecords for the keys that the sender does not have.
+    RecordsNotFound {
+        tree: String,
+        keys: Vec<GenericKey>,
+    },
     HotSyncEvent(HotSyncEvent),
 
     GetKeySet {
         tree: String,
+        /// How many keys the client wants, the server issues at most MAX_KEYS_PER_REQUEST
+        count: u32,
     },
     KeySet {
         tree: String,
         keys: Range<u32>,
     },
+    /// Reply to GetKeySet when the id space of a tree is used up, no more keys will be issued.
+    KeysExhausted {
+        tree: String,
+    },
 
     CheckOut {
         tree: String,
         keys: Vec<GenericKey>,
+        /// Join the queue if a record is already checked out by another client, otherwise leave it as is.
+        wait: bool,
     },
     Return {
         tree: String,
         keys: Vec<GenericKey>,
     },
+    /// Leave the queue of a record that was not yet checked out by the sender.
+    CancelCheckOut {
+        tree: String,
+        keys: Vec<GenericKey>,
+    },
+    /// Sent periodically for records the sender holds, so that the server does not take them away, see ServerConfig.
+    RenewCheckOut {
+        tree: String,
+        keys: Vec<GenericKey>,
+    },
     CheckedOut {
         tree: String,
         key: GenericKey,
         queue: Vec<[u8; 16]>,
     },
+
+    /// Reply to a request that the server did not apply, e.g. a change sent to a read replica.
+    /// Refused changes stay pending on the client and are sent again on the next connection.
+    Refused {
+        tree: String,
+        keys: Vec<GenericKey>,
+        reason: String,
+    },
+
+    /// Sent by server when another client advertised a different schema for the same tree.
+    SchemaDrift {
+        tree: String,
+        peer: [u8; 16],
+        their_evolution: SimpleVersion,
+    },
+
+    /// Sent by server to all the other clients when a tree is seen for the first time.
+    /// Schema is only known if the tree was first seen in an overview.
+    TreeCreated {
+        tree: String,
+        schema: Option<TreeSchema>,
+    },
+
+    /// Sent by client for each opened tree whose ids are not issued by the server (see IdStrategy), before any of
+    /// its records. Only sent if both sides have consts::CLIENT_IDS_CAPABILITY.
+    ClientIdTree {
+        tree: String,
+    },
+    /// Sent by client right after PresentSelf when its database was unlinked from another server
+    /// (see HillsClient::unlink_server), so that ids of its records are checked against the ones issued here.
+    /// Only sent if both sides have consts::REHOME_CAPABILITY.
+    Rehomed,
+    /// Reply to a tree overview of a re-homed client: ids its records use that were already issued by this server.
+    /// Client moves those records to new ids, changes it sends with the old ones are ignored until it asks
+    /// for the tree overview again.
+    IdsTaken {
+        tree: String,
+        ids: Vec<u32>,
+    },
 }
 
 #[derive(Archive, Clone, Serialize, Deserialize)]
@@ -88,7 +154,6 @@ pub enum Event {
 pub struct HotSyncEvent {
     pub tree_name: String,
     pub key: GenericKey,
-    pub source_addr: Option<SocketAddr>,
     pub kind: HotSyncEventKind,
 }
 
@@ -109,6 +174,15 @@ pub enum HotSyncEventKind {
     Removed,
 }
 
+#[derive(Archive, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct TreeSchema {
+    pub evolution: SimpleVersion,
+    /// TypeCollection::schema_hash of the types used
+    pub hash: u64,
+}
+
 #[derive(Archive, Clone, Serialize, Deserialize)]
 #[archive(check_bytes)]
 pub struct RecordIteration {
@@ -120,6 +194,8 @@ pub struct RecordIteration {
 pub(crate) struct RecordBorrows {
     /// tree name -> key -> queue of clients
     pub(crate) borrows: HashMap<String, HashMap<GenericKey, Vec<Uuid>>>,
+    /// Server only: tree name -> key -> when the first client in the queue got the record or last renewed it
+    pub(crate) leased_at: HashMap<String, HashMap<GenericKey, Instant>>,
 }
 
 impl Display for RecordIteration {
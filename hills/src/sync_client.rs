@@ -1,37 +1,58 @@
-use crate::common::{Error, ManagedTrees};
-use crate::consts::SERVER_UUID;
+use crate::common::{Error, ManagedTrees, WsLimits};
+use crate::consts::{
+    CAPABILITIES, CLIENT_IDS_CAPABILITY, KEYS_PER_REQUEST, KEY_POOL, PENDING_CHANGES_TREE,
+    PENDING_KEY_REQUESTS, REHOMED, REHOME_CAPABILITY, SERVER_UUID,
+};
+use crate::db::ClientConfig;
 use crate::handle_result;
 use crate::index::TreeIndex;
-use crate::key_pool::KeyPool;
+use crate::key_pool::{KeyPool, PendingKeyRequests};
 use crate::opaque::OpaqueKey;
-use crate::sync::{ArchivedEvent, ChangeKind, Event, RecordBorrows, RecordHotChange};
+use crate::pending::PendingChanges;
+use crate::sync::{
+    ArchivedEvent, ArchivedRecordIteration, ChangeKind, Event, RecordBorrows, RecordHotChange,
+    TreeSchema,
+};
 use crate::sync_common::{
-    compare_and_request_missing_records, handle_incoming_record, present_self, send_hot_change,
-    send_records, send_tree_overviews,
+    compare_and_request_missing_records, connect_ws, handle_control_message,
+    handle_incoming_record, is_same_overview, negotiate_capabilities, present_self,
+    request_tree_overview, send_hot_change, send_records, send_tree_overview, send_tree_overviews,
 };
+use crate::temporary::{assign_global_ids, has_temporary_records, move_to_temporary, Reassigned};
+use crate::throughput::{Metered, Throughput};
 use core::ops::Range;
 use futures_util::Sink;
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt, TryStreamExt,
 };
-use hills_base::GenericKey;
+use hills_base::generic_key::ArchivedGenericKey;
+use hills_base::{GenericKey, SimpleVersion};
 use log::{error, info, trace, warn};
 use postage::mpsc::{channel, Receiver, Sender};
+use postage::oneshot;
 use postage::prelude::Stream;
+use rkyv::collections::ArchivedHashMap;
 use rkyv::{check_archived_root, to_bytes};
-use sled::Db;
-use std::collections::HashMap;
+use sled::{Db, Tree};
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
-use tokio_tungstenite::tungstenite::Message;
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use uuid::Uuid;
 
+type WsStream = Metered<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
 pub(crate) struct SyncHandle {
     db: Db,
+    self_uuid: Uuid,
 }
 
 #[derive(Clone, Debug)]
@@ -45,206 +66,628 @@ pub enum ChangeNotification {
         key: GenericKey,
         queue: Vec<Uuid>,
     },
+    /// This client became the holder of a record, right away or after waiting in the queue, and can now modify it.
+    CheckOutGranted {
+        key: OpaqueKey,
+    },
     Connected,
     Disconnected,
     GotKeys {
         tree_name: String,
         keys: Range<u32>,
     },
+    /// Server has no more keys to give out for this tree, records inserted once the local pool is empty
+    /// stay temporary.
+    KeysExhausted {
+        tree_name: String,
+    },
+    /// Record created while no keys were available was moved from its temporary key to a global one and sent
+    /// to the server, see TypedTree::global_key.
+    GlobalIdAssigned {
+        tree_name: String,
+        temporary: GenericKey,
+        global: GenericKey,
+    },
+    /// Record moved over from another server (see HillsClient::unlink_server) used an id that the new server
+    /// had issued to another client. It was moved to a temporary key, GlobalIdAssigned follows once it gets a new one.
+    IdTaken {
+        tree_name: String,
+        key: GenericKey,
+        temporary: GenericKey,
+    },
+    /// Server did not apply a request of this client, see Event::Refused.
+    Refused {
+        tree_name: String,
+        keys: Vec<GenericKey>,
+        reason: String,
+    },
+    /// Server saw this tree for the first time, created by another client.
+    TreeAppeared(String),
+    /// Another client is using a different schema for the same tree, it might not be able to read records from this one or vice versa.
+    SchemaDrift {
+        tree_name: String,
+        peer: Uuid,
+        their_evolution: SimpleVersion,
+    },
 }
 
 impl SyncHandle {
-    pub(crate) fn new(db: Db) -> Self {
-        Self { db }
+    pub(crate) fn new(db: Db, self_uuid: Uuid) -> Self {
+        Self { db, self_uuid }
     }
 
     pub(crate) fn start(
         self,
         rt: &Runtime,
+        config: ClientConfig,
         updates_tx: postage::broadcast::Sender<ChangeNotification>,
         borrows: Arc<RwLock<RecordBorrows>>,
     ) -> (Sender<SyncClientCommand>, VhrdDbTelem, JoinHandle<()>) {
-        let (cmd_tx, cmd_rx) = channel(64);
+        let (cmd_tx, cmd_rx) = channel(config.command_capacity);
         let telem = SyncClientTelemetry::default();
         let telem = Arc::new(RwLock::new(telem));
         let telem_2 = telem.clone();
-        let join_handle = rt
-            .spawn(async move { event_loop(self.db, cmd_rx, updates_tx, telem_2, borrows).await });
+        let join_handle = rt.spawn(async move {
+            event_loop(
+                self.db,
+                self.self_uuid,
+                cmd_rx,
+                config,
+                updates_tx,
+                telem_2,
+                borrows,
+            )
+            .await
+        });
 
         (cmd_tx, telem, join_handle)
     }
 }
 
+/// Consume and drop commands on a plain thread, for a client that never connects anywhere.
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) fn start_local() -> (Sender<SyncClientCommand>, VhrdDbTelem) {
+    let (cmd_tx, mut cmd_rx) = channel(64);
+    let telem = Arc::new(RwLock::new(SyncClientTelemetry::default()));
+    std::thread::spawn(move || while cmd_rx.blocking_recv().is_some() {});
+    (cmd_tx, telem)
+}
+
+/// Answer of the server to TryCheckOut.
+pub(crate) enum CheckOutReply {
+    /// Check out queue of the record, this client holds it if it is the first one
+    Queue(Vec<Uuid>),
+    Refused(String),
+}
+
 pub(crate) enum SyncClientCommand {
     Connect(IpAddr, u16),
     Disconnect,
     TreeCreated(String),
+    /// Tree was opened with the provided schema, advertised to the server in tree overviews.
+    TreeOpened {
+        tree_name: String,
+        schema: TreeSchema,
+        /// Ids of new records are chosen by clients, see IdStrategy
+        client_ids: bool,
+    },
     RegisterIndex {
         tree_name: String,
         indexer: Box<dyn TreeIndex + Send>,
     },
     Change(RecordHotChange),
     CheckOut(String, GenericKey),
+    /// Same as CheckOut, but the server does not queue this client if the record is already checked out.
+    /// done gets the queue the server replied with, it is dropped if the request cannot be answered.
+    TryCheckOut {
+        tree_name: String,
+        key: GenericKey,
+        done: oneshot::Sender<CheckOutReply>,
+    },
     Release(String, GenericKey),
-    // FullReSync,
+    CancelCheckOut(String, GenericKey),
+    /// Exchange overviews of all trees with the server, pulling and pushing anything missing or outdated.
+    FullReSync,
+    /// Same as FullReSync, but only for one tree.
+    ReSyncTree(String),
+    /// Request one record from the server, done is notified once the reply is applied locally.
+    /// It is dropped instead if the request cannot be answered (not connected or disconnected while waiting).
+    FetchRecord {
+        tree_name: String,
+        key: GenericKey,
+        done: oneshot::Sender<()>,
+    },
+    /// Database was unlinked from its server, drop the connection and link with the next server that answers.
+    UnlinkServer,
+    /// Exchange overviews of all trees until they are the same on both ends, done is notified with what was exchanged meanwhile.
+    /// Dropped instead if not connected, disconnected while waiting or still not settled by the deadline.
+    Quiesce {
+        deadline: Instant,
+        done: oneshot::Sender<SyncSummary>,
+    },
 }
 
 pub(crate) type VhrdDbCmdTx = Sender<SyncClientCommand>;
 
 #[derive(Default)]
 pub struct SyncClientTelemetry {
+    /// Server this database is linked with, None until the first successful connection to any server.
+    /// Unlike connected it stays set while offline.
+    pub linked_server: Option<Uuid>,
     pub connected: bool,
     pub error_message: String,
+    /// Bytes of binary messages sent over all connections
     pub bytes_sent: usize,
+    /// Bytes per second sent over the last second
     pub tx_bps: usize,
+    /// Bytes of binary messages received over all connections
     pub bytes_received: usize,
+    /// Bytes per second received over the last second
     pub rx_bps: usize,
+    /// Local changes waiting to be replayed once connected, including ones left over from a previous run
     pub backlog: usize,
+    /// Optional protocol features negotiated with the connected server
+    pub capabilities: Vec<String>,
+    /// Attempts to reconnect made since the connection was lost, reset once the server accepts this client again
+    pub reconnect_attempts: usize,
+}
+
+/// Delays between automatic reconnects after an established connection is lost, see ClientConfig::reconnect.
+///
+/// The first attempt is made after `first`, each following one waits twice as long up to `max`.
+/// Delays start over from `first` once the server accepts the client again. A manual disconnect stops reconnecting.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectBackoff {
+    pub first: Duration,
+    pub max: Duration,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        ReconnectBackoff {
+            first: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+/// When and how many keys are requested from the server for each tree, see ClientConfig::key_requests.
+///
+/// A tree that creates records in bursts might need larger batches so it does not run out between grants,
+/// a rarely used one can ask for less, to not waste the id space.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyRequests {
+    /// Keys asked for in one request
+    pub count: u32,
+    /// More keys are requested once fewer than this are left in the pool
+    pub low_water: u32,
+}
+
+impl Default for KeyRequests {
+    fn default() -> Self {
+        KeyRequests {
+            count: KEYS_PER_REQUEST,
+            low_water: 3,
+        }
+    }
 }
 
 pub type VhrdDbTelem = Arc<RwLock<SyncClientTelemetry>>;
 
+/// Records exchanged with the server while HillsClient::sync_and_quiesce was waiting for the trees to settle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncSummary {
+    /// Records requested by the server and ones created offline or changed meanwhile
+    pub sent: usize,
+    /// Records pulled from the server, including changes made by other clients meanwhile
+    pub received: usize,
+}
+
+/// Ongoing HillsClient::sync_and_quiesce.
+struct Quiesce {
+    done: oneshot::Sender<SyncSummary>,
+    deadline: Instant,
+    /// Overviews were exchanged, which waits for PresentSelf on a fresh connection
+    started: bool,
+    /// Trees that were not yet seen with the same records on both ends
+    unsettled: HashSet<String>,
+    summary: SyncSummary,
+}
+
+/// Server the database was linked with on its first connection, None for a database that never connected.
+pub(crate) fn load_server_uuid(db: &Db) -> Result<Option<Uuid>, sled::Error> {
+    let Some(uuid_bytes) = db.get(SERVER_UUID)? else {
+        return Ok(None);
+    };
+    Ok(Uuid::from_slice(&uuid_bytes).ok())
+}
+
+/// Remove the link with the server and everything that only has meaning for it:
+/// unused keys it issued and key requests still waiting for an answer.
+/// Records keep their ids, the next server is told so with Event::Rehomed.
+pub(crate) fn forget_server(db: &Db) -> Result<(), Error> {
+    db.remove(SERVER_UUID)?;
+    db.insert(REHOMED, &[])?;
+    for tree_name in ManagedTrees::managed(db)? {
+        db.open_tree(tree_name)?.remove(KEY_POOL)?;
+    }
+    db.remove(PENDING_KEY_REQUESTS)?;
+    Ok(())
+}
+
 async fn event_loop(
     mut db: Db,
+    self_uuid: Uuid,
     mut cmd_rx: Receiver<SyncClientCommand>,
+    config: ClientConfig,
     mut updates_tx: postage::broadcast::Sender<ChangeNotification>,
     telem: VhrdDbTelem,
     borrows: Arc<RwLock<RecordBorrows>>,
 ) {
-    let mut ws_txrx: Option<(SplitSink<_, _>, SplitStream<_>)> = None;
-    // let mut to_replay = Vec::new();
+    let mut ws_txrx: Option<(SplitSink<WsStream, Message>, SplitStream<WsStream>)> = None;
     let mut indexers: HashMap<String, Vec<Box<dyn TreeIndex + Send>>> = HashMap::new();
-
-    let mut server_uuid = match db.get(SERVER_UUID) {
-        Ok(Some(uuid_bytes)) => {
-            if uuid_bytes.len() != 16 {
-                None
-            } else {
-                let mut uuid = [0u8; 16];
-                uuid[..].copy_from_slice(&uuid_bytes);
-                let uuid = Uuid::from_bytes(uuid);
-                trace!("Server uuid must be {uuid}");
-                Some(uuid)
-            }
+    let mut schemas: HashMap<String, TreeSchema> = HashMap::new();
+    // Opened trees with client chosen ids, declared to the server on each connection
+    let mut client_id_trees: HashSet<String> = HashSet::new();
+    // Server supports CLIENT_IDS_CAPABILITY, known after PresentSelf
+    let mut has_client_ids = false;
+    let mut fetches: HashMap<(String, GenericKey), Vec<oneshot::Sender<()>>> = HashMap::new();
+    let mut try_check_outs: HashMap<(String, GenericKey), Vec<oneshot::Sender<CheckOutReply>>> =
+        HashMap::new();
+    let mut quiesce: Option<Quiesce> = None;
+    // PresentSelf was exchanged on the current connection
+    let mut is_presented = false;
+    // Server to reconnect to once the connection is lost, None after a manual disconnect
+    let mut target: Option<(IpAddr, u16)> = None;
+    let mut reconnect_at: Option<tokio::time::Instant> = None;
+    let mut reconnect_delay = config.reconnect.first;
+    let mut reconnect_attempts = 0;
+    let mut throughput = Throughput::new();
+    let mut renewal = tokio::time::interval(config.check_out_renewal);
+    renewal.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let pending = match db.open_tree(PENDING_CHANGES_TREE) {
+        Ok(pending) => pending,
+        Err(e) => {
+            error!("Failed to open pending changes tree: {e:?}, terminating");
+            return;
         }
-        _ => None,
     };
 
+    let mut server_uuid = load_server_uuid(&db).ok().flatten();
+    if let Some(uuid) = server_uuid {
+        trace!("Server uuid must be {uuid}");
+    }
+    {
+        let mut telem = telem.write().await;
+        telem.linked_server = server_uuid;
+        // Left over from the previous run
+        telem.backlog = pending.len();
+    }
+
+    // Set while handling an event, connection is closed at the start of the next iteration
+    let mut should_disconnect = false;
     loop {
-        let mut should_disconnect = false;
+        if should_disconnect {
+            should_disconnect = false;
+            if let Some((ws_tx, ws_rx)) = ws_txrx.take() {
+                if let Ok(ws) = ws_rx.reunite(ws_tx) {
+                    let _ = ws.into_inner().close(None).await;
+                }
+            }
+            throughput.stop(&telem).await;
+
+            {
+                let mut telem = telem.write().await;
+                telem.connected = false;
+                telem.capabilities.clear();
+                has_client_ids = false;
+                // Including changes that failed to send when the connection dropped
+                telem.backlog = pending.len();
+            }
+            // Replies will never arrive, let the waiters know
+            fetches.clear();
+            try_check_outs.clear();
+            quiesce = None;
+            is_presented = false;
+            if postage::sink::Sink::send(&mut updates_tx, ChangeNotification::Disconnected)
+                .await
+                .is_err()
+            {
+                warn!("Notification send: mpsc fail");
+            }
+            if let Some((ip_addr, port)) = target {
+                info!("Reconnecting to {ip_addr}:{port} in {reconnect_delay:?}");
+                reconnect_at = Some(tokio::time::Instant::now() + reconnect_delay);
+                reconnect_delay = (reconnect_delay * 2).min(config.reconnect.max);
+            }
+        }
+        if quiesce
+            .as_ref()
+            .is_some_and(|q| Instant::now() >= q.deadline)
+        {
+            warn!("Trees did not settle in time, giving up on quiesce");
+            quiesce = None;
+        }
         if let Some((ws_tx, ws_rx)) = &mut ws_txrx {
             tokio::select! {
                 message = ws_rx.try_next() => {
-                    if let Ok(Some(Message::Close(_))) = &message {
-                        should_disconnect = true;
-                    }
-                    if let Ok(Some(Message::Binary(bytes))) = message {
-                        let Ok(ev) = check_archived_root::<Event>(&bytes) else {
-                            error!("message unarchive failed");
-                            continue
-                        };
-                        match ev {
-                            ArchivedEvent::PresentSelf { uuid, .. } => {
-                                let uuid = Uuid::from_bytes(*uuid);
-                                trace!("Server uuid is: {uuid}");
-                                match server_uuid {
-                                    Some(server_uuid) => {
-                                        if server_uuid == uuid {
-                                            let r = present_self(&db, ws_tx).await;
-                                            handle_result!(r);
-                                            let r = send_tree_overviews(&db, ws_tx).await;
-                                            handle_result!(r);
-                                            let r = request_keys(&db, ws_tx).await;
-                                            handle_result!(r);
-                                        } else {
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(e) => {
+                            let e = Error::from(e);
+                            error!("{e}");
+                            telem.write().await.error_message = e.to_string();
+                            should_disconnect = true;
+                            None
+                        }
+                    };
+                    match message {
+                        Some(Message::Binary(bytes)) => {
+                            let Ok(ev) = check_archived_root::<Event>(&bytes) else {
+                                error!("message unarchive failed");
+                                continue
+                            };
+                            match ev {
+                                ArchivedEvent::PresentSelf { uuid, capabilities, .. } => {
+                                    let uuid = Uuid::from_bytes(*uuid);
+                                    let capabilities = negotiate_capabilities(CAPABILITIES, capabilities.iter().map(|c| c.as_str()));
+                                    trace!("Negotiated capabilities: {capabilities:?}");
+                                    has_client_ids = capabilities.contains(CLIENT_IDS_CAPABILITY);
+                                    let has_rehome = capabilities.contains(REHOME_CAPABILITY);
+                                    telem.write().await.capabilities = capabilities.into_iter().collect();
+                                    trace!("Server uuid is: {uuid}");
+                                    let is_linked = match server_uuid {
+                                        Some(server_uuid) if server_uuid != uuid => {
                                             let mut telem = telem.write().await;
                                             telem.error_message = "Server UUID does not match with the current database".to_string();
                                             warn!("{}", telem.error_message);
+                                            // Would be refused again
+                                            target = None;
                                             should_disconnect = true;
+                                            false
                                         }
+                                        Some(_) => true,
+                                        None => {
+                                            server_uuid = Some(uuid);
+                                            let uuid_bytes = uuid.into_bytes();
+                                            let r = db.insert(SERVER_UUID, &uuid_bytes);
+                                            info!("Linking this database with connected server: {}", r.is_ok());
+                                            telem.write().await.linked_server = server_uuid;
+                                            true
+                                        }
+                                    };
+                                    if is_linked {
+                                        let client_id_trees = has_client_ids.then_some(&client_id_trees);
+                                        let r = catch_up(&db, has_rehome, client_id_trees, &schemas, &pending, &telem, &mut quiesce, config.key_requests, ws_tx).await;
+                                        handle_result!(r, should_disconnect);
+                                        is_presented = true;
+                                        let r = start_pending_quiesce(&db, &schemas, &mut quiesce, ws_tx).await;
+                                        handle_result!(r, should_disconnect);
                                     }
-                                    None => {
-                                        server_uuid = Some(uuid);
-                                        let uuid_bytes = uuid.into_bytes();
-                                        let r = db.insert(SERVER_UUID, &uuid_bytes);
-                                        info!("Linking this database with connected server: {}", r.is_ok());
-                                        let r = present_self(&db, ws_tx).await;
-                                        handle_result!(r);
-                                        let r = send_tree_overviews(&db, ws_tx).await;
-                                        handle_result!(r);
-                                        let r = request_keys(&db, ws_tx).await;
-                                        handle_result!(r);
+                                    if is_presented {
+                                        reconnect_delay = config.reconnect.first;
+                                        reconnect_attempts = 0;
+                                        telem.write().await.reconnect_attempts = 0;
                                     }
                                 }
-                            }
-                            ArchivedEvent::GetTreeOverview { .. } => {}
-                            ArchivedEvent::TreeOverview { tree, records } => {
-                                trace!("Got {tree} overview {records:?}");
-                                if let Err(e) = compare_and_request_missing_records(&db, tree, records, ws_tx, None).await {
-                                    error!("tree overview: {e:?}");
+                                ArchivedEvent::GetTreeOverview { tree } => {
+                                    let schema = schemas.get(tree.as_str()).copied();
+                                    let r = send_tree_overview(&db, tree.as_str(), schema, ws_tx).await;
+                                    handle_result!(r, should_disconnect);
                                 }
-                            }
-                            ArchivedEvent::KeySet { tree, keys } => {
-                                trace!("Got more keys for {tree} {keys:?}");
-                                let Ok(db_tree) = db.open_tree(tree.as_str()) else {
-                                    error!("key set open_tree failed");
-                                    continue
-                                };
-                                let keys = keys.start..keys.end;
-                                if let Err(e) = KeyPool::feed_for(&db_tree, keys.clone()).map_err(Error::Internal) {
-                                    error!("key set: {e:?}");
+                                ArchivedEvent::TreeOverview { tree, records, .. } => {
+                                    // Server offers all of its trees, only the ones opened here are synced
+                                    if !ManagedTrees::managed(&db).is_ok_and(|managed| managed.iter().any(|t| t == tree.as_str())) {
+                                        trace!("Skipping overview of {tree}, not opened here");
+                                        continue;
+                                    }
+                                    trace!("Got {tree} overview {records:?}");
+                                    if let Err(e) = compare_and_request_missing_records(&db, tree, records, ws_tx, None).await {
+                                        error!("tree overview: {e:?}");
+                                    }
+                                    if let Some(q) = quiesce.as_mut().filter(|q| q.unsettled.contains(tree.as_str())) {
+                                        let r = settle_tree(&db, &schemas, q, tree.as_str(), records, ws_tx).await;
+                                        handle_result!(r, should_disconnect);
+                                        finish_quiesce(&mut quiesce);
+                                    }
                                 }
-                                let notification = ChangeNotification::GotKeys { tree_name: tree.to_string(), keys };
-                                if let Err(_) = postage::sink::Sink::send(&mut updates_tx, notification).await {
-                                    warn!("Notification send: mpsc fail");
+                                ArchivedEvent::KeySet { tree, keys } => {
+                                    trace!("Got more keys for {tree} {keys:?}");
+                                    let Ok(db_tree) = db.open_tree(tree.as_str()) else {
+                                        error!("key set open_tree failed");
+                                        continue
+                                    };
+                                    let keys = keys.start..keys.end;
+                                    if let Err(e) = KeyPool::feed_for(&db_tree, keys.clone()).map_err(Error::Internal) {
+                                        error!("key set: {e:?}");
+                                    }
+                                    if let Err(e) = PendingKeyRequests::set_pending(&db, tree.as_str(), false) {
+                                        error!("key set: clear pending: {e:?}");
+                                    }
+                                    // Answered after the overviews, so the server has seen the ids of the moved records
+                                    if let Err(e) = db.remove(REHOMED) {
+                                        error!("key set: clear rehomed: {e:?}");
+                                    }
+                                    let notification = ChangeNotification::GotKeys { tree_name: tree.to_string(), keys };
+                                    if postage::sink::Sink::send(&mut updates_tx, notification).await.is_err() {
+                                        warn!("Notification send: mpsc fail");
+                                    }
+                                    let r = send_offline_records(&db, &pending, tree.as_str(), &mut indexers, config.key_requests, ws_tx, &mut updates_tx).await;
+                                    if let (Ok(sent), Some(q)) = (&r, &mut quiesce) {
+                                        q.summary.sent += sent;
+                                    }
+                                    handle_result!(r, should_disconnect);
+                                    // Records waiting for global ids were sent, see if the tree settles now
+                                    if let Some(q) = quiesce.as_ref().filter(|q| q.unsettled.contains(tree.as_str())) {
+                                        if q.started {
+                                            let r = resync_tree(&db, &schemas, tree.as_str(), ws_tx).await;
+                                            handle_result!(r, should_disconnect);
+                                        }
+                                    }
                                 }
-                            }
-                            ArchivedEvent::CheckedOut { tree, key, queue } => {
-                                let borrows = &mut borrows.write().await.borrows;
-                                let borrowed_keys = borrows.entry(tree.as_str().to_string()).or_default();
-                                let queue: Vec<Uuid> = queue.iter().map(|uuid| Uuid::from_bytes(*uuid)).collect();
-                                let key = GenericKey::from_archived(key);
-                                trace!("Now checked out for {}/{}: {:?}", tree.as_str(), key, queue);
-                                borrowed_keys.insert(key, queue.clone());
-                                let notification = ChangeNotification::BorrowsChanged { tree_name: tree.to_string(), key, queue };
-                                if let Err(_) = postage::sink::Sink::send(&mut updates_tx, notification).await {
-                                    warn!("Notification send: mpsc fail");
+                                ArchivedEvent::IdsTaken { tree, ids } => {
+                                    warn!("{tree}/{ids:?} were issued to other clients by this server, moving the records to new ids");
+                                    let r = move_to_temporary(&db, &pending, tree.as_str(), ids.iter().copied(), indexers.get_mut(tree.as_str()));
+                                    match r {
+                                        Ok(moved) => {
+                                            for (key, temporary) in moved {
+                                                let notification = ChangeNotification::IdTaken { tree_name: tree.to_string(), key, temporary };
+                                                if postage::sink::Sink::send(&mut updates_tx, notification).await.is_err() {
+                                                    warn!("Notification send: mpsc fail");
+                                                }
+                                            }
+                                        }
+                                        Err(e) => error!("ids taken: {e:?}"),
+                                    }
+                                    // Records of the server with these ids come with the overview, moved ones go up once they get global ids
+                                    let r = request_tree_overview(tree.as_str(), ws_tx).await;
+                                    handle_result!(r, should_disconnect);
+                                    let r = send_offline_records(&db, &pending, tree.as_str(), &mut indexers, config.key_requests, ws_tx, &mut updates_tx).await;
+                                    if let (Ok(sent), Some(q)) = (&r, &mut quiesce) {
+                                        q.summary.sent += sent;
+                                    }
+                                    handle_result!(r, should_disconnect);
                                 }
-                            }
-                            ArchivedEvent::HotSyncEvent(hot_sync_event) => {
-                                let tree_name = hot_sync_event.tree_name.as_str();
-                                let key = GenericKey::from_archived(&hot_sync_event.key);
-                                trace!(
-                                    "Got hot sync {tree_name}/{key}: {}",
-                                    hot_sync_event.kind
-                                );
-                                if let Err(e) = handle_incoming_record(&mut db, hot_sync_event, "server", Some(&mut indexers)) {
-                                    error!("hot sync event, handle_incoming_record: {e:?}");
+                                ArchivedEvent::KeysExhausted { tree } => {
+                                    // Request is left pending, so that no more GetKeySet are sent until reconnect
+                                    let mut telem = telem.write().await;
+                                    telem.error_message = format!("Server ran out of keys for {tree}");
+                                    error!("{}", telem.error_message);
+                                    let notification = ChangeNotification::KeysExhausted { tree_name: tree.to_string() };
+                                    if postage::sink::Sink::send(&mut updates_tx, notification).await.is_err() {
+                                        warn!("Notification send: mpsc fail");
+                                    }
                                 }
-                                let notification = ChangeNotification::Tree {
-                                    key: OpaqueKey::new(Arc::new(tree_name.to_string()), key),
-                                    kind: (&hot_sync_event.kind).into(),
-                                };
-                                if let Err(_) = postage::sink::Sink::send(&mut updates_tx, notification).await {
-                                    warn!("Notification send: mpsc fail");
+                                ArchivedEvent::Refused { tree, keys, reason } => {
+                                    let keys: Vec<GenericKey> = keys.iter().map(GenericKey::from_archived).collect();
+                                    for key in &keys {
+                                        for mut done in try_check_outs.remove(&(tree.to_string(), *key)).unwrap_or_default() {
+                                            let _ = postage::sink::Sink::try_send(&mut done, CheckOutReply::Refused(reason.to_string()));
+                                        }
+                                    }
+                                    let mut telem = telem.write().await;
+                                    telem.error_message = format!("Server refused a request for {tree} {keys:?}: {reason}");
+                                    warn!("{}", telem.error_message);
+                                    let notification = ChangeNotification::Refused { tree_name: tree.to_string(), keys, reason: reason.to_string() };
+                                    if postage::sink::Sink::send(&mut updates_tx, notification).await.is_err() {
+                                        warn!("Notification send: mpsc fail");
+                                    }
                                 }
-                            }
-                            ArchivedEvent::CheckOut { .. }
-                            | ArchivedEvent::Return { .. }
-                            | ArchivedEvent::GetKeySet { .. } => {
-                                warn!("Unsupported event from server");
-                            }
-                            ArchivedEvent::RequestRecords { tree, keys } => {
-                                if let Err(e) = send_records(&db, tree.as_str(), keys, ws_tx, None).await {
-                                    error!("send_records: {e:?}");
+                                ArchivedEvent::CheckedOut { tree, key, queue } => {
+                                    let borrows = &mut borrows.write().await.borrows;
+                                    let borrowed_keys = borrows.entry(tree.as_str().to_string()).or_default();
+                                    let queue: Vec<Uuid> = queue.iter().map(|uuid| Uuid::from_bytes(*uuid)).collect();
+                                    let key = GenericKey::from_archived(key);
+                                    trace!("Now checked out for {}/{}: {:?}", tree.as_str(), key, queue);
+                                    let is_granted = queue.first() == Some(&self_uuid);
+                                    // Reply to a try check out always has a holder, an empty queue is from an earlier release
+                                    if !queue.is_empty() {
+                                        for mut done in try_check_outs.remove(&(tree.to_string(), key)).unwrap_or_default() {
+                                            let _ = postage::sink::Sink::try_send(&mut done, CheckOutReply::Queue(queue.clone()));
+                                        }
+                                    }
+                                    let previous = borrowed_keys.insert(key, queue.clone());
+                                    let was_holder = previous.is_some_and(|previous| previous.first() == Some(&self_uuid));
+                                    let notification = ChangeNotification::BorrowsChanged { tree_name: tree.to_string(), key, queue };
+                                    if postage::sink::Sink::send(&mut updates_tx, notification).await.is_err() {
+                                        warn!("Notification send: mpsc fail");
+                                    }
+                                    if is_granted && !was_holder {
+                                        let notification = ChangeNotification::CheckOutGranted { key: OpaqueKey::new(Arc::new(tree.to_string()), key) };
+                                        if postage::sink::Sink::send(&mut updates_tx, notification).await.is_err() {
+                                            warn!("Notification send: mpsc fail");
+                                        }
+                                    }
+                                }
+                                ArchivedEvent::HotSyncEvent(hot_sync_event) => {
+                                    let tree_name = hot_sync_event.tree_name.as_str();
+                                    let key = GenericKey::from_archived(&hot_sync_event.key);
+                                    trace!(
+                                        "Got hot sync {tree_name}/{key}: {}",
+                                        hot_sync_event.kind
+                                    );
+                                    if let Err(e) = handle_incoming_record(&mut db, hot_sync_event, "server", Some(&mut indexers)) {
+                                        error!("hot sync event, handle_incoming_record: {e:?}");
+                                    }
+                                    let notification = ChangeNotification::Tree {
+                                        key: OpaqueKey::new(Arc::new(tree_name.to_string()), key),
+                                        kind: (&hot_sync_event.kind).into(),
+                                    };
+                                    if postage::sink::Sink::send(&mut updates_tx, notification).await.is_err() {
+                                        warn!("Notification send: mpsc fail");
+                                    }
+                                    fetched(&mut fetches, tree_name, key);
+                                    if let Some(q) = &mut quiesce {
+                                        q.summary.received += 1;
+                                    }
+                                }
+                                ArchivedEvent::SchemaDrift { tree, peer, their_evolution } => {
+                                    let peer = Uuid::from_bytes(*peer);
+                                    let their_evolution = their_evolution.as_original();
+                                    let our_evolution = schemas.get(tree.as_str()).map(|s| s.evolution);
+                                    warn!("Client {peer} is using a different schema for {tree}: {their_evolution}, this client: {our_evolution:?}");
+                                    let notification = ChangeNotification::SchemaDrift { tree_name: tree.to_string(), peer, their_evolution };
+                                    if postage::sink::Sink::send(&mut updates_tx, notification).await.is_err() {
+                                        warn!("Notification send: mpsc fail");
+                                    }
+                                }
+                                ArchivedEvent::TreeCreated { tree, .. } => {
+                                    trace!("Tree {tree} appeared on the server");
+                                    let notification = ChangeNotification::TreeAppeared(tree.to_string());
+                                    if postage::sink::Sink::send(&mut updates_tx, notification).await.is_err() {
+                                        warn!("Notification send: mpsc fail");
+                                    }
+                                }
+                                ArchivedEvent::CheckOut { .. }
+                                | ArchivedEvent::Return { .. }
+                                | ArchivedEvent::CancelCheckOut { .. }
+                                | ArchivedEvent::RenewCheckOut { .. }
+                                | ArchivedEvent::GetKeySet { .. }
+                                | ArchivedEvent::ClientIdTree { .. }
+                                | ArchivedEvent::Rehomed => {
+                                    warn!("Unsupported event from server");
+                                }
+                                ArchivedEvent::RequestRecords { tree, keys } => {
+                                    match send_records(&db, tree.as_str(), keys, ws_tx, None).await {
+                                        Ok(sent) => {
+                                            if let Some(q) = &mut quiesce {
+                                                q.summary.sent += sent;
+                                            }
+                                        }
+                                        Err(e) => error!("send_records: {e:?}"),
+                                    }
+                                }
+                                ArchivedEvent::RecordsNotFound { tree, keys } => {
+                                    trace!("Server does not have {tree}/{keys:?}");
+                                    for key in keys.iter() {
+                                        fetched(&mut fetches, tree.as_str(), GenericKey::from_archived(key));
+                                    }
                                 }
                             }
                         }
-                    } else {
-                        warn!("Unsupported ws message or None from channel, ignoring");
-                        // should_disconnect = true;
+                        Some(Message::Close(_)) => {
+                            should_disconnect = true;
+                        }
+                        Some(message) => {
+                            if let Err(e) = handle_control_message(message, ws_tx).await {
+                                error!("{e}");
+                                telem.write().await.error_message = e.to_string();
+                                should_disconnect = true;
+                            }
+                        }
+                        None => {
+                            // Stream ended without a Close frame
+                            should_disconnect = true;
+                        }
                     }
                 }
+                _ = throughput.tick() => {
+                    throughput.sample(&telem).await;
+                }
+                _ = renewal.tick(), if is_presented => {
+                    let r = renew_check_outs(&borrows, self_uuid, ws_tx).await;
+                    handle_result!(r, should_disconnect);
+                }
                 cmd = cmd_rx.recv() => {
                     let Some(cmd) = cmd else {
                         info!("Sync client: tx end no longer exist, exiting");
@@ -252,34 +695,124 @@ async fn event_loop(
                     };
                     match cmd {
                         SyncClientCommand::Disconnect => {
+                            target = None;
+                            should_disconnect = true;
+                        }
+                        SyncClientCommand::UnlinkServer => {
+                            target = None;
+                            server_uuid = None;
+                            // Key set from the old server could have arrived since the client forgot it
+                            let r = forget_server(&db);
+                            handle_result!(r, should_disconnect);
+                            telem.write().await.linked_server = None;
+                            info!("Database unlinked from the server, disconnecting");
                             should_disconnect = true;
                         }
                         SyncClientCommand::Connect(..) => {}
                         SyncClientCommand::TreeCreated(_tree_name) => {
-                            let r = request_keys(&db, ws_tx).await;
-                            handle_result!(r);
+                            let r = request_keys(&db, config.key_requests, ws_tx, false).await;
+                            handle_result!(r, should_disconnect);
+                        }
+                        SyncClientCommand::TreeOpened { tree_name, schema, client_ids } => {
+                            if client_ids && client_id_trees.insert(tree_name.clone()) && has_client_ids {
+                                let r = send_client_id_trees([&tree_name], ws_tx).await;
+                                handle_result!(r, should_disconnect);
+                            }
+                            if schemas.get(&tree_name) != Some(&schema) {
+                                let r = send_tree_overview(&db, &tree_name, Some(schema), ws_tx).await;
+                                handle_result!(r, should_disconnect);
+                                // Overview sent by the server on connect was skipped if the tree was not opened yet
+                                let r = request_tree_overview(&tree_name, ws_tx).await;
+                                handle_result!(r, should_disconnect);
+                                schemas.insert(tree_name, schema);
+                            }
                         }
                         SyncClientCommand::RegisterIndex { tree_name, indexer } => {
                             indexers.entry(tree_name).or_default().push(indexer);
                         }
                         SyncClientCommand::Change(event) => {
                             trace!("{event:?}");
-                            let r = send_hot_change(&db, event, ws_tx, None).await;
-                            handle_result!(r);
+                            let r = send_pending(&db, &pending, &event, ws_tx).await;
+                            if let (Ok(true), Some(q)) = (&r, &mut quiesce) {
+                                q.summary.sent += 1;
+                            }
+                            handle_result!(r, should_disconnect);
+                            let r = request_keys(&db, config.key_requests, ws_tx, false).await;
+                            handle_result!(r, should_disconnect);
                         }
                         SyncClientCommand::CheckOut(tree, key) => {
-                            let r = check_out(tree, key, ws_tx).await;
-                            handle_result!(r);
+                            let r = check_out(tree, key, true, ws_tx).await;
+                            handle_result!(r, should_disconnect);
+                        },
+                        SyncClientCommand::TryCheckOut { tree_name, key, done } => {
+                            let r = check_out(tree_name.clone(), key, false, ws_tx).await;
+                            handle_result!(r, should_disconnect);
+                            try_check_outs.entry((tree_name, key)).or_default().push(done);
                         },
                         SyncClientCommand::Release(tree, key) => {
                             let r = release(tree, key, ws_tx).await;
-                            handle_result!(r);
+                            handle_result!(r, should_disconnect);
+                        },
+                        SyncClientCommand::CancelCheckOut(tree, key) => {
+                            let r = cancel_check_out(tree, key, ws_tx).await;
+                            handle_result!(r, should_disconnect);
                         },
+                        SyncClientCommand::FullReSync => {
+                            info!("Full re-sync requested");
+                            let r = send_tree_overviews(&db, &schemas, ws_tx).await;
+                            handle_result!(r, should_disconnect);
+                            let r = request_tree_overviews(&db, ws_tx).await;
+                            handle_result!(r, should_disconnect);
+                        }
+                        SyncClientCommand::ReSyncTree(tree_name) => {
+                            info!("Re-sync of {tree_name} requested");
+                            let r = resync_tree(&db, &schemas, &tree_name, ws_tx).await;
+                            handle_result!(r, should_disconnect);
+                        }
+                        SyncClientCommand::Quiesce { deadline, done } => {
+                            info!("Sync and quiesce requested");
+                            // Replaces the previous one, its waiter sees the request as not answered
+                            quiesce = Some(Quiesce {
+                                done,
+                                deadline,
+                                started: false,
+                                unsettled: HashSet::new(),
+                                summary: SyncSummary::default(),
+                            });
+                            if is_presented {
+                                let r = start_pending_quiesce(&db, &schemas, &mut quiesce, ws_tx).await;
+                                handle_result!(r, should_disconnect);
+                            }
+                        }
+                        SyncClientCommand::FetchRecord { tree_name, key, done } => {
+                            trace!("Fetching {tree_name}/{key}");
+                            let r = request_record(tree_name.clone(), key, ws_tx).await;
+                            handle_result!(r, should_disconnect);
+                            fetches.entry((tree_name, key)).or_default().push(done);
+                        }
                     }
                 }
             }
         } else {
             tokio::select! {
+                _ = tokio::time::sleep_until(reconnect_at.unwrap_or_else(tokio::time::Instant::now)), if reconnect_at.is_some() => {
+                    reconnect_at = None;
+                    let Some((ip_addr, port)) = target else {
+                        continue;
+                    };
+                    reconnect_attempts += 1;
+                    telem.write().await.reconnect_attempts = reconnect_attempts;
+                    match connect(ip_addr, port, config.ws_limits, &telem, &mut updates_tx)
+                        .await
+                        .map(|ws_stream| throughput.meter(ws_stream)) {
+                        Some(ws_stream) => ws_txrx = Some(ws_stream.split()),
+                        None => {
+                            info!("Reconnecting to {ip_addr}:{port} in {reconnect_delay:?}");
+                            reconnect_at = Some(tokio::time::Instant::now() + reconnect_delay);
+                            reconnect_delay = (reconnect_delay * 2).min(config.reconnect.max);
+                        }
+                    }
+                }
                 cmd = cmd_rx.recv() => {
                     let Some(cmd) = cmd else {
                         info!("Sync client: tx end no longer exist, exiting");
@@ -287,62 +820,98 @@ async fn event_loop(
                     };
                     match cmd {
                         SyncClientCommand::Connect(ip_addr, port) => {
-                            let url = format!("ws://{ip_addr}:{port}");
-                            info!("ws: Connecting to remote {url}");
-                            let ws_stream = match tokio_tungstenite::connect_async(url).await {
-                                Ok((ws_stream, _)) => {
-                                    let mut telem = telem.write().await;
-                                    telem.connected = true;
-                                    telem.error_message.clear();
-                                    if let Err(_) = postage::sink::Sink::send(&mut updates_tx, ChangeNotification::Connected).await {
-                                        warn!("Notification send: mpsc fail");
-                                    }
-                                    ws_stream
-                                },
-                                Err(e) => {
-                                    warn!("{e:?}");
-                                    let mut telem = telem.write().await;
-                                    telem.connected = false;
-                                    telem.error_message = format!("{e:?}");
-                                    continue;
-                                }
-                            };
-                            ws_txrx = Some(ws_stream.split());
+                            target = Some((ip_addr, port));
+                            reconnect_at = None;
+                            reconnect_delay = config.reconnect.first;
+                            reconnect_attempts = 0;
+                            telem.write().await.reconnect_attempts = 0;
+                            if let Some(ws_stream) = connect(ip_addr, port, config.ws_limits, &telem, &mut updates_tx)
+                        .await
+                        .map(|ws_stream| throughput.meter(ws_stream)) {
+                                ws_txrx = Some(ws_stream.split());
+                            }
+                        }
+                        SyncClientCommand::Disconnect => {
+                            // Stop reconnecting
+                            target = None;
+                            reconnect_at = None;
+                        }
+                        SyncClientCommand::UnlinkServer => {
+                            target = None;
+                            reconnect_at = None;
+                            server_uuid = None;
+                            telem.write().await.linked_server = None;
+                            info!("Database unlinked from the server");
                         }
-                        SyncClientCommand::Disconnect => {}
                         SyncClientCommand::TreeCreated(_tree_name) => {
                         }
+                        SyncClientCommand::TreeOpened { tree_name, schema, client_ids } => {
+                            if client_ids {
+                                client_id_trees.insert(tree_name.clone());
+                            }
+                            schemas.insert(tree_name, schema);
+                        }
                         SyncClientCommand::RegisterIndex { tree_name, indexer } => {
                             indexers.entry(tree_name).or_default().push(indexer);
                         }
                         SyncClientCommand::Change(_event) => {
-                            // to_replay.push(event);
-                            telem.write().await.backlog += 1;
+                            // Already persisted as pending by the tree, sent after reconnecting
+                            telem.write().await.backlog = pending.len();
                         }
-                        SyncClientCommand::CheckOut(tree, key) => {
+                        SyncClientCommand::CheckOut(tree, key) | SyncClientCommand::TryCheckOut { tree_name: tree, key, .. } => {
                             warn!("Ignoring CheckOut {tree}/{key} because of disconnected state");
                         },
                         SyncClientCommand::Release(tree, key) => {
                             warn!("Ignoring Release {tree}/{key} because of disconnected state");
                         },
+                        SyncClientCommand::CancelCheckOut(tree, key) => {
+                            warn!("Ignoring CancelCheckOut {tree}/{key} because of disconnected state");
+                        },
+                        SyncClientCommand::FullReSync | SyncClientCommand::ReSyncTree(_) => {
+                            warn!("Ignoring re-sync request because of disconnected state");
+                        }
+                        SyncClientCommand::FetchRecord { tree_name, key, .. } => {
+                            warn!("Ignoring fetch of {tree_name}/{key} because of disconnected state");
+                        }
+                        SyncClientCommand::Quiesce { .. } => {
+                            warn!("Ignoring sync and quiesce request because of disconnected state");
+                        }
                     }
                 }
             }
         }
+    }
+}
 
-        if should_disconnect {
-            if let Some((ws_tx, ws_rx)) = ws_txrx.take() {
-                if let Ok(mut ws) = ws_rx.reunite(ws_tx) {
-                    let _ = ws.close(None).await;
-                }
-            }
-
-            telem.write().await.connected = false;
-            if let Err(_) =
-                postage::sink::Sink::send(&mut updates_tx, ChangeNotification::Disconnected).await
+/// Open a websocket to the server, failures are reported through telemetry.
+async fn connect(
+    ip_addr: IpAddr,
+    port: u16,
+    ws_limits: WsLimits,
+    telem: &VhrdDbTelem,
+    updates_tx: &mut postage::broadcast::Sender<ChangeNotification>,
+) -> Option<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let url = format!("ws://{ip_addr}:{port}");
+    info!("ws: Connecting to remote {url}");
+    match connect_ws(&url, ws_limits).await {
+        Ok(ws_stream) => {
+            let mut telem = telem.write().await;
+            telem.connected = true;
+            telem.error_message.clear();
+            if postage::sink::Sink::send(updates_tx, ChangeNotification::Connected)
+                .await
+                .is_err()
             {
                 warn!("Notification send: mpsc fail");
             }
+            Some(ws_stream)
+        }
+        Err(e) => {
+            warn!("{e:?}");
+            let mut telem = telem.write().await;
+            telem.connected = false;
+            telem.error_message = format!("{e:?}");
+            None
         }
     }
 }
@@ -351,68 +920,447 @@ async fn event_loop(
 // async fn process_message(
 //     ws_message: Message,
 //     db: &mut Db,
-//     mut ws_tx: impl Sink<Message> + Unpin,
+//     mut ws_tx: impl Sink<Message, Error = tungstenite::Error> + Unpin,
 //     indexers: &HashMap<String, Vec<Box<dyn TreeIndex + Send>>>,
 // ) -> Result<(), Error> {
 //     Ok(())
 // }
 
+/// Start the quiesce that waited for PresentSelf, by exchanging overviews of all trees.
+/// Handshake steps after the server was accepted: present this client, say that it moved over from another server
+/// and declare trees with client chosen ids (only if the server supports them), exchange overviews,
+/// replay changes made offline and ask for keys.
+#[allow(clippy::too_many_arguments)]
+async fn catch_up(
+    db: &Db,
+    has_rehome: bool,
+    client_id_trees: Option<&HashSet<String>>,
+    schemas: &HashMap<String, TreeSchema>,
+    pending: &Tree,
+    telem: &VhrdDbTelem,
+    quiesce: &mut Option<Quiesce>,
+    key_requests: KeyRequests,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    present_self(db, ws_tx).await?;
+    if has_rehome && db.contains_key(REHOMED)? {
+        info!("Moved over from another server, letting the server check ids of the records");
+        let ev_bytes = to_bytes::<_, 8>(&Event::Rehomed)?;
+        ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
+    }
+    if let Some(client_id_trees) = client_id_trees {
+        send_client_id_trees(client_id_trees, ws_tx).await?;
+    }
+    send_tree_overviews(db, schemas, ws_tx).await?;
+    let sent = replay_pending(db, pending, telem, ws_tx).await?;
+    if let Some(q) = quiesce {
+        q.summary.sent += sent;
+    }
+    request_keys(db, key_requests, ws_tx, true).await
+}
+
+async fn start_pending_quiesce(
+    db: &Db,
+    schemas: &HashMap<String, TreeSchema>,
+    quiesce: &mut Option<Quiesce>,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    let Some(q) = quiesce.as_mut().filter(|q| !q.started) else {
+        return Ok(());
+    };
+    q.started = true;
+    q.unsettled = ManagedTrees::managed(db)?.into_iter().collect();
+    for tree_name in &q.unsettled {
+        resync_tree(db, schemas, tree_name, ws_tx).await?;
+    }
+    finish_quiesce(quiesce);
+    Ok(())
+}
+
+/// Check the server's overview of a tree against the local one, the tree is settled once they are the same.
+///
+/// Otherwise overviews are exchanged again, after the records requested by both ends, so that the next one
+/// is checked after they arrived. Records waiting for global ids restart the exchange once they are sent instead.
+async fn settle_tree(
+    db: &Db,
+    schemas: &HashMap<String, TreeSchema>,
+    quiesce: &mut Quiesce,
+    tree_name: &str,
+    records: &ArchivedHashMap<ArchivedGenericKey, ArchivedRecordIteration>,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    let tree = db.open_tree(tree_name)?;
+    if has_temporary_records(&tree) {
+        trace!("quiesce: {tree_name} waits for global ids");
+        return Ok(());
+    }
+    if is_same_overview(&tree, tree_name, records)? {
+        trace!("quiesce: {tree_name} settled");
+        quiesce.unsettled.remove(tree_name);
+        return Ok(());
+    }
+    resync_tree(db, schemas, tree_name, ws_tx).await
+}
+
+/// Notify the waiter once all trees settled.
+fn finish_quiesce(quiesce: &mut Option<Quiesce>) {
+    if !quiesce
+        .as_ref()
+        .is_some_and(|q| q.started && q.unsettled.is_empty())
+    {
+        return;
+    }
+    if let Some(mut q) = quiesce.take() {
+        info!("Sync quiesced: {:?}", q.summary);
+        let _ = postage::sink::Sink::try_send(&mut q.done, q.summary);
+    }
+}
+
+/// Send the local overview of a tree and ask for the server's one.
+async fn resync_tree(
+    db: &Db,
+    schemas: &HashMap<String, TreeSchema>,
+    tree_name: &str,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    let schema = schemas.get(tree_name).copied();
+    send_tree_overview(db, tree_name, schema, ws_tx).await?;
+    request_tree_overview(tree_name, ws_tx).await
+}
+
+/// Send changes made while offline, or not sent before the connection was lost, in the order they were made.
+///
+/// Changes to records that were removed since then are dropped, removals are sent regardless.
+/// Each one is forgotten once written out, so the rest is replayed again if the connection drops midway.
+async fn replay_pending(
+    db: &Db,
+    pending: &Tree,
+    telem: &VhrdDbTelem,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<usize, Error> {
+    let changes = PendingChanges::queued(pending)?;
+    if changes.is_empty() {
+        return Ok(0);
+    }
+    info!("Replaying {} changes made while offline", changes.len());
+    let mut sent = 0;
+    for change in changes {
+        let is_removed = !matches!(change.kind, ChangeKind::Remove)
+            && !db
+                .open_tree(change.tree.as_str())?
+                .contains_key(change.key.to_bytes())?;
+        if is_removed {
+            trace!(
+                "replay: {}/{} was removed since, dropping {:?}",
+                change.tree,
+                change.key,
+                change.kind
+            );
+            PendingChanges::claim(pending, &change)?;
+        } else if send_pending(db, pending, &change, ws_tx).await? {
+            sent += 1;
+        }
+        let mut telem = telem.write().await;
+        telem.backlog = telem.backlog.saturating_sub(1);
+    }
+    Ok(sent)
+}
+
+/// Send a change queued by a tree, false if it was superseded by a newer one or withdrawn meanwhile.
+/// It is claimed before the record is read, so that TypedTree::remove cannot withdraw a creation that is being sent.
+async fn send_pending(
+    db: &Db,
+    pending: &Tree,
+    change: &RecordHotChange,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<bool, Error> {
+    let Some(claimed) = PendingChanges::claim(pending, change)? else {
+        return Ok(false);
+    };
+    if let Err(e) = send_hot_change(db, change.clone(), ws_tx).await {
+        // Replayed after reconnecting
+        PendingChanges::restore(pending, change, claimed)?;
+        return Err(e);
+    }
+    Ok(true)
+}
+
+/// Notify everyone waiting for a record fetched from the server.
+fn fetched(
+    fetches: &mut HashMap<(String, GenericKey), Vec<oneshot::Sender<()>>>,
+    tree_name: &str,
+    key: GenericKey,
+) {
+    let Some(waiters) = fetches.remove(&(tree_name.to_string(), key)) else {
+        return;
+    };
+    for mut done in waiters {
+        let _ = postage::sink::Sink::try_send(&mut done, ());
+    }
+}
+
+/// Move records created while no keys were available to the keys just received and send them to the server.
+async fn send_offline_records(
+    db: &Db,
+    pending: &Tree,
+    tree_name: &str,
+    indexers: &mut HashMap<String, Vec<Box<dyn TreeIndex + Send>>>,
+    key_requests: KeyRequests,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    updates_tx: &mut postage::broadcast::Sender<ChangeNotification>,
+) -> Result<usize, Error> {
+    let reassigned = assign_global_ids(db, tree_name, indexers.get_mut(tree_name))?;
+    if reassigned.is_empty() {
+        return Ok(0);
+    }
+    let sent = reassigned.len();
+    info!(
+        "{tree_name}: {} records created offline got global ids",
+        reassigned.len()
+    );
+    for Reassigned {
+        temporary,
+        global,
+        change,
+    } in reassigned
+    {
+        // Kept as pending until sent, in case the connection drops in between
+        PendingChanges::push(pending, &change)?;
+        let notification = ChangeNotification::GlobalIdAssigned {
+            tree_name: tree_name.to_string(),
+            temporary,
+            global,
+        };
+        if postage::sink::Sink::send(updates_tx, notification)
+            .await
+            .is_err()
+        {
+            warn!("Notification send: mpsc fail");
+        }
+        send_pending(db, pending, &change, ws_tx).await?;
+    }
+    // Pool might have run out before all of them were moved
+    request_keys(db, key_requests, ws_tx, false).await?;
+    Ok(sent)
+}
+
+/// Request more keys for trees that are running low on them.
+///
+/// Only one request per tree is kept in flight, unanswered ones are re-sent if `reissue_pending` is true
+/// (after connecting to the server, since previous connection might have been lost before KeySet arrived).
 pub async fn request_keys(
     db: &Db,
-    ws_tx: &mut (impl futures_util::Sink<Message> + Unpin),
+    key_requests: KeyRequests,
+    ws_tx: &mut (impl futures_util::Sink<Message, Error = tungstenite::Error> + Unpin),
+    reissue_pending: bool,
 ) -> Result<(), Error> {
     let trees = ManagedTrees::managed(db)?;
+    let mut requested = Vec::new();
     for tree_name in &trees {
         let tree = db.open_tree(tree_name.as_str())?;
         let available_keys = KeyPool::stats_for(&tree)?;
-        trace!("request_keys: {} available: {}", tree_name, available_keys);
-        if available_keys < 3 {
+        let is_pending = PendingKeyRequests::is_pending(db, tree_name)?;
+        trace!("request_keys: {tree_name} available: {available_keys} pending: {is_pending}");
+        let is_low = available_keys < key_requests.low_water;
+        if (is_low && !is_pending) || (is_pending && reissue_pending) {
             let ev = Event::GetKeySet {
                 tree: tree_name.to_string(),
+                count: key_requests.count,
             };
             let ev_bytes = to_bytes::<_, 128>(&ev)?;
-            ws_tx
-                .feed(Message::Binary(ev_bytes.to_vec()))
-                .await
-                .map_err(|_| Error::Ws)?;
+            ws_tx.feed(Message::Binary(ev_bytes.to_vec())).await?;
+            requested.push(tree_name);
         }
     }
-    ws_tx.flush().await.map_err(|_| Error::Ws)?;
+    ws_tx.flush().await?;
+    // Only mark as pending once actually sent, otherwise requests lost on a failed flush would never be reissued
+    for tree_name in requested {
+        PendingKeyRequests::set_pending(db, tree_name, true)?;
+    }
+    Ok(())
+}
+
+async fn request_tree_overviews(
+    db: &Db,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    let trees = ManagedTrees::managed(db)?;
+    for tree_name in trees {
+        request_tree_overview(tree_name, ws_tx).await?;
+    }
+    Ok(())
+}
+
+/// Declare trees with client chosen ids to the server, before any of their records are sent.
+async fn send_client_id_trees<'a>(
+    trees: impl IntoIterator<Item = &'a String>,
+    tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    for tree in trees {
+        trace!("{tree} ids are chosen by clients");
+        let event = Event::ClientIdTree { tree: tree.clone() };
+        let event_bytes = to_bytes::<_, 128>(&event)?;
+        tx.send(Message::Binary(event_bytes.to_vec())).await?;
+    }
+    Ok(())
+}
+
+async fn request_record(
+    tree: String,
+    key: GenericKey,
+    tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    let event = Event::RequestRecords {
+        tree,
+        keys: vec![key],
+    };
+    let event_bytes = to_bytes::<_, 128>(&event)?;
+    tx.send(Message::Binary(event_bytes.to_vec())).await?;
     Ok(())
 }
 
 async fn check_out(
     tree: String,
     key: GenericKey,
-    tx: &mut (impl Sink<Message> + Unpin),
+    wait: bool,
+    tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
 ) -> Result<(), Error> {
     let event = Event::CheckOut {
         tree,
         keys: vec![key],
+        wait,
     };
     let id_event = to_bytes::<_, 8>(&event)?;
-    tx.feed(Message::Binary(id_event.to_vec()))
-        .await
-        .map_err(|_| Error::Ws)?;
+    tx.feed(Message::Binary(id_event.to_vec())).await?;
 
-    tx.flush().await.map_err(|_| Error::Ws)?;
+    tx.flush().await?;
     Ok(())
 }
 
 async fn release(
     tree: String,
     key: GenericKey,
-    tx: &mut (impl Sink<Message> + Unpin),
+    tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
 ) -> Result<(), Error> {
     let event = Event::Return {
         tree,
         keys: vec![key],
     };
     let id_event = to_bytes::<_, 8>(&event)?;
-    tx.feed(Message::Binary(id_event.to_vec()))
-        .await
-        .map_err(|_| Error::Ws)?;
+    tx.feed(Message::Binary(id_event.to_vec())).await?;
 
-    tx.flush().await.map_err(|_| Error::Ws)?;
+    tx.flush().await?;
     Ok(())
 }
+
+async fn cancel_check_out(
+    tree: String,
+    key: GenericKey,
+    tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    let event = Event::CancelCheckOut {
+        tree,
+        keys: vec![key],
+    };
+    let id_event = to_bytes::<_, 8>(&event)?;
+    tx.feed(Message::Binary(id_event.to_vec())).await?;
+
+    tx.flush().await?;
+    Ok(())
+}
+
+/// Let the server know that records checked out by this client are still in use.
+async fn renew_check_outs(
+    borrows: &RwLock<RecordBorrows>,
+    self_uuid: Uuid,
+    tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    let held: Vec<(String, Vec<GenericKey>)> = borrows
+        .read()
+        .await
+        .borrows
+        .iter()
+        .map(|(tree, borrowed_keys)| {
+            let keys = borrowed_keys
+                .iter()
+                .filter(|(_, queue)| queue.first() == Some(&self_uuid))
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>();
+            (tree.clone(), keys)
+        })
+        .filter(|(_, keys)| !keys.is_empty())
+        .collect();
+    if held.is_empty() {
+        return Ok(());
+    }
+    for (tree, keys) in held {
+        let event = Event::RenewCheckOut { tree, keys };
+        let event_bytes = to_bytes::<_, 64>(&event)?;
+        tx.feed(Message::Binary(event_bytes.to_vec())).await?;
+    }
+    tx.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replay_pending, request_keys, KeyRequests, VhrdDbTelem};
+    use crate::common::{Error, ManagedTrees};
+    use crate::consts::PENDING_CHANGES_TREE;
+    use crate::key_pool::PendingKeyRequests;
+    use crate::pending::PendingChanges;
+    use crate::sync::{ChangeKind, RecordHotChange};
+    use hills_base::GenericKey;
+    use std::sync::{Arc, Mutex};
+    use tokio_tungstenite::tungstenite::{self, Message};
+
+    #[test]
+    fn key_request_is_not_pending_if_flush_failed() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ManagedTrees::add_to_managed(&db, "items").unwrap();
+
+        // Messages are only written out on flush, which then fails
+        let mut ws_tx = Box::pin(futures_util::sink::unfold((), |_, _: Message| async {
+            Err::<(), _>(tungstenite::Error::ConnectionClosed)
+        }));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let r = rt.block_on(request_keys(&db, KeyRequests::default(), &mut ws_tx, false));
+        assert!(matches!(r, Err(Error::Ws(_))));
+        assert!(!PendingKeyRequests::is_pending(&db, "items").unwrap());
+    }
+
+    #[test]
+    fn replay_drops_changes_to_removed_records() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let pending = db.open_tree(PENDING_CHANGES_TREE).unwrap();
+        let change = |id, kind| RecordHotChange {
+            tree: "items".to_string(),
+            key: GenericKey::new(id, 0),
+            meta_iteration: 0,
+            data_iteration: 1,
+            kind,
+        };
+        // Record 1 is not in the tree anymore, removal of 2 is still worth sending
+        PendingChanges::push(&pending, &change(1, ChangeKind::CreateOrChange)).unwrap();
+        PendingChanges::push(&pending, &change(2, ChangeKind::Remove)).unwrap();
+        let telem = VhrdDbTelem::default();
+        telem.blocking_write().backlog = 2;
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let mut ws_tx = Box::pin(futures_util::sink::unfold(
+            messages.clone(),
+            |messages, message: Message| async move {
+                messages.lock().unwrap().push(message);
+                Ok::<_, tungstenite::Error>(messages)
+            },
+        ));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let sent = rt
+            .block_on(replay_pending(&db, &pending, &telem, &mut ws_tx))
+            .unwrap();
+        assert_eq!(sent, 1);
+        assert_eq!(messages.lock().unwrap().len(), 1);
+        assert!(PendingChanges::queued(&pending).unwrap().is_empty());
+        assert_eq!(telem.blocking_read().backlog, 0);
+    }
+}
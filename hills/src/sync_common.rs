@@ -1,26 +1,93 @@
-use crate::common::{Error, ManagedTrees};
-use crate::consts::{KEY_POOL, READABLE_NAME, SELF_UUID};
-use crate::index::{Action, TreeIndex, TypeErasedTree};
-use crate::record::{Record, RecordMeta};
+use crate::common::{default_readable_name, record_key, Error, ManagedTrees, WsLimits};
+use crate::consts::{CAPABILITIES, CLIENT_IDS, PROTOCOL_VERSION, READABLE_NAME, SELF_UUID};
+use crate::index::{Action, IndexData, TreeIndex, TypeErasedTree};
+use crate::journal::{self, Action as JournalAction};
+use crate::record::{Record, RecordHeader, RecordMeta};
 use crate::sync::{
     ArchivedHotSyncEvent, ArchivedHotSyncEventKind, ArchivedRecordIteration, ChangeKind, Event,
-    HotSyncEvent, HotSyncEventKind, RecordHotChange, RecordIteration,
+    HotSyncEvent, HotSyncEventKind, RecordHotChange, RecordIteration, TreeSchema,
 };
+use crate::temporary::is_temporary;
 use futures_util::{Sink, SinkExt};
 use hills_base::generic_key::ArchivedGenericKey;
-use hills_base::GenericKey;
-use log::{error, trace, warn};
+use hills_base::{GenericKey, UtcDateTime};
+use log::{debug, error, trace, warn};
 use rkyv::collections::ArchivedHashMap;
 use rkyv::vec::ArchivedVec;
 use rkyv::{check_archived_root, to_bytes, AlignedVec, Deserialize};
 use sled::{Db, Tree};
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use tokio_tungstenite::tungstenite::Message;
+use std::collections::{HashMap, HashSet};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::{HeaderMap, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+/// Connect to a server that speaks the same sync protocol version, see consts::PROTOCOL_VERSION.
+pub(crate) async fn connect_ws(
+    url: &str,
+    ws_limits: WsLimits,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Error> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(PROTOCOL_VERSION),
+    );
+    let (ws_stream, response) =
+        tokio_tungstenite::connect_async_with_config(request, Some(ws_limits.ws_config()), false)
+            .await?;
+    // Servers without versioning accept the connection, but never confirm the subprotocol
+    if !lists_protocol_version(response.headers()) {
+        return Err(Error::Protocol(format!(
+            "server does not speak {PROTOCOL_VERSION}"
+        )));
+    }
+    Ok(ws_stream)
+}
+
+/// Accept a websocket connection if the client asked for the sync protocol version of this build.
+#[allow(clippy::result_large_err)] // Handshake callback signature is set by tungstenite
+pub(crate) async fn accept_ws(
+    tcp_stream: TcpStream,
+    ws_limits: WsLimits,
+) -> Result<WebSocketStream<TcpStream>, Error> {
+    let check_version = |request: &Request, mut response: Response| {
+        if !lists_protocol_version(request.headers()) {
+            let mut refusal =
+                ErrorResponse::new(Some(format!("sync protocol {PROTOCOL_VERSION} required")));
+            *refusal.status_mut() = StatusCode::BAD_REQUEST;
+            return Err(refusal);
+        }
+        response.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(PROTOCOL_VERSION),
+        );
+        Ok(response)
+    };
+    let ws_stream = tokio_tungstenite::accept_hdr_async_with_config(
+        tcp_stream,
+        check_version,
+        Some(ws_limits.ws_config()),
+    )
+    .await?;
+    Ok(ws_stream)
+}
+
+fn lists_protocol_version(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == PROTOCOL_VERSION)
+}
 
 pub(crate) async fn present_self(
     db: &Db,
-    tx: &mut (impl Sink<Message> + Unpin),
+    tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
 ) -> Result<(), Error> {
     let Some(uuid_bytes) = db.get(SELF_UUID)? else {
         return Err(Error::Internal("self uuid is absent".into()));
@@ -30,29 +97,72 @@ pub(crate) async fn present_self(
     }
     let mut uuid = [0u8; 16];
     uuid[..].copy_from_slice(&uuid_bytes);
-    let readable_name = if let Some(name) = db.get(READABLE_NAME)? {
-        std::str::from_utf8(&name).unwrap_or("").to_string()
-    } else {
-        String::new()
-    };
+    let readable_name = db
+        .get(READABLE_NAME)?
+        .and_then(|name| {
+            std::str::from_utf8(&name)
+                .ok()
+                .map(|name| name.trim().to_string())
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| default_readable_name(Uuid::from_bytes(uuid)));
     let id_event = Event::PresentSelf {
         uuid,
         readable_name,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
     };
     let id_event = to_bytes::<_, 8>(&id_event)?;
-    tx.feed(Message::Binary(id_event.to_vec()))
-        .await
-        .map_err(|_| Error::Ws)?;
+    tx.feed(Message::Binary(id_event.to_vec())).await?;
 
-    tx.flush().await.map_err(|_| Error::Ws)?;
+    tx.flush().await?;
     Ok(())
 }
 
+/// Handle a ws message that does not carry an Event, Close is left to the caller.
+///
+/// Ping is answered with Pong, Pong and raw frames are ignored, Text frames are rejected with Error::Protocol.
+pub(crate) async fn handle_control_message(
+    message: Message,
+    tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    match message {
+        Message::Ping(payload) => {
+            tx.send(Message::Pong(payload)).await?;
+            Ok(())
+        }
+        Message::Pong(_) | Message::Frame(_) => Ok(()),
+        Message::Text(text) => Err(Error::Protocol(format!(
+            "text frames are not supported, got {} bytes",
+            text.len()
+        ))),
+        Message::Binary(_) | Message::Close(_) => Ok(()),
+    }
+}
+
+/// Key of a tombstone in REMOVED_RECORDS_TREE: tree name followed by record key.
+pub(crate) fn removed_record_key(tree_name: &str, key: GenericKey) -> Vec<u8> {
+    let mut removed_record_key = Vec::with_capacity(tree_name.len() + GenericKey::BYTES);
+    removed_record_key.extend_from_slice(tree_name.as_bytes());
+    removed_record_key.extend_from_slice(&key.to_bytes());
+    removed_record_key
+}
+
+/// Features that both this node and the remote one support, remote capabilities come from its PresentSelf.
+pub(crate) fn negotiate_capabilities<'a>(
+    local: &[&str],
+    remote: impl IntoIterator<Item = &'a str>,
+) -> HashSet<String> {
+    remote
+        .into_iter()
+        .filter(|c| local.contains(c))
+        .map(|c| c.to_string())
+        .collect()
+}
+
 pub(crate) async fn send_hot_change(
     db: &Db,
     change: RecordHotChange,
-    ws_tx: &mut (impl Sink<Message> + Unpin),
-    source_addr: Option<SocketAddr>,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
 ) -> Result<(), Error> {
     trace!(
         "send_hot_change: {:?} for {}/{} m{} d{}",
@@ -80,7 +190,6 @@ pub(crate) async fn send_hot_change(
                     HotSyncEvent {
                         tree_name: change.tree,
                         key: change.key,
-                        source_addr,
                         kind: HotSyncEventKind::CreatedOrChanged {
                             meta,
                             meta_iteration: record.meta_iteration,
@@ -93,7 +202,6 @@ pub(crate) async fn send_hot_change(
                 ChangeKind::ModifyMeta => HotSyncEvent {
                     tree_name: change.tree,
                     key: change.key,
-                    source_addr,
                     kind: HotSyncEventKind::MetaChanged {
                         meta,
                         meta_iteration: record.meta_iteration,
@@ -105,56 +213,111 @@ pub(crate) async fn send_hot_change(
         ChangeKind::Remove => HotSyncEvent {
             tree_name: change.tree,
             key: change.key,
-            source_addr,
             kind: HotSyncEventKind::Removed,
         },
     };
     let ev_bytes = to_bytes::<_, 128>(&Event::HotSyncEvent(hot_change_ev))?;
-    ws_tx
-        .send(Message::Binary(ev_bytes.to_vec()))
-        .await
-        .map_err(|_| Error::Ws)?;
+    ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
     Ok(())
 }
 
 /// For each tree in use: send a list of keys it contains, so the other end could request what's missing.
 pub(crate) async fn send_tree_overviews(
     db: &Db,
-    ws_tx: &mut (impl Sink<Message> + Unpin),
+    schemas: &HashMap<String, TreeSchema>,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
 ) -> Result<(), Error> {
     let trees = ManagedTrees::managed(db)?;
     for tree_name in trees {
-        let tree = db.open_tree(&tree_name)?;
-        let mut records = HashMap::new();
-        for db_record in tree.iter() {
-            let (key_bytes, record_bytes) = db_record?;
-            if key_bytes == KEY_POOL {
+        let schema = schemas.get(&tree_name).copied();
+        send_tree_overview(db, tree_name, schema, ws_tx).await?;
+    }
+    Ok(())
+}
+
+/// Send a list of keys one tree contains, so the other end could request what's missing.
+pub(crate) async fn send_tree_overview(
+    db: &Db,
+    tree_name: impl AsRef<str>,
+    schema: Option<TreeSchema>,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    let tree_name = tree_name.as_ref();
+    let tree = db.open_tree(tree_name)?;
+    let ev = Event::TreeOverview {
+        tree: tree_name.to_string(),
+        records: tree_overview(&tree, tree_name)?,
+        schema,
+    };
+    let ev_bytes = to_bytes::<_, 128>(&ev)?;
+    ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
+    Ok(())
+}
+
+/// Iterations of all the records with global ids, as sent to the other end in a tree overview.
+pub(crate) fn tree_overview(
+    tree: &Tree,
+    tree_name: &str,
+) -> Result<HashMap<GenericKey, RecordIteration>, Error> {
+    let mut records = HashMap::new();
+    for db_record in tree.iter() {
+        let (key_bytes, record_bytes) = db_record?;
+        let Some(key) = record_key(&key_bytes) else {
+            continue;
+        };
+        if is_temporary(key.id) {
+            // Sent once a global id is assigned
+            continue;
+        }
+        let header = match RecordHeader::check(&record_bytes) {
+            Ok(header) => header,
+            Err(e) => {
+                warn!("{tree_name}/{key} cannot be decoded, left out of overview: {e:?}");
                 continue;
             }
-            let Some(key) = GenericKey::from_bytes(&key_bytes) else {
-                return Err(Error::Internal(
-                    "Malformed key in tree {tree_name}: {key_bytes:?}".into(),
-                ));
-            };
-            let record = check_archived_root::<Record>(&record_bytes)?;
-            records.insert(
-                key,
-                RecordIteration {
-                    meta_iteration: record.meta_iteration,
-                    data_iteration: record.data_iteration,
-                },
-            );
-        }
-        let ev = Event::TreeOverview {
-            tree: tree_name,
-            records,
         };
-        let ev_bytes = to_bytes::<_, 128>(&ev)?;
-        ws_tx
-            .send(Message::Binary(ev_bytes.to_vec()))
-            .await
-            .map_err(|_| Error::Ws)?;
+        records.insert(
+            key,
+            RecordIteration {
+                meta_iteration: header.meta_iteration(),
+                data_iteration: header.data_iteration(),
+            },
+        );
     }
+    Ok(records)
+}
+
+/// Whether the other end's overview lists exactly the same records with the same iterations as the local tree.
+pub(crate) fn is_same_overview(
+    tree: &Tree,
+    tree_name: &str,
+    records: &ArchivedHashMap<ArchivedGenericKey, ArchivedRecordIteration>,
+) -> Result<bool, Error> {
+    let local = tree_overview(tree, tree_name)?;
+    if local.len() != records.len() {
+        return Ok(false);
+    }
+    let is_same = records.iter().all(|(key, remote)| {
+        local
+            .get(&GenericKey::from_archived(key))
+            .is_some_and(|local| {
+                local.meta_iteration == remote.meta_iteration
+                    && local.data_iteration == remote.data_iteration
+            })
+    });
+    Ok(is_same)
+}
+
+/// Ask the other end to send it's overview of a tree, so that missing or outdated records are requested in response.
+pub(crate) async fn request_tree_overview(
+    tree_name: impl AsRef<str>,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    let ev = Event::GetTreeOverview {
+        tree: tree_name.as_ref().to_string(),
+    };
+    let ev_bytes = to_bytes::<_, 128>(&ev)?;
+    ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
     Ok(())
 }
 
@@ -166,7 +329,7 @@ pub(crate) async fn compare_and_request_missing_records(
     db: &Db,
     tree_name: impl AsRef<str>,
     records: &ArchivedHashMap<ArchivedGenericKey, ArchivedRecordIteration>,
-    ws_tx: &mut (impl Sink<Message> + Unpin),
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
     removed_records: Option<&Tree>,
 ) -> Result<Vec<GenericKey>, Error> {
     let tree_name = tree_name.as_ref();
@@ -174,7 +337,7 @@ pub(crate) async fn compare_and_request_missing_records(
     let mut missing_or_outdated = Vec::new();
     let mut found_in_removed = Vec::new();
 
-    let tree_name_len = tree_name.as_bytes().len();
+    let tree_name_len = tree_name.len();
     let mut removed_records_key = Vec::with_capacity(tree_name_len + 8);
     removed_records_key.extend_from_slice(tree_name.as_bytes());
     removed_records_key.extend_from_slice(&[0; 8]);
@@ -193,14 +356,19 @@ pub(crate) async fn compare_and_request_missing_records(
 
         let key_bytes = key.to_bytes();
         match tree.get(key_bytes)? {
-            Some(record) => {
-                let record = check_archived_root::<Record>(&record)?;
-                if record.data_iteration < remote_record.data_iteration
-                    || record.meta_iteration < remote_record.meta_iteration
-                {
+            Some(record) => match RecordHeader::check(&record) {
+                Ok(header) => {
+                    if header.data_iteration() < remote_record.data_iteration
+                        || header.meta_iteration() < remote_record.meta_iteration
+                    {
+                        missing_or_outdated.push(key);
+                    }
+                }
+                Err(e) => {
+                    warn!("{tree_name}/{key} cannot be decoded, requesting remote copy: {e:?}");
                     missing_or_outdated.push(key);
                 }
-            }
+            },
             None => {
                 missing_or_outdated.push(key);
             }
@@ -212,23 +380,38 @@ pub(crate) async fn compare_and_request_missing_records(
         keys: missing_or_outdated,
     };
     let ev_bytes = to_bytes::<_, 128>(&ev)?;
-    ws_tx
-        .send(Message::Binary(ev_bytes.to_vec()))
-        .await
-        .map_err(|_| Error::Ws)?;
+    ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
     Ok(found_in_removed)
 }
 
+/// Log an error returned in an event loop, returning from it on the ones it cannot recover from.
+///
+/// With a disconnect flag, a ws error (e.g. connection dropped mid-send) sets it and skips the rest of the loop
+/// iteration instead, so that nothing sent after the failed message is marked as sent.
 #[macro_export]
 macro_rules! handle_result {
+    ($r:ident, $should_disconnect:ident) => {{
+        if let Err(Error::Ws(e)) = &$r {
+            log::warn!("Encountered ws stream error in event loop: {e}, disconnecting");
+            $should_disconnect = true;
+            continue;
+        }
+        $crate::handle_result!($r);
+    }};
     ($r:ident) => {{
         match $r {
             Err(Error::Sled(_f)) => {
                 log::error!("Encountered sled error in event loop, terminating");
                 return;
             }
-            Err(Error::Ws) => {
-                log::warn!("Encountered ws stream error in event loop, terminating");
+            Err(Error::Ws(e)) => {
+                log::warn!("Encountered ws stream error in event loop: {e}, terminating");
+                return;
+            }
+            Err(Error::MessageTooLarge { size, max_size }) => {
+                log::error!(
+                    "Received ws message of {size} bytes, but the limit is {max_size}, terminating"
+                );
                 return;
             }
             Err(Error::Internal(i)) => {
@@ -244,6 +427,10 @@ macro_rules! handle_result {
             Err(Error::PostageBroadcast) => {
                 log::error!("postage broadcast failed");
             }
+            Err(Error::Protocol(e)) => {
+                log::warn!("Protocol violation in event loop: {e}, terminating");
+                return;
+            }
             Ok(_) => {}
         }
     }};
@@ -254,12 +441,17 @@ pub(crate) fn handle_incoming_record(
     db: &mut Db,
     ev: &ArchivedHotSyncEvent,
     remote_name: &str,
-    indexers: Option<&mut HashMap<String, Vec<Box<dyn TreeIndex + Send>>>>,
+    mut indexers: Option<&mut HashMap<String, Vec<Box<dyn TreeIndex + Send>>>>,
 ) -> Result<(), Error> {
     let tree_name = ev.tree_name.as_str();
     let key = GenericKey::from_archived(&ev.key);
     let key_bytes = key.to_bytes();
     let db_tree = db.open_tree(tree_name)?;
+    let journal = journal::journal_of(db, &db_tree, tree_name)?;
+    if is_id_collision(&db_tree, ev)? {
+        error!("{remote_name}: {tree_name}/{key} was created elsewhere with the same id, keeping the local one");
+        return Ok(());
+    }
     match &ev.kind {
         ArchivedHotSyncEventKind::MetaChanged {
             meta,
@@ -293,6 +485,10 @@ pub(crate) fn handle_incoming_record(
             };
             let record_bytes = to_bytes::<_, 128>(&record)?;
             db_tree.insert(key_bytes, record_bytes.as_slice())?;
+            journal::append(journal.as_ref(), key, || {
+                JournalAction::Modify(record_bytes.to_vec())
+            })?;
+            meta_changed(indexers, tree_name, key, &record.meta);
             trace!(
                 "{} updated meta {}/{} m.it{}->{}",
                 remote_name,
@@ -327,8 +523,12 @@ pub(crate) fn handle_incoming_record(
 
                     let mut new_data = AlignedVec::new();
                     new_data.extend_from_slice(data.as_slice());
-                    if let Some(indexers) = indexers {
+                    if let Some(indexers) = indexers.as_mut() {
                         if let Some(indexers) = indexers.get_mut(tree_name) {
+                            let index_data = IndexData::new(&new_data);
+                            let old_data = (old_record.data_evolution.as_original()
+                                == data_evolution)
+                                .then(|| IndexData::new(old_record.data.as_slice()));
                             for indexer in indexers {
                                 if let Err(e) = indexer.update(
                                     TypeErasedTree {
@@ -336,7 +536,8 @@ pub(crate) fn handle_incoming_record(
                                         evolution: data_evolution,
                                     },
                                     key,
-                                    &new_data,
+                                    old_data.as_ref(),
+                                    &index_data,
                                     Action::Update,
                                 ) {
                                     error!("indexer failed on CreatedOrChanged, {tree_name}:{key} {e:?}");
@@ -355,6 +556,10 @@ pub(crate) fn handle_incoming_record(
                     };
                     let record_bytes = to_bytes::<_, 128>(&record)?;
                     db_tree.insert(key_bytes, record_bytes.as_slice())?;
+                    journal::append(journal.as_ref(), key, || {
+                        JournalAction::Modify(record_bytes.to_vec())
+                    })?;
+                    meta_changed(indexers, tree_name, key, &record.meta);
                     trace!(
                         "{} updated record {}/{} d.it{}->{}",
                         remote_name,
@@ -367,8 +572,9 @@ pub(crate) fn handle_incoming_record(
                 None => {
                     let mut new_data = AlignedVec::new();
                     new_data.extend_from_slice(data.as_slice());
-                    if let Some(indexers) = indexers {
+                    if let Some(indexers) = indexers.as_mut() {
                         if let Some(indexers) = indexers.get_mut(tree_name) {
+                            let index_data = IndexData::new(&new_data);
                             for indexer in indexers {
                                 if let Err(e) = indexer.update(
                                     TypeErasedTree {
@@ -376,7 +582,8 @@ pub(crate) fn handle_incoming_record(
                                         evolution: data_evolution,
                                     },
                                     key,
-                                    &new_data,
+                                    None,
+                                    &index_data,
                                     Action::Insert,
                                 ) {
                                     error!("indexer failed on hot sync on creation, {tree_name}:{key} {e:?}");
@@ -394,6 +601,10 @@ pub(crate) fn handle_incoming_record(
                     };
                     let record_bytes = to_bytes::<_, 128>(&record)?;
                     db_tree.insert(key_bytes, record_bytes.as_slice())?;
+                    journal::append(journal.as_ref(), key, || {
+                        JournalAction::Create(record_bytes.to_vec())
+                    })?;
+                    meta_changed(indexers, tree_name, key, &record.meta);
                     trace!("{} created record {}/{}", remote_name, tree_name, key);
                 }
             }
@@ -408,6 +619,7 @@ pub(crate) fn handle_incoming_record(
 
                 if let Some(indexers) = indexers {
                     if let Some(indexers) = indexers.get_mut(tree_name) {
+                        let index_data = IndexData::new(&archived_record.data);
                         for indexer in indexers {
                             if let Err(e) = indexer.update(
                                 TypeErasedTree {
@@ -415,7 +627,8 @@ pub(crate) fn handle_incoming_record(
                                     evolution: data_evolution,
                                 },
                                 key,
-                                &archived_record.data,
+                                None,
+                                &index_data,
                                 Action::Remove,
                             ) {
                                 error!("indexer failed on hot sync on removal, {tree_name}:{key} {e:?}");
@@ -425,40 +638,101 @@ pub(crate) fn handle_incoming_record(
                 }
 
                 db_tree.remove(key_bytes)?;
+                journal::append(journal.as_ref(), key, || JournalAction::Remove)?;
             }
             None => {
-                warn!(
-                    "{} tried to remove non-existing record: {}/{}",
-                    remote_name, tree_name, key
-                );
+                // Same remove can be delivered more than once, after reconnects or through a relay
+                debug!("{remote_name}: {tree_name}/{key} is already removed");
             }
         },
     }
     Ok(())
 }
 
+/// Whether an incoming record with a client chosen id (see IdStrategy) was created apart from the existing one with
+/// the same key, e.g. for two different items that hashed to the same id on nodes that were not connected.
+/// Records are told apart by their creation time, which stays the same through all the changes.
+pub(crate) fn is_id_collision(db_tree: &Tree, ev: &ArchivedHotSyncEvent) -> Result<bool, Error> {
+    let key = GenericKey::from_archived(&ev.key);
+    if !CLIENT_IDS.contains(&key.id) {
+        return Ok(false);
+    }
+    let meta = match &ev.kind {
+        ArchivedHotSyncEventKind::MetaChanged { meta, .. }
+        | ArchivedHotSyncEventKind::CreatedOrChanged { meta, .. } => meta,
+        ArchivedHotSyncEventKind::Removed => return Ok(false),
+    };
+    let Some(existing) = db_tree.get(key.to_bytes())? else {
+        return Ok(false);
+    };
+    let existing = check_archived_root::<Record>(&existing)?;
+    let created: UtcDateTime = meta.created.deserialize(&mut rkyv::Infallible)?;
+    let existing_created: UtcDateTime = existing.meta.created.deserialize(&mut rkyv::Infallible)?;
+    Ok(created != existing_created)
+}
+
+/// Let meta aware indexes know about a record that was just written.
+fn meta_changed(
+    indexers: Option<&mut HashMap<String, Vec<Box<dyn TreeIndex + Send>>>>,
+    tree_name: &str,
+    key: GenericKey,
+    meta: &RecordMeta,
+) {
+    let Some(indexers) = indexers.and_then(|indexers| indexers.get_mut(tree_name)) else {
+        return;
+    };
+    for indexer in indexers {
+        if let Err(e) = indexer.meta_changed(key, meta) {
+            error!("indexer failed on meta change, {tree_name}:{key} {e:?}");
+        }
+    }
+}
+
+/// Send the requested records, returns how many of them were sent (not counting removed or missing ones).
 pub(crate) async fn send_records(
     db: &Db,
     tree_name: impl AsRef<str>,
     keys: &ArchivedVec<ArchivedGenericKey>,
-    ws_tx: &mut (impl Sink<Message> + Unpin),
-    source_addr: Option<SocketAddr>,
-) -> Result<(), Error> {
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    removed_records: Option<&Tree>,
+) -> Result<usize, Error> {
     let tree_name = tree_name.as_ref();
     let tree = db.open_tree(tree_name)?;
+    let mut not_found = vec![];
+    let mut sent = 0;
     for key in keys.iter() {
         let key = GenericKey::from_archived(key);
+        // Record could have been removed after it was requested, never serve it again
+        if let Some(removed_records) = removed_records {
+            if removed_records.contains_key(removed_record_key(tree_name, key))? {
+                trace!("send_records: {tree_name}/{key} was removed, sending remove instead");
+                let ev = Event::HotSyncEvent(HotSyncEvent {
+                    tree_name: tree_name.to_string(),
+                    key,
+                    kind: HotSyncEventKind::Removed,
+                });
+                let ev_bytes = to_bytes::<_, 128>(&ev)?;
+                ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
+                continue;
+            }
+        }
         let key_bytes = key.to_bytes();
         let Some(record_bytes) = tree.get(key_bytes)? else {
             warn!("send_records: {key} do not actually exist");
+            not_found.push(key);
             continue;
         };
-        let record = check_archived_root::<Record>(&record_bytes)?;
+        let record = match check_archived_root::<Record>(&record_bytes) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("send_records: {tree_name}/{key} cannot be decoded, skipping: {e:?}");
+                continue;
+            }
+        };
         let meta: RecordMeta = record.meta.deserialize(&mut rkyv::Infallible).expect("");
         let ev = Event::HotSyncEvent(HotSyncEvent {
             tree_name: tree_name.to_string(),
             key,
-            source_addr,
             kind: HotSyncEventKind::CreatedOrChanged {
                 meta,
                 meta_iteration: record.meta_iteration,
@@ -468,10 +742,245 @@ pub(crate) async fn send_records(
             },
         });
         let ev_bytes = to_bytes::<_, 128>(&ev)?;
-        ws_tx
-            .send(Message::Binary(ev_bytes.to_vec()))
-            .await
-            .map_err(|_| Error::Ws)?;
+        ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
+        sent += 1;
+    }
+    if !not_found.is_empty() {
+        let ev = Event::RecordsNotFound {
+            tree: tree_name.to_string(),
+            keys: not_found,
+        };
+        let ev_bytes = to_bytes::<_, 128>(&ev)?;
+        ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        handle_incoming_record, negotiate_capabilities, removed_record_key, send_records,
+        send_tree_overview,
+    };
+    use crate::db::Error;
+    use crate::index::{Action, IndexData, TreeIndex, TypeErasedTree};
+    use crate::record::{Record, RecordHeader, RecordMeta, Version};
+    use crate::sync::{
+        ArchivedEvent, ArchivedHotSyncEventKind, Event, HotSyncEvent, HotSyncEventKind,
+    };
+    use chrono::Utc;
+    use futures_util::Sink;
+    use hills_base::{GenericKey, SimpleVersion};
+    use rkyv::{check_archived_root, to_bytes, AlignedVec};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio_tungstenite::tungstenite::{self, Message};
+
+    fn meta(key: GenericKey) -> RecordMeta {
+        RecordMeta {
+            key,
+            version: Version::NonVersioned,
+            modified_by: String::new(),
+            modified_on: [0; 16],
+            modified: Utc::now().into(),
+            created: Utc::now().into(),
+            rkyv_version: SimpleVersion::rkyv_version(),
+        }
+    }
+
+    fn put_record(tree: &sled::Tree, key: GenericKey) {
+        let record = Record {
+            meta_iteration: 0,
+            meta: meta(key),
+            data_iteration: 0,
+            data_evolution: SimpleVersion::new(0, 0),
+            data: AlignedVec::new(),
+        };
+        let record_bytes = to_bytes::<_, 128>(&record).unwrap();
+        tree.insert(key.to_bytes(), record_bytes.as_slice())
+            .unwrap();
+    }
+
+    /// Sink that collects sent messages, aligned so that they can be checked directly.
+    fn capture() -> (
+        Arc<Mutex<Vec<AlignedVec>>>,
+        impl Sink<Message, Error = tungstenite::Error> + Unpin,
+    ) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let ws_tx = Box::pin(futures_util::sink::unfold(
+            sent.clone(),
+            |sent: Arc<Mutex<Vec<AlignedVec>>>, message: Message| async move {
+                let mut aligned = AlignedVec::new();
+                aligned.extend_from_slice(&message.into_data());
+                sent.lock().unwrap().push(aligned);
+                Ok::<_, tungstenite::Error>(sent)
+            },
+        ));
+        (sent, ws_tx)
+    }
+
+    #[test]
+    fn corrupt_record_is_left_out_of_overview() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("items").unwrap();
+        let good = GenericKey::new(1, 0);
+        put_record(&tree, good);
+        tree.insert(GenericKey::new(2, 0).to_bytes(), &b"garbage"[..])
+            .unwrap();
+
+        let (sent, mut ws_tx) = capture();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(send_tree_overview(&db, "items", None, &mut ws_tx))
+            .unwrap();
+
+        let sent = sent.lock().unwrap();
+        let Ok(ArchivedEvent::TreeOverview { records, .. }) =
+            check_archived_root::<Event>(&sent[0])
+        else {
+            panic!("not an overview");
+        };
+        assert_eq!(records.len(), 1);
+        assert!(records.keys().all(|k| GenericKey::from_archived(k) == good));
+    }
+
+    #[test]
+    fn removed_record_is_not_served() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("items").unwrap();
+        let removed = db.open_tree("removed").unwrap();
+        let kept = GenericKey::new(1, 0);
+        let deleted = GenericKey::new(2, 0);
+        put_record(&tree, kept);
+        put_record(&tree, deleted);
+        removed
+            .insert(removed_record_key("items", deleted), &[])
+            .unwrap();
+
+        let keys = to_bytes::<_, 64>(&vec![kept, deleted]).unwrap();
+        let keys = check_archived_root::<Vec<GenericKey>>(&keys).unwrap();
+        let (sent, mut ws_tx) = capture();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(send_records(&db, "items", keys, &mut ws_tx, Some(&removed)))
+            .unwrap();
+
+        let sent = sent.lock().unwrap();
+        let kinds: Vec<(GenericKey, bool)> = sent
+            .iter()
+            .map(|bytes| {
+                let Ok(ArchivedEvent::HotSyncEvent(ev)) = check_archived_root::<Event>(bytes)
+                else {
+                    panic!("not a hot sync event");
+                };
+                let is_removed = matches!(ev.kind, ArchivedHotSyncEventKind::Removed);
+                (GenericKey::from_archived(&ev.key), is_removed)
+            })
+            .collect();
+        assert_eq!(kinds, vec![(kept, false), (deleted, true)]);
+    }
+
+    /// Counts every change it is told about, to see whether a delivery was applied.
+    #[derive(Clone, Default)]
+    struct CountingIndex(Arc<AtomicUsize>);
+
+    impl TreeIndex for CountingIndex {
+        fn rebuild(&mut self, _tree: TypeErasedTree) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn update(
+            &mut self,
+            _tree: TypeErasedTree,
+            _key: GenericKey,
+            _old_data: Option<&IndexData>,
+            _data: &IndexData,
+            _action: Action,
+        ) -> Result<(), Error> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn meta_changed(&mut self, _key: GenericKey, _meta: &RecordMeta) -> Result<(), Error> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn duplicate_delivery_is_idempotent() {
+        let mut db = sled::Config::new().temporary(true).open().unwrap();
+        let key = GenericKey::new(1, 0);
+        let index = CountingIndex::default();
+        let mut indexers: HashMap<String, Vec<Box<dyn TreeIndex + Send>>> = HashMap::new();
+        indexers.insert("idempotent".to_string(), vec![Box::new(index.clone())]);
+        let mut deliver_twice = |db: &mut sled::Db, kind: HotSyncEventKind| {
+            let ev = HotSyncEvent {
+                tree_name: "idempotent".to_string(),
+                key,
+                kind,
+            };
+            let ev_bytes = to_bytes::<_, 128>(&ev).unwrap();
+            let ev = check_archived_root::<HotSyncEvent>(&ev_bytes).unwrap();
+            let before = index.0.load(Ordering::Relaxed);
+            handle_incoming_record(db, ev, "remote", Some(&mut indexers)).unwrap();
+            let first: Vec<_> = db.open_tree("idempotent").unwrap().iter().collect();
+            let applied = index.0.load(Ordering::Relaxed);
+            assert!(applied > before, "first delivery was not applied");
+            handle_incoming_record(db, ev, "remote", Some(&mut indexers)).unwrap();
+            let second: Vec<_> = db.open_tree("idempotent").unwrap().iter().collect();
+            assert_eq!(first, second);
+            assert_eq!(
+                index.0.load(Ordering::Relaxed),
+                applied,
+                "second delivery reached the indexers"
+            );
+        };
+        let iterations = |db: &sled::Db| {
+            let tree = db.open_tree("idempotent").unwrap();
+            let bytes = tree.get(key.to_bytes()).unwrap().unwrap();
+            let header = RecordHeader::check(&bytes).unwrap();
+            (tree.len(), header.meta_iteration(), header.data_iteration())
+        };
+
+        deliver_twice(
+            &mut db,
+            HotSyncEventKind::CreatedOrChanged {
+                meta: meta(key),
+                meta_iteration: 0,
+                data: vec![],
+                data_evolution: SimpleVersion::new(0, 0),
+                data_iteration: 0,
+            },
+        );
+        assert_eq!(iterations(&db), (1, 0, 0));
+        deliver_twice(
+            &mut db,
+            HotSyncEventKind::CreatedOrChanged {
+                meta: meta(key),
+                meta_iteration: 1,
+                data: vec![],
+                data_evolution: SimpleVersion::new(0, 0),
+                data_iteration: 1,
+            },
+        );
+        assert_eq!(iterations(&db), (1, 1, 1));
+        deliver_twice(
+            &mut db,
+            HotSyncEventKind::MetaChanged {
+                meta: meta(key),
+                meta_iteration: 2,
+            },
+        );
+        assert_eq!(iterations(&db), (1, 2, 1));
+        deliver_twice(&mut db, HotSyncEventKind::Removed);
+
+        assert!(db.open_tree("idempotent").unwrap().is_empty());
+    }
+
+    #[test]
+    fn capabilities_are_intersected() {
+        let negotiated = negotiate_capabilities(&["a", "b"], ["b", "c"]);
+        assert_eq!(negotiated.into_iter().collect::<Vec<_>>(), vec!["b"]);
+        assert!(negotiate_capabilities(&[], ["a"]).is_empty());
     }
-    Ok(())
 }
@@ -1,34 +1,73 @@
-use crate::common::{Error, ManagedTrees};
-use crate::consts::{CLIENTS_TREE, KEYS_PER_REQUEST, REMOVED_RECORDS_TREE, SELF_UUID};
+use crate::common::{
+    default_readable_name, record_keys_in, Error, ManagedTrees, OpenMode, WsLimits,
+};
+use crate::consts::{
+    CAPABILITIES, CLAIMED_KEYS_TREE, CLIENTS_TREE, CLIENT_IDS, CLIENT_IDS_CAPABILITY,
+    CLIENT_ID_FLOOR, CLIENT_ID_TREES_TREE, LAST_SEEN_TREE, MAX_KEYS_PER_REQUEST,
+    RECLAIMED_KEYS_TREE, REHOME_CAPABILITY, REMOVED_RECORDS_TREE, RESERVED_CEILING, SELF_UUID,
+};
 use crate::sync::{
     ArchivedEvent, ArchivedHotSyncEventKind, Event, HotSyncEvent, HotSyncEventKind, RecordBorrows,
+    TreeSchema,
 };
 use crate::sync_common::{
-    compare_and_request_missing_records, present_self, send_records, send_tree_overviews,
+    accept_ws, compare_and_request_missing_records, connect_ws, handle_control_message,
+    negotiate_capabilities, present_self, removed_record_key, send_records, send_tree_overview,
+    send_tree_overviews,
 };
 use crate::{handle_result, sync_common};
+use chrono::Utc;
 use futures_util::{Sink, SinkExt, Stream, StreamExt, TryStreamExt};
-use hills_base::GenericKey;
+use hills_base::{GenericKey, SimpleVersion, UtcDateTime};
 use log::{error, info, trace, warn};
-use rkyv::{check_archived_root, to_bytes, Archive, Deserialize, Serialize};
-use sled::{Db, Tree};
-use std::collections::{HashMap, HashSet};
-use std::net::SocketAddr;
+use rkyv::option::ArchivedOption;
+use rkyv::{check_archived_root, to_bytes, AlignedVec, Archive, Deserialize, Serialize};
+use sled::transaction::{abort, ConflictableTransactionError, TransactionError, Transactional};
+use sled::{Db, IVec, Tree};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::ops::Range;
 use std::path::Path;
-use std::sync::Arc;
-use tokio::net::{TcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
 use tokio::runtime::Runtime;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
-use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::{self, Message};
 use uuid::Uuid;
 
 pub struct HillsServer {
     pub join: JoinHandle<()>,
+    db: Db,
+    connected: ConnectedClients,
+    /// Address the server is listening on, useful when started on port 0
+    pub local_addr: SocketAddr,
+}
+
+/// Tunables for HillsServer::start_with_config.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub ws_limits: WsLimits,
+    /// Check out that was not renewed for this long is taken away, so that a client that crashed or went away does not
+    /// hold a record forever. Must be well above ClientConfig::check_out_renewal of the clients.
+    pub check_out_lease: Duration,
+    /// Ids below this value are never issued and are left for TypedTree::insert_at,
+    /// must be the same as ClientConfig::reserved_ceiling of the clients.
+    pub reserved_ceiling: u32,
 }
 
-#[derive(Archive, Default, Debug, Serialize, Deserialize)]
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            ws_limits: WsLimits::default(),
+            check_out_lease: Duration::from_secs(60),
+            reserved_ceiling: RESERVED_CEILING,
+        }
+    }
+}
+
+#[derive(Archive, Clone, Default, Debug, Serialize, Deserialize)]
 #[archive(check_bytes)]
 #[archive_attr(derive(Debug))]
 struct ClientInfo {
@@ -41,7 +80,172 @@ struct ClientInfo {
     // to_replay: Vec<RecordHotChange>,
 }
 
+/// Client that connected to this server at least once, see HillsServer::clients.
+#[derive(Clone, Debug)]
+pub struct KnownClient {
+    pub uuid: Uuid,
+    pub readable_name: String,
+    /// When the client last connected or disconnected
+    pub last_seen: Option<UtcDateTime>,
+    pub is_connected: bool,
+    /// Key ranges issued to the client for each tree, except the ones it already used up
+    pub key_ranges: HashMap<String, Vec<Range<u32>>>,
+}
+
+/// Key ranges of a tree, persisted under the tree name in RECLAIMED_KEYS_TREE (unused ones to issue again)
+/// and CLAIMED_KEYS_TREE (used by moved records, sorted and never issued).
+#[derive(Archive, Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[archive(check_bytes)]
+struct KeyRanges {
+    ranges: Vec<Range<u32>>,
+}
+
+impl KeyRanges {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        // Short values are stored inline by sled and might not be aligned enough for rkyv
+        let mut aligned = AlignedVec::new();
+        aligned.extend_from_slice(bytes);
+        let reclaimed = check_archived_root::<KeyRanges>(&aligned)?;
+        Ok(reclaimed.deserialize(&mut rkyv::Infallible)?)
+    }
+
+    fn contains(&self, id: u32) -> bool {
+        self.ranges.iter().any(|r| r.contains(&id))
+    }
+
+    /// Add one id, keeping the ranges sorted and merging adjacent ones.
+    fn insert(&mut self, id: u32) {
+        if self.contains(id) {
+            return;
+        }
+        let at = self.ranges.partition_point(|r| r.end <= id);
+        let joins_previous = at > 0 && self.ranges[at - 1].end == id;
+        let joins_next = self.ranges.get(at).is_some_and(|r| r.start == id + 1);
+        match (joins_previous, joins_next) {
+            (true, true) => {
+                self.ranges[at - 1].end = self.ranges[at].end;
+                self.ranges.remove(at);
+            }
+            (true, false) => self.ranges[at - 1].end = id + 1,
+            (false, true) => self.ranges[at].start = id,
+            (false, false) => self.ranges.insert(at, id..id + 1),
+        }
+    }
+
+    /// Take one id out, splitting the range it is in. Returns false if it was not there.
+    fn remove(&mut self, id: u32) -> bool {
+        let Some(at) = self.ranges.iter().position(|r| r.contains(&id)) else {
+            return false;
+        };
+        let range = self.ranges.remove(at);
+        if id + 1 < range.end {
+            self.ranges.insert(at, id + 1..range.end);
+        }
+        if range.start < id {
+            self.ranges.insert(at, range.start..id);
+        }
+        true
+    }
+}
+
 impl ClientInfo {
+    /// Remember an issued range, merging it with the previous one if they are adjacent.
+    fn add_key_range(&mut self, tree: impl AsRef<str>, range: Range<u32>) {
+        let ranges = self
+            .key_ranges
+            .entry(tree.as_ref().to_string())
+            .or_default();
+        match ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => ranges.push(range),
+        }
+    }
+
+    /// Drop ranges whose keys were all used to create records, and merge the remaining adjacent ones.
+    /// Ranges with unused keys are always kept, the client might still create records with them.
+    fn compact_key_ranges(
+        &mut self,
+        tree_name: impl AsRef<str>,
+        db: &Db,
+        removed: &Tree,
+    ) -> Result<(), Error> {
+        let tree_name = tree_name.as_ref();
+        let Some(ranges) = self.key_ranges.get_mut(tree_name) else {
+            return Ok(());
+        };
+        let tree = db.open_tree(tree_name)?;
+        let mut consumed = Vec::new();
+        for (idx, range) in ranges.iter().enumerate() {
+            let ids = GenericKey::id_range(range.clone());
+            let is_first_revision =
+                |key: &[u8]| GenericKey::from_bytes(key).map(|k| k.revision == 0) == Some(true);
+            let mut created = record_keys_in(&tree, ids.clone())
+                .filter(|key| key.revision == 0)
+                .count() as u32;
+            let mut removed_start = tree_name.as_bytes().to_vec();
+            removed_start.extend_from_slice(&ids.start);
+            let mut removed_end = tree_name.as_bytes().to_vec();
+            removed_end.extend_from_slice(&ids.end);
+            for key in removed.range(removed_start..removed_end).keys() {
+                if is_first_revision(&key?[tree_name.len()..]) {
+                    created += 1;
+                }
+            }
+            if created >= range.end - range.start {
+                consumed.push(idx);
+            }
+        }
+        for idx in consumed.into_iter().rev() {
+            let range = ranges.remove(idx);
+            trace!("{tree_name}/{range:?} is used up, forgetting");
+        }
+        // Reclaimed keys are issued out of order, so neighbours are not always next to each other
+        ranges.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<u32>> = Vec::with_capacity(ranges.len());
+        for range in ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => merged.push(range),
+            }
+        }
+        *ranges = merged;
+        Ok(())
+    }
+
+    /// Parts of the issued ranges after the last key that was used to create a record.
+    ///
+    /// Keys in between used ones are not included, a record with such key might still exist on the client only.
+    fn unused_key_ranges(
+        &self,
+        db: &Db,
+        removed: &Tree,
+    ) -> Result<Vec<(String, Range<u32>)>, Error> {
+        let mut unused = vec![];
+        for (tree_name, ranges) in &self.key_ranges {
+            let tree = db.open_tree(tree_name)?;
+            for range in ranges {
+                let ids = GenericKey::id_range(range.clone());
+                let mut first_unused = range.start;
+                if let Some(key) = record_keys_in(&tree, ids.clone()).next_back() {
+                    first_unused = first_unused.max(key.id + 1);
+                }
+                let mut removed_start = tree_name.as_bytes().to_vec();
+                removed_start.extend_from_slice(&ids.start);
+                let mut removed_end = tree_name.as_bytes().to_vec();
+                removed_end.extend_from_slice(&ids.end);
+                if let Some(key) = removed.range(removed_start..removed_end).keys().next_back() {
+                    if let Some(key) = GenericKey::from_bytes(&key?[tree_name.len()..]) {
+                        first_unused = first_unused.max(key.id + 1);
+                    }
+                }
+                if first_unused < range.end {
+                    unused.push((tree_name.clone(), first_unused..range.end));
+                }
+            }
+        }
+        Ok(unused)
+    }
+
     fn owns_key(&self, tree: impl AsRef<str>, key: GenericKey) -> bool {
         if let Some(ranges) = self.key_ranges.get(tree.as_ref()) {
             for r in ranges {
@@ -66,33 +270,95 @@ struct TreeInfo {
 struct State {
     remote_addr: SocketAddr,
     info: Option<ClientInfo>,
+    /// Optional protocol features both sides support, known after PresentSelf
+    capabilities: HashSet<String>,
+    /// Client was unlinked from another server, ids in its overviews are checked with claim_moved_ids
+    rehomed: bool,
+    /// Ids the client was told to move its records away from, see Event::IdsTaken
+    taken_ids: HashSet<(String, u32)>,
 }
 
 impl State {
     fn client_name(&self) -> String {
         match &self.info {
-            Some(info) => format!("{}({})", info.readable_name, Uuid::from_bytes(info.uuid)),
+            Some(info) => format!(
+                "'{}'({})({})",
+                info.readable_name,
+                self.remote_addr,
+                Uuid::from_bytes(info.uuid)
+            ),
             None => format!("{}", self.remote_addr),
         }
     }
 }
 
+/// State shared between all client event loops.
+#[derive(Clone, Default)]
+struct SharedState {
+    borrows: Arc<RwLock<RecordBorrows>>,
+    schemas: Arc<RwLock<AdvertisedSchemas>>,
+    connected: ConnectedClients,
+    /// Set when this server is a read replica of another one
+    upstream: Option<SocketAddr>,
+    /// See ServerConfig::reserved_ceiling
+    reserved_ceiling: u32,
+}
+
+/// client -> number of its open connections, only counted after PresentSelf
+type ConnectedClients = Arc<Mutex<HashMap<Uuid, usize>>>;
+
+/// tree name -> client -> schema it advertised in the last tree overview
+type AdvertisedSchemas = HashMap<String, HashMap<Uuid, TreeSchema>>;
+
 #[derive(Clone)]
 enum BroadcastEvent {
-    Sync(HotSyncEvent),
+    /// Change to relay to clients, except the one it came from (None if it came from upstream).
+    /// Source address is never sent to clients.
+    Sync {
+        event: HotSyncEvent,
+        source: Option<SocketAddr>,
+    },
     BorrowsChanged(String, Vec<GenericKey>),
+    /// New tree to announce to clients, except the one that created it (None if it came from upstream).
+    TreeCreated {
+        tree: String,
+        schema: Option<TreeSchema>,
+        source: Option<SocketAddr>,
+    },
+    SchemaDrift {
+        tree: String,
+        peer: Uuid,
+        their_evolution: SimpleVersion,
+        notify: Vec<Uuid>,
+    },
 }
 
 impl HillsServer {
-    pub fn start<P: AsRef<Path>, A: ToSocketAddrs + Send + 'static>(
+    /// Start serving clients on the provided address.
+    ///
+    /// If upstream is provided, this server acts as a read replica: it connects to the upstream server as a client,
+    /// pulls all the records and check outs from it and relays them to its own clients. Replica does not accept
+    /// changes, key requests or check outs, clients that write must be connected to the primary server.
+    pub fn start<P: AsRef<Path>, A: ToSocketAddrs>(
         path: P,
+        mode: OpenMode,
         addr: A,
+        upstream: Option<SocketAddr>,
         rt: &Runtime,
     ) -> Result<Self, Error> {
-        #[cfg(not(test))]
-        let db = sled::open(path)?;
-        #[cfg(test)]
-        let db = sled::Config::new().temporary(true).path(path).open()?;
+        Self::start_with_config(path, mode, addr, upstream, rt, ServerConfig::default())
+    }
+
+    /// Same as start, but with non-default tunables.
+    pub fn start_with_config<P: AsRef<Path>, A: ToSocketAddrs>(
+        path: P,
+        mode: OpenMode,
+        addr: A,
+        upstream: Option<SocketAddr>,
+        rt: &Runtime,
+        config: ServerConfig,
+    ) -> Result<Self, Error> {
+        let db = mode.open(path.as_ref())?;
 
         if !db.contains_key(SELF_UUID)? {
             let uuid = Uuid::new_v4();
@@ -101,25 +367,149 @@ impl HillsServer {
             db.insert(SELF_UUID, &uuid_bytes)?;
         }
 
+        let listener = std::net::TcpListener::bind(addr)
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|e| Error::Internal(format!("bind: {e}")))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| Error::Internal(format!("local_addr: {e}")))?;
+        let shared = SharedState {
+            upstream,
+            reserved_ceiling: config.reserved_ceiling,
+            ..Default::default()
+        };
+        let connected = shared.connected.clone();
+        let db_clone = db.clone();
+        let listener = {
+            // Registering with the reactor needs the runtime context
+            let _guard = rt.enter();
+            TcpListener::from_std(listener)
+                .map_err(|e| Error::Internal(format!("listener: {e}")))?
+        };
         let join = rt.spawn(async move {
-            let listener = TcpListener::bind(addr).await.unwrap();
-            ws_server_acceptor(listener, db).await;
+            ws_server_acceptor(listener, db_clone, shared, config).await;
         });
 
-        Ok(HillsServer { join })
+        Ok(HillsServer {
+            join,
+            db,
+            connected,
+            local_addr,
+        })
+    }
+
+    /// All clients that ever connected to this server and were not pruned yet.
+    pub fn clients(&self) -> Result<Vec<KnownClient>, Error> {
+        let connected = self
+            .connected
+            .lock()
+            .map_err(|_| Error::Internal("connected clients lock poisoned".to_string()))?;
+        let clients = self.db.open_tree(CLIENTS_TREE)?;
+        let last_seen = self.db.open_tree(LAST_SEEN_TREE)?;
+        let mut known = vec![];
+        for client_info_bytes in clients.iter().values() {
+            let client_info_bytes = client_info_bytes?;
+            let client_info = check_archived_root::<ClientInfo>(&client_info_bytes)?;
+            let client_info: ClientInfo = client_info.deserialize(&mut rkyv::Infallible)?;
+            let uuid = Uuid::from_bytes(client_info.uuid);
+            known.push(KnownClient {
+                uuid,
+                readable_name: client_info.readable_name,
+                last_seen: load_last_seen(&last_seen, uuid)?,
+                is_connected: connected.contains_key(&uuid),
+                key_ranges: client_info.key_ranges,
+            });
+        }
+        Ok(known)
+    }
+
+    /// Forget clients that are not connected and were not seen for longer than absent_for, returns the forgotten ones.
+    ///
+    /// If reclaim_keys is true, unused parts of their key ranges are given out to other clients again.
+    /// Only do so for clients that will not come back: one that still has keys from a reclaimed range in its pool
+    /// would create records with the same keys as the clients that got the range.
+    /// A pruned client that connects again is treated as a new one.
+    pub fn prune_clients(
+        &self,
+        absent_for: Duration,
+        reclaim_keys: bool,
+    ) -> Result<Vec<Uuid>, Error> {
+        // Held throughout, so that a client cannot connect while its info is being removed
+        let connected = self
+            .connected
+            .lock()
+            .map_err(|_| Error::Internal("connected clients lock poisoned".to_string()))?;
+        let absent_for = chrono::Duration::from_std(absent_for)
+            .map_err(|e| Error::Internal(format!("absent_for: {e}")))?;
+        let now = Utc::now();
+        let clients = self.db.open_tree(CLIENTS_TREE)?;
+        let removed = self.db.open_tree(REMOVED_RECORDS_TREE)?;
+        let last_seen_tree = self.db.open_tree(LAST_SEEN_TREE)?;
+        let mut pruned = vec![];
+        for entry in clients.iter() {
+            let (uuid_bytes, client_info_bytes) = entry?;
+            let client_info = check_archived_root::<ClientInfo>(&client_info_bytes)?;
+            let client_info: ClientInfo = client_info.deserialize(&mut rkyv::Infallible)?;
+            let uuid = Uuid::from_bytes(client_info.uuid);
+            let last_seen = load_last_seen(&last_seen_tree, uuid)?;
+            let is_absent = match last_seen {
+                Some(last_seen) => now - chrono::DateTime::<Utc>::from(last_seen) > absent_for,
+                None => true,
+            };
+            if connected.contains_key(&uuid) || !is_absent {
+                continue;
+            }
+            if reclaim_keys {
+                for (tree_name, range) in client_info.unused_key_ranges(&self.db, &removed)? {
+                    info!(
+                        "Reclaiming {tree_name}/{range:?} from {}({uuid})",
+                        client_info.readable_name
+                    );
+                    reclaim_key_range(&self.db, &tree_name, range)?;
+                }
+            }
+            info!(
+                "Pruning client {}({uuid}), last seen {:?}",
+                client_info.readable_name, last_seen
+            );
+            clients.remove(uuid_bytes)?;
+            last_seen_tree.remove(uuid.as_bytes())?;
+            pruned.push(uuid);
+        }
+        Ok(pruned)
     }
 }
 
-async fn ws_server_acceptor(listener: TcpListener, db: Db) {
+async fn ws_server_acceptor(
+    listener: TcpListener,
+    db: Db,
+    shared: SharedState,
+    config: ServerConfig,
+) {
     info!("Server event loop started");
+    let ws_limits = config.ws_limits;
     let (broadcast_tx, broadcast_rx) = postage::broadcast::channel(256);
     drop(broadcast_rx);
-    let borrows = Arc::new(RwLock::new(RecordBorrows::default()));
+    if let Some(upstream) = shared.upstream {
+        let db = db.clone();
+        let broadcast_tx = broadcast_tx.clone();
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            upstream_event_loop(upstream, db, ws_limits, broadcast_tx, shared).await
+        });
+    } else {
+        // Replica mirrors check outs of the upstream server, leases are only kept by the primary one
+        let broadcast_tx = broadcast_tx.clone();
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            lease_expiry_loop(shared, config.check_out_lease, broadcast_tx).await
+        });
+    }
     loop {
         match listener.accept().await {
             Ok((tcp_stream, remote_addr)) => {
                 info!("Got new connection from: {remote_addr}");
-                let ws_stream = match tokio_tungstenite::accept_async(tcp_stream).await {
+                let ws_stream = match accept_ws(tcp_stream, ws_limits).await {
                     Ok(ws_stream) => ws_stream,
                     Err(e) => {
                         warn!("Error during the websocket handshake occurred {e:?}");
@@ -133,12 +523,15 @@ async fn ws_server_acceptor(listener: TcpListener, db: Db) {
                 let state = State {
                     remote_addr,
                     info: None,
+                    capabilities: HashSet::new(),
+                    rehomed: false,
+                    taken_ids: HashSet::new(),
                 };
                 let rx = broadcast_tx.subscribe();
                 let tx = broadcast_tx.clone();
-                let borrows = borrows.clone();
+                let shared = shared.clone();
                 tokio::spawn(async move {
-                    ws_event_loop(ws_sink, ws_source, db_clone, state, rx, tx, borrows).await
+                    ws_event_loop(ws_sink, ws_source, db_clone, state, rx, tx, shared).await
                 });
             }
             Err(e) => {
@@ -149,13 +542,13 @@ async fn ws_server_acceptor(listener: TcpListener, db: Db) {
 }
 
 async fn ws_event_loop(
-    mut ws_tx: impl Sink<Message> + Unpin,
+    mut ws_tx: impl Sink<Message, Error = tungstenite::Error> + Unpin,
     mut ws_rx: impl Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
     mut db: Db,
     mut state: State,
     mut broadcast_rx: postage::broadcast::Receiver<BroadcastEvent>,
     mut broadcast_tx: postage::broadcast::Sender<BroadcastEvent>,
-    borrows: Arc<RwLock<RecordBorrows>>,
+    shared: SharedState,
 ) {
     info!("Event loop for {}: started", state.remote_addr);
     let r = present_self(&db, &mut ws_tx).await;
@@ -163,7 +556,13 @@ async fn ws_event_loop(
 
     use postage::prelude::Stream;
 
-    let removed = db.open_tree(REMOVED_RECORDS_TREE).unwrap();
+    let removed = match db.open_tree(REMOVED_RECORDS_TREE) {
+        Ok(removed) => removed,
+        Err(e) => {
+            error!("Event loop for {}: {e:?}", state.remote_addr);
+            return;
+        }
+    };
     loop {
         tokio::select! {
             message = ws_rx.try_next() => {
@@ -172,14 +571,14 @@ async fn ws_event_loop(
                         if let Message::Close(_) = &message {
                             break;
                         }
-                        let r = process_message(message, &mut ws_tx, &mut db, &mut state, &mut broadcast_tx, &removed, &borrows).await;
+                        let r = process_message(message, &mut ws_tx, &mut db, &mut state, &mut broadcast_tx, &removed, &shared).await;
                         handle_result!(r);
                     }
                     Ok(None) => {
                         break;
                     }
                     Err(e) => {
-                        warn!("{e}");
+                        error!("{}: {}", state.client_name(), Error::from(e));
                         break;
                     }
                 }
@@ -190,8 +589,8 @@ async fn ws_event_loop(
                     continue
                 };
                 match event {
-                    BroadcastEvent::Sync(event) => {
-                        if event.source_addr != Some(state.remote_addr) {
+                    BroadcastEvent::Sync { event, source } => {
+                        if source != Some(state.remote_addr) {
                             trace!("relaying event to {}", state.client_name());
                             let Ok(ev_bytes) = to_bytes::<_, 128>(&Event::HotSyncEvent(event)) else {
                                 error!("relay serialize error");
@@ -204,7 +603,7 @@ async fn ws_event_loop(
                         }
                     }
                     BroadcastEvent::BorrowsChanged(tree, keys) => {
-                        let borrows = &borrows.read().await.borrows;
+                        let borrows = &shared.borrows.read().await.borrows;
                         let Some(borrowed_keys) = borrows.get(tree.as_str()) else {
                             continue
                         };
@@ -226,27 +625,76 @@ async fn ws_event_loop(
                             }
                         }
                     }
+                    BroadcastEvent::TreeCreated { tree, schema, source } => {
+                        if source == Some(state.remote_addr) {
+                            continue
+                        }
+                        let Ok(ev_bytes) = to_bytes::<_, 128>(&Event::TreeCreated { tree, schema }) else {
+                            error!("tree created serialize error");
+                            continue
+                        };
+                        let r = ws_tx.send(Message::Binary(ev_bytes.to_vec())).await;
+                        if r.is_err() {
+                            warn!("relay error");
+                        }
+                    }
+                    BroadcastEvent::SchemaDrift { tree, peer, their_evolution, notify } => {
+                        let Some(info) = &state.info else {
+                            continue
+                        };
+                        if !notify.contains(&Uuid::from_bytes(info.uuid)) {
+                            continue
+                        }
+                        let Ok(ev_bytes) = to_bytes::<_, 128>(&Event::SchemaDrift {
+                            tree,
+                            peer: peer.into_bytes(),
+                            their_evolution,
+                        }) else {
+                            error!("schema drift serialize error");
+                            continue
+                        };
+                        let r = ws_tx.send(Message::Binary(ev_bytes.to_vec())).await;
+                        if r.is_err() {
+                            warn!("relay error");
+                        }
+                    }
                 }
             }
         }
     }
 
+    if let Some(info) = &state.info {
+        let uuid = Uuid::from_bytes(info.uuid);
+        for tree_schemas in shared.schemas.write().await.values_mut() {
+            tree_schemas.remove(&uuid);
+        }
+        if let Err(e) = touch_client(&db, uuid) {
+            warn!("{}: last seen not updated: {e:?}", state.client_name());
+        }
+        if let Ok(mut connected) = shared.connected.lock() {
+            if let Some(count) = connected.get_mut(&uuid) {
+                *count -= 1;
+                if *count == 0 {
+                    connected.remove(&uuid);
+                }
+            }
+        }
+    }
     info!("Event loop {}: exiting", state.client_name());
 }
 
 async fn process_message(
     ws_message: Message,
-    mut ws_tx: impl Sink<Message> + Unpin,
+    mut ws_tx: impl Sink<Message, Error = tungstenite::Error> + Unpin,
     db: &mut Db,
     state: &mut State,
     broadcast_tx: &mut postage::broadcast::Sender<BroadcastEvent>,
     removed: &Tree,
-    borrows: &Arc<RwLock<RecordBorrows>>,
+    shared: &SharedState,
 ) -> Result<(), Error> {
     use postage::prelude::Sink;
     let Message::Binary(bytes) = ws_message else {
-        warn!("Unsupported ws message: {ws_message:?}");
-        return Ok(());
+        return handle_control_message(ws_message, &mut ws_tx).await;
     };
 
     let client_event = check_archived_root::<Event>(&bytes)?;
@@ -254,14 +702,31 @@ async fn process_message(
         ArchivedEvent::PresentSelf {
             uuid,
             readable_name,
+            capabilities,
         } => {
             trace!("Client presenting uuid: {}", Uuid::from_bytes(*uuid));
+            state.capabilities =
+                negotiate_capabilities(CAPABILITIES, capabilities.iter().map(|c| c.as_str()));
+            if state.info.is_none() {
+                // Counted before reading the info, so that prune_clients cannot remove it in the meantime
+                if let Ok(mut connected) = shared.connected.lock() {
+                    *connected.entry(Uuid::from_bytes(*uuid)).or_default() += 1;
+                }
+            }
+            // Older clients present a blank name when none was set
+            let readable_name = match readable_name.trim() {
+                "" => default_readable_name(Uuid::from_bytes(*uuid)),
+                name => name.to_string(),
+            };
             let clients = db.open_tree(CLIENTS_TREE)?;
             let client_info = if let Some(client_info_bytes) = clients.get(uuid)? {
                 let client_info = check_archived_root::<ClientInfo>(&client_info_bytes)?;
-                let client_info: ClientInfo =
+                let mut client_info: ClientInfo =
                     client_info.deserialize(&mut rkyv::Infallible).expect("");
                 trace!("Known client {client_info:?}");
+                client_info.readable_name = readable_name;
+                let client_info_bytes = to_bytes::<_, 128>(&client_info)?;
+                clients.insert(uuid, client_info_bytes.as_slice())?;
                 client_info
             } else {
                 trace!(
@@ -269,41 +734,88 @@ async fn process_message(
                     state.remote_addr,
                     Uuid::from_bytes(*uuid)
                 );
-                let mut client_info = ClientInfo {
+                let client_info = ClientInfo {
                     uuid: *uuid,
-                    readable_name: readable_name.to_string(),
+                    readable_name,
                     ..Default::default()
                 };
                 let client_info_bytes = to_bytes::<_, 128>(&client_info)?;
                 clients.insert(uuid, client_info_bytes.as_slice())?;
-                client_info.readable_name =
-                    format!("'{}'({})", client_info.readable_name, state.remote_addr);
                 client_info
             };
+            touch_client(db, Uuid::from_bytes(*uuid))?;
             state.info = Some(client_info);
-            send_tree_overviews(db, &mut ws_tx).await?;
-            send_current_borrows(borrows, &mut ws_tx).await?;
+            trace!(
+                "{}: negotiated capabilities {:?}",
+                state.client_name(),
+                state.capabilities
+            );
+            send_tree_overviews(db, &HashMap::new(), &mut ws_tx).await?;
+            send_current_borrows(&shared.borrows, &mut ws_tx).await?;
         }
-        ArchivedEvent::GetTreeOverview { .. } => {}
-        ArchivedEvent::TreeOverview { tree, records } => {
+        ArchivedEvent::GetTreeOverview { tree } => {
+            trace!("{}: GetTreeOverview for {tree}", state.client_name());
+            // Asked for after moving records away from taken ids, changes with them are the server's records now
+            state
+                .taken_ids
+                .retain(|(taken_in, _)| taken_in != tree.as_str());
+            send_tree_overview(db, tree.as_str(), None, &mut ws_tx).await?;
+        }
+        ArchivedEvent::TreeOverview {
+            tree,
+            records,
+            schema,
+        } => {
             trace!("Got {}/{tree} overview {records:?}", state.client_name());
-            let info_key = format!("{tree}_info");
-            if !db.contains_key(info_key.as_bytes())? {
-                trace!("New tree {tree}");
-                ManagedTrees::add_to_managed(db, tree)?;
-                let tree_info = TreeInfo {
-                    next_key: 0,
-                    ..Default::default()
+            if ensure_tree_info(db, tree, shared.reserved_ceiling)? {
+                let schema = match schema {
+                    ArchivedOption::Some(schema) => {
+                        Some(schema.deserialize(&mut rkyv::Infallible)?)
+                    }
+                    ArchivedOption::None => None,
                 };
-                let tree_info_bytes = to_bytes::<_, 0>(&tree_info)?;
-                db.insert(info_key.as_bytes(), tree_info_bytes.as_slice())?;
+                announce_tree(tree, schema, Some(state.remote_addr), broadcast_tx).await?;
             }
             if let Some(info) = &mut state.info {
                 info.subscribed_to.insert(tree.to_string());
             }
+            if let (Some(info), ArchivedOption::Some(schema)) = (&state.info, schema) {
+                let schema: TreeSchema = schema.deserialize(&mut rkyv::Infallible)?;
+                check_schema_drift(
+                    tree.as_str(),
+                    Uuid::from_bytes(info.uuid),
+                    schema,
+                    &shared.schemas,
+                    &mut ws_tx,
+                    broadcast_tx,
+                )
+                .await?;
+            }
 
+            let client_name = state.client_name();
+            // Replicas do not issue keys
+            if let (true, Some(info), None) = (state.rehomed, &mut state.info, shared.upstream) {
+                let ids: BTreeSet<u32> = records
+                    .keys()
+                    .map(|key| GenericKey::from_archived(key).id)
+                    .filter(|id| (shared.reserved_ceiling..CLIENT_ID_FLOOR).contains(id))
+                    .collect();
+                let taken = claim_moved_ids(db, tree, ids, shared.reserved_ceiling, info, removed)?;
+                if !taken.is_empty() {
+                    warn!("{client_name}: {tree}/{taken:?} were issued by this server already, asking to move them");
+                    state
+                        .taken_ids
+                        .extend(taken.iter().map(|id| (tree.to_string(), *id)));
+                    let ev = Event::IdsTaken {
+                        tree: tree.to_string(),
+                        ids: taken,
+                    };
+                    let ev_bytes = to_bytes::<_, 128>(&ev)?;
+                    ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
+                }
+            }
             let found_in_removed =
-                compare_and_request_missing_records(db, tree, records, &mut ws_tx, Some(&removed))
+                compare_and_request_missing_records(db, tree, records, &mut ws_tx, Some(removed))
                     .await?;
             if !found_in_removed.is_empty() {
                 trace!("To be removed on client: {found_in_removed:?}");
@@ -312,86 +824,104 @@ async fn process_message(
                 let ev = Event::HotSyncEvent(HotSyncEvent {
                     tree_name: tree.to_string(),
                     key,
-                    source_addr: None,
                     kind: HotSyncEventKind::Removed,
                 });
                 let ev_bytes = to_bytes::<_, 128>(&ev)?;
-                ws_tx
-                    .send(Message::Binary(ev_bytes.to_vec()))
-                    .await
-                    .map_err(|_| Error::Ws)?;
+                ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
             }
         }
-        ArchivedEvent::GetKeySet { tree } => {
-            trace!("{}: GetKeySet for {tree}", state.client_name());
+        ArchivedEvent::GetKeySet { tree, .. } if shared.upstream.is_some() => {
+            refuse_on_replica(tree, Vec::new(), "GetKeySet", state, &mut ws_tx).await?;
+        }
+        ArchivedEvent::CheckOut { tree, keys, .. }
+        | ArchivedEvent::Return { tree, keys }
+        | ArchivedEvent::CancelCheckOut { tree, keys }
+        | ArchivedEvent::RenewCheckOut { tree, keys }
+            if shared.upstream.is_some() =>
+        {
+            let keys = keys.iter().map(GenericKey::from_archived).collect();
+            refuse_on_replica(tree, keys, "check out change", state, &mut ws_tx).await?;
+        }
+        ArchivedEvent::HotSyncEvent(hot_sync_event) if shared.upstream.is_some() => {
+            let key = GenericKey::from_archived(&hot_sync_event.key);
+            refuse_on_replica(
+                &hot_sync_event.tree_name,
+                vec![key],
+                "change",
+                state,
+                &mut ws_tx,
+            )
+            .await?;
+        }
+        ArchivedEvent::GetKeySet { tree, count } => {
+            trace!("{}: GetKeySet for {count} {tree} keys", state.client_name());
             let Some(client_info) = &mut state.info else {
                 return Ok(());
             };
-            // TODO: use transaction here, but only access through tx_db in the closure
-            // let next_key = db.transaction::<_, _, Error>(|db_tx| {
-            let next_key = {
-                let key = format!("{tree}_info");
-                if let Some(tree_info_bytes) = db.get(key.as_bytes())? {
-                    let tree_info = check_archived_root::<TreeInfo>(&tree_info_bytes)?;
-                    let next_key: u32 = tree_info.next_key;
-                    trace!("next_key is {next_key}");
-                    let tree_info = TreeInfo {
-                        next_key: next_key + KEYS_PER_REQUEST,
-                        ..Default::default()
-                    };
-                    let tree_info_bytes = to_bytes::<_, 0>(&tree_info)?;
-                    db.insert(key.as_bytes(), tree_info_bytes.as_slice())?;
-                    next_key
-                } else {
-                    error!("No {key} record");
-                    return Ok(());
-                }
-                // }).unwrap();
-            };
-            let new_range = next_key..next_key + KEYS_PER_REQUEST;
-            let ev = Event::KeySet {
-                tree: tree.to_string(),
-                keys: new_range.clone(),
+            // Key request for a new tree can arrive before its overview
+            if ensure_tree_info(db, tree, shared.reserved_ceiling)? {
+                announce_tree(tree, None, Some(state.remote_addr), broadcast_tx).await?;
+            }
+            client_info.compact_key_ranges(tree.as_str(), db, removed)?;
+            let Some(new_range) =
+                issue_key_block(db, tree, *count, shared.reserved_ceiling, client_info)?
+            else {
+                error!("{}: keys exhausted for {tree}", state.client_name());
+                let ev = Event::KeysExhausted {
+                    tree: tree.to_string(),
+                };
+                let ev_bytes = to_bytes::<_, 128>(&ev)?;
+                ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
+                return Ok(());
             };
-
-            client_info
-                .key_ranges
-                .entry(tree.to_string())
-                .and_modify(|ranges| ranges.push(new_range.clone()))
-                .or_insert(vec![new_range]);
-
-            let client_info_bytes = to_bytes::<_, 128>(client_info)?;
-            let clients = db.open_tree(CLIENTS_TREE)?;
-            clients.insert(client_info.uuid, client_info_bytes.as_slice())?;
-
             trace!(
-                "issued: {}/{}..{} to {}",
+                "issued: {}/{:?} to {}",
                 tree,
-                next_key,
-                next_key + KEYS_PER_REQUEST,
+                new_range,
                 state.client_name()
             );
+            let ev = Event::KeySet {
+                tree: tree.to_string(),
+                keys: new_range,
+            };
             let ev_bytes = to_bytes::<_, 128>(&ev)?;
-            ws_tx
-                .send(Message::Binary(ev_bytes.to_vec()))
-                .await
-                .map_err(|_| Error::Ws)?;
+            ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
         }
-        ArchivedEvent::CheckOut { tree, keys } | ArchivedEvent::Return { tree, keys } => {
+        ArchivedEvent::CheckOut { tree, keys, .. }
+        | ArchivedEvent::Return { tree, keys }
+        | ArchivedEvent::CancelCheckOut { tree, keys } => {
             let Some(client_info) = &state.info else {
                 warn!("CheckOut | Return: no client_info");
                 return Ok(());
             };
             let uuid = Uuid::from_bytes(client_info.uuid);
             let is_checking_out = matches!(client_event, ArchivedEvent::CheckOut { .. });
-            let borrows = &mut borrows.write().await.borrows;
-            let borrowed_keys = borrows.entry(tree.as_str().to_string()).or_default();
+            let is_waiting = matches!(client_event, ArchivedEvent::CheckOut { wait: true, .. });
+            let is_cancelling = matches!(client_event, ArchivedEvent::CancelCheckOut { .. });
+            let borrows = &mut *shared.borrows.write().await;
+            let borrowed_keys = borrows
+                .borrows
+                .entry(tree.as_str().to_string())
+                .or_default();
+            let leased_at = borrows
+                .leased_at
+                .entry(tree.as_str().to_string())
+                .or_default();
             let mut queue_changed = false;
             for key in keys.iter() {
                 let key = GenericKey::from_archived(key);
                 let queue = borrowed_keys.entry(key).or_default();
+                let holder = queue.first().copied();
                 if is_checking_out {
-                    if !queue.contains(&uuid) {
+                    if !is_waiting && !queue.is_empty() && !queue.contains(&uuid) {
+                        // Client is not queued, but still gets the current queue to learn who holds the record
+                        queue_changed = true;
+                        trace!(
+                            "CheckOut without waiting from {}, {tree}/{key} is checked out by {}",
+                            state.client_name(),
+                            queue[0]
+                        );
+                    } else if !queue.contains(&uuid) {
                         queue.push(uuid);
                         queue_changed = true;
                         trace!(
@@ -400,13 +930,39 @@ async fn process_message(
                             queue
                         );
                     } else {
-                        warn!(
-                            "CheckOut while already waiting from {}, {tree}/{key}",
+                        // Queue is sent again, so that the client gets a reply to every CheckOut
+                        queue_changed = true;
+                        trace!(
+                            "CheckOut while already queued from {}, {tree}/{key}",
                             state.client_name(),
                         );
                     }
+                } else if is_cancelling {
+                    match queue.iter().position(|waiting| *waiting == uuid) {
+                        Some(0) => {
+                            warn!(
+                                "CancelCheckOut from {}, but {tree}/{key} is already checked out by it, must be released instead",
+                                state.client_name(),
+                            );
+                        }
+                        Some(position) => {
+                            queue.remove(position);
+                            queue_changed = true;
+                            trace!(
+                                "CancelCheckOut from {}, {tree}/{key} queue: {:?}",
+                                state.client_name(),
+                                queue
+                            );
+                        }
+                        None => {
+                            trace!(
+                                "CancelCheckOut from {}, but it is not waiting for {tree}/{key}",
+                                state.client_name(),
+                            );
+                        }
+                    }
                 } else {
-                    let is_our_borrow = queue.get(0) == Some(&uuid);
+                    let is_our_borrow = queue.first() == Some(&uuid);
                     if is_our_borrow {
                         queue.remove(0);
                         queue_changed = true;
@@ -421,19 +977,82 @@ async fn process_message(
                                 );
                     }
                 }
+                if queue.first() != holder.as_ref() {
+                    start_lease(leased_at, key, queue, Instant::now());
+                }
             }
 
             if queue_changed {
                 broadcast_tx
                     .send(BroadcastEvent::BorrowsChanged(
                         tree.to_string(),
-                        keys.iter().map(|k| GenericKey::from_archived(k)).collect(),
+                        keys.iter().map(GenericKey::from_archived).collect(),
                     ))
                     .await
                     .map_err(|_| Error::PostageBroadcast)?;
             }
         }
-        ArchivedEvent::KeySet { .. } | ArchivedEvent::CheckedOut { .. } => {
+        ArchivedEvent::RenewCheckOut { tree, keys } => {
+            let Some(client_info) = &state.info else {
+                warn!("RenewCheckOut: no client_info");
+                return Ok(());
+            };
+            let uuid = Uuid::from_bytes(client_info.uuid);
+            let borrows = &mut *shared.borrows.write().await;
+            let Some(borrowed_keys) = borrows.borrows.get(tree.as_str()) else {
+                return Ok(());
+            };
+            let leased_at = borrows
+                .leased_at
+                .entry(tree.as_str().to_string())
+                .or_default();
+            let now = Instant::now();
+            for key in keys.iter() {
+                let key = GenericKey::from_archived(key);
+                let holder = borrowed_keys.get(&key).and_then(|queue| queue.first());
+                if holder == Some(&uuid) {
+                    leased_at.insert(key, now);
+                } else {
+                    trace!(
+                        "RenewCheckOut from {}, but {tree}/{key} is not checked out by it",
+                        state.client_name()
+                    );
+                }
+            }
+        }
+        ArchivedEvent::ClientIdTree { tree } => {
+            if !state.capabilities.contains(CLIENT_IDS_CAPABILITY) {
+                warn!(
+                    "{}: {tree} declared with client chosen ids without {CLIENT_IDS_CAPABILITY}, ignoring",
+                    state.client_name()
+                );
+                return Ok(());
+            }
+            trace!("{}: {tree} ids are chosen by clients", state.client_name());
+            db.open_tree(CLIENT_ID_TREES_TREE)?
+                .insert(tree.as_bytes(), &[])?;
+        }
+        ArchivedEvent::Rehomed => {
+            if !state.capabilities.contains(REHOME_CAPABILITY) || state.info.is_none() {
+                warn!(
+                    "{}: Rehomed without {REHOME_CAPABILITY} or before PresentSelf, ignoring",
+                    state.client_name()
+                );
+                return Ok(());
+            }
+            info!(
+                "{}: moved over from another server, checking its ids",
+                state.client_name()
+            );
+            state.rehomed = true;
+        }
+        ArchivedEvent::KeySet { .. }
+        | ArchivedEvent::KeysExhausted { .. }
+        | ArchivedEvent::IdsTaken { .. }
+        | ArchivedEvent::Refused { .. }
+        | ArchivedEvent::CheckedOut { .. }
+        | ArchivedEvent::SchemaDrift { .. }
+        | ArchivedEvent::TreeCreated { .. } => {
             warn!("{}: wrong message", state.client_name());
         }
         ArchivedEvent::HotSyncEvent(hot_sync_event) => {
@@ -449,17 +1068,38 @@ async fn process_message(
                 hot_sync_event.kind
             );
 
-            let tree_name_len = tree_name.as_bytes().len();
-            let mut removed_records_key = Vec::with_capacity(tree_name_len + 8);
-            removed_records_key.extend_from_slice(tree_name.as_bytes());
-            removed_records_key.extend_from_slice(&key.to_bytes());
+            if state.taken_ids.contains(&(tree_name.to_string(), key.id)) {
+                trace!("{remote_name}: {tree_name}/{key} uses an id issued elsewhere, ignoring");
+                return Ok(());
+            }
+            let removed_records_key = removed_record_key(tree_name, key);
             match hot_sync_event.kind {
                 ArchivedHotSyncEventKind::CreatedOrChanged { meta_iteration, .. }
                 | ArchivedHotSyncEventKind::MetaChanged { meta_iteration, .. } => {
-                    if meta_iteration == 0 && !client_info.owns_key(tree_name, key) {
+                    // Next revisions of a record are created by whoever branches it, not only by the owner of its id
+                    let is_creation = meta_iteration == 0
+                        && key.revision == 0
+                        && key.id >= shared.reserved_ceiling;
+                    if is_creation && CLIENT_IDS.contains(&key.id) {
+                        if !state.capabilities.contains(CLIENT_IDS_CAPABILITY)
+                            || !db
+                                .open_tree(CLIENT_ID_TREES_TREE)?
+                                .contains_key(tree_name)?
+                        {
+                            let reason = "ids in this tree are issued by the server";
+                            refuse(tree_name, vec![key], "creation", reason, state, &mut ws_tx)
+                                .await?;
+                            return Ok(());
+                        }
+                    } else if is_creation && !client_info.owns_key(tree_name, key) {
                         warn!("{remote_name} tried to create {tree_name}{key} with a key it doesn't own, ignoring");
                         return Ok(());
                     }
+                    if sync_common::is_id_collision(&db.open_tree(tree_name)?, hot_sync_event)? {
+                        let reason = "id is already used by a record created elsewhere";
+                        refuse(tree_name, vec![key], "change", reason, state, &mut ws_tx).await?;
+                        return Ok(());
+                    }
                     if removed.contains_key(&removed_records_key).unwrap_or(false) {
                         warn!("{remote_name} tried to create or change previously deleted record: {tree_name}/{key}, ignoring");
                         return Ok(());
@@ -467,22 +1107,31 @@ async fn process_message(
                 }
                 ArchivedHotSyncEventKind::Removed => {
                     removed.insert(&removed_records_key, &[])?;
-                    if let Some(borrowed_keys) = borrows.write().await.borrows.get_mut(tree_name) {
+                    let borrows = &mut *shared.borrows.write().await;
+                    if let Some(borrowed_keys) = borrows.borrows.get_mut(tree_name) {
                         borrowed_keys.remove(&key);
                     }
+                    if let Some(leased_at) = borrows.leased_at.get_mut(tree_name) {
+                        leased_at.remove(&key);
+                    }
                 }
             }
             sync_common::handle_incoming_record(db, hot_sync_event, &remote_name, None)?;
-            let mut hot_sync_event_owned: HotSyncEvent =
+            let hot_sync_event_owned: HotSyncEvent =
                 hot_sync_event.deserialize(&mut rkyv::Infallible).expect("");
-            hot_sync_event_owned.source_addr = Some(state.remote_addr);
             broadcast_tx
-                .send(BroadcastEvent::Sync(hot_sync_event_owned))
+                .send(BroadcastEvent::Sync {
+                    event: hot_sync_event_owned,
+                    source: Some(state.remote_addr),
+                })
                 .await
                 .map_err(|_| Error::PostageBroadcast)?;
         }
         ArchivedEvent::RequestRecords { tree, keys } => {
-            send_records(db, tree.as_str(), keys, &mut ws_tx, Some(state.remote_addr)).await?;
+            send_records(db, tree.as_str(), keys, &mut ws_tx, Some(removed)).await?;
+        }
+        ArchivedEvent::RecordsNotFound { tree, keys } => {
+            trace!("{}: does not have {tree}/{keys:?}", state.client_name());
         }
     }
     Ok(())
@@ -505,9 +1154,614 @@ async fn process_message(
 //     false
 // }
 
+/// Mark tree as managed and create its info record if it is not known yet, returns true if tree was created.
+fn ensure_tree_info(db: &Db, tree: &str, reserved_ceiling: u32) -> Result<bool, Error> {
+    let info_key = format!("{tree}_info");
+    if db.contains_key(info_key.as_bytes())? {
+        return Ok(false);
+    }
+    trace!("New tree {tree}");
+    ManagedTrees::add_to_managed(db, tree)?;
+    let tree_info = TreeInfo {
+        next_key: reserved_ceiling,
+        ..Default::default()
+    };
+    let tree_info_bytes = to_bytes::<_, 0>(&tree_info)?;
+    db.insert(info_key.as_bytes(), tree_info_bytes.as_slice())?;
+    Ok(true)
+}
+
+/// Let clients know about a tree they might not have opened yet.
+async fn announce_tree(
+    tree: &str,
+    schema: Option<TreeSchema>,
+    source: Option<SocketAddr>,
+    broadcast_tx: &mut postage::broadcast::Sender<BroadcastEvent>,
+) -> Result<(), Error> {
+    use postage::prelude::Sink;
+    broadcast_tx
+        .send(BroadcastEvent::TreeCreated {
+            tree: tree.to_string(),
+            schema,
+            source,
+        })
+        .await
+        .map_err(|_| Error::PostageBroadcast)
+}
+
+/// Tell the client that a mutating request was not applied, this server only mirrors its upstream.
+async fn refuse_on_replica(
+    tree: &str,
+    keys: Vec<GenericKey>,
+    what: &str,
+    state: &State,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    refuse(tree, keys, what, "read replica", state, ws_tx).await
+}
+
+/// Tell the client that a mutating request was not applied and why.
+async fn refuse(
+    tree: &str,
+    keys: Vec<GenericKey>,
+    what: &str,
+    reason: &str,
+    state: &State,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+) -> Result<(), Error> {
+    warn!(
+        "{}: {what} in {tree} {keys:?} refused: {reason}",
+        state.client_name()
+    );
+    let ev = Event::Refused {
+        tree: tree.to_string(),
+        keys,
+        reason: reason.to_string(),
+    };
+    let ev_bytes = to_bytes::<_, 128>(&ev)?;
+    ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
+    Ok(())
+}
+
+/// Keep a connection to the upstream server, reconnecting if it is lost.
+async fn upstream_event_loop(
+    upstream: SocketAddr,
+    mut db: Db,
+    ws_limits: WsLimits,
+    mut broadcast_tx: postage::broadcast::Sender<BroadcastEvent>,
+    shared: SharedState,
+) {
+    let removed = match db.open_tree(REMOVED_RECORDS_TREE) {
+        Ok(removed) => removed,
+        Err(e) => {
+            error!("Upstream {upstream}: {e:?}");
+            return;
+        }
+    };
+    loop {
+        let url = format!("ws://{upstream}");
+        info!("Connecting to upstream {url}");
+        match connect_ws(&url, ws_limits).await {
+            Ok(ws_stream) => {
+                let (mut ws_tx, mut ws_rx) = StreamExt::split(ws_stream);
+                upstream_session(
+                    &mut ws_tx,
+                    &mut ws_rx,
+                    &mut db,
+                    &mut broadcast_tx,
+                    &removed,
+                    &shared,
+                )
+                .await;
+                warn!("Upstream {upstream} disconnected");
+            }
+            Err(e) => {
+                warn!("Upstream {upstream}: {e:?}");
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+async fn upstream_session(
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    ws_rx: &mut (impl Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin),
+    db: &mut Db,
+    broadcast_tx: &mut postage::broadcast::Sender<BroadcastEvent>,
+    removed: &Tree,
+    shared: &SharedState,
+) {
+    let r = present_self(db, ws_tx).await;
+    handle_result!(r);
+    // Upstream will respond with removes for records that were deleted while disconnected
+    let r = send_tree_overviews(db, &HashMap::new(), ws_tx).await;
+    handle_result!(r);
+    loop {
+        match ws_rx.try_next().await {
+            Ok(Some(Message::Close(_))) | Ok(None) => break,
+            Ok(Some(message)) => {
+                let r = process_upstream_message(message, ws_tx, db, broadcast_tx, removed, shared)
+                    .await;
+                handle_result!(r);
+            }
+            Err(e) => {
+                error!("Upstream: {}", Error::from(e));
+                break;
+            }
+        }
+    }
+}
+
+async fn process_upstream_message(
+    ws_message: Message,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    db: &mut Db,
+    broadcast_tx: &mut postage::broadcast::Sender<BroadcastEvent>,
+    removed: &Tree,
+    shared: &SharedState,
+) -> Result<(), Error> {
+    use postage::prelude::Sink;
+    let Message::Binary(bytes) = ws_message else {
+        return handle_control_message(ws_message, ws_tx).await;
+    };
+
+    let upstream_event = check_archived_root::<Event>(&bytes)?;
+    match upstream_event {
+        ArchivedEvent::PresentSelf {
+            uuid, capabilities, ..
+        } => {
+            trace!("Upstream uuid is: {}", Uuid::from_bytes(*uuid));
+            let capabilities =
+                negotiate_capabilities(CAPABILITIES, capabilities.iter().map(|c| c.as_str()));
+            trace!("Upstream negotiated capabilities {capabilities:?}");
+        }
+        ArchivedEvent::GetTreeOverview { tree } => {
+            send_tree_overview(db, tree.as_str(), None, ws_tx).await?;
+        }
+        ArchivedEvent::TreeOverview { tree, records, .. } => {
+            trace!("Got upstream {tree} overview {records:?}");
+            if ensure_tree_info(db, tree, shared.reserved_ceiling)? {
+                announce_tree(tree, None, None, broadcast_tx).await?;
+            }
+            compare_and_request_missing_records(db, tree, records, ws_tx, None).await?;
+        }
+        ArchivedEvent::RequestRecords { tree, keys } => {
+            send_records(db, tree.as_str(), keys, ws_tx, None).await?;
+        }
+        ArchivedEvent::RecordsNotFound { tree, keys } => {
+            trace!("Upstream does not have {tree}/{keys:?}");
+        }
+        ArchivedEvent::HotSyncEvent(hot_sync_event) => {
+            let tree_name = hot_sync_event.tree_name.as_str();
+            let key = GenericKey::from_archived(&hot_sync_event.key);
+            trace!(
+                "Got upstream sync {tree_name}/{key}: {}",
+                hot_sync_event.kind
+            );
+            if let ArchivedHotSyncEventKind::Removed = hot_sync_event.kind {
+                removed.insert(removed_record_key(tree_name, key), &[])?;
+            }
+            if ensure_tree_info(db, tree_name, shared.reserved_ceiling)? {
+                announce_tree(tree_name, None, None, broadcast_tx).await?;
+            }
+            sync_common::handle_incoming_record(db, hot_sync_event, "upstream", None)?;
+            let hot_sync_event_owned: HotSyncEvent =
+                hot_sync_event.deserialize(&mut rkyv::Infallible).expect("");
+            // No source address, so that it is relayed to all the clients of this replica
+            broadcast_tx
+                .send(BroadcastEvent::Sync {
+                    event: hot_sync_event_owned,
+                    source: None,
+                })
+                .await
+                .map_err(|_| Error::PostageBroadcast)?;
+        }
+        ArchivedEvent::CheckedOut { tree, key, queue } => {
+            let key = GenericKey::from_archived(key);
+            let queue = queue.iter().map(|uuid| Uuid::from_bytes(*uuid)).collect();
+            shared
+                .borrows
+                .write()
+                .await
+                .borrows
+                .entry(tree.to_string())
+                .or_default()
+                .insert(key, queue);
+            broadcast_tx
+                .send(BroadcastEvent::BorrowsChanged(tree.to_string(), vec![key]))
+                .await
+                .map_err(|_| Error::PostageBroadcast)?;
+        }
+        ArchivedEvent::SchemaDrift { .. } => {}
+        ArchivedEvent::TreeCreated { tree, schema } => {
+            if ensure_tree_info(db, tree, shared.reserved_ceiling)? {
+                let schema = match schema {
+                    ArchivedOption::Some(schema) => {
+                        Some(schema.deserialize(&mut rkyv::Infallible)?)
+                    }
+                    ArchivedOption::None => None,
+                };
+                announce_tree(tree, schema, None, broadcast_tx).await?;
+            }
+        }
+        ArchivedEvent::GetKeySet { .. }
+        | ArchivedEvent::KeySet { .. }
+        | ArchivedEvent::KeysExhausted { .. }
+        | ArchivedEvent::Refused { .. }
+        | ArchivedEvent::CheckOut { .. }
+        | ArchivedEvent::Return { .. }
+        | ArchivedEvent::CancelCheckOut { .. }
+        | ArchivedEvent::RenewCheckOut { .. }
+        | ArchivedEvent::ClientIdTree { .. }
+        | ArchivedEvent::Rehomed
+        | ArchivedEvent::IdsTaken { .. } => {
+            warn!("Unexpected event from upstream");
+        }
+    }
+    Ok(())
+}
+
+/// Advance next_key of a tree and record the issued range in client_info, None if the id space is used up.
+///
+/// Client asks for count keys, at most MAX_KEYS_PER_REQUEST are issued and fewer once the id space runs low.
+/// Both are done in one transaction, so that concurrent requests from different clients never get overlapping ranges.
+fn issue_key_block(
+    db: &Db,
+    tree: &str,
+    count: u32,
+    reserved_ceiling: u32,
+    client_info: &mut ClientInfo,
+) -> Result<Option<Range<u32>>, Error> {
+    let count = count.clamp(1, MAX_KEYS_PER_REQUEST);
+    let info_key = format!("{tree}_info");
+    let clients = db.open_tree(CLIENTS_TREE)?;
+    let reclaimed = db.open_tree(RECLAIMED_KEYS_TREE)?;
+    let claimed = db.open_tree(CLAIMED_KEYS_TREE)?;
+    let issued = (&**db, &clients, &reclaimed, &claimed).transaction(
+        |(tx_db, tx_clients, tx_reclaimed, tx_claimed)| {
+            let mut reclaimed: KeyRanges = match tx_reclaimed.get(tree.as_bytes())? {
+                Some(reclaimed_bytes) => KeyRanges::from_bytes(&reclaimed_bytes)
+                    .map_err(ConflictableTransactionError::Abort)?,
+                None => KeyRanges::default(),
+            };
+            let new_range = if let Some(range) = reclaimed.ranges.first_mut() {
+                // Keys taken back from pruned clients are given out first
+                let end = range.end.min(range.start.saturating_add(count));
+                let new_range = range.start..end;
+                range.start = end;
+                if range.start == range.end {
+                    reclaimed.ranges.remove(0);
+                }
+                let reclaimed_bytes = to_bytes::<_, 128>(&reclaimed)
+                    .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                tx_reclaimed.insert(tree.as_bytes(), reclaimed_bytes.as_slice())?;
+                trace!("re-issuing reclaimed {new_range:?}");
+                new_range
+            } else {
+                let Some(tree_info_bytes) = tx_db.get(info_key.as_bytes())? else {
+                    return abort(Error::Internal(format!("No {info_key} record")));
+                };
+                let tree_info = check_archived_root::<TreeInfo>(&tree_info_bytes)
+                    .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                // Trees created before reserved range was introduced or raised might still be below it
+                let mut next_key: u32 = tree_info.next_key.max(reserved_ceiling);
+                trace!("next_key is {next_key}");
+                // Ids used by records moved over from another server are stepped over, block stops right before them
+                let mut claimed = match tx_claimed.get(tree.as_bytes())? {
+                    Some(claimed_bytes) => KeyRanges::from_bytes(&claimed_bytes)
+                        .map_err(ConflictableTransactionError::Abort)?,
+                    None => KeyRanges::default(),
+                };
+                let claimed_before = claimed.ranges.len();
+                while let Some(range) = claimed.ranges.first().filter(|r| r.start <= next_key) {
+                    next_key = next_key.max(range.end);
+                    claimed.ranges.remove(0);
+                }
+                if claimed.ranges.len() != claimed_before {
+                    let claimed_bytes = to_bytes::<_, 128>(&claimed)
+                        .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                    tx_claimed.insert(tree.as_bytes(), claimed_bytes.as_slice())?;
+                }
+                let Some(mut new_range) = next_key_block(next_key, count) else {
+                    return Ok(None);
+                };
+                if let Some(range) = claimed.ranges.first() {
+                    new_range.end = new_range.end.min(range.start);
+                }
+                let tree_info = TreeInfo {
+                    next_key: new_range.end,
+                    ..Default::default()
+                };
+                let tree_info_bytes = to_bytes::<_, 0>(&tree_info)
+                    .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                tx_db.insert(info_key.as_bytes(), tree_info_bytes.as_slice())?;
+                new_range
+            };
+
+            // Transaction might be retried, so work on a copy and only keep it once committed
+            let mut updated_info = client_info.clone();
+            updated_info.add_key_range(tree, new_range.clone());
+            let client_info_bytes = to_bytes::<_, 128>(&updated_info)
+                .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            tx_clients.insert(&updated_info.uuid, client_info_bytes.as_slice())?;
+            Ok(Some((new_range, updated_info)))
+        },
+    );
+    match issued {
+        Ok(Some((new_range, updated_info))) => {
+            *client_info = updated_info;
+            Ok(Some(new_range))
+        }
+        Ok(None) => Ok(None),
+        Err(TransactionError::Abort(e)) => Err(e),
+        Err(TransactionError::Storage(e)) => Err(e.into()),
+    }
+}
+
+/// Check the ids that records of a client moved over from another server use in a tree, see Event::Rehomed.
+///
+/// Ids this server never issued, at or past next_key or taken back by prune_clients, are given to the client
+/// one by one and skipped by issue_key_block from then on, next_key itself does not move.
+/// Ids below next_key were issued to some client already, those and ids of records this server has (or had)
+/// are returned as taken, unless they were issued to this client.
+fn claim_moved_ids(
+    db: &Db,
+    tree: &str,
+    ids: BTreeSet<u32>,
+    reserved_ceiling: u32,
+    client_info: &mut ClientInfo,
+    removed: &Tree,
+) -> Result<Vec<u32>, Error> {
+    let data = db.open_tree(tree)?;
+    let mut taken = Vec::new();
+    let mut candidates = Vec::new();
+    for id in ids {
+        if client_info.owns_key(tree, GenericKey::new(id, 0)) {
+            continue;
+        }
+        let has_records = record_keys_in(&data, GenericKey::id_range(id..id + 1))
+            .next()
+            .is_some();
+        let mut removed_prefix = tree.as_bytes().to_vec();
+        removed_prefix.extend_from_slice(&id.to_be_bytes());
+        if has_records || removed.scan_prefix(removed_prefix).next().is_some() {
+            taken.push(id);
+        } else {
+            candidates.push(id);
+        }
+    }
+    if candidates.is_empty() {
+        return Ok(taken);
+    }
+
+    let info_key = format!("{tree}_info");
+    let clients = db.open_tree(CLIENTS_TREE)?;
+    let reclaimed = db.open_tree(RECLAIMED_KEYS_TREE)?;
+    let claimed = db.open_tree(CLAIMED_KEYS_TREE)?;
+    let outcome = (&**db, &clients, &reclaimed, &claimed).transaction(
+        |(tx_db, tx_clients, tx_reclaimed, tx_claimed)| {
+            let Some(tree_info_bytes) = tx_db.get(info_key.as_bytes())? else {
+                return abort(Error::Internal(format!("No {info_key} record")));
+            };
+            let tree_info = check_archived_root::<TreeInfo>(&tree_info_bytes)
+                .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            let next_key: u32 = tree_info.next_key.max(reserved_ceiling);
+            let load = |bytes: Option<IVec>| match bytes {
+                Some(bytes) => KeyRanges::from_bytes(&bytes),
+                None => Ok(KeyRanges::default()),
+            };
+            let mut reclaimed_keys = load(tx_reclaimed.get(tree.as_bytes())?)
+                .map_err(ConflictableTransactionError::Abort)?;
+            let mut claimed_keys = load(tx_claimed.get(tree.as_bytes())?)
+                .map_err(ConflictableTransactionError::Abort)?;
+
+            // Transaction might be retried, so work on copies and only keep them once committed
+            let mut updated_info = client_info.clone();
+            let mut newly_taken = Vec::new();
+            for &id in &candidates {
+                let is_free = if id >= next_key {
+                    !claimed_keys.contains(id)
+                } else {
+                    reclaimed_keys.remove(id)
+                };
+                if is_free {
+                    if id >= next_key {
+                        claimed_keys.insert(id);
+                    }
+                    updated_info.add_key_range(tree, id..id + 1);
+                } else {
+                    newly_taken.push(id);
+                }
+            }
+            for (tx_tree, keys) in [(tx_reclaimed, &reclaimed_keys), (tx_claimed, &claimed_keys)] {
+                let bytes = to_bytes::<_, 128>(keys)
+                    .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                tx_tree.insert(tree.as_bytes(), bytes.as_slice())?;
+            }
+            let client_info_bytes = to_bytes::<_, 128>(&updated_info)
+                .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            tx_clients.insert(&updated_info.uuid, client_info_bytes.as_slice())?;
+            Ok((newly_taken, updated_info))
+        },
+    );
+    match outcome {
+        Ok((newly_taken, updated_info)) => {
+            *client_info = updated_info;
+            taken.extend(newly_taken);
+            taken.sort_unstable();
+            Ok(taken)
+        }
+        Err(TransactionError::Abort(e)) => Err(e),
+        Err(TransactionError::Storage(e)) => Err(e.into()),
+    }
+}
+
+/// Put a range back to be issued again by issue_key_block.
+fn reclaim_key_range(db: &Db, tree: &str, range: Range<u32>) -> Result<(), Error> {
+    let reclaimed = db.open_tree(RECLAIMED_KEYS_TREE)?;
+    let mut reclaimed_keys: KeyRanges = match reclaimed.get(tree.as_bytes())? {
+        Some(reclaimed_bytes) => KeyRanges::from_bytes(&reclaimed_bytes)?,
+        None => KeyRanges::default(),
+    };
+    reclaimed_keys.ranges.push(range);
+    let reclaimed_bytes = to_bytes::<_, 128>(&reclaimed_keys)?;
+    reclaimed.insert(tree.as_bytes(), reclaimed_bytes.as_slice())?;
+    Ok(())
+}
+
+/// Remember that a client was seen just now, see HillsServer::clients.
+fn touch_client(db: &Db, uuid: Uuid) -> Result<(), Error> {
+    let last_seen = db.open_tree(LAST_SEEN_TREE)?;
+    let now: UtcDateTime = Utc::now().into();
+    let now_bytes = to_bytes::<_, 32>(&now)?;
+    last_seen.insert(uuid.as_bytes(), now_bytes.as_slice())?;
+    Ok(())
+}
+
+fn load_last_seen(last_seen: &Tree, uuid: Uuid) -> Result<Option<UtcDateTime>, Error> {
+    let Some(last_seen_bytes) = last_seen.get(uuid.as_bytes())? else {
+        return Ok(None);
+    };
+    // Short values are stored inline by sled and might not be aligned enough for rkyv
+    let mut aligned = AlignedVec::new();
+    aligned.extend_from_slice(&last_seen_bytes);
+    let last_seen = check_archived_root::<UtcDateTime>(&aligned)?;
+    Ok(Some(last_seen.deserialize(&mut rkyv::Infallible)?))
+}
+
+/// Next block of up to count ids starting at next_key, shorter if fewer are left below CLIENT_ID_FLOOR.
+/// None once there are none left.
+fn next_key_block(next_key: u32, count: u32) -> Option<Range<u32>> {
+    let left = CLIENT_ID_FLOOR.checked_sub(next_key)?;
+    (left > 0).then(|| next_key..next_key + count.min(left))
+}
+
+/// Remember schema a client is using for a tree and notify it and other clients if it differs from what they advertised.
+async fn check_schema_drift(
+    tree: &str,
+    uuid: Uuid,
+    schema: TreeSchema,
+    schemas: &Arc<RwLock<AdvertisedSchemas>>,
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    broadcast_tx: &mut postage::broadcast::Sender<BroadcastEvent>,
+) -> Result<(), Error> {
+    use postage::prelude::Sink;
+    let drifted: Vec<(Uuid, SimpleVersion)> = {
+        let mut schemas = schemas.write().await;
+        let tree_schemas = schemas.entry(tree.to_string()).or_default();
+        let drifted = tree_schemas
+            .iter()
+            .filter(|(peer, peer_schema)| **peer != uuid && peer_schema.hash != schema.hash)
+            .map(|(peer, peer_schema)| (*peer, peer_schema.evolution))
+            .collect();
+        tree_schemas.insert(uuid, schema);
+        drifted
+    };
+
+    // Sent without holding the lock, so that a slow client does not stall the others
+    let mut drifted_peers = Vec::new();
+    for (peer, their_evolution) in drifted {
+        warn!(
+            "Schema drift in {tree}: {uuid} uses {}, {peer} uses {their_evolution}",
+            schema.evolution
+        );
+        let ev = Event::SchemaDrift {
+            tree: tree.to_string(),
+            peer: peer.into_bytes(),
+            their_evolution,
+        };
+        let ev_bytes = to_bytes::<_, 128>(&ev)?;
+        ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
+        drifted_peers.push(peer);
+    }
+
+    if !drifted_peers.is_empty() {
+        broadcast_tx
+            .send(BroadcastEvent::SchemaDrift {
+                tree: tree.to_string(),
+                peer: uuid,
+                their_evolution: schema.evolution,
+                notify: drifted_peers,
+            })
+            .await
+            .map_err(|_| Error::PostageBroadcast)?;
+    }
+    Ok(())
+}
+
+/// Lease of a record starts over whenever it goes to the next client in the queue.
+fn start_lease(
+    leased_at: &mut HashMap<GenericKey, Instant>,
+    key: GenericKey,
+    queue: &[Uuid],
+    now: Instant,
+) {
+    if queue.is_empty() {
+        leased_at.remove(&key);
+    } else {
+        leased_at.insert(key, now);
+    }
+}
+
+/// Take records away from clients that did not renew their check outs for longer than lease.
+///
+/// Returns keys of each tree that went to the next client in the queue or became free.
+fn expire_leases(
+    borrows: &mut RecordBorrows,
+    lease: Duration,
+    now: Instant,
+) -> Vec<(String, Vec<GenericKey>)> {
+    let mut expired = vec![];
+    for (tree_name, leased_at) in &mut borrows.leased_at {
+        let Some(borrowed_keys) = borrows.borrows.get_mut(tree_name) else {
+            continue;
+        };
+        let mut keys = vec![];
+        for (key, queue) in borrowed_keys.iter_mut() {
+            let Some(since) = leased_at.get(key).copied() else {
+                continue;
+            };
+            if queue.is_empty() || now.saturating_duration_since(since) < lease {
+                continue;
+            }
+            let holder = queue.remove(0);
+            info!("Check out of {tree_name}/{key} by {holder} expired, queue: {queue:?}");
+            start_lease(leased_at, *key, queue, now);
+            keys.push(*key);
+        }
+        if !keys.is_empty() {
+            expired.push((tree_name.clone(), keys));
+        }
+    }
+    expired
+}
+
+async fn lease_expiry_loop(
+    shared: SharedState,
+    lease: Duration,
+    mut broadcast_tx: postage::broadcast::Sender<BroadcastEvent>,
+) {
+    use postage::prelude::Sink;
+    let period = (lease / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let expired = expire_leases(&mut *shared.borrows.write().await, lease, Instant::now());
+        for (tree_name, keys) in expired {
+            if broadcast_tx
+                .send(BroadcastEvent::BorrowsChanged(tree_name, keys))
+                .await
+                .is_err()
+            {
+                warn!("lease expiry: broadcast fail");
+            }
+        }
+    }
+}
+
 async fn send_current_borrows(
     borrows: &Arc<RwLock<RecordBorrows>>,
-    ws_tx: &mut (impl Sink<Message> + Unpin),
+    ws_tx: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
 ) -> Result<(), Error> {
     let borrows = &borrows.read().await.borrows;
     for (tree_name, borrowed_keys) in borrows {
@@ -521,11 +1775,316 @@ async fn send_current_borrows(
                 queue: queue.iter().map(|uuid| *uuid.as_bytes()).collect(),
             };
             let ev_bytes = to_bytes::<_, 128>(&ev)?;
-            ws_tx
-                .send(Message::Binary(ev_bytes.to_vec()))
-                .await
-                .map_err(|_| Error::Ws)?;
+            ws_tx.send(Message::Binary(ev_bytes.to_vec())).await?;
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        claim_moved_ids, ensure_tree_info, expire_leases, issue_key_block, next_key_block,
+        reclaim_key_range, start_lease, ClientInfo, KeyRanges,
+    };
+    use crate::consts::{
+        CLIENT_ID_FLOOR, KEYS_PER_REQUEST, MAX_KEYS_PER_REQUEST, REMOVED_RECORDS_TREE,
+        RESERVED_CEILING,
+    };
+    use crate::sync::RecordBorrows;
+    use hills_base::GenericKey;
+    use rkyv::Deserialize;
+    use std::collections::{HashMap, HashSet};
+    use std::ops::Range;
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    #[test]
+    fn key_block_near_u32_boundary() {
+        assert_eq!(
+            next_key_block(1024, KEYS_PER_REQUEST),
+            Some(1024..1024 + KEYS_PER_REQUEST)
+        );
+        let last_start = CLIENT_ID_FLOOR - KEYS_PER_REQUEST;
+        assert_eq!(
+            next_key_block(last_start, KEYS_PER_REQUEST),
+            Some(last_start..CLIENT_ID_FLOOR)
+        );
+        assert_eq!(
+            next_key_block(last_start + 1, KEYS_PER_REQUEST),
+            Some(last_start + 1..CLIENT_ID_FLOOR)
+        );
+        assert_eq!(
+            next_key_block(CLIENT_ID_FLOOR - 1, KEYS_PER_REQUEST),
+            Some(CLIENT_ID_FLOOR - 1..CLIENT_ID_FLOOR)
+        );
+        assert_eq!(next_key_block(CLIENT_ID_FLOOR, KEYS_PER_REQUEST), None);
+        assert_eq!(next_key_block(u32::MAX, KEYS_PER_REQUEST), None);
+    }
+
+    #[test]
+    fn client_info_in_baseline_layout_is_read() {
+        // Layout of ClientInfo stored by earlier servers, must stay readable
+        #[derive(rkyv::Archive, rkyv::Serialize)]
+        struct BaselineClientInfo {
+            uuid: [u8; 16],
+            key_ranges: HashMap<String, Vec<Range<u32>>>,
+            subscribed_to: HashSet<String>,
+            readable_name: String,
+        }
+        let baseline = BaselineClientInfo {
+            uuid: [7; 16],
+            key_ranges: [("t".to_string(), vec![1024..2024, 3024..4024])].into(),
+            subscribed_to: HashSet::new(),
+            readable_name: "old".to_string(),
+        };
+        let bytes = rkyv::to_bytes::<_, 128>(&baseline).unwrap();
+        let info: ClientInfo = rkyv::check_archived_root::<ClientInfo>(&bytes)
+            .unwrap()
+            .deserialize(&mut rkyv::Infallible)
+            .unwrap();
+        assert_eq!(info.uuid, [7; 16]);
+        assert_eq!(info.readable_name, "old");
+        assert_eq!(info.key_ranges["t"], vec![1024..2024, 3024..4024]);
+    }
+
+    #[test]
+    fn requested_key_count_is_capped() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ensure_tree_info(&db, "t", RESERVED_CEILING).unwrap();
+        let mut info = ClientInfo::default();
+        let small = issue_key_block(&db, "t", 10, RESERVED_CEILING, &mut info)
+            .unwrap()
+            .unwrap();
+        assert_eq!(small.len(), 10);
+        let large = issue_key_block(&db, "t", u32::MAX, RESERVED_CEILING, &mut info)
+            .unwrap()
+            .unwrap();
+        assert_eq!(large, small.end..small.end + MAX_KEYS_PER_REQUEST);
+        assert_eq!(info.key_ranges["t"], vec![small.start..large.end]);
+    }
+
+    #[test]
+    fn keys_start_at_configured_ceiling() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ensure_tree_info(&db, "t", 16).unwrap();
+        let mut info = ClientInfo::default();
+        let first = issue_key_block(&db, "t", 10, 16, &mut info)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, 16..26);
+        // Raised later, ids below the new ceiling are not issued anymore
+        let raised = issue_key_block(&db, "t", 10, 100, &mut info)
+            .unwrap()
+            .unwrap();
+        assert_eq!(raised, 100..110);
+    }
+
+    #[test]
+    fn key_ranges_insert_and_remove_single_ids() {
+        let mut keys = KeyRanges::default();
+        for id in [5, 3, 4, 10, 7] {
+            keys.insert(id);
+        }
+        assert_eq!(keys.ranges, vec![3..6, 7..8, 10..11]);
+        keys.insert(6);
+        assert_eq!(keys.ranges, vec![3..8, 10..11]);
+        assert!(keys.remove(5));
+        assert!(!keys.remove(5));
+        assert_eq!(keys.ranges, vec![3..5, 6..8, 10..11]);
+        assert!(keys.remove(10));
+        assert_eq!(keys.ranges, vec![3..5, 6..8]);
+    }
+
+    #[test]
+    fn moved_ids_are_claimed_one_by_one() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let removed = db.open_tree(REMOVED_RECORDS_TREE).unwrap();
+        ensure_tree_info(&db, "t", RESERVED_CEILING).unwrap();
+        let mut other = ClientInfo {
+            uuid: [1; 16],
+            ..Default::default()
+        };
+        let issued = issue_key_block(&db, "t", 10, RESERVED_CEILING, &mut other)
+            .unwrap()
+            .unwrap();
+        assert_eq!(issued, RESERVED_CEILING..RESERVED_CEILING + 10);
+
+        let mut moved = ClientInfo {
+            uuid: [2; 16],
+            ..Default::default()
+        };
+        let hostile = CLIENT_ID_FLOOR - 1;
+        let ids = [issued.start, issued.end + 1, hostile]
+            .into_iter()
+            .collect();
+        let taken = claim_moved_ids(&db, "t", ids, RESERVED_CEILING, &mut moved, &removed).unwrap();
+        assert_eq!(taken, vec![issued.start]);
+        assert!(moved.owns_key("t", GenericKey::new(issued.end + 1, 0)));
+        assert!(moved.owns_key("t", GenericKey::new(hostile, 0)));
+        assert!(!moved.owns_key("t", GenericKey::new(issued.start, 0)));
+
+        // Claimed ids are stepped over, next_key stays where it was
+        let mut next = ClientInfo {
+            uuid: [3; 16],
+            ..Default::default()
+        };
+        let first = issue_key_block(&db, "t", 10, RESERVED_CEILING, &mut next)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, issued.end..issued.end + 1);
+        let second = issue_key_block(&db, "t", 10, RESERVED_CEILING, &mut next)
+            .unwrap()
+            .unwrap();
+        assert_eq!(second, issued.end + 2..issued.end + 12);
+
+        // Claiming again is refused for ids claimed by another client
+        let mut late = ClientInfo {
+            uuid: [4; 16],
+            ..Default::default()
+        };
+        let ids = [issued.end + 1, hostile, second.start]
+            .into_iter()
+            .collect();
+        let taken = claim_moved_ids(&db, "t", ids, RESERVED_CEILING, &mut late, &removed).unwrap();
+        assert_eq!(taken, vec![issued.end + 1, second.start, hostile]);
+    }
+
+    #[test]
+    fn reclaimed_ids_can_be_claimed() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let removed = db.open_tree(REMOVED_RECORDS_TREE).unwrap();
+        ensure_tree_info(&db, "t", RESERVED_CEILING).unwrap();
+        let mut pruned = ClientInfo::default();
+        let issued = issue_key_block(&db, "t", 10, RESERVED_CEILING, &mut pruned)
+            .unwrap()
+            .unwrap();
+        reclaim_key_range(&db, "t", issued.clone()).unwrap();
+
+        let mut moved = ClientInfo {
+            uuid: [2; 16],
+            ..Default::default()
+        };
+        let ids = [issued.start + 2].into_iter().collect();
+        assert!(
+            claim_moved_ids(&db, "t", ids, RESERVED_CEILING, &mut moved, &removed)
+                .unwrap()
+                .is_empty()
+        );
+        let mut next = ClientInfo {
+            uuid: [3; 16],
+            ..Default::default()
+        };
+        let reissued = issue_key_block(&db, "t", 10, RESERVED_CEILING, &mut next)
+            .unwrap()
+            .unwrap();
+        assert_eq!(reissued, issued.start..issued.start + 2);
+        let reissued = issue_key_block(&db, "t", 10, RESERVED_CEILING, &mut next)
+            .unwrap()
+            .unwrap();
+        assert_eq!(reissued, issued.start + 3..issued.end);
+    }
+
+    #[test]
+    fn concurrent_key_requests_do_not_overlap() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ensure_tree_info(&db, "t", RESERVED_CEILING).unwrap();
+        let handles: Vec<_> = (0..8u8)
+            .map(|client| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    let mut info = ClientInfo {
+                        uuid: [client; 16],
+                        ..Default::default()
+                    };
+                    for _ in 0..16 {
+                        issue_key_block(&db, "t", KEYS_PER_REQUEST, RESERVED_CEILING, &mut info)
+                            .unwrap()
+                            .unwrap();
+                    }
+                    info.key_ranges.remove("t").unwrap()
+                })
+            })
+            .collect();
+        let mut ranges: Vec<_> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+        ranges.sort_by_key(|r| r.start);
+        let issued: u32 = ranges.iter().map(|r| r.end - r.start).sum();
+        assert_eq!(issued, 8 * 16 * KEYS_PER_REQUEST);
+        for pair in ranges.windows(2) {
+            assert!(pair[0].end <= pair[1].start, "{pair:?} overlap");
+        }
+    }
+
+    #[test]
+    fn key_ranges_coalesce_and_compact() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let removed = db.open_tree("removed").unwrap();
+        let mut info = ClientInfo::default();
+        info.add_key_range("t", 0..2);
+        info.add_key_range("t", 2..4);
+        info.add_key_range("t", 10..12);
+        assert_eq!(info.key_ranges["t"], vec![0..4, 10..12]);
+
+        let tree = db.open_tree("t").unwrap();
+        for id in 10..12 {
+            tree.insert(GenericKey::new(id, 0).to_bytes(), &[]).unwrap();
+        }
+        tree.insert(GenericKey::new(0, 0).to_bytes(), &[]).unwrap();
+        tree.insert(GenericKey::new(1, 0).to_bytes(), &[]).unwrap();
+        tree.insert(GenericKey::new(1, 1).to_bytes(), &[]).unwrap();
+        info.compact_key_ranges("t", &db, &removed).unwrap();
+        assert_eq!(info.key_ranges["t"], vec![0..4]);
+
+        let mut removed_key = b"t".to_vec();
+        removed_key.extend_from_slice(&GenericKey::new(2, 0).to_bytes());
+        removed.insert(removed_key, &[]).unwrap();
+        tree.insert(GenericKey::new(3, 0).to_bytes(), &[]).unwrap();
+        info.compact_key_ranges("t", &db, &removed).unwrap();
+        assert!(info.key_ranges["t"].is_empty());
+    }
+
+    #[test]
+    fn ranges_with_unused_keys_are_kept() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let removed = db.open_tree("removed").unwrap();
+        let mut info = ClientInfo::default();
+        for i in (0..40).rev() {
+            info.add_key_range("t", i * 10..i * 10 + 2);
+        }
+        info.add_key_range("t", 2..4);
+        info.compact_key_ranges("t", &db, &removed).unwrap();
+        let ranges = &info.key_ranges["t"];
+        assert_eq!(ranges.len(), 40);
+        assert_eq!(ranges[0], 0..4);
+        assert_eq!(ranges[39], 390..392);
+    }
+
+    #[test]
+    fn expired_check_out_goes_to_next_in_queue() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let (first, second) = (GenericKey::new(1024, 0), GenericKey::new(1025, 0));
+        let mut borrows = RecordBorrows::default();
+        let borrowed_keys = borrows.borrows.entry("items".to_string()).or_default();
+        let leased_at = borrows.leased_at.entry("items".to_string()).or_default();
+        for (key, queue) in [(first, vec![a, b]), (second, vec![a])] {
+            start_lease(leased_at, key, &queue, Instant::now());
+            borrowed_keys.insert(key, queue);
+        }
+        let lease = Duration::from_secs(60);
+
+        let now = Instant::now();
+        assert!(expire_leases(&mut borrows, lease, now).is_empty());
+        let later = now + lease * 2;
+        let mut expired = expire_leases(&mut borrows, lease, later);
+        expired[0].1.sort_by_key(|key| key.id);
+        assert_eq!(expired, vec![("items".to_string(), vec![first, second])]);
+        assert_eq!(borrows.borrows["items"][&first], vec![b]);
+        assert!(borrows.borrows["items"][&second].is_empty());
+        assert!(!borrows.leased_at["items"].contains_key(&second));
+        // Lease of the next client starts when it gets the record
+        assert!(expire_leases(&mut borrows, lease, later).is_empty());
+    }
+}
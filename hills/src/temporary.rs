@@ -0,0 +1,279 @@
+use crate::common::{record_keys_in, Error};
+use crate::consts::{KEY_ID_CEILING, NEXT_TEMPORARY_ID, TEMPORARY_IDS_TREE};
+use crate::index::{Action, IndexData, TreeIndex, TypeErasedTree};
+use crate::journal::{self, Action as JournalAction};
+use crate::key_pool::KeyPool;
+use crate::pending::PendingChanges;
+use crate::record::{ArchivedRecord, Record, RecordMeta};
+use crate::sync::{ChangeKind, RecordHotChange};
+use hills_base::GenericKey;
+use log::{error, trace};
+use rkyv::{check_archived_root, to_bytes, AlignedVec, Deserialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Db, Transactional, Tree};
+use std::collections::BTreeMap;
+
+/// Records created while the key pool is empty get ids from KEY_ID_CEILING upwards, which the server never issues.
+/// They are kept local only, until a key set arrives and assign_global_ids moves them to keys from the pool.
+pub(crate) fn is_temporary(id: u32) -> bool {
+    id >= KEY_ID_CEILING
+}
+
+/// Next temporary id for a data tree, None once all of them were used.
+/// Ids are never reused, so that global_id keeps resolving old ones.
+pub(crate) fn next_temporary_id(data: &Tree) -> Result<Option<u32>, Error> {
+    let mut id = None;
+    data.update_and_fetch(NEXT_TEMPORARY_ID, |next| {
+        let next = next
+            .and_then(|next| next.try_into().ok())
+            .map(u32::from_be_bytes)
+            .unwrap_or(KEY_ID_CEILING);
+        if next == u32::MAX {
+            id = None;
+            return Some(next.to_be_bytes().to_vec());
+        }
+        id = Some(next);
+        Some((next + 1).to_be_bytes().to_vec())
+    })?;
+    Ok(id)
+}
+
+fn mapping_key(tree_name: &str, temporary_id: u32) -> Vec<u8> {
+    let mut key = Vec::with_capacity(tree_name.len() + 1 + 4);
+    key.extend_from_slice(tree_name.as_bytes());
+    key.push(b'/');
+    key.extend_from_slice(&temporary_id.to_be_bytes());
+    key
+}
+
+/// Whether any record in a data tree still waits for a global id.
+pub(crate) fn has_temporary_records(data: &Tree) -> bool {
+    record_keys_in(data, GenericKey::id_range(KEY_ID_CEILING..u32::MAX))
+        .next()
+        .is_some()
+}
+
+/// Global id that replaced a temporary one, None if it was not assigned yet.
+pub(crate) fn global_id(
+    temporary_ids: &Tree,
+    tree_name: &str,
+    temporary_id: u32,
+) -> Result<Option<u32>, Error> {
+    let Some(global_id) = temporary_ids.get(mapping_key(tree_name, temporary_id))? else {
+        return Ok(None);
+    };
+    let global_id: [u8; 4] = global_id
+        .as_ref()
+        .try_into()
+        .map_err(|_| Error::Internal("malformed temporary id mapping".to_string()))?;
+    Ok(Some(u32::from_be_bytes(global_id)))
+}
+
+/// Record that was moved from a temporary key to a global one.
+pub(crate) struct Reassigned {
+    pub temporary: GenericKey,
+    pub global: GenericKey,
+    /// Creation of the record under the global key, to be sent to the server.
+    pub change: RecordHotChange,
+}
+
+/// Move records with temporary ids to keys taken from the pool, all revisions of one id are moved together.
+///
+/// Stops when the pool runs out, remaining records are moved once more keys arrive.
+pub(crate) fn assign_global_ids(
+    db: &Db,
+    tree_name: &str,
+    mut indexers: Option<&mut Vec<Box<dyn TreeIndex + Send>>>,
+) -> Result<Vec<Reassigned>, Error> {
+    let data = db.open_tree(tree_name)?;
+    let temporary_ids = db.open_tree(TEMPORARY_IDS_TREE)?;
+    let journal = journal::journal_of(db, &data, tree_name)?;
+    let mut revisions: BTreeMap<u32, Vec<GenericKey>> = BTreeMap::new();
+    for key in record_keys_in(&data, GenericKey::id_range(KEY_ID_CEILING..u32::MAX)) {
+        revisions.entry(key.id).or_default().push(key);
+    }
+
+    let mut reassigned = Vec::new();
+    for (temporary_id, keys) in revisions {
+        let moved = (&data, &temporary_ids).transaction(|(tx_data, tx_ids)| {
+            let Some(global_id) = KeyPool::take_in(tx_data)? else {
+                return Ok(None);
+            };
+            let mut moved = Vec::new();
+            for &temporary in &keys {
+                let Some(record_bytes) = tx_data.remove(&temporary.to_bytes())? else {
+                    continue;
+                };
+                let record = check_archived_root::<Record>(&record_bytes)
+                    .map_err(|_| ConflictableTransactionError::Abort("check_archived_root"))?;
+                let global = GenericKey::new(global_id, temporary.revision);
+                let record = with_key(record, global);
+                let record_bytes = to_bytes::<_, 128>(&record)
+                    .map_err(|_| ConflictableTransactionError::Abort("to_bytes"))?;
+                tx_data.insert(&global.to_bytes(), record_bytes.as_slice())?;
+                moved.push((temporary, global, record, record_bytes));
+            }
+            tx_ids.insert(
+                mapping_key(tree_name, temporary_id),
+                &global_id.to_be_bytes(),
+            )?;
+            Ok(Some(moved))
+        });
+        let moved = match moved {
+            Ok(Some(moved)) => moved,
+            Ok(None) => break,
+            Err(TransactionError::Abort(e)) => return Err(Error::Internal(e.to_string())),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        };
+
+        for (temporary, global, record, record_bytes) in moved {
+            trace!("{tree_name}: temporary {temporary} is now {global}");
+            record_move(
+                tree_name,
+                &data,
+                journal.as_ref(),
+                indexers.as_deref_mut(),
+                (temporary, global),
+                &record,
+                &record_bytes,
+            )?;
+            reassigned.push(Reassigned {
+                temporary,
+                global,
+                change: RecordHotChange {
+                    tree: tree_name.to_string(),
+                    key: global,
+                    meta_iteration: record.meta_iteration,
+                    data_iteration: record.data_iteration,
+                    kind: ChangeKind::CreateOrChange,
+                },
+            });
+        }
+    }
+    Ok(reassigned)
+}
+
+/// Move records whose ids the server already issued to other clients to temporary ids, all revisions of one id
+/// together, so that they get new global ids the same way as records created offline, see Event::IdsTaken.
+///
+/// Their pending changes are dropped, the server never applied them. Returns old and temporary keys of moved records.
+pub(crate) fn move_to_temporary(
+    db: &Db,
+    pending: &Tree,
+    tree_name: &str,
+    ids: impl IntoIterator<Item = u32>,
+    mut indexers: Option<&mut Vec<Box<dyn TreeIndex + Send>>>,
+) -> Result<Vec<(GenericKey, GenericKey)>, Error> {
+    let data = db.open_tree(tree_name)?;
+    let journal = journal::journal_of(db, &data, tree_name)?;
+    let mut moved = Vec::new();
+    for id in ids {
+        let keys: Vec<GenericKey> =
+            record_keys_in(&data, GenericKey::id_range(id..id + 1)).collect();
+        if keys.is_empty() {
+            continue;
+        }
+        let Some(temporary_id) = next_temporary_id(&data)? else {
+            return Err(Error::Internal(format!(
+                "{tree_name}: no temporary ids left to move {id} to"
+            )));
+        };
+        for key in keys {
+            let Some(record_bytes) = data.get(key.to_bytes())? else {
+                continue;
+            };
+            let temporary = GenericKey::new(temporary_id, key.revision);
+            let record = with_key(check_archived_root::<Record>(&record_bytes)?, temporary);
+            let record_bytes = to_bytes::<_, 128>(&record)?;
+            let mut batch = sled::Batch::default();
+            batch.remove(&key.to_bytes());
+            batch.insert(&temporary.to_bytes(), record_bytes.as_slice());
+            data.apply_batch(batch)?;
+            PendingChanges::forget(pending, tree_name, key)?;
+            trace!("{tree_name}: {key} is taken, moved to {temporary}");
+            record_move(
+                tree_name,
+                &data,
+                journal.as_ref(),
+                indexers.as_deref_mut(),
+                (key, temporary),
+                &record,
+                &record_bytes,
+            )?;
+            moved.push((key, temporary));
+        }
+    }
+    Ok(moved)
+}
+
+/// Copy of a record under another key.
+fn with_key(record: &ArchivedRecord, key: GenericKey) -> Record {
+    let mut meta: RecordMeta = record.meta.deserialize(&mut rkyv::Infallible).expect("");
+    meta.key = key;
+    let mut data = AlignedVec::new();
+    data.extend_from_slice(record.data.as_slice());
+    Record {
+        meta_iteration: record.meta_iteration,
+        meta,
+        data_iteration: record.data_iteration,
+        data_evolution: record.data_evolution.as_original(),
+        data,
+    }
+}
+
+/// Journal and index a record that was moved from one key to another.
+fn record_move(
+    tree_name: &str,
+    data: &Tree,
+    journal: Option<&Tree>,
+    indexers: Option<&mut Vec<Box<dyn TreeIndex + Send>>>,
+    (from, to): (GenericKey, GenericKey),
+    record: &Record,
+    record_bytes: &[u8],
+) -> Result<(), Error> {
+    journal::append(journal, from, || JournalAction::Remove)?;
+    journal::append(journal, to, || JournalAction::Create(record_bytes.to_vec()))?;
+    let Some(indexers) = indexers else {
+        return Ok(());
+    };
+    let tree = TypeErasedTree {
+        tree: data,
+        evolution: record.data_evolution,
+    };
+    let index_data = IndexData::new(&record.data);
+    for indexer in indexers.iter_mut() {
+        let r = indexer
+            .update(tree, from, None, &index_data, Action::Remove)
+            .and_then(|_| indexer.update(tree, to, None, &index_data, Action::Insert))
+            .and_then(|_| indexer.meta_changed(to, &record.meta));
+        if let Err(e) = r {
+            error!("indexer failed on moving {tree_name}:{from} to {to} {e:?}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assign_global_ids, global_id, is_temporary, next_temporary_id};
+    use crate::consts::{KEY_ID_CEILING, TEMPORARY_IDS_TREE};
+    use crate::key_pool::KeyPool;
+
+    #[test]
+    fn temporary_ids_are_not_reused() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let data = db.open_tree("items").unwrap();
+        let first = next_temporary_id(&data).unwrap().unwrap();
+        let second = next_temporary_id(&data).unwrap().unwrap();
+        assert_eq!(first, KEY_ID_CEILING);
+        assert_eq!(second, KEY_ID_CEILING + 1);
+        assert!(is_temporary(first) && !is_temporary(KEY_ID_CEILING - 1));
+
+        // Nothing to move, keys stay in the pool
+        KeyPool::feed_for(&data, 2000..2001).unwrap();
+        assert!(assign_global_ids(&db, "items", None).unwrap().is_empty());
+        assert_eq!(KeyPool::stats_for(&data).unwrap(), 1);
+        let temporary_ids = db.open_tree(TEMPORARY_IDS_TREE).unwrap();
+        assert_eq!(global_id(&temporary_ids, "items", first).unwrap(), None);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::sync_client::VhrdDbTelem;
+use futures_util::{Sink, Stream};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{interval, Instant, Interval, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// Bytes of binary messages that went through a Metered stream since the last sample.
+#[derive(Default)]
+struct ByteCounters {
+    sent: AtomicUsize,
+    received: AtomicUsize,
+}
+
+/// Websocket stream that counts bytes of binary messages going both ways, without touching the telemetry lock.
+pub(crate) struct Metered<S> {
+    inner: S,
+    counters: Arc<ByteCounters>,
+}
+
+impl<S> Metered<S> {
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> Stream for Metered<S>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let message = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(Message::Binary(bytes)))) = &message {
+            self.counters
+                .received
+                .fetch_add(bytes.len(), Ordering::Relaxed);
+        }
+        message
+    }
+}
+
+impl<S> Sink<Message> for Metered<S>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    type Error = tungstenite::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message) -> Result<(), Self::Error> {
+        let len = match &message {
+            Message::Binary(bytes) => bytes.len(),
+            _ => 0,
+        };
+        Pin::new(&mut self.inner).start_send(message)?;
+        self.counters.sent.fetch_add(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Turns byte counts of the current connection into totals and per second rates in SyncClientTelemetry.
+pub(crate) struct Throughput {
+    interval: Interval,
+    counters: Arc<ByteCounters>,
+    last_sample: Instant,
+}
+
+impl Throughput {
+    pub fn new() -> Self {
+        let mut interval = interval(Duration::from_secs(1));
+        // Rates are computed from the actual time passed, late ticks are not worth catching up
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Throughput {
+            interval,
+            counters: Arc::new(ByteCounters::default()),
+            last_sample: Instant::now(),
+        }
+    }
+
+    /// Start counting a fresh connection, rates are measured from now on.
+    pub fn meter<S>(&mut self, inner: S) -> Metered<S> {
+        self.interval.reset();
+        self.last_sample = Instant::now();
+        Metered {
+            inner,
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Resolves once a second, only polled while connected.
+    pub async fn tick(&mut self) {
+        self.interval.tick().await;
+    }
+
+    /// Add bytes counted since the last sample to the totals and update the rates.
+    pub async fn sample(&mut self, telem: &VhrdDbTelem) {
+        let sent = self.counters.sent.swap(0, Ordering::Relaxed);
+        let received = self.counters.received.swap(0, Ordering::Relaxed);
+        let elapsed_ms = self.last_sample.elapsed().as_millis().max(1) as usize;
+        self.last_sample = Instant::now();
+        let mut telem = telem.write().await;
+        telem.bytes_sent += sent;
+        telem.bytes_received += received;
+        telem.tx_bps = sent * 1000 / elapsed_ms;
+        telem.rx_bps = received * 1000 / elapsed_ms;
+    }
+
+    /// Account for the rest of the bytes of a closed connection, nothing flows until the next one.
+    pub async fn stop(&mut self, telem: &VhrdDbTelem) {
+        self.sample(telem).await;
+        let mut telem = telem.write().await;
+        telem.tx_bps = 0;
+        telem.rx_bps = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Throughput;
+    use crate::sync_client::VhrdDbTelem;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::{self, Message};
+
+    #[test]
+    fn only_binary_messages_are_counted() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut throughput = Throughput::new();
+            let incoming = futures_util::stream::iter([
+                Ok::<_, tungstenite::Error>(Message::Binary(vec![0; 10])),
+                Ok(Message::Ping(vec![0; 4])),
+            ]);
+            let mut rx = throughput.meter(incoming);
+            while rx.next().await.is_some() {}
+            let sink =
+                futures_util::sink::drain().sink_map_err(|_| tungstenite::Error::AlreadyClosed);
+            let mut tx = throughput.meter(sink);
+            tx.send(Message::Binary(vec![0; 3])).await.unwrap();
+            tx.send(Message::Text("ignored".to_string())).await.unwrap();
+
+            let telem = VhrdDbTelem::default();
+            throughput.stop(&telem).await;
+            let telem = telem.read().await;
+            assert_eq!((telem.bytes_sent, telem.bytes_received), (3, 10));
+            assert_eq!((telem.tx_bps, telem.rx_bps), (0, 0));
+        });
+    }
+}
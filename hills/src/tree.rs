@@ -1,8 +1,16 @@
+use crate::db::Error;
 use hills_base::SimpleVersion;
 use hills_base::TypeCollection;
-use rkyv::{Archive, Deserialize, Serialize};
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::ser::Serializer;
+use rkyv::{check_archived_root, AlignedVec, Archive, Deserialize, Serialize};
 use std::collections::HashMap;
 
+/// Stored descriptors start with this prefix and the format version, followed by the archived TreeDescriptor.
+/// Descriptors written before the prefix was introduced have no prefix and the [v0] layout.
+const DESCRIPTOR_MAGIC: &[u8] = b"hillsTD";
+const DESCRIPTOR_FORMAT: u8 = 1;
+
 #[derive(Archive, Debug, Serialize, Deserialize)]
 #[archive(check_bytes)]
 #[archive_attr(derive(Debug))]
@@ -13,3 +21,187 @@ pub struct TreeDescriptor {
     /// Whether Record's created in a tree will be NonVersioned or Draft
     pub versioning: bool,
 }
+
+impl TreeDescriptor {
+    /// Bytes to store in the descriptors tree, in the current format.
+    pub(crate) fn to_stored_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut serializer = AllocSerializer::<1024>::default();
+        serializer.serialize_value(self)?;
+        let archived = serializer.into_serializer().into_inner();
+        let mut bytes = Vec::with_capacity(DESCRIPTOR_MAGIC.len() + 1 + archived.len());
+        bytes.extend_from_slice(DESCRIPTOR_MAGIC);
+        bytes.push(DESCRIPTOR_FORMAT);
+        bytes.extend_from_slice(&archived);
+        Ok(bytes)
+    }
+
+    /// Decode a stored descriptor of any known format.
+    /// Also returns whether it was stored in an older format and should be written back with to_stored_bytes.
+    pub(crate) fn from_stored_bytes(bytes: &[u8]) -> Result<(TreeDescriptor, bool), Error> {
+        // Small values are stored inline by sled and might not be aligned enough for rkyv
+        let mut aligned = AlignedVec::new();
+        match bytes.strip_prefix(DESCRIPTOR_MAGIC) {
+            Some([DESCRIPTOR_FORMAT, archived @ ..]) => {
+                aligned.extend_from_slice(archived);
+                let descriptor = check_archived_root::<TreeDescriptor>(&aligned)?
+                    .deserialize(&mut rkyv::Infallible)?;
+                Ok((descriptor, false))
+            }
+            Some(format) => Err(Error::Internal(format!(
+                "unknown descriptor format {:?}",
+                format.first()
+            ))),
+            None => {
+                aligned.extend_from_slice(bytes);
+                let descriptor: v0::TreeDescriptor =
+                    check_archived_root::<v0::TreeDescriptor>(&aligned)?
+                        .deserialize(&mut rkyv::Infallible)?;
+                Ok((descriptor.into(), true))
+            }
+        }
+    }
+}
+
+/// Layout of the descriptors written before doc comments, field ids and defaults were reflected.
+pub(crate) mod v0 {
+    use hills_base::SimpleVersion;
+    use rkyv::{Archive, Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive(check_bytes)]
+    pub struct TreeDescriptor {
+        pub evolutions: HashMap<SimpleVersion, TypeCollection>,
+        pub versioning: bool,
+    }
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive(check_bytes)]
+    pub struct TypeCollection {
+        pub root: String,
+        pub refs: HashMap<String, TypeInfo>,
+    }
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive(check_bytes)]
+    pub enum TypeInfo {
+        Struct(StructInfo),
+        Enum(EnumInfo),
+    }
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive(check_bytes)]
+    pub struct StructInfo {
+        pub fields: Vec<StructField>,
+    }
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive(check_bytes)]
+    pub struct StructField {
+        pub ident: String,
+        pub ty: String,
+    }
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive(check_bytes)]
+    pub struct EnumInfo {
+        pub variants: Vec<EnumVariant>,
+    }
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive(check_bytes)]
+    pub struct EnumVariant {
+        pub ident: String,
+        pub fields: EnumFields,
+    }
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive(check_bytes)]
+    pub enum EnumFields {
+        Named(Vec<StructField>),
+        Unnamed(Vec<String>),
+        Unit,
+    }
+
+    impl From<TreeDescriptor> for super::TreeDescriptor {
+        fn from(descriptor: TreeDescriptor) -> Self {
+            super::TreeDescriptor {
+                evolutions: descriptor
+                    .evolutions
+                    .into_iter()
+                    .map(|(evolution, tc)| (evolution, tc.into()))
+                    .collect(),
+                versioning: descriptor.versioning,
+            }
+        }
+    }
+
+    impl From<TypeCollection> for hills_base::TypeCollection {
+        fn from(tc: TypeCollection) -> Self {
+            hills_base::TypeCollection {
+                root: tc.root,
+                refs: tc
+                    .refs
+                    .into_iter()
+                    .map(|(name, info)| (name, info.into()))
+                    .collect(),
+            }
+        }
+    }
+
+    impl From<TypeInfo> for hills_base::TypeInfo {
+        fn from(info: TypeInfo) -> Self {
+            match info {
+                TypeInfo::Struct(si) => hills_base::TypeInfo::Struct(hills_base::StructInfo {
+                    doc: String::new(),
+                    fields: si.fields.into_iter().map(Into::into).collect(),
+                }),
+                TypeInfo::Enum(ei) => hills_base::TypeInfo::Enum(hills_base::EnumInfo {
+                    doc: String::new(),
+                    variants: ei
+                        .variants
+                        .into_iter()
+                        .map(|v| hills_base::EnumVariant {
+                            ident: v.ident,
+                            fields: match v.fields {
+                                EnumFields::Named(fields) => hills_base::EnumFields::Named(
+                                    fields.into_iter().map(Into::into).collect(),
+                                ),
+                                EnumFields::Unnamed(tys) => hills_base::EnumFields::Unnamed(tys),
+                                EnumFields::Unit => hills_base::EnumFields::Unit,
+                            },
+                            doc: String::new(),
+                        })
+                        .collect(),
+                }),
+            }
+        }
+    }
+
+    impl From<StructField> for hills_base::StructField {
+        fn from(field: StructField) -> Self {
+            hills_base::StructField {
+                ident: field.ident,
+                ty: field.ty,
+                id: None,
+                default: false,
+                doc: String::new(),
+            }
+        }
+    }
+}
+
+/// Size of one tree, see HillsClient::tree_stats.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Distinct record ids, including the ones with temporary ids
+    pub ids: usize,
+    /// All revisions of all records
+    pub revisions: usize,
+    /// Size of the record keys and values, sled's own overhead is not counted
+    pub approximate_bytes: u64,
+    /// Keys left in the key pool
+    pub key_pool: u32,
+    /// Latest evolution in the tree descriptor, None if the tree was never opened
+    pub latest_evolution: Option<SimpleVersion>,
+}
@@ -0,0 +1,201 @@
+//! Test harness with a server on an ephemeral port and any number of clients connected to it.
+
+use hills::sync_client::ChangeNotification;
+use hills::sync_server::{HillsServer, ServerConfig};
+use hills::{ClientConfig, HillsClient, OpenMode, TreeKey, TypedTree};
+use hills_base::{IdStrategy, SimpleVersion, TreeRoot};
+use hills_derive::rkyv_common_derives;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+#[rkyv_common_derives]
+pub struct Item {
+    pub name: String,
+}
+
+impl TreeRoot for Item {
+    fn tree_name() -> &'static str {
+        "items"
+    }
+
+    fn evolution() -> SimpleVersion {
+        SimpleVersion::new(0, 0)
+    }
+
+    fn versioning() -> bool {
+        false
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ItemKey(hills::GenericKey);
+
+impl TreeKey for ItemKey {
+    fn tree_name() -> &'static str {
+        "items"
+    }
+
+    fn from_generic(key: hills::GenericKey) -> Self {
+        ItemKey(key)
+    }
+
+    fn to_generic(&self) -> hills::GenericKey {
+        self.0
+    }
+}
+
+/// Part with a natural key, its id is a hash of the part number.
+#[rkyv_common_derives]
+pub struct Part {
+    pub number: String,
+}
+
+impl TreeRoot for Part {
+    fn tree_name() -> &'static str {
+        "parts"
+    }
+
+    fn evolution() -> SimpleVersion {
+        SimpleVersion::new(0, 0)
+    }
+
+    fn versioning() -> bool {
+        false
+    }
+
+    fn id_strategy() -> IdStrategy<Self> {
+        IdStrategy::ClientHash(|part| part.number.as_bytes())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PartKey(hills::GenericKey);
+
+impl TreeKey for PartKey {
+    fn tree_name() -> &'static str {
+        "parts"
+    }
+
+    fn from_generic(key: hills::GenericKey) -> Self {
+        PartKey(key)
+    }
+
+    fn to_generic(&self) -> hills::GenericKey {
+        self.0
+    }
+}
+
+pub struct Harness {
+    pub rt: Runtime,
+    pub server: HillsServer,
+    dirs: Vec<PathBuf>,
+}
+
+pub struct Client {
+    pub db: HillsClient,
+    #[allow(dead_code)]
+    pub updates_rx: postage::broadcast::Receiver<ChangeNotification>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        Self::with_server_config(ServerConfig::default())
+    }
+
+    pub fn with_server_config(config: ServerConfig) -> Self {
+        Self::start(config, None)
+    }
+
+    /// Start a read replica that mirrors the server of another harness.
+    #[allow(dead_code)]
+    pub fn replica_of(primary: &Harness) -> Self {
+        Self::start(ServerConfig::default(), Some(primary.server.local_addr))
+    }
+
+    fn start(config: ServerConfig, upstream: Option<SocketAddr>) -> Self {
+        let rt = Runtime::new().unwrap();
+        let server_dir = temp_path("server");
+        let server = HillsServer::start_with_config(
+            &server_dir,
+            OpenMode::Persistent,
+            "127.0.0.1:0",
+            upstream,
+            &rt,
+            config,
+        )
+        .unwrap();
+        Harness {
+            rt,
+            server,
+            dirs: vec![server_dir],
+        }
+    }
+
+    /// Open a new client database and start connecting it to the server.
+    pub fn client(&mut self, name: &str) -> Client {
+        self.client_with_config(name, ClientConfig::default())
+    }
+
+    pub fn client_with_config(&mut self, name: &str, config: ClientConfig) -> Client {
+        let mut client = self.offline_client_with_config(name, config);
+        self.connect(&mut client);
+        client
+    }
+
+    /// Open a new client database without connecting it to the server.
+    #[allow(dead_code)]
+    pub fn offline_client(&mut self, name: &str) -> Client {
+        self.offline_client_with_config(name, ClientConfig::default())
+    }
+
+    pub fn offline_client_with_config(&mut self, name: &str, config: ClientConfig) -> Client {
+        let dir = temp_path(name);
+        let (mut db, updates_rx, _join) =
+            HillsClient::open_with_config(&dir, OpenMode::Persistent, &self.rt, config).unwrap();
+        self.dirs.push(dir);
+        db.set_readable_name(name).unwrap();
+        Client { db, updates_rx }
+    }
+
+    pub fn connect(&self, client: &mut Client) {
+        client
+            .db
+            .connect(self.server.local_addr.ip(), self.server.local_addr.port());
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        for dir in &self.dirs {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("hills_e2e_{name}_{}", hills::uuid::Uuid::new_v4()))
+}
+
+/// Poll the condition until it is true, panics after a few seconds.
+pub fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        if started.elapsed() > Duration::from_secs(5) {
+            panic!("timed out waiting for {what}");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Wait until both trees have the same records with the same iterations.
+pub fn wait_synced(a: &TypedTree<ItemKey, Item>, b: &TypedTree<ItemKey, Item>) {
+    let iterations = |tree: &TypedTree<ItemKey, Item>| {
+        tree.all_revisions()
+            .map(|key| (key, tree.iterations(key).ok().flatten()))
+            .collect::<HashMap<_, _>>()
+    };
+    wait_until("trees to sync", || iterations(a) == iterations(b));
+}
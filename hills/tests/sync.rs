@@ -0,0 +1,985 @@
+mod common;
+
+use common::{wait_synced, wait_until, Harness, Item, ItemKey, Part, PartKey};
+use hills::db::{Error, RecordCheckOutState};
+use hills::sync_client::ChangeNotification;
+use hills::sync_server::ServerConfig;
+use hills::{
+    ClientConfig, HillsClient, KeyRequests, ReconnectBackoff, TreeKey, TypedTree, WsLimits,
+};
+use postage::stream::Stream;
+use std::time::Duration;
+
+#[test]
+fn record_propagates_between_clients() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "first".to_string(),
+        })
+        .unwrap();
+
+    let mut b = harness.client("b");
+    let items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+    assert_eq!(items_b.get(key).unwrap().name, "first");
+
+    let key = items_a
+        .insert(Item {
+            name: "second".to_string(),
+        })
+        .unwrap();
+    wait_until("hot sync to b", || items_b.get(key).is_ok());
+}
+
+#[test]
+fn check_out_propagates_between_clients() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "first".to_string(),
+        })
+        .unwrap();
+    let mut b = harness.client("b");
+    let items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+
+    items_a.check_out(key);
+    wait_until("check out on a", || items_a.is_checked_out(key));
+    wait_until("check out visible on b", || {
+        matches!(
+            items_b.checked_out_by(key),
+            RecordCheckOutState::CheckedOutBy(_)
+        )
+    });
+
+    items_a
+        .update(
+            key,
+            Item {
+                name: "renamed".to_string(),
+            },
+        )
+        .unwrap();
+    items_a.release(key);
+    wait_until("release visible on b", || {
+        matches!(items_b.checked_out_by(key), RecordCheckOutState::Empty)
+    });
+    wait_synced(&items_a, &items_b);
+    assert_eq!(items_b.get(key).unwrap().name, "renamed");
+}
+
+#[test]
+fn wait_for_iteration_resolves_on_incoming_change() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "first".to_string(),
+        })
+        .unwrap();
+    let mut b = harness.client("b");
+    let items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+
+    let (_, data_iteration, _) = items_b.iterations(key).unwrap().unwrap();
+    let already_reached = items_b.wait_for_iteration(key, data_iteration, Duration::from_secs(5));
+    harness.rt.block_on(already_reached).unwrap();
+    let never_reached =
+        items_b.wait_for_iteration(key, data_iteration + 2, Duration::from_millis(100));
+    assert!(matches!(
+        harness.rt.block_on(never_reached),
+        Err(Error::Timeout(_))
+    ));
+
+    let next = items_b.wait_for_iteration(key, data_iteration + 1, Duration::from_secs(5));
+    items_a.check_out(key);
+    wait_until("check out on a", || items_a.is_checked_out(key));
+    items_a
+        .update(
+            key,
+            Item {
+                name: "renamed".to_string(),
+            },
+        )
+        .unwrap();
+    harness.rt.block_on(next).unwrap();
+    assert_eq!(items_b.get(key).unwrap().name, "renamed");
+}
+
+#[test]
+fn too_large_message_is_reported() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let config = ClientConfig {
+        ws_limits: WsLimits {
+            max_message_size: 16 * 1024,
+            max_frame_size: 16 * 1024,
+        },
+        ..Default::default()
+    };
+    let mut b = harness.client_with_config("b", config);
+    let items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_until("b connected", || b.db.telem.blocking_read().connected);
+    wait_synced(&items_a, &items_b);
+
+    items_a
+        .insert(Item {
+            name: "x".repeat(64 * 1024),
+        })
+        .unwrap();
+    wait_until("b to reject the record", || {
+        b.db.telem
+            .blocking_read()
+            .error_message
+            .contains("exceeds the limit")
+    });
+}
+
+#[test]
+fn new_tree_is_announced_to_other_clients() {
+    let mut harness = Harness::new();
+    let mut b = harness.client("b");
+    wait_until("b connected", || b.db.telem.blocking_read().connected);
+
+    let mut a = harness.client("a");
+    let _items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("tree to appear on b", || loop {
+        match b.updates_rx.try_recv() {
+            Ok(ChangeNotification::TreeAppeared(tree)) if tree == "items" => break true,
+            Ok(_) => continue,
+            Err(_) => break false,
+        }
+    });
+}
+
+#[test]
+fn record_is_fetched_on_demand() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "fetched".to_string(),
+        })
+        .unwrap();
+    items_a
+        .insert(Item {
+            name: "not fetched".to_string(),
+        })
+        .unwrap();
+    let mut c = harness.client("c");
+    let items_c = c.db.open_tree::<ItemKey, Item>("c").unwrap();
+    wait_synced(&items_a, &items_c);
+
+    let mut b = harness.client("b");
+    wait_until("b connected", || b.db.telem.blocking_read().connected);
+    let fetched = harness
+        .rt
+        .block_on(b.db.fetch_record::<ItemKey, Item>(key))
+        .unwrap();
+    assert_eq!(fetched.map(|item| item.name).as_deref(), Some("fetched"));
+
+    let missing = ItemKey::from_generic(hills::GenericKey::new(key.to_generic().id + 1000, 0));
+    let fetched = harness
+        .rt
+        .block_on(b.db.fetch_record::<ItemKey, Item>(missing))
+        .unwrap();
+    assert!(fetched.is_none());
+
+    // Give a tree sync, if one was started, time to pull the other record before looking offline
+    std::thread::sleep(Duration::from_millis(300));
+    b.db.disconnect();
+    wait_until("b disconnected", || !b.db.telem.blocking_read().connected);
+    let items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    assert_eq!(items_b.all_revisions().collect::<Vec<_>>(), vec![key]);
+
+    let mut local = HillsClient::open_local_for_test();
+    let r = harness
+        .rt
+        .block_on(local.fetch_record::<ItemKey, Item>(key));
+    assert!(matches!(r, Err(hills::db::Error::NotConnected)));
+}
+
+#[test]
+fn queued_check_out_can_be_cancelled() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "first".to_string(),
+        })
+        .unwrap();
+    let mut b = harness.client("b");
+    let mut items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+
+    items_a.check_out(key);
+    wait_until("check out on a", || items_a.is_checked_out(key));
+    items_b.check_out(key);
+    wait_until("b waiting", || {
+        matches!(
+            items_b.checked_out_by(key),
+            RecordCheckOutState::WaitingFor(_, 1)
+        )
+    });
+
+    let queued = items_b.queue_position(key).unwrap();
+    assert_eq!(queued.position, 1);
+    assert_eq!(queued.ahead, vec![queued.holder]);
+    assert_eq!(items_a.queue_position(key), None);
+    wait_until("queue visible on a", || {
+        items_a.checkout_queue(key).len() == 2
+    });
+    assert_eq!(items_a.checkout_queue(key), items_b.checkout_queue(key));
+    assert_eq!(items_a.checkout_queue(key)[0], queued.holder);
+
+    items_b.cancel_checkout(key);
+    wait_until("b no longer waiting", || {
+        matches!(
+            items_b.checked_out_by(key),
+            RecordCheckOutState::CheckedOutBy(_)
+        )
+    });
+    items_a.release(key);
+    wait_until("release visible on b", || {
+        matches!(items_b.checked_out_by(key), RecordCheckOutState::Empty)
+    });
+    assert!(!items_b.is_checked_out(key));
+}
+
+#[test]
+fn try_check_out_does_not_queue() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "first".to_string(),
+        })
+        .unwrap();
+    let mut b = harness.client("b");
+    let mut items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+
+    harness.rt.block_on(items_a.try_check_out(key)).unwrap();
+    assert!(items_a.is_checked_out(key));
+    // b has not necessarily seen a's check out yet, the answer must come from the server
+    assert!(matches!(
+        harness.rt.block_on(items_b.try_check_out(key)),
+        Err(Error::CheckedOutByOther(_))
+    ));
+    assert_eq!(items_b.queue_position(key), None);
+
+    items_a.release(key);
+    wait_until("release visible on b", || {
+        matches!(items_b.checked_out_by(key), RecordCheckOutState::Empty)
+    });
+    harness.rt.block_on(items_b.try_check_out(key)).unwrap();
+    assert!(items_b.is_checked_out(key));
+}
+
+#[cfg(feature = "gateway")]
+#[test]
+fn gateway_checks_records_out_for_changes() {
+    use hills::gateway::Gateway;
+
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "first".to_string(),
+        })
+        .unwrap();
+    let mut b = harness.client("b");
+    let items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+    let mut gateway = Gateway::new(harness.rt.handle().clone());
+    gateway.add_tree("items", Box::new(items_b.clone()));
+    let path = format!("/items/{}", key.to_generic());
+
+    harness.rt.block_on(items_a.try_check_out(key)).unwrap();
+    let r = gateway.handle("PUT", &path, r#"{"name": "second"}"#);
+    assert_eq!(r.status, 409);
+    items_a.release(key);
+    wait_until("release visible on b", || {
+        matches!(items_b.checked_out_by(key), RecordCheckOutState::Empty)
+    });
+
+    let r = gateway.handle("PUT", &path, r#"{"name": "second"}"#);
+    assert_eq!(r.status, 204);
+    wait_synced(&items_a, &items_b);
+    assert_eq!(items_a.get(key).unwrap().name, "second");
+    wait_until("released by the gateway", || {
+        matches!(items_a.checked_out_by(key), RecordCheckOutState::Empty)
+    });
+
+    assert_eq!(gateway.handle("DELETE", &path, "").status, 204);
+    wait_until("removed on a", || items_a.get(key).is_err());
+}
+
+#[test]
+fn waiting_client_is_notified_when_granted() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "first".to_string(),
+        })
+        .unwrap();
+    let mut b = harness.client("b");
+    let mut items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+
+    items_a.check_out(key);
+    wait_until("check out on a", || items_a.is_checked_out(key));
+    items_b.check_out(key);
+    wait_until("b waiting", || items_b.queue_position(key).is_some());
+    while b.updates_rx.try_recv().is_ok() {}
+
+    items_a.release(key);
+    wait_until("check out granted to b", || loop {
+        match b.updates_rx.try_recv() {
+            Ok(ChangeNotification::CheckOutGranted { key: granted }) => {
+                break granted.id == key.to_generic().id
+            }
+            Ok(_) => continue,
+            Err(_) => break false,
+        }
+    });
+    assert!(items_b.is_checked_out(key));
+}
+
+#[test]
+fn abandoned_check_out_expires() {
+    let mut harness = Harness::with_server_config(ServerConfig {
+        check_out_lease: Duration::from_millis(500),
+        ..Default::default()
+    });
+    let renewing = ClientConfig {
+        check_out_renewal: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let mut a = harness.client_with_config("a", renewing.clone());
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "first".to_string(),
+        })
+        .unwrap();
+    // Stands for a client that stopped responding, it never renews after the first time
+    let silent = ClientConfig {
+        check_out_renewal: Duration::from_secs(3600),
+        ..Default::default()
+    };
+    let mut b = harness.client_with_config("b", silent);
+    let mut items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+
+    items_a.check_out(key);
+    wait_until("check out on a", || items_a.is_checked_out(key));
+    std::thread::sleep(Duration::from_millis(1500));
+    assert!(items_a.is_checked_out(key), "renewed check out expired");
+    items_a.release(key);
+    wait_until("release visible on b", || {
+        matches!(items_b.checked_out_by(key), RecordCheckOutState::Empty)
+    });
+
+    items_b.check_out(key);
+    wait_until("check out on b", || items_b.is_checked_out(key));
+    items_a.check_out(key);
+    wait_until("b check out expired", || items_a.is_checked_out(key));
+    assert!(!items_b.is_checked_out(key));
+}
+
+#[test]
+fn stale_client_is_pruned_and_its_keys_reissued() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "first".to_string(),
+        })
+        .unwrap();
+    let mut b = harness.client("b");
+    let items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+
+    let is_connected = |name: &str| {
+        harness
+            .server
+            .clients()
+            .unwrap()
+            .into_iter()
+            .find(|c| c.readable_name == name)
+            .map(|c| c.is_connected)
+    };
+    assert_eq!(is_connected("a"), Some(true));
+    assert!(harness
+        .server
+        .prune_clients(Duration::ZERO, true)
+        .unwrap()
+        .is_empty());
+
+    a.db.disconnect();
+    wait_until("a disconnected", || is_connected("a") == Some(false));
+    let pruned = harness.server.prune_clients(Duration::ZERO, true).unwrap();
+    assert_eq!(pruned.len(), 1);
+    assert_eq!(is_connected("a"), None);
+    assert_eq!(is_connected("b"), Some(true));
+
+    let mut c = harness.client("c");
+    let mut items_c = c.db.open_tree::<ItemKey, Item>("c").unwrap();
+    wait_until("keys on c", || items_c.key_pool_stats().unwrap() > 0);
+    let reissued = items_c
+        .insert(Item {
+            name: "second".to_string(),
+        })
+        .unwrap();
+    assert_eq!(reissued.to_generic().id, key.to_generic().id + 1);
+}
+
+#[test]
+fn ping_is_answered_and_text_is_rejected() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let h = Harness::new();
+    let url = format!("ws://{}", h.server.local_addr);
+    h.rt.block_on(async {
+        assert!(
+            tokio_tungstenite::connect_async(url.as_str())
+                .await
+                .is_err(),
+            "server accepted a client without the protocol version"
+        );
+        let mut request = url.into_client_request().unwrap();
+        request.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(hills::PROTOCOL_VERSION),
+        );
+        let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        ws.send(Message::Ping(b"hi".to_vec())).await.unwrap();
+        let pong = loop {
+            match ws.next().await.unwrap().unwrap() {
+                Message::Pong(payload) => break payload,
+                _ => continue,
+            }
+        };
+        assert_eq!(pong, b"hi");
+
+        ws.send(Message::Text("hello".to_string())).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match ws.next().await {
+                    None | Some(Err(_)) | Some(Ok(Message::Close(_))) => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        })
+        .await;
+        assert!(
+            closed.is_ok(),
+            "server kept the connection after a Text frame"
+        );
+    });
+}
+
+#[test]
+fn records_created_offline_get_global_ids_on_connect() {
+    let mut harness = Harness::new();
+    let mut b = harness.offline_client("b");
+    let mut items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    let item = |name: &str| Item {
+        name: name.to_string(),
+    };
+    let first = items_b.insert(item("first")).unwrap();
+    let second = items_b.insert(item("second")).unwrap();
+    let removed = items_b.insert(item("removed")).unwrap();
+    items_b.update(second, item("second edited")).unwrap();
+    items_b.remove(removed).unwrap();
+    assert!(items_b.is_temporary(first) && items_b.is_temporary(second));
+    assert_eq!(items_b.global_key(first).unwrap(), None);
+    assert!(b.db.pending_changes().unwrap().is_empty());
+
+    harness.connect(&mut b);
+    wait_until("global ids on b", || {
+        items_b.global_key(first).unwrap().is_some()
+            && items_b.global_key(second).unwrap().is_some()
+    });
+    let second = items_b.global_key(second).unwrap().unwrap();
+    assert!(!items_b.is_temporary(second));
+    assert_eq!(items_b.get(second).unwrap().name, "second edited");
+    assert_eq!(items_b.all_revisions().count(), 2);
+    let mut assigned = 0;
+    while let Ok(notification) = b.updates_rx.try_recv() {
+        if let ChangeNotification::GlobalIdAssigned { .. } = notification {
+            assigned += 1;
+        }
+    }
+    assert_eq!(assigned, 2);
+
+    let mut a = harness.client("a");
+    let items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_synced(&items_a, &items_b);
+    assert_eq!(items_a.get(second).unwrap().name, "second edited");
+    assert!(items_a
+        .all_revisions()
+        .all(|key| !items_a.is_temporary(key)));
+}
+
+#[test]
+fn offline_changes_are_replayed_after_reconnect() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut b = harness.client("b");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    let items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "online".to_string(),
+        })
+        .unwrap();
+    wait_synced(&items_a, &items_b);
+    items_a.check_out(key);
+    wait_until("check out on a", || items_a.is_checked_out(key));
+
+    a.db.disconnect();
+    wait_until("a disconnected", || !a.db.telem.blocking_read().connected);
+    items_a
+        .update(
+            key,
+            Item {
+                name: "offline".to_string(),
+            },
+        )
+        .unwrap();
+    wait_until("backlog on a", || a.db.telem.blocking_read().backlog == 1);
+    assert!(items_a.is_pending(key).unwrap());
+
+    harness.connect(&mut a);
+    wait_until("replayed change on b", || {
+        items_b.get(key).is_ok_and(|item| item.name == "offline")
+    });
+    wait_until("backlog drained", || {
+        a.db.telem.blocking_read().backlog == 0 && !items_a.is_pending(key).unwrap()
+    });
+}
+
+#[test]
+fn throughput_is_reported() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    items_a
+        .insert(Item {
+            name: "counted".to_string(),
+        })
+        .unwrap();
+    wait_until("bytes counted on a", || {
+        let telem = a.db.telem.blocking_read();
+        telem.bytes_sent > 0 && telem.bytes_received > 0
+    });
+
+    a.db.disconnect();
+    wait_until("rates reset on a", || {
+        let telem = a.db.telem.blocking_read();
+        !telem.connected && telem.tx_bps == 0 && telem.rx_bps == 0
+    });
+}
+
+#[test]
+fn key_batch_size_is_configurable() {
+    let mut harness = Harness::new();
+    let config = ClientConfig {
+        key_requests: KeyRequests {
+            count: 10,
+            low_water: 5,
+        },
+        ..Default::default()
+    };
+    let mut a = harness.client_with_config("a", config);
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() == 10);
+    for i in 0..6 {
+        items_a
+            .insert(Item {
+                name: format!("{i}"),
+            })
+            .unwrap();
+    }
+    // Dropping below the low water mark brings another batch of the same size
+    wait_until("more keys on a", || items_a.key_pool_stats().unwrap() == 14);
+}
+
+#[test]
+fn hashed_ids_agree_between_clients() {
+    let mut harness = Harness::new();
+    let part = |number: &str| Part {
+        number: number.to_string(),
+    };
+    // No keys are needed, so it can be created before the server is reached
+    let mut a = harness.offline_client("a");
+    let mut parts_a = a.db.open_tree::<PartKey, Part>("a").unwrap();
+    let key = parts_a.insert(part("M3x8")).unwrap();
+    assert!(!parts_a.is_temporary(key));
+    harness.connect(&mut a);
+
+    let mut b = harness.client("b");
+    let mut parts_b = b.db.open_tree::<PartKey, Part>("b").unwrap();
+    wait_until("part on b", || parts_b.get(key).is_ok());
+    assert!(matches!(parts_b.insert(part("M3x8")), Err(Error::Usage(_))));
+    assert!(matches!(
+        parts_b.insert_with_id(hills::CLIENT_IDS.start, part("M3x10")),
+        Err(Error::Usage(_))
+    ));
+    let other = parts_b.insert(part("M3x10")).unwrap();
+    wait_until("part on a", || parts_a.get(other).is_ok());
+}
+
+#[test]
+fn colliding_client_ids_are_refused() {
+    let mut harness = Harness::new();
+    let part = || Part {
+        number: "M3x8".to_string(),
+    };
+    let created =
+        |parts: &hills::TypedTree<PartKey, Part>, key| parts.meta(key).unwrap().unwrap().1.created;
+    // Same id, created apart from each other
+    let mut a = harness.offline_client("a");
+    let mut parts_a = a.db.open_tree::<PartKey, Part>("a").unwrap();
+    let key = parts_a.insert(part()).unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+    let mut b = harness.offline_client("b");
+    let mut parts_b = b.db.open_tree::<PartKey, Part>("b").unwrap();
+    assert_eq!(parts_b.insert(part()).unwrap(), key);
+    let (created_a, created_b) = (created(&parts_a, key), created(&parts_b, key));
+    assert_ne!(created_a, created_b);
+
+    harness.connect(&mut a);
+    wait_until("a synced", || a.db.pending_changes().unwrap().is_empty());
+    harness.connect(&mut b);
+    wait_until("collision reported to b", || loop {
+        match b.updates_rx.try_recv() {
+            Ok(ChangeNotification::Refused { keys, .. }) => break keys == [key.to_generic()],
+            Ok(_) => continue,
+            Err(_) => break false,
+        }
+    });
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(created(&parts_a, key), created_a);
+    assert_eq!(created(&parts_b, key), created_b);
+}
+
+#[test]
+fn fresh_database_is_not_linked() {
+    let mut harness = Harness::new();
+    let mut a = harness.offline_client("a");
+    assert_eq!(a.db.server_uuid().unwrap(), None);
+    let mut linked = Some(hills::uuid::Uuid::nil());
+    a.db.telemetry(|telem| linked = telem.linked_server);
+    assert_eq!(linked, None);
+
+    harness.connect(&mut a);
+    wait_until("linked", || a.db.server_uuid().unwrap().is_some());
+    let server_uuid = a.db.server_uuid().unwrap();
+    wait_until("linked in telemetry", || {
+        let mut linked = None;
+        a.db.telemetry(|telem| linked = telem.linked_server);
+        linked == server_uuid
+    });
+}
+
+#[test]
+fn unlinked_database_moves_to_another_server() {
+    let mut old = Harness::new();
+    let mut new = Harness::new();
+    // First keys of the old server go elsewhere, so that the moved record does not use the first id of the new one
+    let mut c = old.client("c");
+    let items_c = c.db.open_tree::<ItemKey, Item>("c").unwrap();
+    wait_until("keys on c", || items_c.key_pool_stats().unwrap() > 0);
+    let mut a = old.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "moved".to_string(),
+        })
+        .unwrap();
+    let old_uuid = a.db.server_uuid().unwrap();
+    assert!(old_uuid.is_some());
+
+    a.db.disconnect();
+    wait_until("disconnected", || {
+        let mut connected = true;
+        a.db.telemetry(|telem| connected = telem.connected);
+        !connected
+    });
+    new.connect(&mut a);
+    wait_until("refused by the new server", || {
+        let mut refused = false;
+        a.db.telemetry(|telem| {
+            refused = !telem.connected && telem.error_message.contains("does not match")
+        });
+        refused
+    });
+    assert_eq!(a.db.server_uuid().unwrap(), old_uuid);
+
+    a.db.unlink_server().unwrap();
+    assert_eq!(a.db.server_uuid().unwrap(), None);
+    assert_eq!(items_a.key_pool_stats().unwrap(), 0);
+    new.connect(&mut a);
+    wait_until("linked with the new server", || {
+        a.db.server_uuid()
+            .unwrap()
+            .is_some_and(|uuid| Some(uuid) != old_uuid)
+    });
+
+    let mut b = new.client("b");
+    let mut items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_synced(&items_a, &items_b);
+    assert_eq!(items_b.get(key).unwrap().name, "moved");
+
+    // Ids the moved records use are not issued again by the new server
+    wait_until("keys on b", || items_b.key_pool_stats().unwrap() > 0);
+    let from_b = items_b
+        .insert(Item {
+            name: "from b".to_string(),
+        })
+        .unwrap();
+    assert_ne!(from_b, key);
+    wait_synced(&items_a, &items_b);
+    assert_eq!(items_a.get(key).unwrap().name, "moved");
+    assert_eq!(items_a.get(from_b).unwrap().name, "from b");
+}
+
+#[test]
+fn moved_records_with_ids_issued_elsewhere_get_new_ids() {
+    let mut old = Harness::new();
+    let mut new = Harness::new();
+    let name = |items: &TypedTree<ItemKey, Item>, key| items.get(key).unwrap().name;
+    // Both servers issue the same first ids
+    let mut a = old.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let moved = items_a
+        .insert(Item {
+            name: "moved".to_string(),
+        })
+        .unwrap();
+    let mut b = new.client("b");
+    let mut items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    wait_until("keys on b", || items_b.key_pool_stats().unwrap() > 0);
+    let from_b = items_b
+        .insert(Item {
+            name: "from b".to_string(),
+        })
+        .unwrap();
+    assert_eq!(moved, from_b);
+
+    a.db.disconnect();
+    wait_until("disconnected", || {
+        let mut connected = true;
+        a.db.telemetry(|telem| connected = telem.connected);
+        !connected
+    });
+    a.db.unlink_server().unwrap();
+    new.connect(&mut a);
+    let mut temporary = None;
+    wait_until("moved record to get a new id", || loop {
+        match a.updates_rx.try_recv() {
+            Ok(ChangeNotification::IdTaken {
+                key, temporary: t, ..
+            }) => {
+                assert_eq!(key, moved.to_generic());
+                temporary = Some(t);
+            }
+            Ok(ChangeNotification::GlobalIdAssigned {
+                temporary: t,
+                global,
+                ..
+            }) if Some(t) == temporary => break global != moved.to_generic(),
+            Ok(_) => continue,
+            Err(_) => break false,
+        }
+    });
+    let moved = items_a
+        .global_key(ItemKey::from_generic(temporary.unwrap()))
+        .unwrap()
+        .unwrap();
+    wait_synced(&items_a, &items_b);
+    assert_eq!(items_a.all_revisions().count(), 2);
+    assert_eq!(name(&items_a, from_b), "from b");
+    assert_eq!(name(&items_b, from_b), "from b");
+    assert_eq!(name(&items_b, moved), "moved");
+}
+
+#[test]
+fn sync_and_quiesce_pushes_and_pulls_everything() {
+    let mut harness = Harness::new();
+    let mut a = harness.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let from_a = items_a
+        .insert(Item {
+            name: "from a".to_string(),
+        })
+        .unwrap();
+
+    let mut b = harness.offline_client("b");
+    let mut items_b = b.db.open_tree::<ItemKey, Item>("b").unwrap();
+    for name in ["offline 1", "offline 2"] {
+        items_b
+            .insert(Item {
+                name: name.to_string(),
+            })
+            .unwrap();
+    }
+    let never_connected = harness
+        .rt
+        .block_on(b.db.sync_and_quiesce(Duration::from_secs(1)));
+    assert!(matches!(never_connected, Err(Error::NotConnected)));
+
+    harness.connect(&mut b);
+    b.db.disconnect();
+    let summary = harness
+        .rt
+        .block_on(b.db.sync_and_quiesce(Duration::from_secs(5)))
+        .unwrap();
+    assert!(summary.sent >= 2, "{summary:?}");
+    assert!(summary.received >= 1, "{summary:?}");
+
+    // Nothing left to wait for once resolved
+    assert_eq!(items_b.get(from_a).unwrap().name, "from a");
+    assert!(items_b
+        .all_revisions()
+        .all(|key| key.to_generic().id < hills::CLIENT_IDS.start));
+    wait_synced(&items_a, &items_b);
+}
+
+#[test]
+fn lost_connection_is_retried_until_disconnected() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+    use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    let mut h = Harness::new();
+    // Accepts websockets and drops them right away, as a flaky network would
+    let listener =
+        h.rt.block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+    let flaky_addr = listener.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let accepted_2 = accepted.clone();
+    h.rt.spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            #[allow(clippy::result_large_err)]
+            let speak_protocol = |_: &Request, mut response: Response| {
+                response.headers_mut().insert(
+                    SEC_WEBSOCKET_PROTOCOL,
+                    HeaderValue::from_static(hills::PROTOCOL_VERSION),
+                );
+                Ok(response)
+            };
+            if tokio_tungstenite::accept_hdr_async(stream, speak_protocol)
+                .await
+                .is_ok()
+            {
+                accepted_2.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+
+    let config = ClientConfig {
+        reconnect: ReconnectBackoff {
+            first: Duration::from_millis(20),
+            max: Duration::from_millis(40),
+        },
+        ..Default::default()
+    };
+    let mut a = h.offline_client_with_config("a", config);
+    a.db.connect(flaky_addr.ip(), flaky_addr.port());
+    wait_until("reconnects", || accepted.load(Ordering::Relaxed) >= 3);
+    assert!(a.db.telem.blocking_read().reconnect_attempts >= 2);
+
+    a.db.disconnect();
+    std::thread::sleep(Duration::from_millis(100));
+    let after_disconnect = accepted.load(Ordering::Relaxed);
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(accepted.load(Ordering::Relaxed), after_disconnect);
+
+    // Attempts are reset once a server accepts the client
+    h.connect(&mut a);
+    wait_until("connected to the server", || {
+        let telem = a.db.telem.blocking_read();
+        telem.connected && telem.reconnect_attempts == 0 && telem.linked_server.is_some()
+    });
+}
+
+#[test]
+fn replica_refuses_changes() {
+    let mut primary = Harness::new();
+    let mut a = primary.client("a");
+    let mut items_a = a.db.open_tree::<ItemKey, Item>("a").unwrap();
+    wait_until("keys on a", || items_a.key_pool_stats().unwrap() > 0);
+    let key = items_a
+        .insert(Item {
+            name: "mirrored".to_string(),
+        })
+        .unwrap();
+
+    let mut replica = Harness::replica_of(&primary);
+    let mut r = replica.client("r");
+    let mut items_r = r.db.open_tree::<ItemKey, Item>("r").unwrap();
+    let refused = |r: &mut common::Client, expected: &[hills::GenericKey]| {
+        wait_until("refusal", || loop {
+            match r.updates_rx.try_recv() {
+                Ok(ChangeNotification::Refused { keys, .. }) => break keys == expected,
+                Ok(_) => continue,
+                Err(_) => break false,
+            }
+        })
+    };
+    // Key request sent on connect
+    refused(&mut r, &[]);
+    assert_eq!(items_r.key_pool_stats().unwrap(), 0);
+
+    items_r.check_out(key);
+    refused(&mut r, &[key.to_generic()]);
+    assert!(matches!(
+        items_r.checked_out_by(key),
+        RecordCheckOutState::Empty
+    ));
+    items_r.release(key);
+    refused(&mut r, &[key.to_generic()]);
+    items_r.cancel_checkout(key);
+    refused(&mut r, &[key.to_generic()]);
+}
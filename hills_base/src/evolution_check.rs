@@ -1,15 +1,25 @@
-use crate::{EnumFields, TypeCollection, TypeInfo};
+use crate::{EnumFields, StructField, TypeCollection, TypeInfo};
 
 /// Checks whether new type set is backwards compatible with the previous according to rules:
 /// * Struct field and enum variant renaming is allowed.
-/// * Adding new struct fields  is allowed.
+/// * Adding new fields to the root struct is allowed, if they are `Option` or marked with `#[reflect(default)]`,
+///   so that they can be filled in when older records are read.
 /// * Changing types in structs or in enum fields is forbidden.
 /// * Adding new enum fields is forbidden.
+/// * Fields that declare an ordinal with `#[reflect(id = N)]` must keep it at the same position, a field cannot gain
+///   or lose its ordinal.
+///
+/// Fields are compared by position, since that is how they are laid out in archived data. Reordering fields of
+/// the same type therefore passes unnoticed, while the data would be read into the wrong fields. Declaring ordinals
+/// turns such reordering into an error.
+///
+/// Root types are compared regardless of their names, since older evolutions are usually kept in separate modules.
+/// Same goes for the nested types, they must have exactly the same shape, but could be defined elsewhere.
 pub fn is_backwards_compatible(previous: &TypeCollection, next: &TypeCollection) -> bool {
     let Some(prev_root) = previous.refs.get(previous.root.as_str()) else {
         return false;
     };
-    let Some(next_root) = next.refs.get(previous.root.as_str()) else {
+    let Some(next_root) = next.refs.get(next.root.as_str()) else {
         return false;
     };
     match prev_root {
@@ -18,37 +28,145 @@ pub fn is_backwards_compatible(previous: &TypeCollection, next: &TypeCollection)
                 if prev_si.fields.len() > next_si.fields.len() {
                     return false;
                 }
-                for (f, f_new) in prev_si.fields.iter().zip(next_si.fields.iter()) {
-                    if f.ty != f_new.ty {
-                        return false;
-                    }
-                }
-                true
-            }
-            TypeInfo::Enum(_) => {
-                return false;
-            }
-        },
-        TypeInfo::Enum(prev_ei) => match next_root {
-            TypeInfo::Enum(next_ei) => {
-                if prev_ei.variants.len() != next_ei.variants.len() {
+                let added = &next_si.fields[prev_si.fields.len()..];
+                if !added.iter().all(is_optional) {
                     return false;
                 }
-                for (f, f_new) in prev_ei.variants.iter().zip(next_ei.variants.iter()) {
-                    if !is_enum_fields_compatible(&f.fields, &f_new.fields) {
-                        return false;
-                    }
-                }
-                true
-            }
-            TypeInfo::Struct(_) => {
-                return false;
+                prev_si
+                    .fields
+                    .iter()
+                    .zip(next_si.fields.iter())
+                    .all(|(f, f_new)| is_same_field(previous, f, next, f_new))
             }
+            TypeInfo::Enum(_) => false,
         },
+        TypeInfo::Enum(_) => is_same_shape(previous, prev_root, next, next_root),
+    }
+}
+
+/// Field that can be missing in older records.
+fn is_optional(field: &StructField) -> bool {
+    field.default || split_generic_args(&field.ty).0 == "Option"
+}
+
+/// Same type and same ordinal, or no ordinal on both sides.
+fn is_same_field(
+    previous: &TypeCollection,
+    prev_field: &StructField,
+    next: &TypeCollection,
+    next_field: &StructField,
+) -> bool {
+    let is_same_id = match (prev_field.id, next_field.id) {
+        (Some(id), Some(id_new)) => id == id_new,
+        (None, None) => true,
+        // Field without an ordinal moved to where one with an ordinal was, or the other way around
+        (Some(_), None) | (None, Some(_)) => false,
+    };
+    is_same_id && is_same_type(previous, &prev_field.ty, next, &next_field.ty)
+}
+
+/// Types are the same if both are std types with the same name and generic arguments or if both have the same shape.
+fn is_same_type(
+    previous: &TypeCollection,
+    prev_ty: &str,
+    next: &TypeCollection,
+    next_ty: &str,
+) -> bool {
+    match (previous.refs.get(prev_ty), next.refs.get(next_ty)) {
+        (Some(prev_info), Some(next_info)) => is_same_shape(previous, prev_info, next, next_info),
+        (None, None) => {
+            let (prev_name, prev_args) = split_generic_args(prev_ty);
+            let (next_name, next_args) = split_generic_args(next_ty);
+            prev_name == next_name
+                && prev_args.len() == next_args.len()
+                && prev_args
+                    .iter()
+                    .zip(next_args.iter())
+                    .all(|(arg, arg_new)| is_same_type(previous, arg, next, arg_new))
+        }
+        _ => false,
     }
 }
 
-fn is_enum_fields_compatible(prev_field: &EnumFields, next_fields: &EnumFields) -> bool {
+/// Split `HashMap<String,Vec<u8>>` into `HashMap` and `[String, Vec<u8>]`.
+///
+/// Arrays and tuples are split the same way, with the brackets standing in for the name:
+/// `[Vec<u8>;4]` into `;4]` and `[Vec<u8>]`, `(u32,String)` into `()` and `[u32, String]`.
+pub(crate) fn split_generic_args(ty: &str) -> (&str, Vec<&str>) {
+    if let Some(inner) = ty.strip_prefix('(').and_then(|ty| ty.strip_suffix(')')) {
+        return ("()", split_top_level(inner, ','));
+    }
+    if let Some(inner) = ty.strip_prefix('[').and_then(|ty| ty.strip_suffix(']')) {
+        let Some(&elem) = split_top_level(inner, ';').first() else {
+            return (ty, vec![]);
+        };
+        // Length is kept in the name, so that arrays of different sizes are not the same type
+        return (&ty[elem.len() + 1..], vec![elem]);
+    }
+    let (Some(start), true) = (ty.find('<'), ty.ends_with('>')) else {
+        return (ty, vec![]);
+    };
+    (
+        &ty[..start],
+        split_top_level(&ty[start + 1..ty.len() - 1], ','),
+    )
+}
+
+/// Split on separators that are not nested in any brackets.
+fn split_top_level(args: &str, separator: char) -> Vec<&str> {
+    let mut split = vec![];
+    let mut depth = 0;
+    let mut arg_start = 0;
+    for (i, c) in args.char_indices() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' | ')' | ']' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                split.push(args[arg_start..i].trim());
+                arg_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(args[arg_start..].trim());
+    split
+}
+
+fn is_same_shape(
+    previous: &TypeCollection,
+    prev_info: &TypeInfo,
+    next: &TypeCollection,
+    next_info: &TypeInfo,
+) -> bool {
+    match (prev_info, next_info) {
+        (TypeInfo::Struct(prev_si), TypeInfo::Struct(next_si)) => {
+            prev_si.fields.len() == next_si.fields.len()
+                && prev_si
+                    .fields
+                    .iter()
+                    .zip(next_si.fields.iter())
+                    .all(|(f, f_new)| is_same_field(previous, f, next, f_new))
+        }
+        (TypeInfo::Enum(prev_ei), TypeInfo::Enum(next_ei)) => {
+            prev_ei.variants.len() == next_ei.variants.len()
+                && prev_ei
+                    .variants
+                    .iter()
+                    .zip(next_ei.variants.iter())
+                    .all(|(v, v_new)| {
+                        is_enum_fields_compatible(previous, &v.fields, next, &v_new.fields)
+                    })
+        }
+        _ => false,
+    }
+}
+
+fn is_enum_fields_compatible(
+    previous: &TypeCollection,
+    prev_field: &EnumFields,
+    next: &TypeCollection,
+    next_fields: &EnumFields,
+) -> bool {
     match prev_field {
         EnumFields::Named(prev_named) => {
             let EnumFields::Named(next_named) = next_fields else {
@@ -57,18 +175,20 @@ fn is_enum_fields_compatible(prev_field: &EnumFields, next_fields: &EnumFields)
             if prev_named.len() != next_named.len() {
                 return false;
             }
-            for (f, f_new) in prev_named.iter().zip(next_named.iter()) {
-                if f.ty != f_new.ty {
-                    return false;
-                }
-            }
-            true
+            prev_named
+                .iter()
+                .zip(next_named.iter())
+                .all(|(f, f_new)| is_same_field(previous, f, next, f_new))
         }
         EnumFields::Unnamed(prev_unnamed) => {
             let EnumFields::Unnamed(next_unnamed) = next_fields else {
                 return false;
             };
-            prev_unnamed == next_unnamed
+            prev_unnamed.len() == next_unnamed.len()
+                && prev_unnamed
+                    .iter()
+                    .zip(next_unnamed.iter())
+                    .all(|(ty, ty_new)| is_same_type(previous, ty, next, ty_new))
         }
         EnumFields::Unit => next_fields == &EnumFields::Unit,
     }
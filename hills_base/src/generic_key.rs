@@ -1,5 +1,6 @@
 use rkyv::{Archive, Deserialize, Serialize};
 use std::fmt::{Debug, Display, Formatter};
+use std::ops::{Range, RangeInclusive};
 
 pub trait TreeKey {
     fn tree_name() -> &'static str;
@@ -7,7 +8,18 @@ pub trait TreeKey {
     fn to_generic(&self) -> GenericKey;
 }
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[derive(
+    Copy,
+    Clone,
+    Hash,
+    PartialEq,
+    Eq,
+    Archive,
+    Serialize,
+    Deserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 #[archive(check_bytes)]
 #[archive_attr(derive(Hash, PartialEq, Eq))]
 pub struct GenericKey {
@@ -16,6 +28,9 @@ pub struct GenericKey {
 }
 
 impl GenericKey {
+    /// Length of the serialized key, see to_bytes.
+    pub const BYTES: usize = 8;
+
     pub fn new(id: u32, revision: u32) -> Self {
         GenericKey { id, revision }
     }
@@ -38,15 +53,43 @@ impl GenericKey {
         }
     }
 
-    pub fn to_bytes(&self) -> [u8; 8] {
-        let mut bytes = [0u8; 8];
+    /// Key as stored in sled: id and then revision, both big endian.
+    ///
+    /// Byte-wise order of serialized keys is the same as (id, revision) order, so sled iterates all the revisions
+    /// of an id next to each other, oldest first, and ids in ascending order. A range scan over
+    /// `GenericKey::new(id, 0).to_bytes()..GenericKey::new(id + 1, 0).to_bytes()` yields all revisions of one id.
+    /// Ids of records that reached the server are never reused, only those of drafts removed before syncing
+    /// go back to the key pool, so a tree can hold at most u32::MAX synced ids over its lifetime.
+    pub fn to_bytes(&self) -> [u8; Self::BYTES] {
+        let mut bytes = [0u8; Self::BYTES];
         bytes[0..=3].copy_from_slice(&self.id.to_be_bytes());
         bytes[4..=7].copy_from_slice(&self.revision.to_be_bytes());
         bytes
     }
 
+    /// Bounds of a sled range over every record key, see to_bytes.
+    ///
+    /// Internal keys are never 8 bytes long, but can still sort in between records, scans have to skip them by length.
+    pub fn range_all_ids() -> RangeInclusive<[u8; Self::BYTES]> {
+        [0x00; Self::BYTES]..=[0xff; Self::BYTES]
+    }
+
+    /// Bounds of a sled range over all revisions of the ids.
+    pub fn id_range(ids: Range<u32>) -> Range<[u8; Self::BYTES]> {
+        GenericKey::new(ids.start, 0).to_bytes()..GenericKey::new(ids.end, 0).to_bytes()
+    }
+
+    /// Bounds of a sled range over all revisions of one id, oldest first.
+    /// For u32::MAX the last revision is left out, such id is never issued.
+    pub fn all_revisions_of(id: u32) -> Range<[u8; Self::BYTES]> {
+        match id.checked_add(1) {
+            Some(next_id) => Self::id_range(id..next_id),
+            None => GenericKey::new(id, 0).to_bytes()..GenericKey::new(id, u32::MAX).to_bytes(),
+        }
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() != 8 {
+        if bytes.len() != Self::BYTES {
             return None;
         }
         let mut word = [0u8; 4];
@@ -75,3 +118,44 @@ impl Debug for ArchivedGenericKey {
         write!(f, "{}.{}", self.id, self.revision)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::GenericKey;
+
+    #[test]
+    fn byte_order_matches_id_revision_order() {
+        let keys = [
+            GenericKey::new(1, 0),
+            GenericKey::new(1, 1),
+            GenericKey::new(1, 256),
+            GenericKey::new(2, 0),
+            GenericKey::new(256, 0),
+        ];
+        for pair in keys.windows(2) {
+            assert!(pair[0].to_bytes() < pair[1].to_bytes());
+        }
+        let key = GenericKey::new(0x0102_0304, 5);
+        assert_eq!(GenericKey::from_bytes(&key.to_bytes()), Some(key));
+    }
+
+    #[test]
+    fn range_bounds() {
+        let all = GenericKey::range_all_ids();
+        assert!(all.contains(&GenericKey::new(0, 0).to_bytes()));
+        assert!(all.contains(&GenericKey::new(u32::MAX, u32::MAX).to_bytes()));
+
+        let ids = GenericKey::id_range(10..12);
+        assert!(!ids.contains(&GenericKey::new(9, u32::MAX).to_bytes()));
+        assert!(ids.contains(&GenericKey::new(10, 0).to_bytes()));
+        assert!(ids.contains(&GenericKey::new(11, u32::MAX).to_bytes()));
+        assert!(!ids.contains(&GenericKey::new(12, 0).to_bytes()));
+
+        let revisions = GenericKey::all_revisions_of(7);
+        assert!(revisions.contains(&GenericKey::new(7, 0).to_bytes()));
+        assert!(revisions.contains(&GenericKey::new(7, u32::MAX).to_bytes()));
+        assert!(!revisions.contains(&GenericKey::new(8, 0).to_bytes()));
+        let last = GenericKey::all_revisions_of(u32::MAX);
+        assert!(last.contains(&GenericKey::new(u32::MAX, 1).to_bytes()));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::GenericKey;
 use rkyv::validation::{validators::DefaultValidatorError, CheckArchiveError};
 use std::fmt::Debug;
 use thiserror::Error;
@@ -7,8 +8,11 @@ pub enum IndexError {
     #[error("check_archived_root failed: {}", .0)]
     RkyvCheckArchivedRoot(String),
 
-    #[error("Duplicate key({}) in an indexer that does not allow it", .0)]
-    Duplicate(String),
+    /// Indexed value is already used by an existing record.
+    #[error(
+        "Duplicate key({value}) in an indexer that does not allow it, already used by {existing}"
+    )]
+    Duplicate { value: String, existing: GenericKey },
 
     #[error("RwLock failed")]
     RwLock,
@@ -2,6 +2,7 @@ pub mod date_time;
 pub mod evolution_check;
 pub mod generic_key;
 pub mod index;
+pub mod reflect_impls;
 pub mod simple_ast;
 pub mod simple_version;
 
@@ -12,17 +13,64 @@ pub use simple_ast::*;
 pub use simple_version::*;
 
 pub trait Reflect {
+    /// Name of the type in TypeCollection, module-qualified for derived types (`crate::module::Type`),
+    /// so that same-named types from different modules do not collide.
+    fn type_name() -> &'static str;
+
     fn reflect(to: &mut TypeCollection);
 }
 
+/// Name of a generic type instantiation, e.g. `crate::Wrapper<crate::Meters>`, as &'static str for Reflect::type_name.
+/// Each distinct name is leaked once and reused afterwards, there are only as many of them as instantiations in the code.
+#[doc(hidden)]
+pub fn intern_type_name(name: String) -> &'static str {
+    static NAMES: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    let mut names = NAMES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some(name) = names.get(name.as_str()) {
+        return name;
+    }
+    let name: &'static str = Box::leak(name.into_boxed_str());
+    names.insert(name);
+    name
+}
+
 pub trait TreeRoot {
     fn tree_name() -> &'static str;
     fn evolution() -> SimpleVersion;
     fn versioning() -> bool;
+
+    /// How ids of new records are chosen, server issued ones by default.
+    fn id_strategy() -> IdStrategy<Self>
+    where
+        Self: Sized,
+    {
+        IdStrategy::ServerPool
+    }
+}
+
+/// Where ids of new records in a tree come from.
+pub enum IdStrategy<V> {
+    /// Ranges of ids are issued by the server to each client.
+    ServerPool,
+    /// Id is derived from a hash of the returned bytes, e.g. of a natural key field, so that the same item gets
+    /// the same id on every node without coordination.
+    ///
+    /// Ids are spread over about 1.9 billion values (CLIENT_IDS), so among tens of thousands of items some collide.
+    /// insert refuses a collision with a local record. Records created with the same id on nodes that were not
+    /// connected, be it the same item or not, are told apart by their creation time: the server keeps the first one
+    /// and refuses the others, which stay local.
+    ClientHash(fn(&V) -> &[u8]),
+    /// Ids are provided by the caller, with TypedTree::insert_with_id.
+    Explicit,
 }
 
 use rkyv::with::AsBox;
 use rkyv::{Archive, Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock};
 
 /// This wrapper type serializes the contained value out-of-line so that newer
 /// versions can be viewed as the older version.
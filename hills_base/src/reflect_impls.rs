@@ -0,0 +1,59 @@
+//! Reflect implementations for types that cannot derive it.
+//!
+//! Derive macro calls `reflect` on every field type that is not a primitive or std collection,
+//! so such types must implement Reflect, even if they are defined in another crate.
+//! Orphan rule prevents implementing Reflect for a foreign type outside of hills_base, in this case
+//! wrap it into a newtype and use [impl_reflect_struct] on it:
+//!
+//! ```
+//! struct Rgb([u8; 3]);
+//!
+//! hills_base::impl_reflect_struct!(Rgb, "Rgb", { "0": "[u8; 3]" });
+//! ```
+
+use crate::{GenericKey, SimpleVersion, UtcDateTime};
+
+/// Implement Reflect for a type as a struct with the provided field names and types.
+/// Field types are only used to detect changes, so they should be updated each time actual types are changed.
+#[macro_export]
+macro_rules! impl_reflect_struct {
+    ($ty:ty, $name:literal, { $($field:literal : $field_ty:literal),* $(,)? }) => {
+        impl $crate::Reflect for $ty {
+            fn type_name() -> &'static str {
+                $name
+            }
+
+            fn reflect(to: &mut $crate::TypeCollection) {
+                if to.root.is_empty() {
+                    to.root = $name.to_string();
+                }
+                to.refs.insert(
+                    $name.to_string(),
+                    $crate::TypeInfo::Struct($crate::StructInfo {
+                        doc: String::new(),
+                        fields: vec![$($crate::StructField {
+                            ident: $field.to_string(),
+                            ty: $field_ty.to_string(),
+                            id: None,
+                            default: false,
+                            doc: String::new(),
+                        }),*],
+                    }),
+                );
+            }
+        }
+    };
+}
+
+impl_reflect_struct!(GenericKey, "GenericKey", { "id": "u32", "revision": "u32" });
+impl_reflect_struct!(SimpleVersion, "SimpleVersion", { "major": "u16", "minor": "u16" });
+impl_reflect_struct!(UtcDateTime, "UtcDateTime", {
+    "year": "i32",
+    "month": "u32",
+    "day": "u32",
+    "hour": "u32",
+    "min": "u32",
+    "secs": "u32",
+    "milli": "u32",
+});
+impl_reflect_struct!(uuid::Uuid, "Uuid", { "0": "[u8; 16]" });
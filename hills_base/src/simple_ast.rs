@@ -1,20 +1,252 @@
+use crate::evolution_check::split_generic_args;
 use rkyv::{Archive, Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter, Write};
 
 #[derive(Archive, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[archive(check_bytes)]
 #[archive_attr(derive(Debug))]
 pub struct TypeCollection {
     pub root: String,
-    pub refs: HashMap<String, TypeInfo>,
+    /// Sorted by type name, so that the same schema always serializes to the same bytes.
+    pub refs: BTreeMap<String, TypeInfo>,
+}
+
+impl Default for TypeCollection {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TypeCollection {
     pub fn new() -> TypeCollection {
         TypeCollection {
             root: String::new(),
-            refs: HashMap::new(),
+            refs: BTreeMap::new(),
+        }
+    }
+
+    /// Hash of all the type definitions, that does not depend on insertion order, process or platform.
+    /// Used to quickly compare schemas between nodes, doc comments are left out.
+    pub fn schema_hash(&self) -> u64 {
+        // FNV-1a
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in self.canonical().as_bytes() {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    /// Same type definitions, doc comments and module paths of type names can differ.
+    pub fn is_same_schema(&self, other: &TypeCollection) -> bool {
+        self.canonical() == other.canonical()
+    }
+
+    /// All the type definitions without doc comments, in a stable order.
+    ///
+    /// Type names are compared without module paths, descriptors stored before names were qualified
+    /// have bare ones.
+    fn canonical(&self) -> String {
+        let field = |f: &StructField| {
+            format!(
+                "{}:{}#{:?}{}",
+                f.ident,
+                strip_module_paths(&f.ty),
+                f.id,
+                f.default
+            )
+        };
+        let mut types = vec![];
+        for (name, ty) in &self.refs {
+            let body: Vec<String> = match ty {
+                TypeInfo::Struct(si) => si.fields.iter().map(field).collect(),
+                TypeInfo::Enum(ei) => ei
+                    .variants
+                    .iter()
+                    .map(|v| match &v.fields {
+                        EnumFields::Named(fields) => {
+                            let fields: Vec<String> = fields.iter().map(field).collect();
+                            format!("{}{{{}}}", v.ident, fields.join(","))
+                        }
+                        EnumFields::Unnamed(tys) => {
+                            let tys: Vec<String> =
+                                tys.iter().map(|ty| strip_module_paths(ty)).collect();
+                            format!("{}({})", v.ident, tys.join(","))
+                        }
+                        EnumFields::Unit => v.ident.clone(),
+                    })
+                    .collect(),
+            };
+            let kind = match ty {
+                TypeInfo::Struct(_) => "struct",
+                TypeInfo::Enum(_) => "enum",
+            };
+            types.push(format!(
+                ";{}={kind}[{}]",
+                strip_module_paths(name),
+                body.join(";")
+            ));
+        }
+        // Order of refs depends on the module paths
+        types.sort();
+        let mut canonical = strip_module_paths(&self.root);
+        for ty in types {
+            canonical.push_str(&ty);
         }
+        canonical
+    }
+
+    /// Human readable schema: root type with all the types it refers to expanded under the fields using them,
+    /// followed by the types not reachable from the root, sorted by name.
+    pub fn to_schema_string(&self) -> String {
+        let mut out = String::new();
+        let mut shown = HashSet::new();
+        self.write_type(&mut out, &self.root, 0, &mut vec![], &mut shown);
+        let unreachable: Vec<&String> = self
+            .refs
+            .keys()
+            .filter(|name| !shown.contains(name.as_str()))
+            .collect();
+        for name in unreachable {
+            self.write_type(&mut out, name, 0, &mut vec![], &mut shown);
+        }
+        out
+    }
+
+    fn write_type<'a>(
+        &'a self,
+        out: &mut String,
+        name: &'a str,
+        indent: usize,
+        path: &mut Vec<&'a str>,
+        shown: &mut HashSet<&'a str>,
+    ) {
+        let _ = writeln!(out, "{:indent$}{name}", "", indent = indent * 2);
+        shown.insert(name);
+        let Some(info) = self.refs.get(name) else {
+            return;
+        };
+        path.push(name);
+        self.write_body(out, info, indent + 1, path, shown);
+        path.pop();
+    }
+
+    fn write_body<'a>(
+        &'a self,
+        out: &mut String,
+        info: &'a TypeInfo,
+        indent: usize,
+        path: &mut Vec<&'a str>,
+        shown: &mut HashSet<&'a str>,
+    ) {
+        match info {
+            TypeInfo::Struct(si) => {
+                for f in &si.fields {
+                    let id = f.id.map(|id| format!(" #{id}")).unwrap_or_default();
+                    let _ = writeln!(
+                        out,
+                        "{:indent$}{}: {}{id}",
+                        "",
+                        f.ident,
+                        f.ty,
+                        indent = indent * 2
+                    );
+                    self.write_referenced(out, [f.ty.as_str()], indent + 1, path, shown);
+                }
+            }
+            TypeInfo::Enum(ei) => {
+                for v in &ei.variants {
+                    let shape = match &v.fields {
+                        EnumFields::Named(fields) => {
+                            let fields: Vec<String> = fields
+                                .iter()
+                                .map(|f| format!("{}: {}", f.ident, f.ty))
+                                .collect();
+                            format!(" {{ {} }}", fields.join(", "))
+                        }
+                        EnumFields::Unnamed(tys) => format!("({})", tys.join(", ")),
+                        EnumFields::Unit => String::new(),
+                    };
+                    let _ = writeln!(out, "{:indent$}{}{shape}", "", v.ident, indent = indent * 2);
+                    let tys = fields_tys(&v.fields);
+                    self.write_referenced(out, tys, indent + 1, path, shown);
+                }
+            }
+        }
+    }
+
+    /// Expand the types that are known in refs, including the ones used as generic arguments.
+    /// Body is written right away if only one type is referenced, otherwise each one is written under its name.
+    fn write_referenced<'a>(
+        &'a self,
+        out: &mut String,
+        tys: impl IntoIterator<Item = &'a str>,
+        indent: usize,
+        path: &mut Vec<&'a str>,
+        shown: &mut HashSet<&'a str>,
+    ) {
+        let mut referenced = vec![];
+        for ty in tys {
+            self.collect_referenced(ty, &mut referenced);
+        }
+        referenced.retain(|name| !path.contains(name));
+        if let [name] = referenced[..] {
+            let Some(info) = self.refs.get(name) else {
+                return;
+            };
+            shown.insert(name);
+            path.push(name);
+            self.write_body(out, info, indent, path, shown);
+            path.pop();
+        } else {
+            for name in referenced {
+                self.write_type(out, name, indent, path, shown);
+            }
+        }
+    }
+
+    fn collect_referenced<'a>(&'a self, ty: &'a str, referenced: &mut Vec<&'a str>) {
+        if let Some((name, _)) = self.refs.get_key_value(ty) {
+            if !referenced.contains(&name.as_str()) {
+                referenced.push(name.as_str());
+            }
+            return;
+        }
+        let (_, args) = split_generic_args(ty);
+        for arg in args {
+            self.collect_referenced(arg, referenced);
+        }
+    }
+}
+
+fn fields_tys(fields: &EnumFields) -> Vec<&str> {
+    match fields {
+        EnumFields::Named(fields) => fields.iter().map(|f| f.ty.as_str()).collect(),
+        EnumFields::Unnamed(tys) => tys.iter().map(|ty| ty.as_str()).collect(),
+        EnumFields::Unit => vec![],
+    }
+}
+
+/// `crate::module::Type<crate::other::Arg>` into `Type<Arg>`.
+fn strip_module_paths(ty: &str) -> String {
+    let mut stripped = String::with_capacity(ty.len());
+    let mut rest = ty;
+    while let Some(separator) = rest.find("::") {
+        let before = &rest[..separator];
+        let segment_start = before
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |i| i + 1);
+        stripped.push_str(&before[..segment_start]);
+        rest = &rest[separator + 2..];
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+impl Display for TypeCollection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_schema_string())
     }
 }
 
@@ -30,6 +262,8 @@ pub enum TypeInfo {
 #[archive(check_bytes)]
 #[archive_attr(derive(Debug))]
 pub struct StructInfo {
+    /// Doc comment of the type, lines joined with '\n'. Ignored when comparing schemas.
+    pub doc: String,
     pub fields: Vec<StructField>,
 }
 
@@ -39,12 +273,19 @@ pub struct StructInfo {
 pub struct StructField {
     pub ident: String,
     pub ty: String,
+    /// Ordinal declared with `#[reflect(id = N)]`, see is_backwards_compatible.
+    pub id: Option<u32>,
+    /// Declared with `#[reflect(default)]`, field can be added to a root type in a newer evolution.
+    pub default: bool,
+    pub doc: String,
 }
 
 #[derive(Archive, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[archive(check_bytes)]
 #[archive_attr(derive(Debug))]
 pub struct EnumInfo {
+    /// Doc comment of the type, lines joined with '\n'. Ignored when comparing schemas.
+    pub doc: String,
     pub variants: Vec<EnumVariant>,
 }
 
@@ -54,6 +295,7 @@ pub struct EnumInfo {
 pub struct EnumVariant {
     pub ident: String,
     pub fields: EnumFields,
+    pub doc: String,
 }
 
 #[derive(Archive, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -64,3 +306,162 @@ pub enum EnumFields {
     Unnamed(Vec<String>),
     Unit,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EnumFields, EnumInfo, EnumVariant, StructField, StructInfo, TypeCollection, TypeInfo,
+    };
+
+    fn my_struct() -> TypeInfo {
+        TypeInfo::Struct(StructInfo {
+            doc: String::new(),
+            fields: vec![StructField {
+                ident: "x".into(),
+                ty: "MyEnum".into(),
+                id: None,
+                default: false,
+                doc: String::new(),
+            }],
+        })
+    }
+
+    fn my_enum() -> TypeInfo {
+        TypeInfo::Enum(EnumInfo {
+            doc: String::new(),
+            variants: vec![EnumVariant {
+                ident: "A".into(),
+                fields: EnumFields::Unit,
+                doc: String::new(),
+            }],
+        })
+    }
+
+    #[test]
+    fn schema_hash_is_order_independent() {
+        let mut tc_a = TypeCollection::new();
+        tc_a.root = "MyStruct".into();
+        tc_a.refs.insert("MyStruct".into(), my_struct());
+        tc_a.refs.insert("MyEnum".into(), my_enum());
+
+        let mut tc_b = TypeCollection::new();
+        tc_b.root = "MyStruct".into();
+        tc_b.refs.insert("MyEnum".into(), my_enum());
+        tc_b.refs.insert("MyStruct".into(), my_struct());
+        assert_eq!(tc_a.schema_hash(), tc_b.schema_hash());
+
+        tc_b.refs.insert("MyEnum".into(), my_struct());
+        assert_ne!(tc_a.schema_hash(), tc_b.schema_hash());
+    }
+
+    #[test]
+    fn module_paths_are_not_part_of_schema() {
+        let mut bare = TypeCollection::new();
+        bare.root = "MyStruct".into();
+        bare.refs.insert("MyStruct".into(), my_struct());
+        bare.refs.insert("MyEnum".into(), my_enum());
+
+        let mut qualified = TypeCollection::new();
+        qualified.root = "app::v1::MyStruct".into();
+        let TypeInfo::Struct(mut si) = my_struct() else {
+            unreachable!()
+        };
+        si.fields[0].ty = "app::types::MyEnum".into();
+        qualified
+            .refs
+            .insert("app::v1::MyStruct".into(), TypeInfo::Struct(si));
+        qualified
+            .refs
+            .insert("app::types::MyEnum".into(), my_enum());
+        assert!(bare.is_same_schema(&qualified));
+        assert_eq!(bare.schema_hash(), qualified.schema_hash());
+
+        assert_eq!(
+            super::strip_module_paths("Vec<(a::B,[c::D;4])>"),
+            "Vec<(B,[D;4])>"
+        );
+    }
+
+    #[test]
+    fn docs_are_not_part_of_schema() {
+        let mut tc_a = TypeCollection::new();
+        tc_a.root = "MyStruct".into();
+        tc_a.refs.insert("MyStruct".into(), my_struct());
+        tc_a.refs.insert("MyEnum".into(), my_enum());
+
+        let mut tc_b = TypeCollection::new();
+        tc_b.root = "MyStruct".into();
+        let mut documented = my_struct();
+        if let TypeInfo::Struct(si) = &mut documented {
+            si.doc = "Documented later".into();
+            si.fields[0].doc = "Field doc".into();
+        }
+        tc_b.refs.insert("MyStruct".into(), documented);
+        tc_b.refs.insert("MyEnum".into(), my_enum());
+        assert_ne!(tc_a, tc_b);
+        assert!(tc_a.is_same_schema(&tc_b));
+        assert_eq!(tc_a.schema_hash(), tc_b.schema_hash());
+        assert!(crate::is_backwards_compatible(&tc_a, &tc_b));
+
+        tc_b.refs.insert("MyEnum".into(), my_struct());
+        assert!(!tc_a.is_same_schema(&tc_b));
+    }
+
+    #[test]
+    fn serialized_bytes_are_order_independent() {
+        let mut tc_a = TypeCollection::new();
+        tc_a.root = "MyStruct".into();
+        tc_a.refs.insert("MyStruct".into(), my_struct());
+        tc_a.refs.insert("MyEnum".into(), my_enum());
+
+        let mut tc_b = TypeCollection::new();
+        tc_b.root = "MyStruct".into();
+        tc_b.refs.insert("MyEnum".into(), my_enum());
+        tc_b.refs.insert("MyStruct".into(), my_struct());
+
+        let bytes_a = rkyv::to_bytes::<_, 256>(&tc_a).unwrap();
+        let bytes_b = rkyv::to_bytes::<_, 256>(&tc_b).unwrap();
+        assert_eq!(bytes_a.as_slice(), bytes_b.as_slice());
+    }
+
+    #[test]
+    fn schema_string_expands_refs() {
+        let mut tc = TypeCollection::new();
+        tc.root = "MyStruct".into();
+        tc.refs.insert("MyStruct".into(), my_struct());
+        tc.refs.insert("MyEnum".into(), my_enum());
+        tc.refs.insert(
+            "Other".into(),
+            TypeInfo::Struct(StructInfo {
+                doc: String::new(),
+                fields: vec![
+                    StructField {
+                        ident: "a".into(),
+                        ty: "u8".into(),
+                        id: None,
+                        default: false,
+                        doc: String::new(),
+                    },
+                    StructField {
+                        ident: "b".into(),
+                        ty: "Vec<MyEnum>".into(),
+                        id: Some(2),
+                        default: false,
+                        doc: String::new(),
+                    },
+                ],
+            }),
+        );
+        let expected = "\
+MyStruct
+  x: MyEnum
+    A
+Other
+  a: u8
+  b: Vec<MyEnum> #2
+    A
+";
+        assert_eq!(tc.to_schema_string(), expected);
+        assert_eq!(tc.to_string(), expected);
+    }
+}
@@ -2,7 +2,19 @@ use rkyv::{Archive, Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::fmt::{Display, Formatter};
 
-#[derive(Archive, Clone, PartialEq, Eq, Debug, Copy, Hash, Serialize, Deserialize)]
+#[derive(
+    Archive,
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Copy,
+    Hash,
+    Serialize,
+    Deserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 #[archive(check_bytes)]
 #[archive_attr(derive(PartialEq, Eq, Debug, Hash))]
 pub struct SimpleVersion {
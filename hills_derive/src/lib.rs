@@ -1,28 +1,44 @@
 mod evolve;
 mod reflect;
+mod tree_root;
 
 use proc_macro::TokenStream;
-use proc_macro_error::__export::proc_macro2::Span;
-use proc_macro_error::abort;
-use quote::{quote, TokenStreamExt};
+use proc_macro_error::{abort, proc_macro_error};
+use quote::{quote, ToTokens, TokenStreamExt};
 use syn::spanned::Spanned;
-use syn::{parse_macro_input, Data, DeriveInput};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, GenericParam};
 
-#[proc_macro_derive(Reflect)]
+#[proc_macro_derive(Reflect, attributes(reflect))]
+#[proc_macro_error]
 pub fn reflect_fn(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    match input.span() {
-        Span { .. } => {}
-    }
-    if input.generics.lt_token.is_some() {
-        abort!(input.generics.span(), "Generics are not supported");
+    let mut generics = input.generics.clone();
+    let mut type_params = Vec::new();
+    for param in generics.params.iter_mut() {
+        match param {
+            GenericParam::Type(param) => {
+                param.bounds.push(parse_quote!(hills_base::Reflect));
+                type_params.push(param.ident.clone());
+            }
+            GenericParam::Lifetime(param) => {
+                abort!(
+                    param.span(),
+                    "Lifetime parameters are not supported, tree types must own their data"
+                );
+            }
+            GenericParam::Const(param) => {
+                abort!(param.span(), "Const generic parameters are not supported");
+            }
+        }
     }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     // eprintln!("{:?}", input.attrs);
     let mut non_std_types = Vec::new();
 
+    let doc = reflect::collect_docs(&input.attrs);
     let reflect_ts = match input.data {
-        Data::Struct(ds) => reflect::process_struct(&mut non_std_types, ds),
-        Data::Enum(de) => reflect::process_enum(&mut non_std_types, de),
+        Data::Struct(ds) => reflect::process_struct(&mut non_std_types, ds, doc),
+        Data::Enum(de) => reflect::process_enum(&mut non_std_types, de, doc),
         Data::Union(_) => {
             abort!(input.span(), "Unions are not supported");
         }
@@ -32,17 +48,30 @@ pub fn reflect_fn(input: TokenStream) -> TokenStream {
     let mut reflect_non_std_ts = quote!();
     for ty in non_std_types {
         reflect_non_std_ts.append_all(quote!(
-            #ty::reflect(to);
+            <#ty as hills_base::Reflect>::reflect(to);
         ));
     }
 
     let ident = input.ident;
     let ident_str = ident.to_string();
-    // let ty_name = format!("{}::{}", module_path!(), #ident_str);
+    // Instantiations are told apart by the names of their type arguments, e.g. `crate::Wrapper<crate::Meters>`
+    let type_name_ts = if type_params.is_empty() {
+        quote!(concat!(module_path!(), "::", #ident_str))
+    } else {
+        quote!(hills_base::intern_type_name(format!(
+            "{}<{}>",
+            concat!(module_path!(), "::", #ident_str),
+            [#(<#type_params as hills_base::Reflect>::type_name()),*].join(",")
+        )))
+    };
     quote!(
-        impl hills_base::Reflect for #ident {
+        impl #impl_generics hills_base::Reflect for #ident #ty_generics #where_clause {
+            fn type_name() -> &'static str {
+                #type_name_ts
+            }
+
             fn reflect(to: &mut hills_base::TypeCollection) {
-                let ty_name = #ident_str.to_string();
+                let ty_name = Self::type_name().to_string();
                 if to.root.is_empty() {
                     to.root = ty_name.clone();
                 }
@@ -55,15 +84,55 @@ pub fn reflect_fn(input: TokenStream) -> TokenStream {
     .into()
 }
 
+/// Implement TreeRoot from `#[tree(name = "items", evolution = "0.1", versioning)]`, the name is checked
+/// at compile time instead of failing in HillsClient::open_tree. Evolution defaults to 0.0, versioning to off.
+/// Implement TreeRoot manually to choose an IdStrategy other than the default one.
+#[proc_macro_derive(TreeRoot, attributes(tree))]
+#[proc_macro_error]
+pub fn tree_root_fn(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let tree_root::TreeAttrs {
+        name,
+        evolution: (major, minor),
+        versioning,
+    } = tree_root::tree_attrs(&input.attrs, input.ident.span());
+    let ident = input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote!(
+        impl #impl_generics hills_base::TreeRoot for #ident #ty_generics #where_clause {
+            fn tree_name() -> &'static str {
+                #name
+            }
+
+            fn evolution() -> hills_base::SimpleVersion {
+                hills_base::SimpleVersion::new(#major, #minor)
+            }
+
+            fn versioning() -> bool {
+                #versioning
+            }
+        }
+    )
+    .into()
+}
+
 #[proc_macro_attribute]
-pub fn evolve(attr: TokenStream, item: TokenStream) -> TokenStream {
-    println!("attr: \"{}\"", attr.to_string());
-    println!("item: \"{}\"", item.to_string());
+pub fn evolve(_attr: TokenStream, item: TokenStream) -> TokenStream {
     item
 }
 
+/// Fields marked with `#[reflect(default)]` also get `#[serde(default)]`, so that they are filled in
+/// when older records are upgraded, see HillsClient::add_upgrade.
 #[proc_macro_attribute]
 pub fn rkyv_common_derives(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    if let Data::Struct(ds) = &mut input.data {
+        for f in ds.fields.iter_mut() {
+            if reflect::field_attrs(&f.attrs).default {
+                f.attrs.push(parse_quote!(#[serde(default)]));
+            }
+        }
+    }
     let mut output = TokenStream::from(quote! {
         #[derive(
             rkyv::Archive,
@@ -79,16 +148,6 @@ pub fn rkyv_common_derives(_args: TokenStream, input: TokenStream) -> TokenStrea
         #[archive(check_bytes)]
         #[archive_attr(derive(Debug))]
     });
-    output.extend(input);
+    output.extend(TokenStream::from(input.to_token_stream()));
     output
 }
-
-// fn collect_docs(attrs: &[Attribute]) -> String {
-//     let mut doc = String::new();
-//     for attr in attrs {
-//         if attr.meta.path().to_token_stream().to_string() == "doc" {
-//             doc.push_str(format!("{:?}", attr.meta).as_str());
-//         }
-//     }
-//     doc
-// }
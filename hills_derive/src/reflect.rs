@@ -2,32 +2,34 @@ use proc_macro2::TokenStream;
 use proc_macro_error::abort;
 use quote::{quote, TokenStreamExt};
 use syn::spanned::Spanned;
-use syn::{DataEnum, DataStruct, Fields, Path, Type};
+use syn::{
+    Attribute, DataEnum, DataStruct, Expr, ExprLit, Field, Fields, GenericArgument, Lit, LitInt,
+    LitStr, Meta, MetaNameValue, Path, PathArguments, Type,
+};
 
-pub fn process_struct(non_std_types: &mut Vec<Path>, ds: DataStruct) -> TokenStream {
+/// Named fields keep their names, tuple struct fields are named by their index ("0", "1", ..).
+/// Either way fields are compared by position when checking evolution compatibility, `#[reflect(id = N)]`
+/// additionally pins a field to an ordinal, so that reordering is caught. `#[reflect(default)]` marks a field
+/// that can be added in a newer evolution, because it can be filled in when reading older records.
+/// `#[reflect(skip)]` leaves a field that is not serialized out of the schema, `#[reflect(rename = "..")]`
+/// overrides the name it is reflected with.
+/// Unit and zero-field structs are reflected as structs without fields, so fields can be added to them later
+/// just like to any other root type.
+pub fn process_struct(non_std_types: &mut Vec<Path>, ds: DataStruct, doc: String) -> TokenStream {
     let mut ts = quote!(
         let mut fields = Vec::new();
     );
-    for (idx, f) in ds.fields.into_iter().enumerate() {
-        let ident = match f.ident {
-            Some(ident) => ident.to_string(),
-            None => idx.to_string(),
-        };
-        let ty = ty_to_str(&f.ty);
-        if !STD_TYPES.contains(&ty.as_str()) {
-            if let Type::Path(p) = &f.ty {
-                non_std_types.push(p.path.clone());
-            }
+    let attrs = fields_attrs(ds.fields.iter());
+    for (idx, (f, attrs)) in ds.fields.iter().zip(attrs).enumerate() {
+        if let Some(field) = struct_field(non_std_types, idx, f, attrs) {
+            ts.append_all(quote!(
+                fields.push(#field);
+            ));
         }
-        ts.append_all(quote!(
-            fields.push(hills_base::StructField {
-                ident: #ident.to_string(),
-                ty: #ty.to_string(),
-            });
-        ));
     }
     ts.append_all(quote!(
         let self_reflect = hills_base::TypeInfo::Struct(hills_base::StructInfo {
+            doc: #doc.to_string(),
             fields
         });
     ));
@@ -35,7 +37,7 @@ pub fn process_struct(non_std_types: &mut Vec<Path>, ds: DataStruct) -> TokenStr
     ts
 }
 
-pub fn process_enum(non_std_types: &mut Vec<Path>, de: DataEnum) -> TokenStream {
+pub fn process_enum(non_std_types: &mut Vec<Path>, de: DataEnum, doc: String) -> TokenStream {
     let mut ts = quote!(
         let mut variants = Vec::new();
     );
@@ -43,40 +45,36 @@ pub fn process_enum(non_std_types: &mut Vec<Path>, de: DataEnum) -> TokenStream
     for variant in de.variants.iter() {
         let fields = match &variant.fields {
             Fields::Named(fields_named) => {
-                let mut idents = Vec::new();
-                let mut tys = Vec::new();
-                for (idx, f) in fields_named.named.iter().enumerate() {
-                    let ty = ty_to_str(&f.ty);
-                    let ident = match &f.ident {
-                        Some(ident) => ident.to_string(),
-                        None => idx.to_string(),
-                    };
-                    idents.push(ident);
-                    if !STD_TYPES.contains(&ty.as_str()) {
-                        if let Type::Path(p) = &f.ty {
-                            non_std_types.push(p.path.clone());
-                        }
-                    }
-                    tys.push(ty);
-                }
+                let attrs = fields_attrs(fields_named.named.iter());
+                let fields: Vec<_> = fields_named
+                    .named
+                    .iter()
+                    .zip(attrs)
+                    .enumerate()
+                    .filter_map(|(idx, (f, attrs))| struct_field(non_std_types, idx, f, attrs))
+                    .collect();
                 quote! { hills_base::EnumFields::Named([
-                    #( hills_base::StructField { ident: #idents.to_string(), ty: #tys.to_string() } ),*
+                    #(#fields),*
                 ].into()) }
             }
             Fields::Unnamed(fields_unnamed) => {
                 let mut list = Vec::new();
-                for f in &fields_unnamed.unnamed {
-                    let ty = ty_to_str(&f.ty);
-                    if !STD_TYPES.contains(&ty.as_str()) {
-                        if let Type::Path(p) = &f.ty {
-                            non_std_types.push(p.path.clone());
-                        }
+                for (f, attrs) in fields_unnamed
+                    .unnamed
+                    .iter()
+                    .zip(fields_attrs(fields_unnamed.unnamed.iter()))
+                {
+                    if attrs.skip {
+                        continue;
+                    }
+                    if attrs.rename.is_some() {
+                        abort!(f.span(), "Fields of tuple variants have no names to rename");
                     }
-                    list.push(ty);
+                    list.push(field_ty(non_std_types, &f.ty));
                 }
 
                 quote!(hills_base::EnumFields::Unnamed([
-                            #(#list.to_string()),*
+                            #(#list),*
                         ].into()))
             }
             Fields::Unit => {
@@ -84,16 +82,19 @@ pub fn process_enum(non_std_types: &mut Vec<Path>, de: DataEnum) -> TokenStream
             }
         };
         let ident = variant.ident.to_string();
+        let variant_doc = collect_docs(&variant.attrs);
         ts.append_all(quote!(
             variants.push(hills_base::EnumVariant {
                 ident: #ident.to_string(),
-                fields: #fields
+                fields: #fields,
+                doc: #variant_doc.to_string(),
             });
         ));
     }
 
     ts.append_all(quote!(
         let self_reflect = hills_base::TypeInfo::Enum(hills_base::EnumInfo {
+            doc: #doc.to_string(),
             variants
         });
     ));
@@ -101,6 +102,128 @@ pub fn process_enum(non_std_types: &mut Vec<Path>, de: DataEnum) -> TokenStream
     ts
 }
 
+/// StructField expression for a field of a struct or of an enum variant, None if it is skipped.
+/// Type of a skipped field is not reflected at all, so it does not have to implement Reflect.
+fn struct_field(
+    non_std_types: &mut Vec<Path>,
+    idx: usize,
+    f: &Field,
+    attrs: FieldAttrs,
+) -> Option<TokenStream> {
+    let FieldAttrs {
+        id,
+        default,
+        skip,
+        rename,
+    } = attrs;
+    if skip {
+        return None;
+    }
+    let ident = match (rename, &f.ident) {
+        (Some(rename), _) => rename,
+        (None, Some(ident)) => ident.to_string(),
+        (None, None) => idx.to_string(),
+    };
+    let ty = field_ty(non_std_types, &f.ty);
+    let id = quote_id(id);
+    let doc = collect_docs(&f.attrs);
+    Some(quote!(hills_base::StructField {
+        ident: #ident.to_string(),
+        ty: #ty,
+        id: #id,
+        default: #default,
+        doc: #doc.to_string(),
+    }))
+}
+
+/// Text of `///` comments and `#[doc = "..."]` attributes, one line per attribute joined with '\n'.
+/// The space rustdoc leaves after `///` is removed.
+pub fn collect_docs(attrs: &[Attribute]) -> String {
+    let mut lines = Vec::new();
+    for attr in attrs {
+        if !attr.path().is_ident("doc") {
+            continue;
+        }
+        let Meta::NameValue(MetaNameValue {
+            value: Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            }),
+            ..
+        }) = &attr.meta
+        else {
+            continue;
+        };
+        let line = s.value();
+        lines.push(line.strip_prefix(' ').unwrap_or(&line).to_string());
+    }
+    lines.join("\n")
+}
+
+/// Field settings from `#[reflect(..)]` attributes.
+#[derive(Default)]
+pub struct FieldAttrs {
+    pub id: Option<u32>,
+    pub default: bool,
+    pub skip: bool,
+    pub rename: Option<String>,
+}
+
+/// Attributes of all the fields, ordinals declared with `#[reflect(id = N)]` must be unique.
+fn fields_attrs<'a>(fields: impl Iterator<Item = &'a Field>) -> Vec<FieldAttrs> {
+    let mut seen = Vec::new();
+    let mut all = Vec::new();
+    for f in fields {
+        let attrs = field_attrs(&f.attrs);
+        if let Some(id) = attrs.id {
+            if seen.contains(&id) {
+                abort!(f.span(), "Duplicate reflect id {}", id);
+            }
+            seen.push(id);
+        }
+        all.push(attrs);
+    }
+    all
+}
+
+pub fn field_attrs(attrs: &[Attribute]) -> FieldAttrs {
+    let mut field_attrs = FieldAttrs::default();
+    for attr in attrs {
+        if !attr.path().is_ident("reflect") {
+            continue;
+        }
+        let r = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                let lit: LitInt = meta.value()?.parse()?;
+                field_attrs.id = Some(lit.base10_parse::<u32>()?);
+                Ok(())
+            } else if meta.path.is_ident("default") {
+                field_attrs.default = true;
+                Ok(())
+            } else if meta.path.is_ident("skip") {
+                field_attrs.skip = true;
+                Ok(())
+            } else if meta.path.is_ident("rename") {
+                let lit: LitStr = meta.value()?.parse()?;
+                field_attrs.rename = Some(lit.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported reflect attribute"))
+            }
+        });
+        if let Err(e) = r {
+            abort!(e.span(), "{}", e);
+        }
+    }
+    field_attrs
+}
+
+fn quote_id(id: Option<u32>) -> TokenStream {
+    match id {
+        Some(id) => quote!(Some(#id)),
+        None => quote!(None),
+    }
+}
+
 const STD_TYPES: [&str; 23] = [
     "u8",
     "u16",
@@ -127,7 +250,62 @@ const STD_TYPES: [&str; 23] = [
     "Decimal",
 ];
 
-// TODO: Handle generics
+/// Expression evaluating to the field type name as stored in a schema: as written for std types and
+/// Reflect::type_name() for all the others, so that it matches the key in TypeCollection.refs.
+/// Generic arguments of std types are rendered as well (`Vec<u32>`, `HashMap<String,crate::Foo>`),
+/// as are arrays (`[u8;4]`) and tuples (`(u32,String)`), without any whitespace so that names can be compared.
+fn field_ty(non_std_types: &mut Vec<Path>, ty: &Type) -> TokenStream {
+    match ty {
+        Type::Array(array) => {
+            let elem = field_ty(non_std_types, &array.elem);
+            let len = &array.len;
+            return quote!(format!("[{};{}]", #elem, #len));
+        }
+        Type::Tuple(tuple) => {
+            let elems: Vec<TokenStream> = tuple
+                .elems
+                .iter()
+                .map(|elem| field_ty(non_std_types, elem))
+                .collect();
+            return if elems.is_empty() {
+                quote!("()".to_string())
+            } else if elems.len() == 1 {
+                quote!(format!("({},)", #(#elems),*))
+            } else {
+                quote!(format!("({})", [#(#elems),*].join(",")))
+            };
+        }
+        Type::Paren(paren) => return field_ty(non_std_types, &paren.elem),
+        Type::Group(group) => return field_ty(non_std_types, &group.elem),
+        _ => {}
+    }
+    let ty_str = ty_to_str(ty);
+    let Type::Path(p) = ty else {
+        abort!(ty.span(), "Only paths, arrays and tuples are supported now");
+    };
+    if !STD_TYPES.contains(&ty_str.as_str()) {
+        non_std_types.push(p.path.clone());
+        let path = &p.path;
+        return quote!(<#path as hills_base::Reflect>::type_name().to_string());
+    }
+    let mut args = Vec::new();
+    if let Some(PathArguments::AngleBracketed(generic_args)) =
+        p.path.segments.last().map(|s| &s.arguments)
+    {
+        for arg in &generic_args.args {
+            if let GenericArgument::Type(arg_ty) = arg {
+                args.push(field_ty(non_std_types, arg_ty));
+            }
+        }
+    }
+    if args.is_empty() {
+        quote!(#ty_str.to_string())
+    } else {
+        quote!(format!("{}<{}>", #ty_str, [#(#args),*].join(",")))
+    }
+}
+
+/// Type path without generic arguments.
 fn ty_to_str(ty: &Type) -> String {
     match ty {
         Type::Path(path) => {
@@ -147,7 +325,7 @@ fn ty_to_str(ty: &Type) -> String {
             path_str
         }
         u => {
-            abort!(u.span(), "Only Path types are supported now");
+            abort!(u.span(), "Only paths, arrays and tuples are supported now");
         }
     }
 }
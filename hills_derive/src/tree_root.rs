@@ -0,0 +1,82 @@
+use proc_macro2::Span;
+use proc_macro_error::abort;
+use syn::{Attribute, LitStr};
+
+/// Tree settings from the `#[tree(..)]` attribute.
+pub struct TreeAttrs {
+    pub name: String,
+    pub evolution: (u16, u16),
+    pub versioning: bool,
+}
+
+/// Parse `#[tree(name = "items", evolution = "0.1", versioning)]`, only the name is required.
+pub fn tree_attrs(attrs: &[Attribute], span: Span) -> TreeAttrs {
+    let mut name = None;
+    let mut evolution = (0, 0);
+    let mut versioning = false;
+    for attr in attrs {
+        if !attr.path().is_ident("tree") {
+            continue;
+        }
+        let r = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                let lit: LitStr = meta.value()?.parse()?;
+                if let Err(e) = validate_tree_name(&lit.value()) {
+                    return Err(syn::Error::new(lit.span(), e));
+                }
+                name = Some(lit.value());
+                Ok(())
+            } else if meta.path.is_ident("evolution") {
+                let lit: LitStr = meta.value()?.parse()?;
+                evolution = parse_evolution(&lit.value()).ok_or_else(|| {
+                    syn::Error::new(lit.span(), "evolution must be \"major.minor\"")
+                })?;
+                Ok(())
+            } else if meta.path.is_ident("versioning") {
+                versioning = true;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported tree attribute"))
+            }
+        });
+        if let Err(e) = r {
+            abort!(e.span(), "{}", e);
+        }
+    }
+    let Some(name) = name else {
+        abort!(span, "Tree name is required: #[tree(name = \"...\")]");
+    };
+    TreeAttrs {
+        name,
+        evolution,
+        versioning,
+    }
+}
+
+/// Same rules as HillsClient::open_tree checks at runtime, plus the characters that are safe to use in
+/// the internal keys built from tree names: names starting with '_' are reserved for internal trees
+/// and '/' separates a tree name from the rest of such keys.
+fn validate_tree_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Tree name cannot be empty".to_string());
+    }
+    if name.starts_with('_') {
+        return Err(format!(
+            "Tree name \"{name}\" cannot start with '_', such names are reserved for internal trees"
+        ));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')))
+    {
+        return Err(format!(
+            "Tree name \"{name}\" contains {c:?}, only ASCII letters, digits, '_', '-' and '.' are allowed"
+        ));
+    }
+    Ok(())
+}
+
+fn parse_evolution(evolution: &str) -> Option<(u16, u16)> {
+    let (major, minor) = evolution.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
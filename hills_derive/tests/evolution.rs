@@ -26,7 +26,7 @@ mod evolving {
         #[derive(Reflect)]
         pub struct MyStruct {
             _z: u32,
-            _y: u32,
+            _y: Option<u32>,
         }
 
         #[derive(Reflect)]
@@ -38,6 +38,16 @@ mod evolving {
         }
     }
 
+    pub mod ev0_1d {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct MyStruct {
+            _x: u32,
+            _y: u32,
+        }
+    }
+
     pub mod ev0_1b {
         use super::*;
 
@@ -55,6 +65,277 @@ mod evolving {
         }
     }
 
+    pub mod nested0_0 {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _inner: Inner,
+        }
+
+        #[derive(Reflect)]
+        pub struct Inner {
+            _x: u32,
+        }
+    }
+
+    pub mod nested0_1a {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _inner: Inner,
+            _added: Option<u8>,
+        }
+
+        #[derive(Reflect)]
+        pub struct Inner {
+            _renamed: u32,
+        }
+    }
+
+    pub mod nested0_1b {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _inner: Inner,
+        }
+
+        #[derive(Reflect)]
+        pub struct Inner {
+            _x: u32,
+            _y: u32,
+        }
+    }
+
+    pub mod generic0_0 {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _list: Vec<Inner>,
+        }
+
+        #[derive(Reflect)]
+        pub struct Inner {
+            _x: u32,
+        }
+    }
+
+    pub mod generic0_1a {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _renamed: Vec<Inner>,
+        }
+
+        #[derive(Reflect)]
+        pub struct Inner {
+            _x: u32,
+        }
+    }
+
+    pub mod generic0_1b {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _list: Vec<u32>,
+        }
+    }
+
+    pub mod tuple0_0 {
+        use super::*;
+
+        #[allow(dead_code)]
+        #[derive(Reflect)]
+        pub struct Wrapper(u32, String);
+    }
+
+    pub mod tuple0_1a {
+        use super::*;
+
+        #[allow(dead_code)]
+        #[derive(Reflect)]
+        pub struct Wrapper(u32, String, #[reflect(default)] u8);
+    }
+
+    pub mod tuple0_1b {
+        use super::*;
+
+        #[allow(dead_code)]
+        #[derive(Reflect)]
+        pub struct Wrapper(u32, u64);
+    }
+
+    pub mod unit0_0 {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Marker;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _marker: Marker,
+        }
+    }
+
+    pub mod unit0_1 {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Marker {
+            _x: Option<u32>,
+        }
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _marker: Marker,
+        }
+    }
+
+    pub mod reorder0_0 {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Point {
+            _x: u32,
+            _y: u32,
+        }
+    }
+
+    pub mod reorder0_1 {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Point {
+            _y: u32,
+            _x: u32,
+        }
+    }
+
+    pub mod ordinal0_0 {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Point {
+            #[reflect(id = 1)]
+            _x: u32,
+            #[reflect(id = 2)]
+            _y: u32,
+        }
+    }
+
+    pub mod ordinal0_1a {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Point {
+            #[reflect(id = 1)]
+            _renamed: u32,
+            #[reflect(id = 2)]
+            _y: u32,
+            #[reflect(id = 3, default)]
+            _z: u32,
+        }
+    }
+
+    pub mod ordinal0_1b {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Point {
+            #[reflect(id = 2)]
+            _y: u32,
+            #[reflect(id = 1)]
+            _x: u32,
+        }
+    }
+
+    pub mod array0_0 {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _samples: [Inner; 4],
+            _pair: (u32, Vec<Inner>),
+        }
+
+        #[derive(Reflect)]
+        pub struct Inner {
+            _x: u32,
+        }
+    }
+
+    pub mod array0_1a {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _samples: [Inner; 4],
+            _pair: (u32, Vec<Inner>),
+        }
+
+        #[derive(Reflect)]
+        pub struct Inner {
+            _renamed: u32,
+        }
+    }
+
+    pub mod array0_1b {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _samples: [Inner; 8],
+            _pair: (u32, Vec<Inner>),
+        }
+
+        #[derive(Reflect)]
+        pub struct Inner {
+            _x: u32,
+        }
+    }
+
+    pub mod array0_1c {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Outer {
+            _samples: [Inner; 4],
+            _pair: (u32, Vec<u16>),
+        }
+
+        #[derive(Reflect)]
+        pub struct Inner {
+            _x: u32,
+        }
+    }
+
+    pub mod ordinal0_1c {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Point {
+            _y: u32,
+            #[reflect(id = 1)]
+            _x: u32,
+        }
+    }
+
+    pub mod ordinal1_0 {
+        use super::*;
+
+        #[derive(Reflect)]
+        pub struct Point {
+            #[reflect(id = 1)]
+            _x: u32,
+            _y: u32,
+        }
+    }
+
     pub mod ev0_1c {
         use super::*;
 
@@ -87,6 +368,11 @@ fn struct_evolution() {
     assert_ne!(tc_ev0_0, tc_ev0_1b);
     // Cannot change field types.
     assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1b));
+
+    let mut tc_ev0_1d = TypeCollection::new();
+    evolving::ev0_1d::MyStruct::reflect(&mut tc_ev0_1d);
+    // Added fields must be optional, older records do not have them.
+    assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1d));
 }
 
 #[test]
@@ -113,3 +399,135 @@ fn enum_evolution() {
     // Adding enum fields if forbidden.
     assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1c));
 }
+
+#[test]
+fn nested_evolution() {
+    let mut tc_ev0_0 = TypeCollection::new();
+    evolving::nested0_0::Outer::reflect(&mut tc_ev0_0);
+
+    let mut tc_ev0_1a = TypeCollection::new();
+    evolving::nested0_1a::Outer::reflect(&mut tc_ev0_1a);
+    // Nested types from another module are compared by shape, not by name.
+    assert!(is_backwards_compatible(&tc_ev0_0, &tc_ev0_1a));
+
+    let mut tc_ev0_1b = TypeCollection::new();
+    evolving::nested0_1b::Outer::reflect(&mut tc_ev0_1b);
+    // Nested types are stored inline, so they cannot get new fields.
+    assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1b));
+}
+
+#[test]
+fn generic_evolution() {
+    let mut tc_ev0_0 = TypeCollection::new();
+    evolving::generic0_0::Outer::reflect(&mut tc_ev0_0);
+
+    let mut tc_ev0_1a = TypeCollection::new();
+    evolving::generic0_1a::Outer::reflect(&mut tc_ev0_1a);
+    assert!(is_backwards_compatible(&tc_ev0_0, &tc_ev0_1a));
+
+    let mut tc_ev0_1b = TypeCollection::new();
+    evolving::generic0_1b::Outer::reflect(&mut tc_ev0_1b);
+    // Element type changes are detected.
+    assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1b));
+}
+
+#[test]
+fn tuple_struct_evolution() {
+    let mut tc_ev0_0 = TypeCollection::new();
+    evolving::tuple0_0::Wrapper::reflect(&mut tc_ev0_0);
+
+    let mut tc_ev0_1a = TypeCollection::new();
+    evolving::tuple0_1a::Wrapper::reflect(&mut tc_ev0_1a);
+    assert_ne!(tc_ev0_0, tc_ev0_1a);
+    // Can add trailing fields.
+    assert!(is_backwards_compatible(&tc_ev0_0, &tc_ev0_1a));
+    // But cannot remove them.
+    assert!(!is_backwards_compatible(&tc_ev0_1a, &tc_ev0_0));
+
+    let mut tc_ev0_1b = TypeCollection::new();
+    evolving::tuple0_1b::Wrapper::reflect(&mut tc_ev0_1b);
+    // Cannot change field types.
+    assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1b));
+}
+
+#[test]
+fn unit_struct_evolution() {
+    let mut tc_ev0_0 = TypeCollection::new();
+    evolving::unit0_0::Marker::reflect(&mut tc_ev0_0);
+    let mut tc_ev0_1 = TypeCollection::new();
+    evolving::unit0_1::Marker::reflect(&mut tc_ev0_1);
+    // Unit root struct can get fields, as any other root struct.
+    assert!(is_backwards_compatible(&tc_ev0_0, &tc_ev0_1));
+    assert!(!is_backwards_compatible(&tc_ev0_1, &tc_ev0_0));
+
+    let mut tc_ev0_0 = TypeCollection::new();
+    evolving::unit0_0::Outer::reflect(&mut tc_ev0_0);
+    let mut tc_ev0_1 = TypeCollection::new();
+    evolving::unit0_1::Outer::reflect(&mut tc_ev0_1);
+    // But not when nested, since it is stored inline.
+    assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1));
+}
+
+#[test]
+fn array_and_tuple_evolution() {
+    let mut tc_ev0_0 = TypeCollection::new();
+    evolving::array0_0::Outer::reflect(&mut tc_ev0_0);
+
+    let mut tc_ev0_1a = TypeCollection::new();
+    evolving::array0_1a::Outer::reflect(&mut tc_ev0_1a);
+    // Element types are compared by shape, as any other nested type.
+    assert!(is_backwards_compatible(&tc_ev0_0, &tc_ev0_1a));
+
+    let mut tc_ev0_1b = TypeCollection::new();
+    evolving::array0_1b::Outer::reflect(&mut tc_ev0_1b);
+    // Array length is part of the type.
+    assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1b));
+
+    let mut tc_ev0_1c = TypeCollection::new();
+    evolving::array0_1c::Outer::reflect(&mut tc_ev0_1c);
+    // Changes inside tuple elements are detected.
+    assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1c));
+}
+
+/// Fields are matched by position, because archived data is laid out in declaration order.
+/// Swapping two fields of the same type looks compatible, but old records would be read with the values swapped.
+#[test]
+fn reordering_fields_is_not_detected_by_position() {
+    let mut tc_ev0_0 = TypeCollection::new();
+    evolving::reorder0_0::Point::reflect(&mut tc_ev0_0);
+    let mut tc_ev0_1 = TypeCollection::new();
+    evolving::reorder0_1::Point::reflect(&mut tc_ev0_1);
+    assert_ne!(tc_ev0_0, tc_ev0_1);
+    assert!(is_backwards_compatible(&tc_ev0_0, &tc_ev0_1));
+}
+
+#[test]
+fn ordinal_evolution() {
+    let mut tc_ev0_0 = TypeCollection::new();
+    evolving::ordinal0_0::Point::reflect(&mut tc_ev0_0);
+
+    let mut tc_ev0_1a = TypeCollection::new();
+    evolving::ordinal0_1a::Point::reflect(&mut tc_ev0_1a);
+    // Renaming and adding fields with new ordinals is still allowed.
+    assert!(is_backwards_compatible(&tc_ev0_0, &tc_ev0_1a));
+
+    let mut tc_ev0_1b = TypeCollection::new();
+    evolving::ordinal0_1b::Point::reflect(&mut tc_ev0_1b);
+    // Ordinals catch the reordering that positions alone cannot.
+    assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev0_1b));
+}
+
+#[test]
+fn reordering_partially_pinned_fields_is_detected() {
+    let mut tc_ev1_0 = TypeCollection::new();
+    evolving::ordinal1_0::Point::reflect(&mut tc_ev1_0);
+    let mut tc_ev1_1 = TypeCollection::new();
+    evolving::ordinal0_1c::Point::reflect(&mut tc_ev1_1);
+    // Field with an ordinal swapped places with one without it.
+    assert!(!is_backwards_compatible(&tc_ev1_0, &tc_ev1_1));
+
+    let mut tc_ev0_0 = TypeCollection::new();
+    evolving::reorder0_0::Point::reflect(&mut tc_ev0_0);
+    // Ordinal cannot be added to a field later on, it would vouch for a position that was never checked.
+    assert!(!is_backwards_compatible(&tc_ev0_0, &tc_ev1_0));
+}
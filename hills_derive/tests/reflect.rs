@@ -1,5 +1,6 @@
-use hills_base::{EnumFields, Reflect, StructField, TypeCollection, TypeInfo};
+use hills_base::{EnumFields, GenericKey, Reflect, StructField, TypeCollection, TypeInfo};
 use hills_derive::Reflect;
+use std::collections::HashMap;
 
 #[derive(Reflect)]
 struct MyStruct {
@@ -13,6 +14,60 @@ struct NonStandard {
     _z: u32,
 }
 
+#[derive(Reflect)]
+struct WithForeign {
+    _key: GenericKey,
+}
+
+mod a {
+    use hills_derive::Reflect;
+
+    #[derive(Reflect)]
+    pub struct Foo {
+        _x: u32,
+    }
+}
+
+mod b {
+    use hills_derive::Reflect;
+
+    #[derive(Reflect)]
+    pub struct Foo {
+        _y: String,
+    }
+}
+
+#[derive(Reflect)]
+struct WithSameNamed {
+    _a: a::Foo,
+    _b: b::Foo,
+}
+
+#[derive(Reflect)]
+#[allow(dead_code)]
+struct Wrapper(u32, String, NonStandard);
+
+#[derive(Reflect)]
+struct Marker;
+
+#[derive(Reflect)]
+struct Empty {}
+
+#[derive(Reflect)]
+struct WithGenerics {
+    _list: Vec<u32>,
+    _map: HashMap<String, NonStandard>,
+    _nested: Option<Vec<a::Foo>>,
+}
+
+#[derive(Reflect)]
+struct WithArrays {
+    _bytes: [u8; 4],
+    _pair: (u32, String),
+    _nested: Option<[(u8, NonStandard); 2]>,
+    _single: (u8,),
+}
+
 #[derive(Reflect)]
 enum MyEnum {
     _A,
@@ -25,8 +80,8 @@ fn struct_test() {
     let mut tc = TypeCollection::new();
     MyStruct::reflect(&mut tc);
     // println!("{tc:#?}");
-    assert_eq!(tc.root, "MyStruct");
-    let my_struct = tc.refs.get("MyStruct").unwrap();
+    assert_eq!(tc.root, "reflect::MyStruct");
+    let my_struct = tc.refs.get("reflect::MyStruct").unwrap();
     assert!(matches!(my_struct, TypeInfo::Struct(_)));
     if let TypeInfo::Struct(s) = my_struct {
         let mut fields = s.fields.iter();
@@ -38,10 +93,10 @@ fn struct_test() {
         assert_eq!(field_y.ty, "u32");
         let field_z = fields.next().unwrap();
         assert_eq!(field_z.ident, "_z");
-        assert_eq!(field_z.ty, "NonStandard");
+        assert_eq!(field_z.ty, "reflect::NonStandard");
     }
 
-    let non_standard = tc.refs.get("NonStandard").unwrap();
+    let non_standard = tc.refs.get("reflect::NonStandard").unwrap();
     assert!(matches!(non_standard, TypeInfo::Struct(_)));
     if let TypeInfo::Struct(s) = non_standard {
         let mut fields = s.fields.iter();
@@ -56,7 +111,7 @@ fn enum_test() {
     let mut tc = TypeCollection::new();
     MyEnum::reflect(&mut tc);
     // println!("{tc:#?}");
-    let my_enum = tc.refs.get("MyEnum").unwrap();
+    let my_enum = tc.refs.get("reflect::MyEnum").unwrap();
     assert!(matches!(my_enum, TypeInfo::Enum(_)));
     if let TypeInfo::Enum(e) = my_enum {
         let mut variants = e.variants.iter();
@@ -79,11 +134,17 @@ fn enum_test() {
                 [
                     StructField {
                         ident: "x".into(),
-                        ty: "u8".to_string()
+                        ty: "u8".to_string(),
+                        id: None,
+                        default: false,
+                        doc: String::new(),
                     },
                     StructField {
                         ident: "y".into(),
-                        ty: "u16".to_string()
+                        ty: "u16".to_string(),
+                        id: None,
+                        default: false,
+                        doc: String::new(),
                     }
                 ]
                 .into()
@@ -91,3 +152,250 @@ fn enum_test() {
         )
     }
 }
+
+#[test]
+fn foreign_type_test() {
+    let mut tc = TypeCollection::new();
+    WithForeign::reflect(&mut tc);
+    assert_eq!(tc.root, "reflect::WithForeign");
+    let generic_key = tc.refs.get("GenericKey").unwrap();
+    let TypeInfo::Struct(s) = generic_key else {
+        panic!("GenericKey must be reflected as a struct");
+    };
+    assert_eq!(s.fields.len(), 2);
+    assert_eq!(s.fields[0].ident, "id");
+    assert_eq!(s.fields[0].ty, "u32");
+}
+
+#[test]
+fn same_named_types_test() {
+    let mut tc = TypeCollection::new();
+    WithSameNamed::reflect(&mut tc);
+    assert_eq!(tc.refs.len(), 3);
+    let TypeInfo::Struct(s) = tc.refs.get(tc.root.as_str()).unwrap() else {
+        panic!("WithSameNamed must be reflected as a struct");
+    };
+    assert_eq!(s.fields[0].ty, "reflect::a::Foo");
+    assert_eq!(s.fields[1].ty, "reflect::b::Foo");
+    let TypeInfo::Struct(a_foo) = tc.refs.get("reflect::a::Foo").unwrap() else {
+        panic!("a::Foo must be reflected as a struct");
+    };
+    assert_eq!(a_foo.fields[0].ty, "u32");
+    let TypeInfo::Struct(b_foo) = tc.refs.get("reflect::b::Foo").unwrap() else {
+        panic!("b::Foo must be reflected as a struct");
+    };
+    assert_eq!(b_foo.fields[0].ty, "String");
+}
+
+#[test]
+fn generic_arguments_test() {
+    let mut tc = TypeCollection::new();
+    WithGenerics::reflect(&mut tc);
+    let TypeInfo::Struct(s) = tc.refs.get(tc.root.as_str()).unwrap() else {
+        panic!("WithGenerics must be reflected as a struct");
+    };
+    assert_eq!(s.fields[0].ty, "Vec<u32>");
+    assert_eq!(s.fields[1].ty, "HashMap<String,reflect::NonStandard>");
+    assert_eq!(s.fields[2].ty, "Option<Vec<reflect::a::Foo>>");
+    assert!(tc.refs.contains_key("reflect::NonStandard"));
+    assert!(tc.refs.contains_key("reflect::a::Foo"));
+}
+
+#[test]
+fn arrays_and_tuples_test() {
+    let mut tc = TypeCollection::new();
+    WithArrays::reflect(&mut tc);
+    let TypeInfo::Struct(s) = tc.refs.get(tc.root.as_str()).unwrap() else {
+        panic!("WithArrays must be reflected as a struct");
+    };
+    let tys: Vec<&str> = s.fields.iter().map(|f| f.ty.as_str()).collect();
+    assert_eq!(
+        tys,
+        [
+            "[u8;4]",
+            "(u32,String)",
+            "Option<[(u8,reflect::NonStandard);2]>",
+            "(u8,)"
+        ]
+    );
+    assert!(tc.refs.contains_key("reflect::NonStandard"));
+}
+
+#[test]
+fn tuple_struct_test() {
+    let mut tc = TypeCollection::new();
+    Wrapper::reflect(&mut tc);
+    assert_eq!(tc.root, "reflect::Wrapper");
+    let TypeInfo::Struct(s) = tc.refs.get("reflect::Wrapper").unwrap() else {
+        panic!("Wrapper must be reflected as a struct");
+    };
+    let fields: Vec<(&str, &str)> = s
+        .fields
+        .iter()
+        .map(|f| (f.ident.as_str(), f.ty.as_str()))
+        .collect();
+    assert_eq!(
+        fields,
+        [("0", "u32"), ("1", "String"), ("2", "reflect::NonStandard")]
+    );
+    assert!(tc.refs.contains_key("reflect::NonStandard"));
+}
+
+#[test]
+fn unit_struct_test() {
+    let mut tc = TypeCollection::new();
+    Marker::reflect(&mut tc);
+    Empty::reflect(&mut tc);
+    assert_eq!(tc.root, "reflect::Marker");
+    for name in ["reflect::Marker", "reflect::Empty"] {
+        let TypeInfo::Struct(s) = tc.refs.get(name).unwrap() else {
+            panic!("{name} must be reflected as a struct");
+        };
+        assert!(s.fields.is_empty());
+    }
+}
+
+#[derive(Reflect)]
+struct Meters;
+
+#[derive(Reflect)]
+struct Measurement<U> {
+    _value: u32,
+    _unit: U,
+    _history: Vec<U>,
+}
+
+#[derive(Reflect)]
+enum Reading<U> {
+    _Missing,
+    _Taken(Measurement<U>),
+}
+
+#[test]
+fn generic_type_test() {
+    let mut tc = TypeCollection::new();
+    Reading::<Meters>::reflect(&mut tc);
+    assert_eq!(tc.root, "reflect::Reading<reflect::Meters>");
+    assert_eq!(
+        Measurement::<Meters>::type_name(),
+        "reflect::Measurement<reflect::Meters>"
+    );
+    let TypeInfo::Enum(e) = tc.refs.get(tc.root.as_str()).unwrap() else {
+        panic!("Reading must be reflected as an enum");
+    };
+    assert_eq!(
+        e.variants[1].fields,
+        EnumFields::Unnamed(["reflect::Measurement<reflect::Meters>".into()].into())
+    );
+    let TypeInfo::Struct(s) = tc
+        .refs
+        .get("reflect::Measurement<reflect::Meters>")
+        .unwrap()
+    else {
+        panic!("Measurement must be reflected as a struct");
+    };
+    let tys: Vec<&str> = s.fields.iter().map(|f| f.ty.as_str()).collect();
+    assert_eq!(tys, ["u32", "reflect::Meters", "Vec<reflect::Meters>"]);
+    assert!(tc.refs.contains_key("reflect::Meters"));
+}
+
+/// Point on a map.
+///
+/// Second paragraph.
+#[derive(Reflect)]
+struct Documented {
+    /// Degrees north
+    _lat: f32,
+    _lon: f32,
+}
+
+/// Where the point came from.
+#[derive(Reflect)]
+enum DocumentedEnum {
+    /// Entered by hand
+    _Manual,
+    _Gps {
+        #[doc = "Meters"]
+        _accuracy: f32,
+    },
+}
+
+#[test]
+fn doc_comments_test() {
+    let mut tc = TypeCollection::new();
+    Documented::reflect(&mut tc);
+    DocumentedEnum::reflect(&mut tc);
+    let TypeInfo::Struct(s) = tc.refs.get("reflect::Documented").unwrap() else {
+        panic!("Documented must be reflected as a struct");
+    };
+    assert_eq!(s.doc, "Point on a map.\n\nSecond paragraph.");
+    assert_eq!(s.fields[0].doc, "Degrees north");
+    assert_eq!(s.fields[1].doc, "");
+
+    let TypeInfo::Enum(e) = tc.refs.get("reflect::DocumentedEnum").unwrap() else {
+        panic!("DocumentedEnum must be reflected as an enum");
+    };
+    assert_eq!(e.doc, "Where the point came from.");
+    assert_eq!(e.variants[0].doc, "Entered by hand");
+    assert_eq!(e.variants[1].doc, "");
+    let EnumFields::Named(fields) = &e.variants[1].fields else {
+        panic!("_Gps has named fields");
+    };
+    assert_eq!(fields[0].doc, "Meters");
+}
+
+/// Not Reflect, only used in skipped fields.
+#[derive(Default)]
+struct Cache;
+
+#[derive(Reflect)]
+struct WithSkipped {
+    _name: String,
+    #[reflect(skip)]
+    _cache: Cache,
+    #[reflect(rename = "count")]
+    _count: u32,
+}
+
+#[derive(Reflect)]
+enum WithSkippedVariants {
+    _Named {
+        #[reflect(skip)]
+        _cache: Cache,
+        #[reflect(rename = "x")]
+        _x: u8,
+    },
+    _Unnamed(#[reflect(skip)] Cache, NonStandard),
+}
+
+#[test]
+fn skip_and_rename_test() {
+    let mut tc = TypeCollection::new();
+    WithSkipped::reflect(&mut tc);
+    let TypeInfo::Struct(s) = tc.refs.get(tc.root.as_str()).unwrap() else {
+        panic!("WithSkipped must be reflected as a struct");
+    };
+    let fields: Vec<(&str, &str)> = s
+        .fields
+        .iter()
+        .map(|f| (f.ident.as_str(), f.ty.as_str()))
+        .collect();
+    assert_eq!(fields, [("_name", "String"), ("count", "u32")]);
+    assert_eq!(tc.refs.len(), 1);
+
+    let mut tc = TypeCollection::new();
+    WithSkippedVariants::reflect(&mut tc);
+    let TypeInfo::Enum(e) = tc.refs.get(tc.root.as_str()).unwrap() else {
+        panic!("WithSkippedVariants must be reflected as an enum");
+    };
+    let EnumFields::Named(named) = &e.variants[0].fields else {
+        panic!("_Named has named fields");
+    };
+    assert_eq!(named.len(), 1);
+    assert_eq!(named[0].ident, "x");
+    assert_eq!(
+        e.variants[1].fields,
+        EnumFields::Unnamed(["reflect::NonStandard".into()].into())
+    );
+    assert_eq!(tc.refs.len(), 2);
+}
@@ -0,0 +1,27 @@
+use hills_base::{SimpleVersion, TreeRoot};
+use hills_derive::TreeRoot;
+
+#[derive(TreeRoot)]
+#[tree(name = "items")]
+struct Item;
+
+#[derive(TreeRoot)]
+#[tree(name = "notes.v2", evolution = "1.2", versioning)]
+struct Note;
+
+#[test]
+fn tree_root_derive() {
+    assert_eq!(Item::tree_name(), "items");
+    assert_eq!(Item::evolution(), SimpleVersion::new(0, 0));
+    assert!(!Item::versioning());
+
+    assert_eq!(Note::tree_name(), "notes.v2");
+    assert_eq!(Note::evolution(), SimpleVersion::new(1, 2));
+    assert!(Note::versioning());
+}
+
+#[test]
+fn invalid_tree_names() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/tree_name_*.rs");
+}
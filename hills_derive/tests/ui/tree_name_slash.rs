@@ -0,0 +1,7 @@
+use hills_derive::TreeRoot;
+
+#[derive(TreeRoot)]
+#[tree(name = "items/old")]
+struct Bad;
+
+fn main() {}
@@ -0,0 +1,7 @@
+use hills_derive::TreeRoot;
+
+#[derive(TreeRoot)]
+#[tree(name = "_bad")]
+struct Bad;
+
+fn main() {}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use hills::sync_server::HillsServer;
+use hills::OpenMode;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
@@ -20,7 +21,15 @@ fn main() -> Result<()> {
     let db_name = args.next().unwrap();
     let db_path = std::path::Path::new(&db_name);
 
-    let server = HillsServer::start(db_path, "0.0.0.0:7070", &runtime)?;
+    let upstream = args.next().map(|addr| addr.parse()).transpose()?;
+
+    let server = HillsServer::start(
+        db_path,
+        OpenMode::Persistent,
+        "0.0.0.0:7070",
+        upstream,
+        &runtime,
+    )?;
     runtime.block_on(server.join)?;
     Ok(())
 }